@@ -0,0 +1,70 @@
+// Baselines for the hot paths: raw CPU throughput on a synthetic loop with
+// rendering off, and whole frames of a ROM that renders.
+//   cargo bench
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use korones::Emu;
+
+const INSTRUCTIONS: u64 = 10_000;
+// nestest draws its menu by then
+const WARMUP_FRAMES: usize = 10;
+
+// NROM running a loop of loads, adds, indexed stores and branches from reset
+fn loop_rom() -> Vec<u8> {
+    let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x00, 0x00];
+    rom.resize(16, 0);
+    let mut prg = vec![0xEA; 0x4000];
+    #[rustfmt::skip]
+    let program = [
+        0xB5, 0x10,             // LDA $10,X
+        0x69, 0x01,             // ADC #1
+        0x9D, 0x00, 0x02,       // STA $0200,X
+        0xE8,                   // INX
+        0xD0, 0xF6,             // BNE $8000
+        0x4C, 0x00, 0x80,       // JMP $8000
+    ];
+    prg[..program.len()].copy_from_slice(&program);
+    prg[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0x80]);
+    rom.extend_from_slice(&prg);
+    rom.resize(rom.len() + 0x2000, 0);
+    rom
+}
+
+fn cpu(c: &mut Criterion) {
+    let mut emu = Emu::new();
+    emu.load_rom(&loop_rom()).unwrap();
+    emu.set_headless(true);
+
+    let mut group = c.benchmark_group("cpu");
+    group.throughput(Throughput::Elements(INSTRUCTIONS));
+    group.bench_function("instructions", |b| {
+        b.iter(|| {
+            for _ in 0..INSTRUCTIONS {
+                emu.step_instruction();
+            }
+        })
+    });
+    group.finish();
+}
+
+fn frames(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frames");
+    group.throughput(Throughput::Elements(1));
+
+    let mut emu = Emu::new();
+    emu.load_rom(&loop_rom()).unwrap();
+    emu.set_headless(true);
+    group.bench_function("headless", |b| b.iter(|| emu.run_frame()));
+
+    let mut emu = Emu::new();
+    emu.load_rom_path("roms/nestest.nes").unwrap();
+    for _ in 0..WARMUP_FRAMES {
+        emu.run_frame();
+    }
+    group.bench_function("rendering", |b| b.iter(|| emu.run_frame()));
+    group.finish();
+}
+
+criterion_group!(benches, cpu, frames);
+criterion_main!(benches);
@@ -0,0 +1,132 @@
+use std::collections::VecDeque;
+use std::env;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use korones::{AudioSink, Button, Config, Emu};
+use minifb::{Key, KeyRepeat, Scale, Window, WindowOptions};
+
+// Keys of players 1 and 2
+const KEYS: [[(Key, Button); 8]; 2] = [
+    [
+        (Key::X, Button::A),
+        (Key::Z, Button::B),
+        (Key::RightShift, Button::Select),
+        (Key::Enter, Button::Start),
+        (Key::Up, Button::Up),
+        (Key::Down, Button::Down),
+        (Key::Left, Button::Left),
+        (Key::Right, Button::Right),
+    ],
+    [
+        (Key::G, Button::A),
+        (Key::F, Button::B),
+        (Key::Q, Button::Select),
+        (Key::E, Button::Start),
+        (Key::W, Button::Up),
+        (Key::S, Button::Down),
+        (Key::A, Button::Left),
+        (Key::D, Button::Right),
+    ],
+];
+
+// Frames run per displayed frame while Tab is held
+const FAST_FORWARD: usize = 4;
+
+// Samples waiting for the audio device, shared with its callback
+#[derive(Clone, Default)]
+struct Queue(Arc<Mutex<VecDeque<i16>>>);
+
+impl AudioSink for Queue {
+    fn samples(&mut self, samples: &[i16]) {
+        self.0.lock().unwrap().extend(samples);
+    }
+}
+
+fn main() -> Result<()> {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => bail!("usage: korones-frontend <rom>"),
+    };
+
+    let device = cpal::default_host()
+        .default_output_device()
+        .context("no audio output device")?;
+    let config = device.default_output_config()?;
+    if config.sample_format() != cpal::SampleFormat::F32 {
+        bail!("unsupported sample format {:?}", config.sample_format());
+    }
+    let sample_rate = config.sample_rate().0;
+    let channels = config.channels();
+
+    let mut emu = Emu::with_config(Config {
+        sample_rate,
+        channels,
+        ..Default::default()
+    });
+    emu.load_rom_path(&path)?;
+    let queue = Queue::default();
+    emu.set_audio_sink(queue.clone());
+
+    let q = queue.clone();
+    let stream = device.build_output_stream(
+        &config.into(),
+        move |data: &mut [f32], _| {
+            let mut q = q.0.lock().unwrap();
+            for s in data {
+                *s = q.pop_front().map_or(0.0, |v| v as f32 / 32768.0);
+            }
+        },
+        |e| eprintln!("audio error: {}", e),
+        None,
+    )?;
+    stream.play()?;
+    // keep at most ~100ms buffered, dropping what fast-forward produces beyond that
+    let max_queued = (sample_rate / 10) as usize * channels as usize;
+
+    let mut window = Window::new(
+        "korones",
+        256,
+        240,
+        WindowOptions {
+            scale: Scale::X2,
+            ..WindowOptions::default()
+        },
+    )?;
+    window.set_target_fps(emu.region().frame_rate().round() as usize);
+
+    let mut saved = None;
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+        for (port, keys) in KEYS.iter().enumerate() {
+            for &(key, button) in keys {
+                emu.set_button(port, button, window.is_key_down(key));
+            }
+        }
+        if window.is_key_pressed(Key::R, KeyRepeat::No) {
+            emu.reset();
+        }
+        if window.is_key_pressed(Key::F5, KeyRepeat::No) {
+            saved = Some(emu.save_state()?);
+        }
+        if let (true, Some(state)) = (window.is_key_pressed(Key::F7, KeyRepeat::No), &saved) {
+            emu.load_state(state)?;
+        }
+
+        let frames = if window.is_key_down(Key::Tab) {
+            FAST_FORWARD
+        } else {
+            1
+        };
+        for _ in 0..frames {
+            emu.run_frame();
+        }
+        {
+            let mut q = queue.0.lock().unwrap();
+            let excess = q.len().saturating_sub(max_queued);
+            q.drain(..excess);
+        }
+        window.update_with_buffer(emu.frame_buffer(), 256, 240)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use std::sync::{Arc, Mutex};
+
+use korones::{AudioSink, Config, Emu};
+use wasm_bindgen::prelude::*;
+
+// The browser has no clock the core could use: the page calls `run_frame` from
+// requestAnimationFrame (or an audio callback) and paces the emulation itself.
+#[wasm_bindgen]
+pub struct Nes {
+    emu: Emu,
+    samples: Arc<Mutex<Vec<i16>>>,
+    rgba: Vec<u8>,
+}
+
+struct Samples(Arc<Mutex<Vec<i16>>>);
+
+impl AudioSink for Samples {
+    fn samples(&mut self, samples: &[i16]) {
+        self.0.lock().unwrap().extend_from_slice(samples);
+    }
+}
+
+#[wasm_bindgen]
+impl Nes {
+    // `rom` is the .nes file fetched or picked by the page
+    #[wasm_bindgen(constructor)]
+    pub fn new(rom: &[u8], sample_rate: u32) -> Result<Nes, JsValue> {
+        let mut emu = Emu::with_config(Config {
+            sample_rate,
+            ..Default::default()
+        });
+        emu.load_rom(rom)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let samples = Arc::new(Mutex::new(Vec::new()));
+        emu.set_audio_sink(Samples(samples.clone()));
+        Ok(Nes {
+            emu,
+            samples,
+            rgba: vec![0; 256 * 240 * 4],
+        })
+    }
+
+    pub fn run_frame(&mut self) {
+        self.emu.run_frame();
+        for (px, &c) in self.rgba.chunks_exact_mut(4).zip(self.emu.frame_buffer()) {
+            px.copy_from_slice(&[(c >> 16) as u8, (c >> 8) as u8, c as u8, 0xFF]);
+        }
+    }
+
+    // The last frame as RGBA bytes, for `new ImageData(..., 256, 240)`
+    pub fn frame(&self) -> Vec<u8> {
+        self.rgba.clone()
+    }
+
+    // Mono samples produced since the last call, as floats for Web Audio
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.samples
+            .lock()
+            .unwrap()
+            .drain(..)
+            .map(|s| s as f32 / 32768.0)
+            .collect()
+    }
+
+    // One bit per button from A in bit 0 to Right in bit 7
+    pub fn set_controller(&mut self, port: usize, state: u8) {
+        self.emu.set_controller_state(port, state);
+    }
+
+    pub fn reset(&mut self) {
+        self.emu.reset();
+    }
+
+    pub fn save_state(&self) -> Result<Vec<u8>, JsValue> {
+        self.emu
+            .save_state()
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), JsValue> {
+        self.emu
+            .load_state(state)
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+}
@@ -0,0 +1,489 @@
+use anyhow::Result;
+
+use crate::config::Region;
+use crate::mapper::Mapper;
+use crate::nes::Nes;
+use crate::state::{StateReader, StateWriter};
+
+mod dmc;
+mod meter;
+mod noise;
+mod pulse;
+mod triangle;
+
+pub(crate) use meter::Meter;
+pub use meter::{Channel, ChannelLevel};
+
+use dmc::Dmc;
+use noise::Noise;
+use pulse::Pulse;
+use triangle::Triangle;
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, //
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// CPU cycles of the frame counter steps; the last one ends the sequence
+const NTSC_FOUR_STEP: [u32; 4] = [7457, 14913, 22371, 29829];
+const NTSC_FIVE_STEP: [u32; 5] = [7457, 14913, 22371, 29829, 37281];
+const PAL_FOUR_STEP: [u32; 4] = [8313, 16627, 24939, 33253];
+const PAL_FIVE_STEP: [u32; 5] = [8313, 16627, 24939, 33253, 41565];
+
+#[derive(Debug, Default)]
+struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    // constant volume, or the divider period
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, value: u8) {
+        self.looping = value & 0x20 != 0;
+        self.constant = value & 0x10 != 0;
+        self.volume = value & 0x0F;
+    }
+
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if 0 < self.decay {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.start);
+        w.write_bool(self.looping);
+        w.write_bool(self.constant);
+        w.write_u8(self.volume);
+        w.write_u8(self.divider);
+        w.write_u8(self.decay);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.start = r.read_bool()?;
+        self.looping = r.read_bool()?;
+        self.constant = r.read_bool()?;
+        self.volume = r.read_u8()?;
+        self.divider = r.read_u8()?;
+        self.decay = r.read_u8()?;
+        Ok(())
+    }
+
+    fn volume(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct LengthCounter {
+    enabled: bool,
+    halt: bool,
+    counter: u8,
+}
+
+impl LengthCounter {
+    fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[index as usize & 0x1F];
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_bool(self.halt);
+        w.write_u8(self.counter);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.enabled = r.read_bool()?;
+        self.halt = r.read_bool()?;
+        self.counter = r.read_u8()?;
+        Ok(())
+    }
+
+    fn clock(&mut self) {
+        if !self.halt && 0 < self.counter {
+            self.counter -= 1;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Apu {
+    pal: bool,
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+
+    // CPU cycles into the frame counter sequence
+    frame_cycle: u32,
+    five_step: bool,
+    irq_inhibit: bool,
+    pub(crate) frame_irq: bool,
+    // A $4017 write resets the sequence after a few cycles
+    pending_reset: Option<u8>,
+    odd_cycle: bool,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new(Region::Ntsc)
+    }
+}
+
+impl Apu {
+    pub(crate) fn new(region: Region) -> Self {
+        // Dendy uses NTSC rates
+        let pal = region == Region::Pal;
+        Self {
+            pal,
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Default::default(),
+            noise: Noise::new(pal),
+            dmc: Dmc::new(pal),
+            frame_cycle: 0,
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            pending_reset: None,
+            odd_cycle: false,
+        }
+    }
+
+    // Reset silences every channel as if by writing $4015 = 0 and restarts the
+    // frame counter in its last mode
+    pub(crate) fn reset(&mut self) {
+        self.write_register(0x4015, 0);
+        self.frame_irq = false;
+        self.pending_reset = Some(3);
+    }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        self.pulse1.save_state(w);
+        self.pulse2.save_state(w);
+        self.triangle.save_state(w);
+        self.noise.save_state(w);
+        self.dmc.save_state(w);
+        w.write_u32(self.frame_cycle);
+        w.write_bool(self.five_step);
+        w.write_bool(self.irq_inhibit);
+        w.write_bool(self.frame_irq);
+        // 0 for no pending reset
+        w.write_u8(self.pending_reset.unwrap_or(0));
+        w.write_bool(self.odd_cycle);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.pulse1.load_state(r)?;
+        self.pulse2.load_state(r)?;
+        self.triangle.load_state(r)?;
+        self.noise.load_state(r)?;
+        self.dmc.load_state(r)?;
+        self.frame_cycle = r.read_u32()?;
+        self.five_step = r.read_bool()?;
+        self.irq_inhibit = r.read_bool()?;
+        self.frame_irq = r.read_bool()?;
+        self.pending_reset = Some(r.read_u8()?).filter(|&n| n != 0);
+        self.odd_cycle = r.read_bool()?;
+        Ok(())
+    }
+
+    pub(crate) fn dmc_irq(&self) -> bool {
+        self.dmc.irq
+    }
+
+    // $4015 read
+    pub(crate) fn read_status(&mut self) -> u8 {
+        let v = self.peek_status();
+        self.frame_irq = false;
+        v
+    }
+
+    // $4015 without acknowledging the frame interrupt
+    pub(crate) fn peek_status(&self) -> u8 {
+        let mut v = 0;
+        v |= (0 < self.pulse1.length.counter) as u8;
+        v |= ((0 < self.pulse2.length.counter) as u8) << 1;
+        v |= ((0 < self.triangle.length.counter) as u8) << 2;
+        v |= ((0 < self.noise.length.counter) as u8) << 3;
+        v |= ((0 < self.dmc.remaining) as u8) << 4;
+        v |= (self.frame_irq as u8) << 6;
+        v |= (self.dmc.irq as u8) << 7;
+        v
+    }
+
+    // $4000-$4013, $4015 and $4017 writes
+    pub(crate) fn write_register(&mut self, addr: u16, value: u8) {
+        let reg = addr & 0x03;
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write(reg, value),
+            0x4004..=0x4007 => self.pulse2.write(reg, value),
+            0x4008..=0x400B => self.triangle.write(reg, value),
+            0x400C..=0x400F => self.noise.write(reg, value),
+            0x4010..=0x4013 => self.dmc.write(reg, value),
+            0x4015 => {
+                self.pulse1.length.set_enabled(value & 0x01 != 0);
+                self.pulse2.length.set_enabled(value & 0x02 != 0);
+                self.triangle.length.set_enabled(value & 0x04 != 0);
+                self.noise.length.set_enabled(value & 0x08 != 0);
+                self.dmc.set_enabled(value & 0x10 != 0);
+            }
+            0x4017 => {
+                self.five_step = value & 0x80 != 0;
+                self.irq_inhibit = value & 0x40 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+                // 3 cycles after the write on an even cycle, 4 on an odd one
+                self.pending_reset = Some(if self.odd_cycle { 4 } else { 3 });
+            }
+            _ => {}
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear();
+    }
+
+    fn clock_half_frame(&mut self) {
+        self.pulse1.length.clock();
+        self.pulse2.length.clock();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+        self.pulse1.clock_sweep();
+        self.pulse2.clock_sweep();
+    }
+
+    fn clock_frame_counter(&mut self) {
+        if let Some(n) = self.pending_reset {
+            if n <= 1 {
+                self.pending_reset = None;
+                self.frame_cycle = 0;
+                if self.five_step {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+                return;
+            }
+            self.pending_reset = Some(n - 1);
+        }
+
+        self.frame_cycle += 1;
+        let steps: &[u32] = match (self.pal, self.five_step) {
+            (false, false) => &NTSC_FOUR_STEP,
+            (false, true) => &NTSC_FIVE_STEP,
+            (true, false) => &PAL_FOUR_STEP,
+            (true, true) => &PAL_FIVE_STEP,
+        };
+        let step = match steps.iter().position(|&c| c == self.frame_cycle) {
+            Some(step) => step,
+            None => return,
+        };
+        // the five-step sequence has nothing on its fourth step
+        if self.five_step && step == 3 {
+            return;
+        }
+        let last = step == steps.len() - 1;
+        self.clock_quarter_frame();
+        if step == 1 || last {
+            self.clock_half_frame();
+        }
+        if last {
+            if !self.five_step && !self.irq_inhibit {
+                self.frame_irq = true;
+            }
+            self.frame_cycle = 0;
+        }
+    }
+
+    // Each channel's output, in `Channel` order
+    pub(crate) fn outputs(&self) -> [u8; 5] {
+        [
+            self.pulse1.output(),
+            self.pulse2.output(),
+            self.triangle.output(),
+            self.noise.output(),
+            self.dmc.output(),
+        ]
+    }
+
+    // Each channel's timer period and volume, for the meter
+    pub(crate) fn settings(&self) -> [(u16, u8); 5] {
+        [
+            (self.pulse1.period(), self.pulse1.volume()),
+            (self.pulse2.period(), self.pulse2.volume()),
+            (self.triangle.period(), self.triangle.volume()),
+            (self.noise.period(), self.noise.volume()),
+            (self.dmc.period(), self.dmc.output()),
+        ]
+    }
+
+    // Non-linear mix of the channels, 0.0 to 1.0
+    pub(crate) fn output(&self) -> f32 {
+        let pulse = (self.pulse1.output() + self.pulse2.output()) as f32;
+        let pulse_out = if pulse == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulse + 100.0)
+        };
+
+        let tnd = self.triangle.output() as f32 / 8227.0
+            + self.noise.output() as f32 / 12241.0
+            + self.dmc.output() as f32 / 22638.0;
+        let tnd_out = if tnd == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / tnd + 100.0)
+        };
+        pulse_out + tnd_out
+    }
+}
+
+// Advance the APU by one CPU cycle
+pub(crate) fn step(nes: &mut Nes) {
+    let apu = &mut nes.apu;
+    apu.clock_frame_counter();
+    if apu.odd_cycle {
+        apu.pulse1.clock_timer();
+        apu.pulse2.clock_timer();
+    }
+    apu.triangle.clock_timer();
+    apu.noise.clock_timer();
+    apu.dmc.clock_timer();
+    apu.odd_cycle = !apu.odd_cycle;
+
+    // the DMC fetches sample bytes from PRG space
+    if nes.apu.dmc.needs_fetch() {
+        let addr = nes.apu.dmc.addr;
+        let v = nes.mapper.read(addr).unwrap_or(nes.open_bus);
+        nes.apu.dmc.fill(v);
+    }
+    if let Some(meter) = &mut nes.meter {
+        meter.sample(nes.apu.outputs());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_length_counter() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write_register(0x4015, 0x01);
+        // length index 1 = 254
+        apu.write_register(0x4003, 0x08);
+        assert_eq!(apu.read_status() & 0x01, 0x01);
+        assert_eq!(apu.pulse1.length.counter, 254);
+
+        // disabling the channel clears its counter
+        apu.write_register(0x4015, 0x00);
+        assert_eq!(apu.read_status() & 0x01, 0x00);
+        // and loads are ignored while disabled
+        apu.write_register(0x4003, 0x08);
+        assert_eq!(apu.pulse1.length.counter, 0);
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        // the sequence restarts 3 cycles after the $4017 write
+        #[rustfmt::skip]
+        let cases = [
+            ("4-step",      Region::Ntsc, 0x00, 3 + 29829, true),
+            ("4-step pal",  Region::Pal,  0x00, 3 + 33253, true),
+            ("inhibited",   Region::Ntsc, 0x40, 3 + 29829, false),
+            ("5-step",      Region::Ntsc, 0x80, 3 + 37281, false),
+        ];
+
+        for (name, region, mode, cycles, irq) in cases {
+            let mut nes = Nes::new();
+            nes.apu = Apu::new(region);
+            nes.apu.write_register(0x4017, mode);
+            for _ in 0..cycles {
+                step(&mut nes);
+            }
+            assert_eq!(nes.apu.frame_irq, irq, "{}", name);
+            // reading $4015 acknowledges the IRQ
+            assert_eq!(nes.apu.read_status() & 0x40 != 0, irq, "{}", name);
+            assert!(!nes.apu.frame_irq, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_metering() {
+        let mut nes = Nes::new();
+        nes.meter = Some(Default::default());
+        nes.apu.write_register(0x4015, 0x01);
+        // 50% duty, constant volume 12, period $0FF
+        nes.apu.write_register(0x4000, 0xBC);
+        nes.apu.write_register(0x4002, 0xFF);
+        nes.apu.write_register(0x4003, 0x08);
+        for _ in 0..2000 {
+            step(&mut nes);
+        }
+        let settings = nes.apu.settings();
+        let meter = nes.meter.as_mut().unwrap();
+        meter.end_frame(settings);
+        let pulse = meter.levels[0];
+        assert_eq!(pulse.channel, Channel::Pulse1);
+        assert_eq!((pulse.level, pulse.period, pulse.volume), (0.8, 0xFF, 12));
+        // the rest are off, the triangle parked at its first step
+        assert!(meter.levels[1..]
+            .iter()
+            .all(|l| l.level == 0.0 && l.volume == 0));
+    }
+
+    #[test]
+    fn test_output() {
+        let mut apu = Apu::new(Region::Ntsc);
+        // the triangle idles at its first step, level 15
+        let idle = apu.output();
+        assert!(0.0 < idle, "{}", idle);
+
+        apu.write_register(0x4015, 0x01);
+        // 50% duty, constant volume 15
+        apu.write_register(0x4000, 0xBF);
+        apu.write_register(0x4002, 0xFF);
+        apu.write_register(0x4003, 0x08);
+        // the sequencer starts on a low step
+        assert_eq!(apu.output(), idle);
+        for _ in 0..=0xFF {
+            apu.pulse1.clock_timer();
+        }
+        // 95.88 / (8128 / 15 + 100)
+        let v = apu.output() - idle;
+        assert!((v - 0.1494).abs() < 0.0001, "{}", v);
+    }
+}
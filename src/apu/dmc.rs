@@ -0,0 +1,178 @@
+use super::*;
+
+// Timer periods in CPU cycles
+const NTSC_RATES: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+const PAL_RATES: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+#[derive(Debug)]
+pub(super) struct Dmc {
+    pal: bool,
+    irq_enabled: bool,
+    pub(super) irq: bool,
+    looping: bool,
+    timer: u16,
+    period: u16,
+    level: u8,
+
+    sample_addr: u16,
+    sample_length: u16,
+    pub(super) addr: u16,
+    pub(super) remaining: u16,
+    pub(super) buffer: Option<u8>,
+
+    shift: u8,
+    bits: u8,
+    silence: bool,
+}
+
+impl Dmc {
+    pub(super) fn new(pal: bool) -> Self {
+        Self {
+            pal,
+            irq_enabled: false,
+            irq: false,
+            looping: false,
+            timer: 0,
+            period: NTSC_RATES[0],
+            level: 0,
+            sample_addr: 0xC000,
+            sample_length: 1,
+            addr: 0xC000,
+            remaining: 0,
+            buffer: None,
+            shift: 0,
+            bits: 8,
+            silence: true,
+        }
+    }
+
+    pub(super) fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                self.irq_enabled = value & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.looping = value & 0x40 != 0;
+                let rates = if self.pal { PAL_RATES } else { NTSC_RATES };
+                self.period = rates[value as usize & 0x0F];
+            }
+            1 => self.level = value & 0x7F,
+            2 => self.sample_addr = 0xC000 | (value as u16) << 6,
+            _ => self.sample_length = (value as u16) << 4 | 1,
+        }
+    }
+
+    pub(super) fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.remaining = 0;
+        } else if self.remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.addr = self.sample_addr;
+        self.remaining = self.sample_length;
+    }
+
+    pub(super) fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.irq_enabled);
+        w.write_bool(self.irq);
+        w.write_bool(self.looping);
+        w.write_u16(self.timer);
+        w.write_u16(self.period);
+        w.write_u8(self.level);
+        w.write_u16(self.sample_addr);
+        w.write_u16(self.sample_length);
+        w.write_u16(self.addr);
+        w.write_u16(self.remaining);
+        w.write_bool(self.buffer.is_some());
+        w.write_u8(self.buffer.unwrap_or(0));
+        w.write_u8(self.shift);
+        w.write_u8(self.bits);
+        w.write_bool(self.silence);
+    }
+
+    pub(super) fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.irq_enabled = r.read_bool()?;
+        self.irq = r.read_bool()?;
+        self.looping = r.read_bool()?;
+        self.timer = r.read_u16()?;
+        self.period = r.read_u16()?.max(1);
+        self.level = r.read_u8()? & 0x7F;
+        self.sample_addr = r.read_u16()?;
+        self.sample_length = r.read_u16()?;
+        self.addr = r.read_u16()?;
+        self.remaining = r.read_u16()?;
+        let buffered = r.read_bool()?;
+        let v = r.read_u8()?;
+        self.buffer = if buffered { Some(v) } else { None };
+        self.shift = r.read_u8()?;
+        self.bits = r.read_u8()?.clamp(1, 8);
+        self.silence = r.read_bool()?;
+        Ok(())
+    }
+
+    // Whether the sample buffer needs a byte from memory
+    pub(super) fn needs_fetch(&self) -> bool {
+        self.buffer.is_none() && 0 < self.remaining
+    }
+
+    pub(super) fn fill(&mut self, value: u8) {
+        self.buffer = Some(value);
+        self.addr = self.addr.checked_add(1).unwrap_or(0x8000);
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    // Clocked every CPU cycle
+    pub(super) fn clock_timer(&mut self) {
+        if 0 < self.timer {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period - 1;
+
+        if !self.silence {
+            if self.shift & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if 2 <= self.level {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        self.bits -= 1;
+        if self.bits == 0 {
+            self.bits = 8;
+            match self.buffer.take() {
+                Some(v) => {
+                    self.shift = v;
+                    self.silence = false;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    pub(super) fn period(&self) -> u16 {
+        self.period
+    }
+
+    pub(super) fn output(&self) -> u8 {
+        self.level
+    }
+}
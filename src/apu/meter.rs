@@ -0,0 +1,120 @@
+// Per-channel levels for visualizers, measured over each frame from what the
+// channels output. Only the swing of an output counts, so a triangle halted
+// mid-step or a DMC holding its level reads as silent.
+
+// What's left of a level after a frame without sound
+const RELEASE: f32 = 0.85;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+}
+
+const CHANNELS: [Channel; 5] = [
+    Channel::Pulse1,
+    Channel::Pulse2,
+    Channel::Triangle,
+    Channel::Noise,
+    Channel::Dmc,
+];
+
+// Highest output of each channel
+const RANGE: [u8; 5] = [15, 15, 15, 15, 127];
+
+// A channel as of the last frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelLevel {
+    pub channel: Channel,
+    // How far the output swung during the frame, from 0.0 to 1.0 of the
+    // channel's range. It rises at once and falls off over a few frames.
+    pub level: f32,
+    // Timer period: the register value for the pulses and triangle, CPU cycles
+    // for the noise and DMC
+    pub period: u16,
+    // Envelope volume 0-15 while the channel plays, 15 for a running triangle,
+    // and the DMC's 7-bit output level
+    pub volume: u8,
+}
+
+#[derive(Debug)]
+pub(crate) struct Meter {
+    // Lowest and highest outputs of the frame so far
+    low: [u8; 5],
+    high: [u8; 5],
+    pub(crate) levels: Vec<ChannelLevel>,
+}
+
+impl Default for Meter {
+    fn default() -> Self {
+        Self {
+            low: [u8::MAX; 5],
+            high: [0; 5],
+            levels: CHANNELS
+                .iter()
+                .map(|&channel| ChannelLevel {
+                    channel,
+                    level: 0.0,
+                    period: 0,
+                    volume: 0,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Meter {
+    // Take in one CPU cycle's channel outputs
+    pub(crate) fn sample(&mut self, outputs: [u8; 5]) {
+        for (i, &v) in outputs.iter().enumerate() {
+            self.low[i] = self.low[i].min(v);
+            self.high[i] = self.high[i].max(v);
+        }
+    }
+
+    // Update the levels with the frame's swings and the channels' periods and
+    // volumes at its end
+    pub(crate) fn end_frame(&mut self, settings: [(u16, u8); 5]) {
+        for (i, level) in self.levels.iter_mut().enumerate() {
+            let swing = self.high[i].saturating_sub(self.low[i]) as f32 / RANGE[i] as f32;
+            level.level = swing.max(level.level * RELEASE);
+            let (period, volume) = settings[i];
+            level.period = period;
+            level.volume = volume;
+        }
+        self.low = [u8::MAX; 5];
+        self.high = [0; 5];
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_meter() {
+        let mut meter = Meter::default();
+        // a pulse at full volume, the triangle parked, the DMC at half swing
+        for v in [0, 15, 0, 15] {
+            meter.sample([v, 0, 7, 0, 32 + v * 4]);
+        }
+        meter.end_frame([(0x1FD, 15), (0, 0), (0x100, 0), (4, 0), (428, 92)]);
+        let levels: Vec<f32> = meter.levels.iter().map(|l| l.level).collect();
+        assert_eq!(levels, [1.0, 0.0, 0.0, 0.0, 60.0 / 127.0]);
+        assert_eq!(meter.levels[0].channel, Channel::Pulse1);
+        assert_eq!(
+            (meter.levels[0].period, meter.levels[0].volume),
+            (0x1FD, 15)
+        );
+        assert_eq!((meter.levels[4].period, meter.levels[4].volume), (428, 92));
+
+        // silence falls off
+        meter.sample([0; 5]);
+        meter.end_frame([(0, 0); 5]);
+        assert_eq!(meter.levels[0].level, RELEASE);
+        assert_eq!(meter.levels[0].volume, 0);
+    }
+}
@@ -0,0 +1,104 @@
+use super::*;
+
+// Timer periods in CPU cycles
+const NTSC_PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+const PAL_PERIODS: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+#[derive(Debug)]
+pub(super) struct Noise {
+    pal: bool,
+    mode: bool,
+    shift: u16,
+    timer: u16,
+    period: u16,
+    pub(super) envelope: Envelope,
+    pub(super) length: LengthCounter,
+}
+
+impl Noise {
+    pub(super) fn new(pal: bool) -> Self {
+        Self {
+            pal,
+            mode: false,
+            shift: 1,
+            timer: 0,
+            period: NTSC_PERIODS[0],
+            envelope: Default::default(),
+            length: Default::default(),
+        }
+    }
+
+    pub(super) fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                self.length.halt = value & 0x20 != 0;
+                self.envelope.write(value);
+            }
+            2 => {
+                self.mode = value & 0x80 != 0;
+                let periods = if self.pal { PAL_PERIODS } else { NTSC_PERIODS };
+                self.period = periods[value as usize & 0x0F];
+            }
+            3 => {
+                self.length.load(value >> 3);
+                self.envelope.start = true;
+            }
+            _ => {}
+        }
+    }
+
+    pub(super) fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.mode);
+        w.write_u16(self.shift);
+        w.write_u16(self.timer);
+        w.write_u16(self.period);
+        self.envelope.save_state(w);
+        self.length.save_state(w);
+    }
+
+    pub(super) fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.mode = r.read_bool()?;
+        self.shift = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        self.period = r.read_u16()?.max(1);
+        self.envelope.load_state(r)?;
+        self.length.load_state(r)?;
+        Ok(())
+    }
+
+    // Clocked every CPU cycle
+    pub(super) fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period - 1;
+            let tap = if self.mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub(super) fn period(&self) -> u16 {
+        self.period
+    }
+
+    pub(super) fn volume(&self) -> u8 {
+        if self.length.counter == 0 {
+            0
+        } else {
+            self.envelope.volume()
+        }
+    }
+
+    pub(super) fn output(&self) -> u8 {
+        if self.length.counter == 0 || self.shift & 1 != 0 {
+            0
+        } else {
+            self.envelope.volume()
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use super::*;
+
+const DUTY: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+#[derive(Debug, Default)]
+pub(super) struct Pulse {
+    // pulse 1 negates with one's complement, pulse 2 with two's complement
+    ones_complement: bool,
+    duty: u8,
+    step: u8,
+    timer: u16,
+    period: u16,
+    pub(super) envelope: Envelope,
+    pub(super) length: LengthCounter,
+
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    pub(super) fn new(ones_complement: bool) -> Self {
+        Self {
+            ones_complement,
+            ..Default::default()
+        }
+    }
+
+    pub(super) fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                self.duty = value >> 6;
+                self.length.halt = value & 0x20 != 0;
+                self.envelope.write(value);
+            }
+            1 => {
+                self.sweep_enabled = value & 0x80 != 0;
+                self.sweep_period = (value >> 4) & 0x07;
+                self.sweep_negate = value & 0x08 != 0;
+                self.sweep_shift = value & 0x07;
+                self.sweep_reload = true;
+            }
+            2 => self.period = (self.period & 0x0700) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((value as u16 & 0x07) << 8);
+                self.length.load(value >> 3);
+                self.step = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    pub(super) fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.duty);
+        w.write_u8(self.step);
+        w.write_u16(self.timer);
+        w.write_u16(self.period);
+        self.envelope.save_state(w);
+        self.length.save_state(w);
+        w.write_bool(self.sweep_enabled);
+        w.write_u8(self.sweep_period);
+        w.write_bool(self.sweep_negate);
+        w.write_u8(self.sweep_shift);
+        w.write_u8(self.sweep_divider);
+        w.write_bool(self.sweep_reload);
+    }
+
+    pub(super) fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.duty = r.read_u8()? & 0x03;
+        self.step = r.read_u8()? & 0x07;
+        self.timer = r.read_u16()?;
+        self.period = r.read_u16()?;
+        self.envelope.load_state(r)?;
+        self.length.load_state(r)?;
+        self.sweep_enabled = r.read_bool()?;
+        self.sweep_period = r.read_u8()?;
+        self.sweep_negate = r.read_bool()?;
+        self.sweep_shift = r.read_u8()?;
+        self.sweep_divider = r.read_u8()?;
+        self.sweep_reload = r.read_bool()?;
+        Ok(())
+    }
+
+    // Clocked every other CPU cycle
+    pub(super) fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn target_period(&self) -> u16 {
+        let change = self.period >> self.sweep_shift;
+        if self.sweep_negate {
+            let change = change + self.ones_complement as u16;
+            self.period.saturating_sub(change)
+        } else {
+            self.period + change
+        }
+    }
+
+    fn muted(&self) -> bool {
+        self.period < 8 || 0x7FF < self.target_period()
+    }
+
+    pub(super) fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && 0 < self.sweep_shift && !self.muted() {
+            self.period = self.target_period();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    pub(super) fn period(&self) -> u16 {
+        self.period
+    }
+
+    pub(super) fn volume(&self) -> u8 {
+        if self.length.counter == 0 || self.muted() {
+            0
+        } else {
+            self.envelope.volume()
+        }
+    }
+
+    pub(super) fn output(&self) -> u8 {
+        if self.length.counter == 0
+            || self.muted()
+            || DUTY[self.duty as usize][self.step as usize] == 0
+        {
+            0
+        } else {
+            self.envelope.volume()
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use super::*;
+
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, //
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+#[derive(Debug, Default)]
+pub(super) struct Triangle {
+    step: u8,
+    timer: u16,
+    period: u16,
+    pub(super) length: LengthCounter,
+    linear_counter: u8,
+    linear_reload: u8,
+    linear_reload_flag: bool,
+}
+
+impl Triangle {
+    pub(super) fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                // the control flag doubles as the length counter halt
+                self.length.halt = value & 0x80 != 0;
+                self.linear_reload = value & 0x7F;
+            }
+            2 => self.period = (self.period & 0x0700) | value as u16,
+            3 => {
+                self.period = (self.period & 0x00FF) | ((value as u16 & 0x07) << 8);
+                self.length.load(value >> 3);
+                self.linear_reload_flag = true;
+            }
+            _ => {}
+        }
+    }
+
+    pub(super) fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.step);
+        w.write_u16(self.timer);
+        w.write_u16(self.period);
+        self.length.save_state(w);
+        w.write_u8(self.linear_counter);
+        w.write_u8(self.linear_reload);
+        w.write_bool(self.linear_reload_flag);
+    }
+
+    pub(super) fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.step = r.read_u8()? & 0x1F;
+        self.timer = r.read_u16()?;
+        self.period = r.read_u16()?;
+        self.length.load_state(r)?;
+        self.linear_counter = r.read_u8()?;
+        self.linear_reload = r.read_u8()?;
+        self.linear_reload_flag = r.read_bool()?;
+        Ok(())
+    }
+
+    // Clocked every CPU cycle
+    pub(super) fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if 0 < self.length.counter && 0 < self.linear_counter {
+                self.step = (self.step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub(super) fn clock_linear(&mut self) {
+        if self.linear_reload_flag {
+            self.linear_counter = self.linear_reload;
+        } else if 0 < self.linear_counter {
+            self.linear_counter -= 1;
+        }
+        if !self.length.halt {
+            self.linear_reload_flag = false;
+        }
+    }
+
+    pub(super) fn period(&self) -> u16 {
+        self.period
+    }
+
+    // The triangle has no volume control, only running or not
+    pub(super) fn volume(&self) -> u8 {
+        if 0 < self.length.counter && 0 < self.linear_counter {
+            15
+        } else {
+            0
+        }
+    }
+
+    pub(super) fn output(&self) -> u8 {
+        SEQUENCE[self.step as usize]
+    }
+}
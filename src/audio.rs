@@ -0,0 +1,231 @@
+use anyhow::Result;
+
+use crate::state::{StateReader, StateWriter};
+
+// Receives the samples produced by each frame, interleaved by channel
+pub trait AudioSink {
+    fn samples(&mut self, samples: &[i16]);
+}
+
+// A sink which keeps every sample until taken
+#[derive(Debug, Clone, Default)]
+pub struct SampleBuffer {
+    samples: Vec<i16>,
+}
+
+impl SampleBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    // Remove and return everything received so far
+    pub fn take(&mut self) -> Vec<i16> {
+        std::mem::take(&mut self.samples)
+    }
+}
+
+impl AudioSink for SampleBuffer {
+    fn samples(&mut self, samples: &[i16]) {
+        self.samples.extend_from_slice(samples);
+    }
+}
+
+// Cutoff of the high-pass filter removing the mixer's DC offset
+const HIGH_PASS_HZ: f32 = 37.0;
+// Mixer levels collected before resampling them in one go
+const BATCH: usize = 128;
+// Frames a second at the slowest, PAL's 50, sizing the sample buffer up front
+const MIN_FRAME_RATE: usize = 50;
+
+// Downsamples the per-CPU-cycle mixer output to the output rate by averaging
+#[derive(Debug)]
+pub(crate) struct Resampler {
+    channels: usize,
+    // CPU cycles per output sample, in 1/sample_rate units to avoid drift
+    cpu_clock: u64,
+    sample_rate: u64,
+    phase: u64,
+    sum: f32,
+    count: u32,
+
+    high_pass: f32,
+    last_in: f32,
+    last_out: f32,
+
+    // Levels pushed since the last batch was resampled
+    levels: [f32; BATCH],
+    queued: usize,
+
+    pub(crate) buffer: Vec<i16>,
+}
+
+impl Default for Resampler {
+    fn default() -> Self {
+        Self::new(1_789_773, 48000, 1)
+    }
+}
+
+impl Resampler {
+    pub(crate) fn new(cpu_clock: u32, sample_rate: u32, channels: u16) -> Self {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * HIGH_PASS_HZ);
+        let dt = 1.0 / sample_rate.max(1) as f32;
+        Self {
+            channels: channels.max(1) as usize,
+            cpu_clock: cpu_clock as u64,
+            sample_rate: sample_rate.max(1) as u64,
+            phase: 0,
+            sum: 0.0,
+            count: 0,
+            high_pass: rc / (rc + dt),
+            last_in: 0.0,
+            last_out: 0.0,
+            levels: [0.0; BATCH],
+            queued: 0,
+            // a frame's worth, with room for the frame to end late
+            buffer: Vec::with_capacity(
+                2 * channels.max(1) as usize * sample_rate as usize / MIN_FRAME_RATE,
+            ),
+        }
+    }
+
+    // Where the output stands between samples, so that a restored state carries
+    // on with the same ones
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.write_u64(self.phase);
+        w.write_u32(self.sum.to_bits());
+        w.write_u32(self.count);
+        w.write_u32(self.last_in.to_bits());
+        w.write_u32(self.last_out.to_bits());
+        w.write_u16(self.queued as u16);
+        for level in &self.levels[..self.queued] {
+            w.write_u32(level.to_bits());
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.phase = r.read_u64()? % self.cpu_clock.max(1);
+        self.sum = f32::from_bits(r.read_u32()?);
+        self.count = r.read_u32()?;
+        self.last_in = f32::from_bits(r.read_u32()?);
+        self.last_out = f32::from_bits(r.read_u32()?);
+        // states of older builds end here, having no batches
+        self.queued = 0;
+        if !r.is_empty() {
+            self.queued = (r.read_u16()? as usize).min(BATCH);
+            for level in &mut self.levels[..self.queued] {
+                *level = f32::from_bits(r.read_u32()?);
+            }
+        }
+        Ok(())
+    }
+
+    // Feed the mixer output of one CPU cycle
+    #[inline]
+    pub(crate) fn push(&mut self, v: f32) {
+        self.levels[self.queued] = v;
+        self.queued += 1;
+        if self.queued == BATCH {
+            self.flush();
+        }
+    }
+
+    // Resample the levels pushed so far into `buffer`
+    pub(crate) fn flush(&mut self) {
+        for i in 0..self.queued {
+            self.sum += self.levels[i];
+            self.count += 1;
+            self.phase += self.sample_rate;
+            if self.phase < self.cpu_clock {
+                continue;
+            }
+            self.phase -= self.cpu_clock;
+
+            let v = self.sum / self.count as f32;
+            self.sum = 0.0;
+            self.count = 0;
+
+            let out = self.high_pass * (self.last_out + v - self.last_in);
+            self.last_in = v;
+            self.last_out = out;
+
+            let sample = (out * i16::MAX as f32).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+            for _ in 0..self.channels {
+                self.buffer.push(sample);
+            }
+        }
+        self.queued = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resampler() {
+        #[rustfmt::skip]
+        let cases = [
+            ("48kHz",         1_789_773, 48000, 1, 48000),
+            ("44.1kHz",       1_789_773, 44100, 1, 44100),
+            ("stereo",        1_789_773, 48000, 2, 96000),
+            ("pal",           1_662_607, 48000, 1, 48000),
+        ];
+
+        for (name, clock, rate, channels, expected) in cases {
+            let mut r = Resampler::new(clock, rate, channels);
+            for _ in 0..clock {
+                r.push(0.5);
+            }
+            r.flush();
+            assert_eq!(r.buffer.len(), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_batch_state() {
+        let level = |i: usize| (i % 90) as f32 / 100.0;
+        let mut r = Resampler::default();
+        for i in 0..1000 {
+            r.push(level(i));
+        }
+        let mut w = StateWriter::new();
+        r.save_state(&mut w);
+        let state = w.into_inner();
+
+        let mut restored = Resampler::default();
+        restored.load_state(&mut StateReader::new(&state)).unwrap();
+        r.buffer.clear();
+        for r in [&mut r, &mut restored] {
+            for i in 1000..2000 {
+                r.push(level(i));
+            }
+            r.flush();
+        }
+        assert_eq!(restored.buffer, r.buffer);
+
+        // states without the batch
+        let mut old = Resampler::default();
+        old.load_state(&mut StateReader::new(&state[..24])).unwrap();
+        assert_eq!(old.queued, 0);
+    }
+
+    #[test]
+    fn test_high_pass() {
+        let mut r = Resampler::new(1_789_773, 48000, 1);
+        // a constant level decays to silence
+        for _ in 0..1_789_773 {
+            r.push(0.5);
+        }
+        r.flush();
+        assert!(16000 < r.buffer[0], "{}", r.buffer[0]);
+        assert_eq!(*r.buffer.last().unwrap(), 0);
+    }
+}
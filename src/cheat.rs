@@ -0,0 +1,138 @@
+use std::fmt;
+
+use anyhow::Result;
+
+// Game Genie letters, in the order of the nibbles they stand for
+const LETTERS: &[u8; 16] = b"APZLGITYEOXUKSVN";
+
+// A patch of PRG reads: reads of `addr` return `value` instead, as long as the
+// byte really there equals `compare` (when given)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cheat {
+    code: String,
+    pub(crate) addr: u16,
+    pub(crate) value: u8,
+    pub(crate) compare: Option<u8>,
+    pub(crate) enabled: bool,
+}
+
+impl Cheat {
+    // Decode a 6- or 8-letter Game Genie code, case-insensitively
+    pub fn from_game_genie(code: &str) -> Result<Self> {
+        let code = code.to_ascii_uppercase();
+        let n = code
+            .bytes()
+            .map(|c| LETTERS.iter().position(|&l| l == c).map(|i| i as u16))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| CheatError::new(&format!("invalid letter in {}", code)))?;
+        if n.len() != 6 && n.len() != 8 {
+            return Err(CheatError::new(&format!("{} isn't 6 or 8 letters long", code)).into());
+        }
+
+        let addr = 0x8000
+            | (n[3] & 7) << 12
+            | (n[5] & 7) << 8
+            | (n[4] & 8) << 8
+            | (n[2] & 7) << 4
+            | (n[1] & 8) << 4
+            | (n[4] & 7)
+            | (n[3] & 8);
+        let data =
+            |lo: u16, hi: u16, top: u16| ((hi & 7) << 4 | (lo & 8) << 4 | (lo & 7) | top) as u8;
+        let (value, compare) = if n.len() == 6 {
+            (data(n[0], n[1], n[5] & 8), None)
+        } else {
+            (data(n[0], n[1], n[7] & 8), Some(data(n[6], n[7], n[5] & 8)))
+        };
+        Ok(Self {
+            code,
+            addr,
+            value,
+            compare,
+            enabled: true,
+        })
+    }
+
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+
+    pub fn addr(&self) -> u16 {
+        self.addr
+    }
+
+    pub fn value(&self) -> u8 {
+        self.value
+    }
+
+    pub fn compare(&self) -> Option<u8> {
+        self.compare
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+}
+
+// Patch a byte read from `addr` by the enabled cheats
+pub(crate) fn apply(cheats: &[Cheat], addr: u16, v: u8) -> u8 {
+    cheats
+        .iter()
+        .find(|c| c.enabled && c.addr == addr && c.compare.is_none_or(|cmp| cmp == v))
+        .map_or(v, |c| c.value)
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct CheatError {
+    msg: String,
+}
+
+impl CheatError {
+    pub(crate) fn new(msg: &str) -> Self {
+        Self {
+            msg: msg.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for CheatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cheat error: {}", self.msg)
+    }
+}
+
+impl std::error::Error for CheatError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_game_genie() {
+        #[rustfmt::skip]
+        let cases = [
+            ("GOSSIP",   Some((0xD1DD, 0x14, None))),
+            ("gossip",   Some((0xD1DD, 0x14, None))),
+            ("ZEXPYGLA", Some((0x94A7, 0x02, Some(0x03)))),
+            ("GOSSIQ",   None),
+            ("GOSSIPA",  None),
+        ];
+
+        for (code, expected) in cases {
+            let cheat = Cheat::from_game_genie(code).ok();
+            let decoded = cheat.map(|c| (c.addr, c.value, c.compare));
+            assert_eq!(decoded, expected, "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut cheats = vec![Cheat::from_game_genie("ZEXPYGLA").unwrap()];
+        assert_eq!(apply(&cheats, 0x94A7, 0x03), 0x02);
+        // the compare value doesn't match another bank's byte
+        assert_eq!(apply(&cheats, 0x94A7, 0x04), 0x04);
+        assert_eq!(apply(&cheats, 0x94A8, 0x03), 0x03);
+        cheats[0].enabled = false;
+        assert_eq!(apply(&cheats, 0x94A7, 0x03), 0x03);
+    }
+}
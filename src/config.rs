@@ -0,0 +1,144 @@
+use crate::rom::Cartridge;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // Force a region instead of detecting it from the cartridge
+    pub region: Option<Region>,
+    // Audio output rate in Hz
+    pub sample_rate: u32,
+    // Samples are duplicated into this many interleaved channels
+    pub channels: u16,
+    // CPU RAM contents at power-on
+    pub ram_init: RamInit,
+    // Scanlines added after the picture, where the CPU keeps running while the
+    // PPU and APU wait: games that run out of time per frame slow down and
+    // flicker less. No console does this, so it's off while a movie records or
+    // plays unless `overclock_movies` is set.
+    pub overclock_lines: u16,
+    pub overclock_movies: bool,
+    // Address past RAM where bytes written by the game are collected as text for
+    // `Emu::take_debug_output`, so homebrew can print through the emulator.
+    // $401A is free on the console; writes still reach whatever else is there.
+    pub debug_port: Option<u16>,
+    // DIP switches of boards that have them, None for each board's factory
+    // setting. On the Nintendo World Championships cartridge (mapper 105) each
+    // step from 0 to 15 adds 18.75s to its 5 minute timer; it ships at 4, 6:15.
+    pub dip_switches: Option<u8>,
+}
+
+// Real consoles power up with RAM in a chip-dependent, partly random state, which
+// some games read before writing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RamInit {
+    #[default]
+    Zero,
+    Ones,
+    // 4 bytes of $00, then 4 of $FF, repeating
+    Striped,
+    // pseudo-random from the seed, the same on every power-on
+    Random(u64),
+}
+
+impl RamInit {
+    pub(crate) fn fill(self, ram: &mut [u8]) {
+        match self {
+            RamInit::Zero => ram.fill(0),
+            RamInit::Ones => ram.fill(0xFF),
+            RamInit::Striped => {
+                for (i, b) in ram.iter_mut().enumerate() {
+                    *b = if i & 4 == 0 { 0x00 } else { 0xFF };
+                }
+            }
+            RamInit::Random(seed) => {
+                // xorshift64*, which needs a non-zero state
+                let mut x = seed | 1;
+                for b in ram {
+                    x ^= x >> 12;
+                    x ^= x << 25;
+                    x ^= x >> 27;
+                    *b = (x.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8;
+                }
+            }
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            region: None,
+            sample_rate: 48000,
+            channels: 1,
+            ram_init: RamInit::Zero,
+            overclock_lines: 0,
+            overclock_movies: false,
+            debug_port: None,
+            dip_switches: None,
+        }
+    }
+}
+
+impl Region {
+    // CPU clock rate in Hz
+    pub(crate) fn cpu_clock(self) -> u32 {
+        match self {
+            Region::Ntsc => 1_789_773,
+            Region::Pal => 1_662_607,
+            Region::Dendy => 1_773_448,
+        }
+    }
+
+    // Frames per second: the PPU's 341 dots per line at 3 (3.2 on PAL) per CPU cycle
+    pub fn frame_rate(self) -> f64 {
+        let (lines, dots_per_cycle) = match self {
+            Region::Ntsc => (262.0, 3.0),
+            Region::Pal => (312.0, 3.2),
+            Region::Dendy => (312.0, 3.0),
+        };
+        self.cpu_clock() as f64 * dots_per_cycle / (lines * 341.0)
+    }
+}
+
+impl Config {
+    pub(crate) fn region(&self, cart: &Cartridge) -> Region {
+        self.region.or_else(|| cart.region()).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ram_init() {
+        #[rustfmt::skip]
+        let cases = [
+            (RamInit::Zero,    &[0x00; 10][..]),
+            (RamInit::Ones,    &[0xFF; 10][..]),
+            (RamInit::Striped, &[0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00][..]),
+        ];
+
+        for (init, expected) in cases {
+            let mut ram = [0x55; 10];
+            init.fill(&mut ram);
+            assert_eq!(ram, expected, "{:?}", init);
+        }
+
+        let fill = |seed| {
+            let mut ram = [0; 64];
+            RamInit::Random(seed).fill(&mut ram);
+            ram
+        };
+        assert_eq!(fill(1), fill(1));
+        assert_ne!(fill(1), fill(2));
+        assert!(fill(1).iter().any(|&b| b != fill(1)[0]));
+    }
+}
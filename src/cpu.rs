@@ -1,43 +1,140 @@
+use anyhow::Result;
+
+use crate::hook::{self, Event};
+use crate::mapper::Mapper;
 use crate::nes::Nes;
+use crate::state::{StateReader, StateWriter};
 use crate::Emu;
 
 mod addressing_mode;
 mod bus;
+mod call_stack;
 mod decoder;
 mod instruction;
 
 #[cfg(test)]
 mod instruction_test;
+#[cfg(test)]
+mod single_step_test;
 
 use bus::{read, read_on_indirect, read_word, write};
 use decoder::{AddressingMode, Instruction, Mnemonic};
 
+pub(crate) use call_stack::CallStack;
+pub use call_stack::{CallFrame, CallKind};
+
 #[derive(Debug, Default)]
 pub struct Cpu {
-    a: u8,
-    x: u8,
-    y: u8,
-    s: u8,
+    pub(crate) a: u8,
+    pub(crate) x: u8,
+    pub(crate) y: u8,
+    pub(crate) s: u8,
     p: Status,
-    pc: u16,
+    pub(crate) pc: u16,
+    // Not part of save states: it only follows what the CPU ran since
+    pub(crate) calls: CallStack,
+}
+
+// Copy of the CPU registers for inspection and modification from outside
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Registers {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub s: u8,
+    pub p: u8,
+    pub pc: u16,
 }
 
 impl Emu {
-    #[allow(dead_code)]
-    fn cpu_step<B: CpuBus, T: CpuTick>(nes: &mut Nes) {
+    pub(crate) fn cpu_step<B: CpuBus>(nes: &mut Nes) {
         use addressing_mode::get_operand;
         use decoder::decode;
         use instruction::execute;
 
-        let opcode = read::<B, T>(nes, nes.cpu.pc);
+        let start = nes.cpu_cycles;
+        if nes.nmi {
+            nes.nmi = false;
+            nes.took_nmi = true;
+            interrupt::<B>(nes, NMI_VECTOR);
+            if let Some(profile) = &mut nes.profile {
+                profile.interrupt((nes.cpu_cycles - start) as u64);
+            }
+            hook::emit(nes, Event::Nmi);
+            return;
+        }
+        if !nes.irq.is_empty() && !nes.cpu.p.contains(Status::I) {
+            nes.took_irq = true;
+            interrupt::<B>(nes, IRQ_VECTOR);
+            if let Some(profile) = &mut nes.profile {
+                profile.interrupt((nes.cpu_cycles - start) as u64);
+            }
+            hook::emit(nes, Event::Irq);
+            return;
+        }
+
+        if !nes.hooks.exec.is_empty() {
+            B::sync(nes);
+            hook::on_exec(nes, nes.cpu.pc);
+        }
+        let pc = nes.cpu.pc;
+        let opcode = read::<B>(nes, pc);
         nes.cpu.pc = nes.cpu.pc.wrapping_add(1);
 
         let inst = decode(opcode);
 
         let (_, addressing_mode) = &inst;
-        let operand = get_operand::<B, T>(nes, *addressing_mode);
+        let operand = get_operand::<B>(nes, *addressing_mode);
+
+        execute::<B>(nes, inst, operand);
+
+        if let Some(profile) = &mut nes.profile {
+            let cycles = (nes.cpu_cycles - start) as u64;
+            profile.instruction(pc, nes.mapper.prg_rom_offset(pc), opcode, cycles);
+        }
+    }
+}
+
+impl Cpu {
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.a);
+        w.write_u8(self.x);
+        w.write_u8(self.y);
+        w.write_u8(self.s);
+        w.write_u8(self.p.bits());
+        w.write_u16(self.pc);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.a = r.read_u8()?;
+        self.x = r.read_u8()?;
+        self.y = r.read_u8()?;
+        self.s = r.read_u8()?;
+        self.p = Status::from_bits_truncate(r.read_u8()?);
+        self.pc = r.read_u16()?;
+        self.calls.clear();
+        Ok(())
+    }
+
+    pub(crate) fn registers(&self) -> Registers {
+        Registers {
+            a: self.a,
+            x: self.x,
+            y: self.y,
+            s: self.s,
+            p: self.p.bits(),
+            pc: self.pc,
+        }
+    }
 
-        execute::<B, T>(nes, inst, operand);
+    pub(crate) fn set_registers(&mut self, r: Registers) {
+        self.a = r.a;
+        self.x = r.x;
+        self.y = r.y;
+        self.s = r.s;
+        self.p = Status::from_bits_truncate(r.p);
+        self.pc = r.pc;
+        self.calls.unwind(self.s);
     }
 }
 
@@ -62,34 +159,82 @@ bitflags! {
     }
 }
 
+// What the CPU runs against: memory accesses and the passing of cycles. The
+// machine's is `nes::SystemBus`, tests put their own memory behind it.
 pub(crate) trait CpuBus {
     fn read(nes: &mut Nes, addr: u16) -> u8;
     fn write(nes: &mut Nes, addr: u16, value: u8);
+    fn tick(nes: &mut Nes);
+
+    // Bring what runs behind the CPU level with it, before anything outside
+    // looks at the machine
+    fn sync(_nes: &mut Nes) {}
+
+    fn tick_n(nes: &mut Nes, n: u128) {
+        for _ in 0..n {
+            Self::tick(nes);
+        }
+    }
 }
 
-pub(crate) trait CpuTick {
-    fn tick(nes: &mut Nes);
-    fn tick_n(nes: &mut Nes, n: u128);
+// Name of the instruction an opcode decodes to, for reports
+pub(crate) fn mnemonic(opcode: u8) -> String {
+    format!("{:?}", decoder::decode(opcode).0)
+}
+
+const NMI_VECTOR: u16 = 0xFFFA;
+const RESET_VECTOR: u16 = 0xFFFC;
+const IRQ_VECTOR: u16 = 0xFFFE;
+
+// The reset sequence: three suppressed stack pushes, then jump through $FFFC
+pub(crate) fn reset<B: CpuBus>(nes: &mut Nes) {
+    B::tick_n(nes, 5);
+    nes.cpu.s = nes.cpu.s.wrapping_sub(3);
+    nes.cpu.p.insert(Status::I);
+    nes.cpu.pc = read_word::<B>(nes, RESET_VECTOR);
+    nes.cpu.calls.clear();
+    B::sync(nes);
+}
+
+fn interrupt<B: CpuBus>(nes: &mut Nes, vector: u16) {
+    let (from, s) = (nes.cpu.pc, nes.cpu.s);
+    B::tick_n(nes, 2);
+    push_stack_word::<B>(nes, nes.cpu.pc);
+    push_stack::<B>(nes, (nes.cpu.p | Status::INTERRUPT_B).bits());
+    nes.cpu.p.insert(Status::I);
+    nes.cpu.pc = read_word::<B>(nes, vector);
+    nes.cpu.calls.call(CallFrame {
+        kind: if vector == NMI_VECTOR {
+            CallKind::Nmi
+        } else {
+            CallKind::Irq
+        },
+        from,
+        to: nes.cpu.pc,
+        return_addr: from,
+        s,
+    });
 }
 
-fn push_stack<B: CpuBus, T: CpuTick>(nes: &mut Nes, v: u8) {
-    write::<B, T>(nes, nes.cpu.s as u16, v);
+fn push_stack<B: CpuBus>(nes: &mut Nes, v: u8) {
+    write::<B>(nes, 0x0100 | nes.cpu.s as u16, v);
     nes.cpu.s = nes.cpu.s.wrapping_sub(1);
 }
 
-fn pull_stack<B: CpuBus, T: CpuTick>(nes: &mut Nes) -> u8 {
+fn pull_stack<B: CpuBus>(nes: &mut Nes) -> u8 {
     nes.cpu.s = nes.cpu.s.wrapping_add(1);
-    read::<B, T>(nes, nes.cpu.s as u16)
+    nes.cpu.calls.unwind(nes.cpu.s);
+    read::<B>(nes, 0x0100 | nes.cpu.s as u16)
 }
 
-fn push_stack_word<B: CpuBus, T: CpuTick>(nes: &mut Nes, v: u16) {
-    push_stack::<B, T>(nes, (v >> 8) as u8);
-    push_stack::<B, T>(nes, (v & 0xFF) as u8);
+pub(crate) fn push_stack_word<B: CpuBus>(nes: &mut Nes, v: u16) {
+    push_stack::<B>(nes, (v >> 8) as u8);
+    push_stack::<B>(nes, (v & 0xFF) as u8);
 }
 
-fn pull_stack_word<B: CpuBus, T: CpuTick>(nes: &mut Nes) -> u16 {
-    let low = pull_stack::<B, T>(nes) as u16;
-    let high = pull_stack::<B, T>(nes) as u16;
+fn pull_stack_word<B: CpuBus>(nes: &mut Nes) -> u16 {
+    let low = pull_stack::<B>(nes) as u16;
+    let high = pull_stack::<B>(nes) as u16;
     low | (high << 8)
 }
 
@@ -101,23 +246,21 @@ fn page_crossed(a: u16, b: u16) -> bool {
 mod test_mock {
     use super::*;
 
-    pub(super) struct CpuTickMock {}
-    impl CpuTick for CpuTickMock {
-        fn tick(nes: &mut Nes) {
-            nes.cpu_cycles = nes.cpu_cycles.wrapping_add(1);
-        }
-        fn tick_n(nes: &mut Nes, n: u128) {
-            nes.cpu_cycles = nes.cpu_cycles.wrapping_add(n);
-        }
-    }
-
     pub(super) struct CpuBusMock {}
     impl CpuBus for CpuBusMock {
         fn read(nes: &mut Nes, addr: u16) -> u8 {
-            nes.wram[addr as usize]
+            nes.wram[addr as usize & 0x07FF]
         }
         fn write(nes: &mut Nes, addr: u16, value: u8) {
-            nes.wram[addr as usize] = value
+            nes.wram[addr as usize & 0x07FF] = value
+        }
+        fn tick(nes: &mut Nes) {
+            tick(nes);
         }
     }
+
+    // Cycles pass without anything else running
+    pub(super) fn tick(nes: &mut Nes) {
+        nes.cpu_cycles = nes.cpu_cycles.wrapping_add(1);
+    }
 }
@@ -1,9 +1,6 @@
 use super::*;
 
-pub(super) fn get_operand<B: CpuBus, T: CpuTick>(
-    nes: &mut Nes,
-    addressing_mode: AddressingMode,
-) -> u16 {
+pub(super) fn get_operand<B: CpuBus>(nes: &mut Nes, addressing_mode: AddressingMode) -> u16 {
     match addressing_mode {
         AddressingMode::Implicit => 0u16,
         AddressingMode::Accumulator => nes.cpu.a as u16,
@@ -13,74 +10,76 @@ pub(super) fn get_operand<B: CpuBus, T: CpuTick>(
             pc
         }
         AddressingMode::ZeroPage => {
-            let v = read::<B, T>(nes, nes.cpu.pc);
+            let v = read::<B>(nes, nes.cpu.pc);
             nes.cpu.pc = nes.cpu.pc.wrapping_add(1);
             v as u16
         }
         AddressingMode::ZeroPageX => {
-            let v = (read::<B, T>(nes, nes.cpu.pc) as u16 + nes.cpu.x as u16) & 0xFF;
+            let v = (read::<B>(nes, nes.cpu.pc) as u16 + nes.cpu.x as u16) & 0xFF;
             nes.cpu.pc = nes.cpu.pc.wrapping_add(1);
-            v as u16
+            B::tick(nes);
+            v
         }
         AddressingMode::ZeroPageY => {
-            let v = (read::<B, T>(nes, nes.cpu.pc) as u16 + nes.cpu.y as u16) & 0xFF;
+            let v = (read::<B>(nes, nes.cpu.pc) as u16 + nes.cpu.y as u16) & 0xFF;
             nes.cpu.pc = nes.cpu.pc.wrapping_add(1);
-            v as u16
+            B::tick(nes);
+            v
         }
         AddressingMode::Absolute => {
-            let v = read_word::<B, T>(nes, nes.cpu.pc);
+            let v = read_word::<B>(nes, nes.cpu.pc);
             nes.cpu.pc = nes.cpu.pc.wrapping_add(2);
-            v as u16
+            v
         }
         AddressingMode::AbsoluteX { oops } => {
-            let v = read_word::<B, T>(nes, nes.cpu.pc);
+            let v = read_word::<B>(nes, nes.cpu.pc);
             nes.cpu.pc = nes.cpu.pc.wrapping_add(2);
             if oops {
                 if page_crossed(nes.cpu.x as u16, v) {
-                    T::tick(nes);
+                    B::tick(nes);
                 }
             } else {
-                T::tick(nes);
+                B::tick(nes);
             }
-            (v as u16).wrapping_add(nes.cpu.x as u16)
+            v.wrapping_add(nes.cpu.x as u16)
         }
         AddressingMode::AbsoluteY { oops } => {
-            let v = read_word::<B, T>(nes, nes.cpu.pc);
+            let v = read_word::<B>(nes, nes.cpu.pc);
             nes.cpu.pc = nes.cpu.pc.wrapping_add(2);
             if oops {
                 if page_crossed(nes.cpu.y as u16, v) {
-                    T::tick(nes);
+                    B::tick(nes);
                 }
             } else {
-                T::tick(nes);
+                B::tick(nes);
             }
-            (v as u16).wrapping_add(nes.cpu.y as u16)
+            v.wrapping_add(nes.cpu.y as u16)
         }
         AddressingMode::Relative => {
-            let v = read::<B, T>(nes, nes.cpu.pc);
+            let v = read::<B>(nes, nes.cpu.pc);
             nes.cpu.pc = nes.cpu.pc.wrapping_add(1);
             v as u16
         }
         AddressingMode::Indirect => {
-            let m = read_word::<B, T>(nes, nes.cpu.pc);
-            let v = read_on_indirect::<B, T>(nes, m);
+            let m = read_word::<B>(nes, nes.cpu.pc);
+            let v = read_on_indirect::<B>(nes, m);
             nes.cpu.pc = nes.cpu.pc.wrapping_add(2);
             v
         }
         AddressingMode::IndexedIndirect => {
-            let m = read::<B, T>(nes, nes.cpu.pc);
-            let v = read_on_indirect::<B, T>(nes, m.wrapping_add(nes.cpu.x) as u16);
+            let m = read::<B>(nes, nes.cpu.pc);
+            let v = read_on_indirect::<B>(nes, m.wrapping_add(nes.cpu.x) as u16);
             nes.cpu.pc = nes.cpu.pc.wrapping_add(1);
-            T::tick(nes);
+            B::tick(nes);
             v
         }
-        AddressingMode::IndirectIndexed => {
-            let m = read::<B, T>(nes, nes.cpu.pc);
-            let n = read_on_indirect::<B, T>(nes, m as u16);
+        AddressingMode::IndirectIndexed { oops } => {
+            let m = read::<B>(nes, nes.cpu.pc);
+            let n = read_on_indirect::<B>(nes, m as u16);
             let v = n.wrapping_add(nes.cpu.y as u16);
             nes.cpu.pc = nes.cpu.pc.wrapping_add(1);
-            if page_crossed(nes.cpu.y as u16, n) {
-                T::tick(nes);
+            if !oops || page_crossed(nes.cpu.y as u16, n) {
+                B::tick(nes);
             }
             v
         }
@@ -96,7 +95,7 @@ mod test {
     fn implicit() {
         let mut nes = Nes::new();
 
-        let v = super::get_operand::<CpuBusMock, CpuTickMock>(&mut nes, AddressingMode::Implicit);
+        let v = super::get_operand::<CpuBusMock>(&mut nes, AddressingMode::Implicit);
         assert_eq!(v, 0);
         assert_eq!(nes.cpu_cycles, 0);
     }
@@ -106,8 +105,7 @@ mod test {
         let mut nes = Nes::new();
         nes.cpu.a = 0xFB;
 
-        let v =
-            super::get_operand::<CpuBusMock, CpuTickMock>(&mut nes, AddressingMode::Accumulator);
+        let v = super::get_operand::<CpuBusMock>(&mut nes, AddressingMode::Accumulator);
         assert_eq!(v, 0xFB);
         assert_eq!(nes.cpu_cycles, 0);
     }
@@ -116,7 +114,7 @@ mod test {
     fn immediate() {
         let mut nes = Nes::new();
         nes.cpu.pc = 0x8234;
-        let v = super::get_operand::<CpuBusMock, CpuTickMock>(&mut nes, AddressingMode::Immediate);
+        let v = super::get_operand::<CpuBusMock>(&mut nes, AddressingMode::Immediate);
         assert_eq!(v, 0x8234);
         assert_eq!(nes.cpu_cycles, 0);
     }
@@ -127,7 +125,7 @@ mod test {
         nes.cpu.pc = 0x0414;
         nes.wram[0x0414] = 0x91;
 
-        let v = super::get_operand::<CpuBusMock, CpuTickMock>(&mut nes, AddressingMode::ZeroPage);
+        let v = super::get_operand::<CpuBusMock>(&mut nes, AddressingMode::ZeroPage);
         assert_eq!(v, 0x91);
         assert_eq!(nes.cpu_cycles, 1);
     }
@@ -139,9 +137,9 @@ mod test {
         nes.wram[0x0100] = 0x80;
         nes.cpu.x = 0x93;
 
-        let v = super::get_operand::<CpuBusMock, CpuTickMock>(&mut nes, AddressingMode::ZeroPageX);
+        let v = super::get_operand::<CpuBusMock>(&mut nes, AddressingMode::ZeroPageX);
         assert_eq!(v, 0x13);
-        assert_eq!(nes.cpu_cycles, 1);
+        assert_eq!(nes.cpu_cycles, 2);
     }
 
     #[test]
@@ -151,9 +149,9 @@ mod test {
         nes.wram[0x0423] = 0x36;
         nes.cpu.y = 0xF1;
 
-        let v = super::get_operand::<CpuBusMock, CpuTickMock>(&mut nes, AddressingMode::ZeroPageY);
+        let v = super::get_operand::<CpuBusMock>(&mut nes, AddressingMode::ZeroPageY);
         assert_eq!(v, 0x27);
-        assert_eq!(nes.cpu_cycles, 1);
+        assert_eq!(nes.cpu_cycles, 2);
     }
 
     #[test]
@@ -163,7 +161,7 @@ mod test {
         nes.wram[0x0423] = 0x36;
         nes.wram[0x0424] = 0xF0;
 
-        let v = super::get_operand::<CpuBusMock, CpuTickMock>(&mut nes, AddressingMode::Absolute);
+        let v = super::get_operand::<CpuBusMock>(&mut nes, AddressingMode::Absolute);
         assert_eq!(v, 0xF036);
         assert_eq!(nes.cpu_cycles, 2);
     }
@@ -185,10 +183,7 @@ mod test {
 
             nes.cpu.x = x;
 
-            let v = super::get_operand::<CpuBusMock, CpuTickMock>(
-                &mut nes,
-                AddressingMode::AbsoluteX { oops },
-            );
+            let v = super::get_operand::<CpuBusMock>(&mut nes, AddressingMode::AbsoluteX { oops });
             assert_eq!(v, expected_operand, "{}", name);
             assert_eq!(nes.cpu_cycles, expected_cycles, "{}", name);
         }
@@ -211,10 +206,7 @@ mod test {
 
             nes.cpu.y = y;
 
-            let v = super::get_operand::<CpuBusMock, CpuTickMock>(
-                &mut nes,
-                AddressingMode::AbsoluteY { oops },
-            );
+            let v = super::get_operand::<CpuBusMock>(&mut nes, AddressingMode::AbsoluteY { oops });
             assert_eq!(v, expected_operand, "{}", name);
             assert_eq!(nes.cpu_cycles, expected_cycles, "{}", name);
         }
@@ -226,7 +218,7 @@ mod test {
         nes.cpu.pc = 0x0414;
         nes.wram[0x0414] = 0x91;
 
-        let v = super::get_operand::<CpuBusMock, CpuTickMock>(&mut nes, AddressingMode::Relative);
+        let v = super::get_operand::<CpuBusMock>(&mut nes, AddressingMode::Relative);
         assert_eq!(v, 0x91);
         assert_eq!(nes.cpu_cycles, 1);
     }
@@ -239,7 +231,7 @@ mod test {
         nes.wram[0x0210] = 0x03;
         nes.wram[0x0310] = 0x9F;
 
-        let v = super::get_operand::<CpuBusMock, CpuTickMock>(&mut nes, AddressingMode::Indirect);
+        let v = super::get_operand::<CpuBusMock>(&mut nes, AddressingMode::Indirect);
         assert_eq!(v, 0x9F);
         assert_eq!(nes.cpu_cycles, 4);
     }
@@ -253,10 +245,7 @@ mod test {
         nes.wram[0x0085] = 0x12;
         nes.wram[0x0086] = 0x90;
 
-        let v = super::get_operand::<CpuBusMock, CpuTickMock>(
-            &mut nes,
-            AddressingMode::IndexedIndirect,
-        );
+        let v = super::get_operand::<CpuBusMock>(&mut nes, AddressingMode::IndexedIndirect);
         assert_eq!(v, 0x9012);
         assert_eq!(nes.cpu_cycles, 4);
     }
@@ -265,11 +254,12 @@ mod test {
     fn indirect_indexed() {
         #[rustfmt::skip]
         let cases = [
-            ("not page crossed", 0x83, 0x9095, 3),
-            ("page crossed",     0xF3, 0x9105, 4),
+            ("no oops",               false, 0x83, 0x9095, 4),
+            ("oops/not page crossed", true,  0x83, 0x9095, 3),
+            ("oops/page crossed",     true,  0xF3, 0x9105, 4),
         ];
 
-        for (name, y, expected_operand, expected_cycles) in cases {
+        for (name, oops, y, expected_operand, expected_cycles) in cases {
             let mut nes = Nes::new();
             nes.cpu.pc = 0x020F;
             nes.wram[0x020F] = 0xF0;
@@ -277,9 +267,9 @@ mod test {
             nes.wram[0x00F1] = 0x90;
             nes.cpu.y = y;
 
-            let v = super::get_operand::<CpuBusMock, CpuTickMock>(
+            let v = super::get_operand::<CpuBusMock>(
                 &mut nes,
-                AddressingMode::IndirectIndexed,
+                AddressingMode::IndirectIndexed { oops },
             );
             assert_eq!(v, expected_operand, "{}", name);
             assert_eq!(nes.cpu_cycles, expected_cycles, "{}", name);
@@ -1,40 +1,45 @@
 use super::*;
 
-struct CpuBusInternal<B: CpuBus, T: CpuTick> {
-    _bus: std::marker::PhantomData<B>,
-    _tick: std::marker::PhantomData<T>,
+// An access and the cycle it takes, starting OAM DMA after a write to $4014
+pub(super) fn read<B: CpuBus>(nes: &mut Nes, addr: u16) -> u8 {
+    let v = B::read(nes, addr);
+    B::tick(nes);
+    v
 }
 
-impl<B: CpuBus, T: CpuTick> CpuBus for CpuBusInternal<B, T> {
-    fn read(nes: &mut Nes, addr: u16) -> u8 {
-        let v = B::read(nes, addr);
-        T::tick(nes);
-        v
+pub(super) fn write<B: CpuBus>(nes: &mut Nes, addr: u16, value: u8) {
+    B::write(nes, addr, value);
+    B::tick(nes);
+    if let Some(page) = nes.oam_dma.take() {
+        oam_dma::<B>(nes, page);
     }
-
-    fn write(nes: &mut Nes, addr: u16, value: u8) {
-        //TODO OAMDMA
-        B::write(nes, addr, value);
-        T::tick(nes);
-    }
-}
-
-pub(super) fn read<B: CpuBus, T: CpuTick>(nes: &mut Nes, addr: u16) -> u8 {
-    CpuBusInternal::<B, T>::read(nes, addr)
 }
 
-pub(super) fn write<B: CpuBus, T: CpuTick>(nes: &mut Nes, addr: u16, value: u8) {
-    CpuBusInternal::<B, T>::write(nes, addr, value)
+// Copy a page to OAM through $2004, halting the CPU for 513 or 514 cycles
+fn oam_dma<B: CpuBus>(nes: &mut Nes, page: u8) {
+    let start = nes.cpu_cycles;
+    B::tick(nes);
+    if nes.cpu_cycles % 2 == 1 {
+        B::tick(nes);
+    }
+    for i in 0..=0xFF {
+        let v = B::read(nes, (page as u16) << 8 | i);
+        B::tick(nes);
+        B::write(nes, 0x2004, v);
+        B::tick(nes);
+    }
+    if let Some(profile) = &mut nes.profile {
+        profile.dma((nes.cpu_cycles - start) as u64);
+    }
 }
 
-pub(super) fn read_word<B: CpuBus, T: CpuTick>(nes: &mut Nes, addr: u16) -> u16 {
-    CpuBusInternal::<B, T>::read(nes, addr) as u16
-        | (CpuBusInternal::<B, T>::read(nes, addr + 1) as u16) << 8
+pub(super) fn read_word<B: CpuBus>(nes: &mut Nes, addr: u16) -> u16 {
+    read::<B>(nes, addr) as u16 | (read::<B>(nes, addr.wrapping_add(1)) as u16) << 8
 }
 
-pub(super) fn read_on_indirect<B: CpuBus, T: CpuTick>(nes: &mut Nes, addr: u16) -> u16 {
-    let low = CpuBusInternal::<B, T>::read(nes, addr) as u16;
+pub(super) fn read_on_indirect<B: CpuBus>(nes: &mut Nes, addr: u16) -> u16 {
+    let low = read::<B>(nes, addr) as u16;
     // Reproduce 6502 bug - http://nesdev.com/6502bugs.txt
-    let high = CpuBusInternal::<B, T>::read(nes, (addr & 0xFF00) | ((addr + 1) & 0x00FF)) as u16;
+    let high = read::<B>(nes, (addr & 0xFF00) | (addr.wrapping_add(1) & 0x00FF)) as u16;
     low | (high << 8)
 }
@@ -0,0 +1,114 @@
+// Shadow call stack for debuggers, kept alongside the real one.
+//
+// Frames are opened by JSR, BRK and interrupts, and closed when S rises back to
+// where it was before the return address was pushed, whether by RTS/RTI, by
+// pulling the address off by hand or by resetting S with TXS. Pushing an address
+// and returning through it, as jump tables do, leaves the frames alone.
+
+// Cap for programs which never return, well beyond what 256 bytes of stack hold
+const MAX_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallKind {
+    Jsr,
+    Brk,
+    Nmi,
+    Irq,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallFrame {
+    pub kind: CallKind,
+    // Address of the JSR or BRK, or of the instruction an interrupt came before
+    pub from: u16,
+    // Address of the subroutine or handler
+    pub to: u16,
+    // Where a plain RTS or RTI resumes
+    pub return_addr: u16,
+    // S before the call pushed anything
+    pub s: u8,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct CallStack {
+    frames: Vec<CallFrame>,
+}
+
+impl CallStack {
+    pub(crate) fn call(&mut self, frame: CallFrame) {
+        self.unwind(frame.s);
+        if self.frames.len() == MAX_DEPTH {
+            self.frames.remove(0);
+        }
+        self.frames.push(frame);
+    }
+
+    // Close the frames whose stack bytes are free again with S at `s`
+    pub(crate) fn unwind(&mut self, s: u8) {
+        while let Some(frame) = self.frames.last() {
+            if s < frame.s {
+                break;
+            }
+            self.frames.pop();
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    // Outermost first
+    pub(crate) fn frames(&self) -> &[CallFrame] {
+        &self.frames
+    }
+
+    pub(crate) fn depth(&self) -> usize {
+        self.frames.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn jsr(s: u8) -> CallFrame {
+        CallFrame {
+            kind: CallKind::Jsr,
+            from: 0x8000,
+            to: 0x9000,
+            return_addr: 0x8003,
+            s,
+        }
+    }
+
+    #[test]
+    fn test_unwind() {
+        #[rustfmt::skip]
+        let cases: [(&str, &[u8], u8, usize); 5] = [
+            ("inside the innermost", &[0xFD, 0xFB], 0xF9, 2),
+            ("one byte pulled", &[0xFD, 0xFB], 0xFA, 2),
+            ("innermost returned", &[0xFD, 0xFB], 0xFB, 1),
+            ("both returned", &[0xFD, 0xFB], 0xFD, 0),
+            ("S reset by TXS", &[0xFD, 0xFB, 0xF9], 0xFF, 0),
+        ];
+
+        for (name, frames, s, depth) in cases {
+            let mut stack = CallStack::default();
+            for &f in frames {
+                stack.call(jsr(f));
+            }
+            stack.unwind(s);
+            assert_eq!(stack.depth(), depth, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_call_over_abandoned_frame() {
+        let mut stack = CallStack::default();
+        stack.call(jsr(0xFD));
+        stack.call(jsr(0xFB));
+        // the inner routine dropped its return address and jumped away
+        stack.call(jsr(0xFB));
+        assert_eq!(stack.depth(), 2);
+    }
+}
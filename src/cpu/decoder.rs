@@ -11,12 +11,13 @@ pub(super) enum AddressingMode {
     AbsoluteX { oops: bool },
     AbsoluteY { oops: bool },
     Relative,
-    Indirect, IndexedIndirect, IndirectIndexed
+    Indirect, IndexedIndirect,
+    IndirectIndexed { oops: bool },
 }
 
 #[derive(Debug)]
 #[rustfmt::skip]
-#[allow(dead_code, clippy::upper_case_acronyms)]
+#[allow(clippy::upper_case_acronyms)]
 pub(super) enum Mnemonic {
     // Load/Store Operations
     LDA, LDX, LDY, STA, STX, STY,
@@ -42,6 +43,7 @@ pub(super) enum Mnemonic {
     BRK, NOP,
     // Unofficial
     LAX, SAX, DCP, ISB, SLO, RLA, SRE, RRA,
+    ANC, ALR, ARR, AXS, LXA, LAS, SHX, SHY,
 }
 
 pub(super) fn decode(opcode: u8) -> Instruction {
@@ -53,7 +55,10 @@ pub(super) fn decode(opcode: u8) -> Instruction {
         0x7D => (Mnemonic::ADC, AddressingMode::AbsoluteX { oops: true }),
         0x79 => (Mnemonic::ADC, AddressingMode::AbsoluteY { oops: true }),
         0x61 => (Mnemonic::ADC, AddressingMode::IndexedIndirect),
-        0x71 => (Mnemonic::ADC, AddressingMode::IndirectIndexed),
+        0x71 => (
+            Mnemonic::ADC,
+            AddressingMode::IndirectIndexed { oops: true },
+        ),
 
         0x29 => (Mnemonic::AND, AddressingMode::Immediate),
         0x25 => (Mnemonic::AND, AddressingMode::ZeroPage),
@@ -62,7 +67,10 @@ pub(super) fn decode(opcode: u8) -> Instruction {
         0x3D => (Mnemonic::AND, AddressingMode::AbsoluteX { oops: true }),
         0x39 => (Mnemonic::AND, AddressingMode::AbsoluteY { oops: true }),
         0x21 => (Mnemonic::AND, AddressingMode::IndexedIndirect),
-        0x31 => (Mnemonic::AND, AddressingMode::IndirectIndexed),
+        0x31 => (
+            Mnemonic::AND,
+            AddressingMode::IndirectIndexed { oops: true },
+        ),
 
         0x0A => (Mnemonic::ASL, AddressingMode::Accumulator),
         0x06 => (Mnemonic::ASL, AddressingMode::ZeroPage),
@@ -96,9 +104,12 @@ pub(super) fn decode(opcode: u8) -> Instruction {
         0xD5 => (Mnemonic::CMP, AddressingMode::ZeroPageX),
         0xCD => (Mnemonic::CMP, AddressingMode::Absolute),
         0xDD => (Mnemonic::CMP, AddressingMode::AbsoluteX { oops: true }),
-        0xD9 => (Mnemonic::CMP, AddressingMode::AbsoluteY { oops: false }),
+        0xD9 => (Mnemonic::CMP, AddressingMode::AbsoluteY { oops: true }),
         0xC1 => (Mnemonic::CMP, AddressingMode::IndexedIndirect),
-        0xD1 => (Mnemonic::CMP, AddressingMode::IndirectIndexed),
+        0xD1 => (
+            Mnemonic::CMP,
+            AddressingMode::IndirectIndexed { oops: true },
+        ),
 
         0xE0 => (Mnemonic::CPX, AddressingMode::Immediate),
         0xE4 => (Mnemonic::CPX, AddressingMode::ZeroPage),
@@ -122,7 +133,10 @@ pub(super) fn decode(opcode: u8) -> Instruction {
         0x5D => (Mnemonic::EOR, AddressingMode::AbsoluteX { oops: true }),
         0x59 => (Mnemonic::EOR, AddressingMode::AbsoluteY { oops: true }),
         0x41 => (Mnemonic::EOR, AddressingMode::IndexedIndirect),
-        0x51 => (Mnemonic::EOR, AddressingMode::IndirectIndexed),
+        0x51 => (
+            Mnemonic::EOR,
+            AddressingMode::IndirectIndexed { oops: true },
+        ),
 
         0xE6 => (Mnemonic::INC, AddressingMode::ZeroPage),
         0xF6 => (Mnemonic::INC, AddressingMode::ZeroPageX),
@@ -144,7 +158,10 @@ pub(super) fn decode(opcode: u8) -> Instruction {
         0xBD => (Mnemonic::LDA, AddressingMode::AbsoluteX { oops: true }),
         0xB9 => (Mnemonic::LDA, AddressingMode::AbsoluteY { oops: true }),
         0xA1 => (Mnemonic::LDA, AddressingMode::IndexedIndirect),
-        0xB1 => (Mnemonic::LDA, AddressingMode::IndirectIndexed),
+        0xB1 => (
+            Mnemonic::LDA,
+            AddressingMode::IndirectIndexed { oops: true },
+        ),
 
         0xA2 => (Mnemonic::LDX, AddressingMode::Immediate),
         0xA6 => (Mnemonic::LDX, AddressingMode::ZeroPage),
@@ -173,7 +190,10 @@ pub(super) fn decode(opcode: u8) -> Instruction {
         0x1D => (Mnemonic::ORA, AddressingMode::AbsoluteX { oops: true }),
         0x19 => (Mnemonic::ORA, AddressingMode::AbsoluteY { oops: true }),
         0x01 => (Mnemonic::ORA, AddressingMode::IndexedIndirect),
-        0x11 => (Mnemonic::ORA, AddressingMode::IndirectIndexed),
+        0x11 => (
+            Mnemonic::ORA,
+            AddressingMode::IndirectIndexed { oops: true },
+        ),
 
         0x48 => (Mnemonic::PHA, AddressingMode::Implicit),
         0x08 => (Mnemonic::PHP, AddressingMode::Implicit),
@@ -202,7 +222,10 @@ pub(super) fn decode(opcode: u8) -> Instruction {
         0xFD => (Mnemonic::SBC, AddressingMode::AbsoluteX { oops: true }),
         0xF9 => (Mnemonic::SBC, AddressingMode::AbsoluteY { oops: true }),
         0xE1 => (Mnemonic::SBC, AddressingMode::IndexedIndirect),
-        0xF1 => (Mnemonic::SBC, AddressingMode::IndirectIndexed),
+        0xF1 => (
+            Mnemonic::SBC,
+            AddressingMode::IndirectIndexed { oops: true },
+        ),
 
         0x38 => (Mnemonic::SEC, AddressingMode::Implicit),
         0xF8 => (Mnemonic::SED, AddressingMode::Implicit),
@@ -213,8 +236,11 @@ pub(super) fn decode(opcode: u8) -> Instruction {
         0x8D => (Mnemonic::STA, AddressingMode::Absolute),
         0x9D => (Mnemonic::STA, AddressingMode::AbsoluteX { oops: false }),
         0x99 => (Mnemonic::STA, AddressingMode::AbsoluteY { oops: false }),
-        0x81 => (Mnemonic::STA, AddressingMode::ZeroPage),
-        0x91 => (Mnemonic::STA, AddressingMode::ZeroPage),
+        0x81 => (Mnemonic::STA, AddressingMode::IndexedIndirect),
+        0x91 => (
+            Mnemonic::STA,
+            AddressingMode::IndirectIndexed { oops: false },
+        ),
 
         0x86 => (Mnemonic::STX, AddressingMode::ZeroPage),
         0x96 => (Mnemonic::STX, AddressingMode::ZeroPageY),
@@ -230,6 +256,115 @@ pub(super) fn decode(opcode: u8) -> Instruction {
         0x9A => (Mnemonic::TXS, AddressingMode::Implicit),
         0x98 => (Mnemonic::TYA, AddressingMode::Implicit),
 
+        // Unofficial opcodes. The unstable ones (XAA, AHX, TAS) and the ones
+        // that halt the CPU run as NOPs.
+        0xA7 => (Mnemonic::LAX, AddressingMode::ZeroPage),
+        0xB7 => (Mnemonic::LAX, AddressingMode::ZeroPageY),
+        0xAF => (Mnemonic::LAX, AddressingMode::Absolute),
+        0xBF => (Mnemonic::LAX, AddressingMode::AbsoluteY { oops: true }),
+        0xA3 => (Mnemonic::LAX, AddressingMode::IndexedIndirect),
+        0xB3 => (
+            Mnemonic::LAX,
+            AddressingMode::IndirectIndexed { oops: true },
+        ),
+        0xAB => (Mnemonic::LXA, AddressingMode::Immediate),
+
+        0x87 => (Mnemonic::SAX, AddressingMode::ZeroPage),
+        0x97 => (Mnemonic::SAX, AddressingMode::ZeroPageY),
+        0x8F => (Mnemonic::SAX, AddressingMode::Absolute),
+        0x83 => (Mnemonic::SAX, AddressingMode::IndexedIndirect),
+
+        0xC7 => (Mnemonic::DCP, AddressingMode::ZeroPage),
+        0xD7 => (Mnemonic::DCP, AddressingMode::ZeroPageX),
+        0xCF => (Mnemonic::DCP, AddressingMode::Absolute),
+        0xDF => (Mnemonic::DCP, AddressingMode::AbsoluteX { oops: false }),
+        0xDB => (Mnemonic::DCP, AddressingMode::AbsoluteY { oops: false }),
+        0xC3 => (Mnemonic::DCP, AddressingMode::IndexedIndirect),
+        0xD3 => (
+            Mnemonic::DCP,
+            AddressingMode::IndirectIndexed { oops: false },
+        ),
+
+        0xE7 => (Mnemonic::ISB, AddressingMode::ZeroPage),
+        0xF7 => (Mnemonic::ISB, AddressingMode::ZeroPageX),
+        0xEF => (Mnemonic::ISB, AddressingMode::Absolute),
+        0xFF => (Mnemonic::ISB, AddressingMode::AbsoluteX { oops: false }),
+        0xFB => (Mnemonic::ISB, AddressingMode::AbsoluteY { oops: false }),
+        0xE3 => (Mnemonic::ISB, AddressingMode::IndexedIndirect),
+        0xF3 => (
+            Mnemonic::ISB,
+            AddressingMode::IndirectIndexed { oops: false },
+        ),
+
+        0x07 => (Mnemonic::SLO, AddressingMode::ZeroPage),
+        0x17 => (Mnemonic::SLO, AddressingMode::ZeroPageX),
+        0x0F => (Mnemonic::SLO, AddressingMode::Absolute),
+        0x1F => (Mnemonic::SLO, AddressingMode::AbsoluteX { oops: false }),
+        0x1B => (Mnemonic::SLO, AddressingMode::AbsoluteY { oops: false }),
+        0x03 => (Mnemonic::SLO, AddressingMode::IndexedIndirect),
+        0x13 => (
+            Mnemonic::SLO,
+            AddressingMode::IndirectIndexed { oops: false },
+        ),
+
+        0x27 => (Mnemonic::RLA, AddressingMode::ZeroPage),
+        0x37 => (Mnemonic::RLA, AddressingMode::ZeroPageX),
+        0x2F => (Mnemonic::RLA, AddressingMode::Absolute),
+        0x3F => (Mnemonic::RLA, AddressingMode::AbsoluteX { oops: false }),
+        0x3B => (Mnemonic::RLA, AddressingMode::AbsoluteY { oops: false }),
+        0x23 => (Mnemonic::RLA, AddressingMode::IndexedIndirect),
+        0x33 => (
+            Mnemonic::RLA,
+            AddressingMode::IndirectIndexed { oops: false },
+        ),
+
+        0x47 => (Mnemonic::SRE, AddressingMode::ZeroPage),
+        0x57 => (Mnemonic::SRE, AddressingMode::ZeroPageX),
+        0x4F => (Mnemonic::SRE, AddressingMode::Absolute),
+        0x5F => (Mnemonic::SRE, AddressingMode::AbsoluteX { oops: false }),
+        0x5B => (Mnemonic::SRE, AddressingMode::AbsoluteY { oops: false }),
+        0x43 => (Mnemonic::SRE, AddressingMode::IndexedIndirect),
+        0x53 => (
+            Mnemonic::SRE,
+            AddressingMode::IndirectIndexed { oops: false },
+        ),
+
+        0x67 => (Mnemonic::RRA, AddressingMode::ZeroPage),
+        0x77 => (Mnemonic::RRA, AddressingMode::ZeroPageX),
+        0x6F => (Mnemonic::RRA, AddressingMode::Absolute),
+        0x7F => (Mnemonic::RRA, AddressingMode::AbsoluteX { oops: false }),
+        0x7B => (Mnemonic::RRA, AddressingMode::AbsoluteY { oops: false }),
+        0x63 => (Mnemonic::RRA, AddressingMode::IndexedIndirect),
+        0x73 => (
+            Mnemonic::RRA,
+            AddressingMode::IndirectIndexed { oops: false },
+        ),
+
+        0x0B | 0x2B => (Mnemonic::ANC, AddressingMode::Immediate),
+        0x4B => (Mnemonic::ALR, AddressingMode::Immediate),
+        0x6B => (Mnemonic::ARR, AddressingMode::Immediate),
+        0xCB => (Mnemonic::AXS, AddressingMode::Immediate),
+        0xEB => (Mnemonic::SBC, AddressingMode::Immediate),
+        0xBB => (Mnemonic::LAS, AddressingMode::AbsoluteY { oops: true }),
+        0x9E => (Mnemonic::SHX, AddressingMode::AbsoluteY { oops: false }),
+        0x9C => (Mnemonic::SHY, AddressingMode::AbsoluteX { oops: false }),
+
+        // NOPs which read an operand
+        0x80 | 0x82 | 0x89 | 0xC2 | 0xE2 => (Mnemonic::NOP, AddressingMode::Immediate),
+        0x04 | 0x44 | 0x64 => (Mnemonic::NOP, AddressingMode::ZeroPage),
+        0x14 | 0x34 | 0x54 | 0x74 | 0xD4 | 0xF4 => (Mnemonic::NOP, AddressingMode::ZeroPageX),
+        0x0C => (Mnemonic::NOP, AddressingMode::Absolute),
+        0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => {
+            (Mnemonic::NOP, AddressingMode::AbsoluteX { oops: true })
+        }
+
+        // unstable stores, only timed
+        0x93 => (
+            Mnemonic::NOP,
+            AddressingMode::IndirectIndexed { oops: false },
+        ),
+        0x9B | 0x9F => (Mnemonic::NOP, AddressingMode::AbsoluteY { oops: false }),
+
         _ => (Mnemonic::NOP, AddressingMode::Implicit),
     }
 }
@@ -1,257 +1,177 @@
 use super::*;
 
-pub(super) fn execute<B: CpuBus, T: CpuTick>(
-    nes: &mut Nes,
-    instruction: Instruction,
-    operand: u16,
-) {
+pub(super) fn execute<B: CpuBus>(nes: &mut Nes, instruction: Instruction, operand: u16) {
     match instruction {
         (Mnemonic::LDA, _) => {
-            nes.cpu.a = read::<B, T>(nes, operand);
+            nes.cpu.a = read::<B>(nes, operand);
             nes.cpu.p.set_zn(nes.cpu.a);
         }
         (Mnemonic::LDX, _) => {
-            nes.cpu.x = read::<B, T>(nes, operand);
+            nes.cpu.x = read::<B>(nes, operand);
             nes.cpu.p.set_zn(nes.cpu.x);
         }
         (Mnemonic::LDY, _) => {
-            nes.cpu.y = read::<B, T>(nes, operand);
+            nes.cpu.y = read::<B>(nes, operand);
             nes.cpu.p.set_zn(nes.cpu.y);
         }
         (Mnemonic::STA, _) => {
-            write::<B, T>(nes, operand, nes.cpu.a);
+            write::<B>(nes, operand, nes.cpu.a);
         }
         (Mnemonic::STX, _) => {
-            write::<B, T>(nes, operand, nes.cpu.x);
+            write::<B>(nes, operand, nes.cpu.x);
         }
         (Mnemonic::STY, _) => {
-            write::<B, T>(nes, operand, nes.cpu.y);
+            write::<B>(nes, operand, nes.cpu.y);
         }
 
         (Mnemonic::TAX, _) => {
             nes.cpu.x = nes.cpu.a;
             nes.cpu.p.set_zn(nes.cpu.x);
-            T::tick(nes);
+            B::tick(nes);
         }
         (Mnemonic::TAY, _) => {
             nes.cpu.y = nes.cpu.a;
             nes.cpu.p.set_zn(nes.cpu.y);
-            T::tick(nes);
+            B::tick(nes);
         }
         (Mnemonic::TXA, _) => {
             nes.cpu.a = nes.cpu.x;
             nes.cpu.p.set_zn(nes.cpu.a);
-            T::tick(nes);
+            B::tick(nes);
         }
         (Mnemonic::TYA, _) => {
             nes.cpu.a = nes.cpu.y;
             nes.cpu.p.set_zn(nes.cpu.a);
-            T::tick(nes);
+            B::tick(nes);
         }
 
         (Mnemonic::TSX, _) => {
             nes.cpu.x = nes.cpu.s;
             nes.cpu.p.set_zn(nes.cpu.x);
-            T::tick(nes);
+            B::tick(nes);
         }
         (Mnemonic::TXS, _) => {
             nes.cpu.s = nes.cpu.x;
-            T::tick(nes);
+            nes.cpu.calls.unwind(nes.cpu.s);
+            B::tick(nes);
         }
         (Mnemonic::PHA, _) => {
-            push_stack::<B, T>(nes, nes.cpu.a);
-            T::tick(nes);
+            push_stack::<B>(nes, nes.cpu.a);
+            B::tick(nes);
         }
         (Mnemonic::PHP, _) => {
             let p = (nes.cpu.p | Status::INSTRUCTION_B).bits();
-            push_stack::<B, T>(nes, p);
-            T::tick(nes);
+            push_stack::<B>(nes, p);
+            B::tick(nes);
         }
         (Mnemonic::PLA, _) => {
-            nes.cpu.a = pull_stack::<B, T>(nes);
+            nes.cpu.a = pull_stack::<B>(nes);
             nes.cpu.p.set_zn(nes.cpu.a);
-            T::tick(nes);
+            B::tick_n(nes, 2);
         }
         (Mnemonic::PLP, _) => {
-            let v = pull_stack::<B, T>(nes);
+            let v = pull_stack::<B>(nes);
             nes.cpu.p = unsafe { Status::from_bits_unchecked(v) & !Status::INSTRUCTION_B };
-            T::tick_n(nes, 2);
+            B::tick_n(nes, 2);
         }
 
         (Mnemonic::AND, _) => {
-            nes.cpu.a &= read::<B, T>(nes, operand);
+            nes.cpu.a &= read::<B>(nes, operand);
             nes.cpu.p.set_zn(nes.cpu.a);
         }
         (Mnemonic::EOR, _) => {
-            nes.cpu.a ^= read::<B, T>(nes, operand);
+            nes.cpu.a ^= read::<B>(nes, operand);
             nes.cpu.p.set_zn(nes.cpu.a);
         }
         (Mnemonic::ORA, _) => {
-            nes.cpu.a |= read::<B, T>(nes, operand);
+            nes.cpu.a |= read::<B>(nes, operand);
             nes.cpu.p.set_zn(nes.cpu.a);
         }
         (Mnemonic::BIT, _) => {
-            let b = nes.cpu.a & read::<B, T>(nes, operand);
-            nes.cpu.p.set_zn(b);
-            nes.cpu.p.set(Status::V, b & 0x40 == 0x40);
+            let m = read::<B>(nes, operand);
+            nes.cpu.p.set(Status::Z, nes.cpu.a & m == 0);
+            nes.cpu.p.set(Status::N, m & 0x80 == 0x80);
+            nes.cpu.p.set(Status::V, m & 0x40 == 0x40);
         }
 
         (Mnemonic::ADC, _) => {
-            let m = read::<B, T>(nes, operand);
-            let mut r = nes.cpu.a.wrapping_add(m);
-
-            if nes.cpu.p.contains(Status::C) {
-                r = r.wrapping_add(1);
-            }
-
-            let a7 = nes.cpu.a >> 7 & 1;
-            let m7 = m >> 7 & 1;
-            let c6 = a7 ^ m7 ^ (r >> 7 & 1);
-            let c7 = (a7 & m7) | (a7 & c6) | (m7 & c6);
-            nes.cpu.p.set(Status::C, c7 == 1);
-            nes.cpu.p.set(Status::V, c6 ^ c7 == 1);
-
-            nes.cpu.a = r;
-            nes.cpu.p.set_zn(nes.cpu.a);
+            let m = read::<B>(nes, operand);
+            adc(nes, m);
         }
+        // A - M - !C is A + !M + C
         (Mnemonic::SBC, _) => {
-            let m = read::<B, T>(nes, operand);
-            let mut r = nes.cpu.a.wrapping_sub(m);
-
-            if nes.cpu.p.contains(Status::C) {
-                r = r.wrapping_add(1);
-            }
-
-            let a7 = nes.cpu.a >> 7 & 1;
-            let m7 = m >> 7 & 1;
-            let c6 = a7 ^ m7 ^ (r >> 7 & 1);
-            let c7 = (a7 & m7) | (a7 & c6) | (m7 & c6);
-            nes.cpu.p.set(Status::C, c7 == 1);
-            nes.cpu.p.set(Status::V, c6 ^ c7 == 1);
-
-            nes.cpu.a = r;
-            nes.cpu.p.set_zn(nes.cpu.a);
+            let m = read::<B>(nes, operand);
+            adc(nes, !m);
         }
         (Mnemonic::CMP, _) => {
-            let r = nes.cpu.a as i16 - read::<B, T>(nes, operand) as i16;
-            nes.cpu.p.set_zn(r as u8);
-            nes.cpu.p.set(Status::C, 0 < r);
+            let m = read::<B>(nes, operand);
+            compare(nes, nes.cpu.a, m);
         }
         (Mnemonic::CPX, _) => {
-            let r = nes.cpu.x as i16 - read::<B, T>(nes, operand) as i16;
-            nes.cpu.p.set_zn(r as u8);
-            nes.cpu.p.set(Status::C, 0 < r);
+            let m = read::<B>(nes, operand);
+            compare(nes, nes.cpu.x, m);
         }
         (Mnemonic::CPY, _) => {
-            let r = nes.cpu.y as i16 - read::<B, T>(nes, operand) as i16;
-            nes.cpu.p.set_zn(r as u8);
-            nes.cpu.p.set(Status::C, 0 < r);
+            let m = read::<B>(nes, operand);
+            compare(nes, nes.cpu.y, m);
         }
 
         (Mnemonic::INC, _) => {
-            let m = read::<B, T>(nes, operand);
-            let r = m.wrapping_add(1);
-            write::<B, T>(nes, operand, r);
+            let r = read_modify_write::<B>(nes, operand, |_, m| m.wrapping_add(1));
             nes.cpu.p.set_zn(r);
-            T::tick(nes);
         }
         (Mnemonic::INX, _) => {
             nes.cpu.x = nes.cpu.x.wrapping_add(1);
             nes.cpu.p.set_zn(nes.cpu.x);
-            T::tick(nes);
+            B::tick(nes);
         }
         (Mnemonic::INY, _) => {
             nes.cpu.y = nes.cpu.y.wrapping_add(1);
             nes.cpu.p.set_zn(nes.cpu.y);
-            T::tick(nes);
+            B::tick(nes);
         }
         (Mnemonic::DEC, _) => {
-            let m = read::<B, T>(nes, operand);
-            let r = m.wrapping_sub(1);
-            write::<B, T>(nes, operand, r);
+            let r = read_modify_write::<B>(nes, operand, |_, m| m.wrapping_sub(1));
             nes.cpu.p.set_zn(r);
-            T::tick(nes);
         }
         (Mnemonic::DEX, _) => {
             nes.cpu.x = nes.cpu.x.wrapping_sub(1);
             nes.cpu.p.set_zn(nes.cpu.x);
-            T::tick(nes);
+            B::tick(nes);
         }
         (Mnemonic::DEY, _) => {
             nes.cpu.y = nes.cpu.y.wrapping_sub(1);
             nes.cpu.p.set_zn(nes.cpu.y);
-            T::tick(nes);
+            B::tick(nes);
         }
 
         (Mnemonic::ASL, AddressingMode::Accumulator) => {
-            nes.cpu.p.set(Status::C, nes.cpu.a & 0x80 == 0x80);
-            nes.cpu.a <<= 1;
-            nes.cpu.p.set_zn(nes.cpu.a);
-            T::tick(nes);
+            nes.cpu.a = asl(nes, nes.cpu.a);
+            B::tick(nes);
         }
         (Mnemonic::ASL, _) => {
-            let mut m = read::<B, T>(nes, operand);
-            m <<= 1;
-            nes.cpu.p.set_zn(m);
-            write::<B, T>(nes, operand, m);
-            T::tick(nes);
+            read_modify_write::<B>(nes, operand, asl);
         }
         (Mnemonic::LSR, AddressingMode::Accumulator) => {
-            nes.cpu.p.set(Status::C, nes.cpu.a & 0x80 == 0x80);
-            nes.cpu.a >>= 1;
-            nes.cpu.p.set_zn(nes.cpu.a);
-            T::tick(nes);
+            nes.cpu.a = lsr(nes, nes.cpu.a);
+            B::tick(nes);
         }
         (Mnemonic::LSR, _) => {
-            let mut m = read::<B, T>(nes, operand);
-            m >>= 1;
-            nes.cpu.p.set_zn(m);
-            write::<B, T>(nes, operand, m);
-            T::tick(nes);
+            read_modify_write::<B>(nes, operand, lsr);
         }
         (Mnemonic::ROL, AddressingMode::Accumulator) => {
-            let c = nes.cpu.a & 0x80;
-            nes.cpu.a <<= 1;
-            if nes.cpu.p.contains(Status::C) {
-                nes.cpu.a |= 1;
-            }
-            nes.cpu.p.set(Status::C, c == 0x80);
-            nes.cpu.p.set_zn(nes.cpu.a);
-            T::tick(nes);
+            nes.cpu.a = rol(nes, nes.cpu.a);
+            B::tick(nes);
         }
         (Mnemonic::ROL, _) => {
-            let mut m = read::<B, T>(nes, operand);
-            let c = m & 0x80;
-            m <<= 1;
-            if nes.cpu.p.contains(Status::C) {
-                m |= 1;
-            }
-            nes.cpu.p.set(Status::C, c == 0x80);
-            nes.cpu.p.set_zn(m);
-            write::<B, T>(nes, operand, m);
-            T::tick(nes);
+            read_modify_write::<B>(nes, operand, rol);
         }
         (Mnemonic::ROR, AddressingMode::Accumulator) => {
-            let c = nes.cpu.a & 1;
-            nes.cpu.a >>= 1;
-            if nes.cpu.p.contains(Status::C) {
-                nes.cpu.a |= 0x80;
-            }
-            nes.cpu.p.set(Status::C, c == 1);
-            nes.cpu.p.set_zn(nes.cpu.a);
-            T::tick(nes);
+            nes.cpu.a = ror(nes, nes.cpu.a);
+            B::tick(nes);
         }
         (Mnemonic::ROR, _) => {
-            let mut m = read::<B, T>(nes, operand);
-            let c = m & 1;
-            m >>= 1;
-            if nes.cpu.p.contains(Status::C) {
-                m |= 0x80;
-            }
-            nes.cpu.p.set(Status::C, c == 1);
-            nes.cpu.p.set_zn(m);
-            write::<B, T>(nes, operand, m);
-            T::tick(nes);
+            read_modify_write::<B>(nes, operand, ror);
         }
 
         (Mnemonic::JMP, _) => {
@@ -259,111 +179,284 @@ pub(super) fn execute<B: CpuBus, T: CpuTick>(
         }
         (Mnemonic::JSR, _) => {
             let rtn = nes.cpu.pc.wrapping_sub(1);
-            push_stack_word::<B, T>(nes, rtn);
+            let s = nes.cpu.s;
+            push_stack_word::<B>(nes, rtn);
             nes.cpu.pc = operand;
-            T::tick(nes);
+            B::tick(nes);
+            nes.cpu.calls.call(CallFrame {
+                kind: CallKind::Jsr,
+                from: rtn.wrapping_sub(2),
+                to: operand,
+                return_addr: rtn.wrapping_add(1),
+                s,
+            });
         }
         (Mnemonic::RTS, _) => {
-            nes.cpu.pc = pull_stack_word::<B, T>(nes);
-            T::tick_n(nes, 3);
+            nes.cpu.pc = pull_stack_word::<B>(nes).wrapping_add(1);
+            B::tick_n(nes, 3);
         }
 
         (Mnemonic::BCC, _) => {
             if !nes.cpu.p.contains(Status::C) {
-                branch::<B, T>(nes, operand);
+                branch::<B>(nes, operand);
             }
         }
         (Mnemonic::BCS, _) => {
             if nes.cpu.p.contains(Status::C) {
-                branch::<B, T>(nes, operand);
+                branch::<B>(nes, operand);
             }
         }
         (Mnemonic::BEQ, _) => {
             if nes.cpu.p.contains(Status::Z) {
-                branch::<B, T>(nes, operand);
+                branch::<B>(nes, operand);
             }
         }
         (Mnemonic::BMI, _) => {
             if nes.cpu.p.contains(Status::N) {
-                branch::<B, T>(nes, operand);
+                branch::<B>(nes, operand);
             }
         }
         (Mnemonic::BNE, _) => {
             if !nes.cpu.p.contains(Status::Z) {
-                branch::<B, T>(nes, operand);
+                branch::<B>(nes, operand);
             }
         }
         (Mnemonic::BPL, _) => {
             if !nes.cpu.p.contains(Status::N) {
-                branch::<B, T>(nes, operand);
+                branch::<B>(nes, operand);
             }
         }
         (Mnemonic::BVC, _) => {
             if !nes.cpu.p.contains(Status::V) {
-                branch::<B, T>(nes, operand);
+                branch::<B>(nes, operand);
             }
         }
         (Mnemonic::BVS, _) => {
             if nes.cpu.p.contains(Status::V) {
-                branch::<B, T>(nes, operand);
+                branch::<B>(nes, operand);
             }
         }
 
         (Mnemonic::CLC, _) => {
             nes.cpu.p.remove(Status::C);
-            T::tick(nes);
+            B::tick(nes);
         }
         (Mnemonic::CLD, _) => {
             nes.cpu.p.remove(Status::D);
-            T::tick(nes);
+            B::tick(nes);
         }
         (Mnemonic::CLI, _) => {
             nes.cpu.p.remove(Status::I);
-            T::tick(nes);
+            B::tick(nes);
         }
         (Mnemonic::CLV, _) => {
             nes.cpu.p.remove(Status::V);
-            T::tick(nes);
+            B::tick(nes);
         }
         (Mnemonic::SEC, _) => {
             nes.cpu.p.insert(Status::C);
-            T::tick(nes);
+            B::tick(nes);
         }
         (Mnemonic::SED, _) => {
             nes.cpu.p.insert(Status::D);
-            T::tick(nes);
+            B::tick(nes);
         }
         (Mnemonic::SEI, _) => {
             nes.cpu.p.insert(Status::I);
-            T::tick(nes);
+            B::tick(nes);
         }
 
+        // the byte after BRK is skipped
         (Mnemonic::BRK, _) => {
-            push_stack_word::<B, T>(nes, nes.cpu.pc);
-            nes.cpu.p.insert(Status::INSTRUCTION_B);
-            push_stack::<B, T>(nes, nes.cpu.p.bits());
-            nes.cpu.pc = read_word::<B, T>(nes, 0xFFFE);
-            T::tick(nes);
-        }
+            let (rtn, s) = (nes.cpu.pc.wrapping_add(1), nes.cpu.s);
+            B::tick(nes);
+            push_stack_word::<B>(nes, rtn);
+            push_stack::<B>(nes, (nes.cpu.p | Status::INSTRUCTION_B).bits());
+            nes.cpu.p.insert(Status::I);
+            nes.cpu.pc = read_word::<B>(nes, 0xFFFE);
+            nes.cpu.calls.call(CallFrame {
+                kind: CallKind::Brk,
+                from: rtn.wrapping_sub(2),
+                to: nes.cpu.pc,
+                return_addr: rtn,
+                s,
+            });
+        }
+        (Mnemonic::NOP, AddressingMode::Implicit) => {
+            B::tick(nes);
+        }
+        // reads its operand and ignores it
         (Mnemonic::NOP, _) => {
-            T::tick(nes);
+            read::<B>(nes, operand);
         }
         (Mnemonic::RTI, _) => {
-            let p = pull_stack::<B, T>(nes);
+            let p = pull_stack::<B>(nes);
             nes.cpu.p = unsafe { Status::from_bits_unchecked(p) & !Status::INSTRUCTION_B };
-            nes.cpu.pc = pull_stack_word::<B, T>(nes);
-            T::tick_n(nes, 2);
+            nes.cpu.pc = pull_stack_word::<B>(nes);
+            B::tick_n(nes, 2);
+        }
+
+        (Mnemonic::LAX, _) => {
+            let m = read::<B>(nes, operand);
+            nes.cpu.a = m;
+            nes.cpu.x = m;
+            nes.cpu.p.set_zn(m);
+        }
+        // ANDs with a value that varies between consoles; $FF is the common one
+        (Mnemonic::LXA, _) => {
+            let m = read::<B>(nes, operand);
+            nes.cpu.a = m;
+            nes.cpu.x = m;
+            nes.cpu.p.set_zn(m);
+        }
+        (Mnemonic::SAX, _) => {
+            write::<B>(nes, operand, nes.cpu.a & nes.cpu.x);
+        }
+        (Mnemonic::DCP, _) => {
+            let m = read_modify_write::<B>(nes, operand, |_, m| m.wrapping_sub(1));
+            compare(nes, nes.cpu.a, m);
+        }
+        (Mnemonic::ISB, _) => {
+            let m = read_modify_write::<B>(nes, operand, |_, m| m.wrapping_add(1));
+            adc(nes, !m);
+        }
+        (Mnemonic::SLO, _) => {
+            let m = read_modify_write::<B>(nes, operand, asl);
+            nes.cpu.a |= m;
+            nes.cpu.p.set_zn(nes.cpu.a);
+        }
+        (Mnemonic::RLA, _) => {
+            let m = read_modify_write::<B>(nes, operand, rol);
+            nes.cpu.a &= m;
+            nes.cpu.p.set_zn(nes.cpu.a);
+        }
+        (Mnemonic::SRE, _) => {
+            let m = read_modify_write::<B>(nes, operand, lsr);
+            nes.cpu.a ^= m;
+            nes.cpu.p.set_zn(nes.cpu.a);
+        }
+        (Mnemonic::RRA, _) => {
+            let m = read_modify_write::<B>(nes, operand, ror);
+            adc(nes, m);
+        }
+        (Mnemonic::ANC, _) => {
+            nes.cpu.a &= read::<B>(nes, operand);
+            nes.cpu.p.set_zn(nes.cpu.a);
+            nes.cpu.p.set(Status::C, nes.cpu.a & 0x80 == 0x80);
+        }
+        (Mnemonic::ALR, _) => {
+            let v = nes.cpu.a & read::<B>(nes, operand);
+            nes.cpu.a = lsr(nes, v);
+        }
+        (Mnemonic::ARR, _) => {
+            let v = nes.cpu.a & read::<B>(nes, operand);
+            nes.cpu.a = ror(nes, v);
+            let a = nes.cpu.a;
+            nes.cpu.p.set(Status::C, a & 0x40 == 0x40);
+            nes.cpu.p.set(Status::V, (a >> 6 ^ a >> 5) & 1 == 1);
+        }
+        (Mnemonic::AXS, _) => {
+            let m = read::<B>(nes, operand);
+            let v = nes.cpu.a & nes.cpu.x;
+            nes.cpu.x = v.wrapping_sub(m);
+            nes.cpu.p.set(Status::C, m <= v);
+            nes.cpu.p.set_zn(nes.cpu.x);
+        }
+        (Mnemonic::LAS, _) => {
+            let v = read::<B>(nes, operand) & nes.cpu.s;
+            nes.cpu.a = v;
+            nes.cpu.x = v;
+            nes.cpu.s = v;
+            nes.cpu.p.set_zn(v);
+        }
+        (Mnemonic::SHX, _) => {
+            store_high_and::<B>(nes, operand, nes.cpu.y, nes.cpu.x);
+        }
+        (Mnemonic::SHY, _) => {
+            store_high_and::<B>(nes, operand, nes.cpu.x, nes.cpu.y);
         }
-        _ => unimplemented!("nop"),
     }
 }
 
-fn branch<B: CpuBus, T: CpuTick>(nes: &mut Nes, operand: u16) {
-    T::tick(nes);
-    if page_crossed(operand, nes.cpu.pc) {
-        T::tick(nes);
+fn adc(nes: &mut Nes, m: u8) {
+    let a = nes.cpu.a;
+    let r = a as u16 + m as u16 + nes.cpu.p.contains(Status::C) as u16;
+    nes.cpu.p.set(Status::C, 0xFF < r);
+    let r = r as u8;
+    // both inputs have the same sign and the result has another
+    nes.cpu.p.set(Status::V, (a ^ r) & (m ^ r) & 0x80 != 0);
+    nes.cpu.a = r;
+    nes.cpu.p.set_zn(r);
+}
+
+fn compare(nes: &mut Nes, register: u8, m: u8) {
+    nes.cpu.p.set_zn(register.wrapping_sub(m));
+    nes.cpu.p.set(Status::C, m <= register);
+}
+
+fn asl(nes: &mut Nes, v: u8) -> u8 {
+    nes.cpu.p.set(Status::C, v & 0x80 == 0x80);
+    let r = v << 1;
+    nes.cpu.p.set_zn(r);
+    r
+}
+
+fn lsr(nes: &mut Nes, v: u8) -> u8 {
+    nes.cpu.p.set(Status::C, v & 1 == 1);
+    let r = v >> 1;
+    nes.cpu.p.set_zn(r);
+    r
+}
+
+fn rol(nes: &mut Nes, v: u8) -> u8 {
+    let r = v << 1 | nes.cpu.p.contains(Status::C) as u8;
+    nes.cpu.p.set(Status::C, v & 0x80 == 0x80);
+    nes.cpu.p.set_zn(r);
+    r
+}
+
+fn ror(nes: &mut Nes, v: u8) -> u8 {
+    let r = v >> 1 | (nes.cpu.p.contains(Status::C) as u8) << 7;
+    nes.cpu.p.set(Status::C, v & 1 == 1);
+    nes.cpu.p.set_zn(r);
+    r
+}
+
+// Read, an idle cycle, then write back the result, which is returned
+fn read_modify_write<B: CpuBus>(
+    nes: &mut Nes,
+    addr: u16,
+    f: impl FnOnce(&mut Nes, u8) -> u8,
+) -> u8 {
+    let m = read::<B>(nes, addr);
+    let r = f(nes, m);
+    write::<B>(nes, addr, r);
+    B::tick(nes);
+    r
+}
+
+// SHX and SHY store the register ANDed with the high byte of the base address
+// plus one; when indexing crosses a page, that value also replaces the high
+// byte of the address
+fn store_high_and<B: CpuBus>(nes: &mut Nes, addr: u16, index: u8, register: u8) {
+    let base = addr.wrapping_sub(index as u16);
+    let v = register & ((base >> 8) as u8).wrapping_add(1);
+    let addr = if base & 0xFF00 != addr & 0xFF00 {
+        (v as u16) << 8 | (addr & 0xFF)
+    } else {
+        addr
+    };
+    write::<B>(nes, addr, v);
+}
+
+fn branch<B: CpuBus>(nes: &mut Nes, operand: u16) {
+    // the offset is signed
+    let offset = operand as u8 as i8 as u16;
+    B::tick(nes);
+    if page_crossed(offset, nes.cpu.pc) {
+        B::tick(nes);
     }
-    nes.cpu.pc = nes.cpu.pc.wrapping_add(operand);
+    nes.cpu.pc = nes.cpu.pc.wrapping_add(offset);
 }
 
 impl Status {
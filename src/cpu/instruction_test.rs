@@ -1,5 +1,6 @@
 use super::test_mock::*;
 use super::*;
+use crate::nes::Irq;
 
 #[test]
 fn load_store_operations() {
@@ -10,7 +11,7 @@ fn load_store_operations() {
         nes.wram[0x020F] = 0xA9;
         nes.wram[0x0210] = 0x31;
 
-        Emu::cpu_step::<CpuBusMock, CpuTickMock>(&mut nes);
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
         assert_eq!(nes.cpu.a, 0x31);
         assert_eq!(nes.cpu_cycles, 2);
         assert_eq!(nes.cpu.p, Status::empty());
@@ -24,7 +25,7 @@ fn load_store_operations() {
         nes.wram[0x0211] = 0x04;
         nes.cpu.a = 0x91;
 
-        Emu::cpu_step::<CpuBusMock, CpuTickMock>(&mut nes);
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
         assert_eq!(CpuBusMock::read(&mut nes, 0x0419), 0x91);
         assert_eq!(nes.cpu_cycles, 4);
     }
@@ -39,7 +40,7 @@ fn register_transfers() {
         nes.wram[0x020F] = 0xAA;
         nes.cpu.a = 0x83;
 
-        Emu::cpu_step::<CpuBusMock, CpuTickMock>(&mut nes);
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
         assert_eq!(nes.cpu.x, 0x83);
         assert_eq!(nes.cpu_cycles, 2);
         assert_eq!(nes.cpu.p, Status::N);
@@ -51,7 +52,7 @@ fn register_transfers() {
         nes.wram[0x020F] = 0x98;
         nes.cpu.y = 0xF0;
 
-        Emu::cpu_step::<CpuBusMock, CpuTickMock>(&mut nes);
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
         assert_eq!(nes.cpu.a, 0xF0);
         assert_eq!(nes.cpu_cycles, 2);
         assert_eq!(nes.cpu.p, Status::N);
@@ -67,7 +68,7 @@ fn stack_operations() {
         nes.wram[0x020F] = 0xBA;
         nes.cpu.s = 0xF3;
 
-        Emu::cpu_step::<CpuBusMock, CpuTickMock>(&mut nes);
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
         assert_eq!(nes.cpu.x, 0xF3);
         assert_eq!(nes.cpu_cycles, 2);
         assert_eq!(nes.cpu.p, Status::N);
@@ -80,9 +81,9 @@ fn stack_operations() {
         nes.cpu.s = 0xFD;
         nes.cpu.a = 0x72;
 
-        Emu::cpu_step::<CpuBusMock, CpuTickMock>(&mut nes);
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
         assert_eq!(nes.cpu.s, 0xFC);
-        assert_eq!(CpuBusMock::read(&mut nes, 0x00FD), 0x72);
+        assert_eq!(CpuBusMock::read(&mut nes, 0x01FD), 0x72);
         assert_eq!(nes.cpu_cycles, 3);
     }
     // PHP
@@ -93,10 +94,10 @@ fn stack_operations() {
         nes.cpu.s = 0xFD;
         nes.cpu.p = Status::N | Status::D | Status::C;
 
-        Emu::cpu_step::<CpuBusMock, CpuTickMock>(&mut nes);
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
         assert_eq!(nes.cpu.s, 0xFC);
         assert_eq!(
-            CpuBusMock::read(&mut nes, 0x00FD),
+            CpuBusMock::read(&mut nes, 0x01FD),
             (nes.cpu.p | Status::INSTRUCTION_B).bits()
         );
         assert_eq!(nes.cpu_cycles, 3);
@@ -107,9 +108,9 @@ fn stack_operations() {
         nes.cpu.pc = 0x020F;
         nes.wram[0x020F] = 0x28;
         nes.cpu.s = 0xBF;
-        nes.wram[0x00C0] = 0x7A;
+        nes.wram[0x01C0] = 0x7A;
 
-        Emu::cpu_step::<CpuBusMock, CpuTickMock>(&mut nes);
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
         assert_eq!(nes.cpu.s, 0xC0);
         assert_eq!(nes.cpu.p.bits(), 0x4A);
         assert_eq!(nes.cpu_cycles, 4);
@@ -126,7 +127,7 @@ fn logical() {
         nes.wram[0x0210] = 0x38;
         nes.cpu.a = 0x21;
 
-        Emu::cpu_step::<CpuBusMock, CpuTickMock>(&mut nes);
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
         assert_eq!(nes.cpu.a, 0x19);
         assert_eq!(nes.cpu_cycles, 2);
         assert_eq!(nes.cpu.p, Status::empty());
@@ -141,9 +142,10 @@ fn logical() {
         nes.wram[0x03B0] = (Status::V | Status::N).bits();
         nes.cpu.a = 0x48;
 
-        Emu::cpu_step::<CpuBusMock, CpuTickMock>(&mut nes);
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
         assert_eq!(nes.cpu_cycles, 4);
-        assert_eq!(nes.cpu.p, Status::V);
+        // N and V come from memory, Z from A AND memory
+        assert_eq!(nes.cpu.p, Status::V | Status::N);
     }
 }
 
@@ -172,7 +174,7 @@ fn arithmetic() {
             nes.wram[0x04D3] = *m;
             nes.cpu.a = *a;
 
-            Emu::cpu_step::<CpuBusMock, CpuTickMock>(&mut nes);
+            Emu::cpu_step::<CpuBusMock>(&mut nes);
             assert_eq!(nes.cpu.a, *expected_a, "{}", i);
             assert_eq!(nes.cpu.p, *expected_p, "{}", i);
         }
@@ -185,7 +187,7 @@ fn arithmetic() {
         nes.wram[0x0210] = 0x36;
         nes.cpu.y = 0x37;
 
-        Emu::cpu_step::<CpuBusMock, CpuTickMock>(&mut nes);
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
         assert_eq!(nes.cpu.p, Status::C)
     }
 }
@@ -201,7 +203,7 @@ fn increments_and_decrements() {
         nes.wram[0x0211] = 0x04;
         nes.wram[0x04D3] = 0x7F;
 
-        Emu::cpu_step::<CpuBusMock, CpuTickMock>(&mut nes);
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
         assert_eq!(CpuBusMock::read(&mut nes, 0x04D3), 0x80);
         assert_eq!(nes.cpu.p, Status::N);
     }
@@ -214,7 +216,7 @@ fn increments_and_decrements() {
         nes.wram[0x0211] = 0x04;
         nes.wram[0x04D3] = 0xC0;
 
-        Emu::cpu_step::<CpuBusMock, CpuTickMock>(&mut nes);
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
         assert_eq!(CpuBusMock::read(&mut nes, 0x04D3), 0xBF);
         assert_eq!(nes.cpu.p, Status::N);
     }
@@ -229,7 +231,7 @@ fn shifts() {
         nes.wram[0x020F] = 0x0A;
         nes.cpu.a = 0b10001010;
 
-        Emu::cpu_step::<CpuBusMock, CpuTickMock>(&mut nes);
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
         assert_eq!(nes.cpu.a, 0b00010100);
         assert_eq!(nes.cpu.p, Status::C);
     }
@@ -241,7 +243,7 @@ fn shifts() {
         nes.cpu.a = 0b10001010;
         nes.cpu.p = Status::C;
 
-        Emu::cpu_step::<CpuBusMock, CpuTickMock>(&mut nes);
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
         assert_eq!(nes.cpu.a, 0b00010101);
         assert_eq!(nes.cpu.p, Status::C);
     }
@@ -252,7 +254,7 @@ fn shifts() {
         nes.cpu.a = 0b10001010;
         nes.cpu.p = Status::N;
 
-        Emu::cpu_step::<CpuBusMock, CpuTickMock>(&mut nes);
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
         assert_eq!(nes.cpu.a, 0b00010100);
         assert_eq!(nes.cpu.p, Status::C);
     }
@@ -269,12 +271,12 @@ fn calls() {
         nes.wram[0x0211] = 0x40;
         nes.cpu.s = 0xBF;
 
-        Emu::cpu_step::<CpuBusMock, CpuTickMock>(&mut nes);
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
         assert_eq!(nes.cpu.s, 0xBD);
         assert_eq!(nes.cpu.pc, 0x4031);
         assert_eq!(nes.cpu_cycles, 6);
-        assert_eq!(CpuBusMock::read(&mut nes, 0xBE), 0x11);
-        assert_eq!(CpuBusMock::read(&mut nes, 0xBF), 0x02);
+        assert_eq!(CpuBusMock::read(&mut nes, 0x01BE), 0x11);
+        assert_eq!(CpuBusMock::read(&mut nes, 0x01BF), 0x02);
     }
     // RTS
     {
@@ -283,12 +285,12 @@ fn calls() {
         nes.wram[0x0031] = 0x60;
 
         nes.cpu.s = 0xBD;
-        nes.wram[0x00BE] = 0x11;
-        nes.wram[0x00BF] = 0x02;
+        nes.wram[0x01BE] = 0x11;
+        nes.wram[0x01BF] = 0x02;
 
-        Emu::cpu_step::<CpuBusMock, CpuTickMock>(&mut nes);
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
         assert_eq!(nes.cpu.s, 0xBF);
-        assert_eq!(nes.cpu.pc, 0x0211);
+        assert_eq!(nes.cpu.pc, 0x0212);
         assert_eq!(nes.cpu_cycles, 6);
     }
 }
@@ -298,23 +300,20 @@ fn branches() {
     // BCC
     #[rustfmt::skip]
         let cases = [
-            ("branch failed",               0x03, false, Status::N | Status::C, 2),
-            ("branch succeed",              0x03, true, Status::N | Status::V, 3),
-            ("branch succeed & new page",   0xD0, true, Status::N | Status::V, 4),
+            ("branch failed",               0x03, Status::N | Status::C, 0x0033, 2),
+            ("branch succeed",              0x03, Status::N | Status::V, 0x0036, 3),
+            ("branch backward",             0xFB, Status::N | Status::V, 0x002E, 3),
+            ("branch backward & new page",  0xC0, Status::N | Status::V, 0xFFF3, 4),
         ];
-    for (name, operand, branch, p, expected_cycles) in cases {
+    for (name, operand, p, expected_pc, expected_cycles) in cases {
         let mut nes = Nes::new();
         nes.cpu.pc = 0x0031;
         nes.wram[0x0031] = 0x90;
         nes.wram[0x0032] = operand;
         nes.cpu.p = p;
 
-        Emu::cpu_step::<CpuBusMock, CpuTickMock>(&mut nes);
-        if branch {
-            assert_eq!(nes.cpu.pc, 0x33 + operand as u16, "{}", name);
-        } else {
-            assert_eq!(nes.cpu.pc, 0x33, "{}", name);
-        }
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
+        assert_eq!(nes.cpu.pc, expected_pc, "{}", name);
         assert_eq!(nes.cpu_cycles, expected_cycles, "{}", name);
     }
 }
@@ -328,7 +327,7 @@ fn status_flag_changes() {
         nes.wram[0x020F] = 0xD8;
         nes.cpu.p = Status::V | Status::D | Status::C;
 
-        Emu::cpu_step::<CpuBusMock, CpuTickMock>(&mut nes);
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
         assert_eq!(nes.cpu.pc, 0x0210);
         assert_eq!(nes.cpu_cycles, 2);
         assert_eq!(nes.cpu.p, Status::V | Status::C);
@@ -340,7 +339,7 @@ fn status_flag_changes() {
         nes.wram[0x020F] = 0x78;
         nes.cpu.p = Status::V | Status::D | Status::C;
 
-        Emu::cpu_step::<CpuBusMock, CpuTickMock>(&mut nes);
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
         assert_eq!(nes.cpu.pc, 0x0210);
         assert_eq!(nes.cpu_cycles, 2);
         assert_eq!(nes.cpu.p, Status::V | Status::D | Status::C | Status::I);
@@ -361,6 +360,9 @@ impl CpuBus for CpuBusMockForBRK {
     fn write(nes: &mut Nes, addr: u16, value: u8) {
         nes.wram[addr as usize] = value
     }
+    fn tick(nes: &mut Nes) {
+        tick(nes);
+    }
 }
 
 #[test]
@@ -374,14 +376,25 @@ fn system_functions() {
         nes.cpu.s = 0xBF;
         // $FFFE/F = 0x23/0x40 in CpuBusMockForBRK
 
-        Emu::cpu_step::<CpuBusMockForBRK, CpuTickMock>(&mut nes);
+        Emu::cpu_step::<CpuBusMockForBRK>(&mut nes);
         assert_eq!(nes.cpu.pc, 0x4023);
         assert_eq!(nes.cpu_cycles, 7);
         assert_eq!(nes.cpu.s, 0xBC);
         assert_eq!(
-            nes.cpu.p,
-            Status::V | Status::D | Status::C | Status::INSTRUCTION_B
+            nes.cpu.calls.frames(),
+            [CallFrame {
+                kind: CallKind::Brk,
+                from: 0x020F,
+                to: 0x4023,
+                return_addr: 0x0211,
+                s: 0xBF,
+            }]
         );
+        assert_eq!(nes.cpu.p, Status::V | Status::D | Status::C | Status::I);
+        // the return address skips the padding byte, and B is set on the stack only
+        assert_eq!(nes.wram[0x01BF], 0x02);
+        assert_eq!(nes.wram[0x01BE], 0x11);
+        assert_eq!(nes.wram[0x01BD], 0x79);
     }
     // RTI
     {
@@ -391,14 +404,131 @@ fn system_functions() {
         nes.cpu.p = Status::V | Status::D | Status::C | Status::I;
 
         nes.cpu.s = 0xBC;
-        nes.wram[0x00BD] = (Status::N | Status::Z).bits();
-        nes.wram[0x00BE] = 0x11;
-        nes.wram[0x00BF] = 0x02;
+        nes.wram[0x01BD] = (Status::N | Status::Z).bits();
+        nes.wram[0x01BE] = 0x11;
+        nes.wram[0x01BF] = 0x02;
 
-        Emu::cpu_step::<CpuBusMock, CpuTickMock>(&mut nes);
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
         assert_eq!(nes.cpu.s, 0xBF);
         assert_eq!(nes.cpu.p, Status::N | Status::Z);
         assert_eq!(nes.cpu.pc, 0x0211);
         assert_eq!(nes.cpu_cycles, 6);
     }
 }
+
+#[test]
+fn interrupts() {
+    // IRQ
+    {
+        let mut nes = Nes::new();
+        nes.cpu.pc = 0x020F;
+        nes.cpu.p = Status::C;
+        nes.cpu.s = 0xBF;
+        nes.irq = Irq::MAPPER;
+        // $FFFE/F = 0x23/0x40 in CpuBusMockForBRK
+
+        Emu::cpu_step::<CpuBusMockForBRK>(&mut nes);
+        assert_eq!(nes.cpu.pc, 0x4023);
+        assert_eq!(nes.cpu_cycles, 7);
+        assert_eq!(nes.cpu.s, 0xBC);
+        assert_eq!(
+            nes.cpu.calls.frames(),
+            [CallFrame {
+                kind: CallKind::Irq,
+                from: 0x020F,
+                to: 0x4023,
+                return_addr: 0x020F,
+                s: 0xBF,
+            }]
+        );
+        assert_eq!(nes.wram[0x01BD], (Status::C | Status::INTERRUPT_B).bits());
+        assert_eq!(nes.wram[0x01BE], 0x0F);
+        assert_eq!(nes.wram[0x01BF], 0x02);
+        assert_eq!(nes.cpu.p, Status::C | Status::I);
+    }
+    // IRQ masked
+    {
+        let mut nes = Nes::new();
+        nes.cpu.pc = 0x020F;
+        nes.wram[0x020F] = 0xEA;
+        nes.cpu.p = Status::I;
+        nes.irq = Irq::MAPPER;
+
+        Emu::cpu_step::<CpuBusMockForBRK>(&mut nes);
+        assert_eq!(nes.cpu.pc, 0x0210);
+        assert_eq!(nes.cpu_cycles, 2);
+    }
+}
+
+#[test]
+fn cycle_counts() {
+    // Without page crossings, from the NMOS 6502 tables instr_timing checks.
+    // 0 marks the opcodes which halt the CPU, and branches are checked in
+    // `branches`.
+    #[rustfmt::skip]
+    let cycles: [u128; 256] = [
+        7, 6, 0, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6,
+        0, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+        6, 6, 0, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6,
+        0, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+        6, 6, 0, 8, 3, 3, 5, 5, 3, 2, 2, 2, 3, 4, 6, 6,
+        0, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+        6, 6, 0, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6,
+        0, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+        2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
+        0, 6, 0, 6, 4, 4, 4, 4, 2, 5, 2, 5, 5, 5, 5, 5,
+        2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
+        0, 5, 0, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4,
+        2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
+        0, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+        2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
+        0, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    ];
+
+    let mut wrong = Vec::new();
+    for (opcode, &expected) in cycles.iter().enumerate() {
+        if expected == 0 {
+            continue;
+        }
+        let mut nes = Nes::new();
+        nes.cpu.pc = 0x0200;
+        nes.cpu.s = 0xFD;
+        nes.wram[0x0200] = opcode as u8;
+
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
+        if nes.cpu_cycles != expected {
+            wrong.push(format!("{:02X}: {}", opcode, nes.cpu_cycles));
+        }
+    }
+    assert!(wrong.is_empty(), "{:?}", wrong);
+}
+
+#[test]
+fn address_space_wraps() {
+    // LDA $0234 with its operand straddling $FFFF
+    {
+        let mut nes = Nes::new();
+        nes.cpu.pc = 0xFFFE;
+        nes.wram[0x07FE] = 0xAD;
+        nes.wram[0x07FF] = 0x34;
+        nes.wram[0x0000] = 0x02;
+        nes.wram[0x0234] = 0x5A;
+
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
+        assert_eq!(nes.cpu.a, 0x5A);
+        assert_eq!(nes.cpu.pc, 0x0001);
+    }
+    // JMP ($FFFF) takes the high byte from $FF00
+    {
+        let mut nes = Nes::new();
+        nes.cpu.pc = 0x0200;
+        nes.wram[0x0200] = 0x6C;
+        nes.wram[0x0201] = 0xFF;
+        nes.wram[0x0202] = 0xFF;
+        nes.wram[0x07FF] = 0x34;
+        nes.wram[0x0700] = 0x12;
+
+        Emu::cpu_step::<CpuBusMock>(&mut nes);
+        assert_eq!(nes.cpu.pc, 0x1234);
+    }
+}
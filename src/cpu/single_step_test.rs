@@ -0,0 +1,234 @@
+// Harness for Tom Harte's single-instruction test vectors (SingleStepTests/65x02,
+// nes6502/v1): one JSON file per opcode, each case an initial CPU and memory
+// state, the state after one instruction and every bus cycle in between. The
+// files are large, so like the test ROMs they're kept out of the repository and
+// the test is ignored unless run with `cargo test -- --ignored` after putting
+// them in roms/nes6502/v1/.
+//
+// The core leaves out dummy reads and the write of the unmodified value in
+// read-modify-write instructions, so its bus accesses only have to appear in
+// order among the vector's, while the cycle count has to match exactly. Bits 4
+// and 5 of P don't exist in the chip and aren't compared.
+
+use std::cell::RefCell;
+use std::path::Path;
+
+use serde_json::Value;
+
+use super::test_mock::tick;
+use super::*;
+
+// Jams, and the unstable opcodes whose results depend on the chip at hand
+#[rustfmt::skip]
+const SKIPPED: [u8; 19] = [
+    0x02, 0x12, 0x22, 0x32, 0x42, 0x52, 0x62, 0x72, 0x92, 0xB2, 0xD2, 0xF2,
+    0x8B, 0x93, 0x9B, 0x9C, 0x9E, 0x9F, 0xAB,
+];
+const P_MASK: u8 = 0xCF;
+// Failures reported per opcode
+const REPORTED: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Read,
+    Write,
+}
+
+type Cycle = (u16, u8, Access);
+
+thread_local! {
+    static MEMORY: RefCell<Vec<u8>> = RefCell::new(vec![0; 0x10000]);
+    static TRACE: RefCell<Vec<Cycle>> = const { RefCell::new(Vec::new()) };
+}
+
+// 64KB of RAM, logging every access
+struct RamBus {}
+
+impl CpuBus for RamBus {
+    fn read(_nes: &mut Nes, addr: u16) -> u8 {
+        let v = MEMORY.with(|m| m.borrow()[addr as usize]);
+        TRACE.with(|t| t.borrow_mut().push((addr, v, Access::Read)));
+        v
+    }
+    fn write(_nes: &mut Nes, addr: u16, value: u8) {
+        MEMORY.with(|m| m.borrow_mut()[addr as usize] = value);
+        TRACE.with(|t| t.borrow_mut().push((addr, value, Access::Write)));
+    }
+    fn tick(nes: &mut Nes) {
+        tick(nes);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct State {
+    pc: u16,
+    s: u8,
+    a: u8,
+    x: u8,
+    y: u8,
+    p: u8,
+    ram: Vec<(u16, u8)>,
+}
+
+fn state(v: &Value) -> Option<State> {
+    let n = |key: &str| v[key].as_u64();
+    let ram = v["ram"]
+        .as_array()?
+        .iter()
+        .map(|e| Some((e[0].as_u64()? as u16, e[1].as_u64()? as u8)))
+        .collect::<Option<Vec<_>>>()?;
+    Some(State {
+        pc: n("pc")? as u16,
+        s: n("s")? as u8,
+        a: n("a")? as u8,
+        x: n("x")? as u8,
+        y: n("y")? as u8,
+        p: n("p")? as u8 & P_MASK,
+        ram,
+    })
+}
+
+fn cycles(v: &Value) -> Option<Vec<Cycle>> {
+    v.as_array()?
+        .iter()
+        .map(|c| {
+            let access = match c[2].as_str()? {
+                "read" => Access::Read,
+                "write" => Access::Write,
+                _ => return None,
+            };
+            Some((c[0].as_u64()? as u16, c[1].as_u64()? as u8, access))
+        })
+        .collect()
+}
+
+// Run one case, describing what went wrong
+fn run_case(case: &Value) -> std::result::Result<(), String> {
+    let (initial, expected, bus) = match (
+        state(&case["initial"]),
+        state(&case["final"]),
+        cycles(&case["cycles"]),
+    ) {
+        (Some(i), Some(f), Some(c)) => (i, f, c),
+        _ => return Err("malformed case".to_string()),
+    };
+
+    let mut nes = Nes::new();
+    nes.cpu.pc = initial.pc;
+    nes.cpu.s = initial.s;
+    nes.cpu.a = initial.a;
+    nes.cpu.x = initial.x;
+    nes.cpu.y = initial.y;
+    nes.cpu.p = Status::from_bits_truncate(initial.p);
+    MEMORY.with(|m| {
+        let mut m = m.borrow_mut();
+        m.iter_mut().for_each(|b| *b = 0);
+        for &(addr, v) in &initial.ram {
+            m[addr as usize] = v;
+        }
+    });
+    TRACE.with(|t| t.borrow_mut().clear());
+
+    Emu::cpu_step::<RamBus>(&mut nes);
+
+    let actual = State {
+        pc: nes.cpu.pc,
+        s: nes.cpu.s,
+        a: nes.cpu.a,
+        x: nes.cpu.x,
+        y: nes.cpu.y,
+        p: nes.cpu.p.bits() & P_MASK,
+        ram: MEMORY.with(|m| {
+            let m = m.borrow();
+            expected
+                .ram
+                .iter()
+                .map(|&(addr, _)| (addr, m[addr as usize]))
+                .collect()
+        }),
+    };
+    if actual != expected {
+        return Err(format!("expected {:X?}, got {:X?}", expected, actual));
+    }
+    if nes.cpu_cycles as usize != bus.len() {
+        return Err(format!("{} cycles, expected {}", nes.cpu_cycles, bus.len()));
+    }
+    let trace = TRACE.with(|t| t.take());
+    let mut remaining = bus.iter();
+    if !trace.iter().all(|c| remaining.any(|b| b == c)) {
+        return Err(format!("bus {:X?}, expected within {:X?}", trace, bus));
+    }
+    Ok(())
+}
+
+// Failures of the cases in `json`, the first few of them described
+fn run_vectors(json: &str) -> (usize, Vec<String>) {
+    let cases: Vec<Value> = match serde_json::from_str(json) {
+        Ok(Value::Array(cases)) => cases,
+        _ => return (1, vec!["not an array of cases".to_string()]),
+    };
+    let mut failed = 0;
+    let mut reports = Vec::new();
+    for case in &cases {
+        if let Err(e) = run_case(case) {
+            failed += 1;
+            if reports.len() < REPORTED {
+                reports.push(format!("{}: {}", case["name"].as_str().unwrap_or("?"), e));
+            }
+        }
+    }
+    (failed, reports)
+}
+
+#[test]
+fn test_run_vectors() {
+    // INC $10 as the 6502 does it, with the dummy write
+    let inc = r#"[{
+        "name": "e6 10 00",
+        "initial": {"pc": 512, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36,
+                    "ram": [[512, 230], [513, 16], [16, 127]]},
+        "final": {"pc": 514, "s": 253, "a": 0, "x": 0, "y": 0, "p": 164,
+                  "ram": [[512, 230], [513, 16], [16, 128]]},
+        "cycles": [[512, 230, "read"], [513, 16, "read"], [16, 127, "read"],
+                   [16, 127, "write"], [16, 128, "write"]]
+    }]"#;
+    assert_eq!(run_vectors(inc), (0, vec![]));
+
+    #[rustfmt::skip]
+    let cases = [
+        ("result",  inc.replace("[16, 128]]", "[16, 129]]"),                 "expected"),
+        ("cycles",  inc.replace(r#", [16, 128, "write"]"#, ""),              "5 cycles"),
+        ("bus",     inc.replace(r#"[16, 128, "write"]"#, r#"[16, 128, "read"]"#), "bus"),
+        ("corrupt", inc.replace("cycles", "cycels"),                          "malformed"),
+    ];
+
+    for (name, json, error) in cases {
+        let (failed, reports) = run_vectors(&json);
+        assert_eq!(failed, 1, "{}", name);
+        assert!(reports[0].contains(error), "{}: {}", name, reports[0]);
+    }
+}
+
+#[test]
+#[ignore]
+fn test_single_step() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("roms/nes6502/v1");
+    let failures: Vec<String> = (0..=0xFFu8)
+        .filter(|op| !SKIPPED.contains(op))
+        .filter_map(|op| {
+            let path = dir.join(format!("{:02x}.json", op));
+            let json = std::fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+            match run_vectors(&json) {
+                (0, _) => None,
+                (failed, reports) => Some(format!(
+                    "{:02X}: {} failed\n  {}",
+                    op,
+                    failed,
+                    reports.join("\n  ")
+                )),
+            }
+        })
+        .collect();
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
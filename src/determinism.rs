@@ -0,0 +1,207 @@
+// Harness for catching nondeterminism: a movie played twice from power-on must
+// give the same picture, sound and machine state on every frame, and so must the
+// rest of it played on a fresh instance restored from a save state taken part
+// way. Anything left out of save states, or depending on more than the
+// input, shows up as the first frame where the runs part.
+
+use std::sync::{Arc, Mutex};
+
+use crate::netplay::hash;
+use crate::{diff_states, AudioSink, Emu, Movie};
+
+// State differences listed when runs part
+const REPORTED_DIFFS: usize = 10;
+
+// Hashes of one frame's output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FrameHash {
+    pub(crate) picture: u64,
+    pub(crate) sound: u64,
+    pub(crate) state: u64,
+}
+
+// Collects the samples of the frame being run
+#[derive(Clone, Default)]
+struct Samples(Arc<Mutex<Vec<i16>>>);
+
+impl AudioSink for Samples {
+    fn samples(&mut self, samples: &[i16]) {
+        self.0.lock().unwrap().extend_from_slice(samples);
+    }
+}
+
+struct Run {
+    emu: Emu,
+    samples: Samples,
+}
+
+impl Run {
+    fn new(rom: &[u8]) -> Self {
+        let mut emu = Emu::new();
+        emu.load_rom(rom).unwrap();
+        let samples = Samples::default();
+        emu.set_audio_sink(samples.clone());
+        Self { emu, samples }
+    }
+
+    fn frame(&mut self) -> FrameHash {
+        self.emu.run_frame();
+        let pixels: Vec<u8> = self
+            .emu
+            .frame_buffer()
+            .iter()
+            .flat_map(|p| p.to_le_bytes())
+            .collect();
+        let samples: Vec<u8> = std::mem::take(&mut *self.samples.0.lock().unwrap())
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+        FrameHash {
+            picture: hash(&pixels),
+            sound: hash(&samples),
+            state: self.emu.state_hash(),
+        }
+    }
+}
+
+// Play the first `frames` frames of `movie` from power-on, hashing each. With
+// `restore_at`, the frames from then on run on a fresh instance restored from a
+// save state.
+fn play(
+    rom: &[u8],
+    movie: &Movie,
+    restore_at: Option<usize>,
+    frames: usize,
+) -> (Vec<FrameHash>, Emu) {
+    let mut run = Run::new(rom);
+    run.emu.play_movie(movie.clone()).unwrap();
+    let at = restore_at.unwrap_or(frames).min(frames);
+    let mut hashes: Vec<FrameHash> = (0..at).map(|_| run.frame()).collect();
+    if at == frames {
+        return (hashes, run.emu);
+    }
+    let state = run.emu.save_state().unwrap();
+
+    let mut run = Run::new(rom);
+    run.emu.load_state(&state).unwrap();
+    // movies play from power-on, so the rest is fed by hand
+    for frame in &movie.frames()[at..frames] {
+        for (port, &state) in frame.controllers.iter().enumerate() {
+            run.emu.set_controller_state(port, state);
+        }
+        if frame.reset {
+            run.emu.reset();
+        }
+        hashes.push(run.frame());
+    }
+    (hashes, run.emu)
+}
+
+// The first frame where two runs part, with what differs in it
+pub(crate) fn mismatch(a: &[FrameHash], b: &[FrameHash]) -> Option<(usize, String)> {
+    let frame = a.iter().zip(b).position(|(a, b)| a != b);
+    let frame = match frame {
+        Some(frame) => frame,
+        None if a.len() != b.len() => {
+            let len = a.len().min(b.len());
+            return Some((len, format!("{} frames against {}", a.len(), b.len())));
+        }
+        None => return None,
+    };
+    let (a, b) = (a[frame], b[frame]);
+    let parts: Vec<&str> = [
+        ("picture", a.picture != b.picture),
+        ("sound", a.sound != b.sound),
+        ("state", a.state != b.state),
+    ]
+    .iter()
+    .filter(|(_, differs)| *differs)
+    .map(|(part, _)| *part)
+    .collect();
+    Some((
+        frame,
+        format!("frame {}: {} differ", frame, parts.join(", ")),
+    ))
+}
+
+// How the machine states of two runs differ after `frame`
+fn state_diff(rom: &[u8], movie: &Movie, restore_at: [Option<usize>; 2], frame: usize) -> String {
+    let [a, b] = restore_at.map(|r| play(rom, movie, r, frame + 1).1.save_state().unwrap());
+    let diffs = diff_states(&a, &b).unwrap();
+    let lines: Vec<String> = diffs
+        .iter()
+        .take(REPORTED_DIFFS)
+        .map(|d| format!("\n  {}", d))
+        .collect();
+    lines.concat()
+}
+
+// Play `movie` twice, and once restored from a state saved at frame `save_at`
+pub(crate) fn assert_deterministic(rom: &[u8], movie: &Movie, save_at: Option<usize>) {
+    let first = play(rom, movie, None, movie.len()).0;
+    let second = play(rom, movie, None, movie.len()).0;
+    if let Some((frame, m)) = mismatch(&first, &second) {
+        let diff = state_diff(rom, movie, [None, None], frame);
+        panic!("replay diverged at {}{}", m, diff);
+    }
+    if let Some(at) = save_at {
+        let restored = play(rom, movie, Some(at), movie.len()).0;
+        if let Some((frame, m)) = mismatch(&first, &restored) {
+            let diff = state_diff(rom, movie, [None, Some(at)], frame);
+            panic!(
+                "replay from the state at frame {} diverged at {}{}",
+                at, m, diff
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Button;
+
+    #[test]
+    fn test_mismatch() {
+        let frame = FrameHash {
+            picture: 1,
+            sound: 2,
+            state: 3,
+        };
+        let other = FrameHash { sound: 0, ..frame };
+        assert_eq!(mismatch(&[frame, frame], &[frame, frame]), None);
+        assert_eq!(
+            mismatch(&[frame, frame], &[frame, other]),
+            Some((1, "frame 1: sound differ".to_string()))
+        );
+        assert_eq!(
+            mismatch(&[frame], &[frame, frame]),
+            Some((1, "1 frames against 2".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_nestest_replay() {
+        let rom = std::fs::read("roms/nestest.nes").unwrap();
+        let mut emu = Emu::new();
+        emu.load_rom(&rom).unwrap();
+        emu.start_recording().unwrap();
+        // walk the menu and start a test, with a reset in between
+        for i in 0..90 {
+            let state = match i % 30 {
+                0..=4 => Button::Down.mask(),
+                10..=14 => Button::Start.mask(),
+                _ => 0,
+            };
+            emu.set_controller_state(0, state);
+            if i == 50 {
+                emu.reset();
+            }
+            emu.run_frame();
+        }
+        let movie = emu.stop_movie().unwrap();
+        assert!(movie.frames().iter().any(|f| f.reset));
+
+        assert_deterministic(&rom, &movie, Some(30));
+    }
+}
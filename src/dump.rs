@@ -0,0 +1,193 @@
+// Recordings of what a run shows and plays, for encoders and bug reports: raw
+// frames and a WAV file side by side, with a manifest of key=value lines
+// describing both. With a movie driving the run, the same files come out every
+// time.
+
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use crate::video;
+
+pub(crate) const FRAMES_FILE: &str = "frames.rgb";
+pub(crate) const AUDIO_FILE: &str = "audio.wav";
+pub(crate) const MANIFEST_FILE: &str = "manifest.txt";
+
+const WAV_HEADER_LEN: u32 = 44;
+
+// What the manifest says about the run besides the dump's own counts
+pub(crate) struct DumpInfo {
+    pub(crate) rom_hash: [u8; 20],
+    pub(crate) width: usize,
+    pub(crate) height: usize,
+    pub(crate) frame_rate: f64,
+    pub(crate) sample_rate: u32,
+    pub(crate) channels: u16,
+}
+
+pub(crate) struct AvDump {
+    dir: PathBuf,
+    info: DumpInfo,
+    frames: BufWriter<File>,
+    audio: WavWriter<BufWriter<File>>,
+    frame_count: u64,
+    pixels: Vec<u32>,
+    rgb: Vec<u8>,
+    // The first failed write, reported by `finish`; frames after it are dropped
+    error: Option<io::Error>,
+}
+
+impl AvDump {
+    // Start the files in `dir`, creating it if needed
+    pub(crate) fn create(dir: &Path, info: DumpInfo) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let frames = BufWriter::new(File::create(dir.join(FRAMES_FILE))?);
+        let audio = BufWriter::new(File::create(dir.join(AUDIO_FILE))?);
+        let audio = WavWriter::new(audio, info.sample_rate, info.channels)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            info,
+            frames,
+            audio,
+            frame_count: 0,
+            pixels: Vec::new(),
+            rgb: Vec::new(),
+            error: None,
+        })
+    }
+
+    // Append a frame of PPU pixels and the samples played during it
+    pub(crate) fn frame(&mut self, buffer: &[u16], samples: &[i16]) {
+        if self.error.is_some() {
+            return;
+        }
+        video::convert(buffer, &mut self.pixels);
+        self.rgb.clear();
+        for &p in &self.pixels {
+            let [b, g, r, _] = p.to_le_bytes();
+            self.rgb.extend_from_slice(&[r, g, b]);
+        }
+        let written = self
+            .frames
+            .write_all(&self.rgb)
+            .and_then(|_| self.audio.write(samples));
+        match written {
+            Ok(()) => self.frame_count += 1,
+            Err(e) => self.error = Some(e),
+        }
+    }
+
+    // Complete the files and write the manifest, returning the number of frames
+    pub(crate) fn finish(mut self) -> Result<u64> {
+        if let Some(e) = self.error {
+            return Err(e.into());
+        }
+        self.frames.flush()?;
+        let samples = self.audio.finish()?;
+
+        let info = &self.info;
+        let hash: String = info.rom_hash.iter().map(|b| format!("{:02x}", b)).collect();
+        let mut manifest = String::new();
+        #[rustfmt::skip]
+        let entries: [(&str, &dyn std::fmt::Display); 11] = [
+            ("rom_sha1",     &hash),
+            ("video",        &FRAMES_FILE),
+            ("pixel_format", &"rgb24"),
+            ("width",        &info.width),
+            ("height",       &info.height),
+            ("frame_rate",   &info.frame_rate),
+            ("frames",       &self.frame_count),
+            ("audio",        &AUDIO_FILE),
+            ("sample_rate",  &info.sample_rate),
+            ("channels",     &info.channels),
+            ("samples",      &samples),
+        ];
+        for (key, value) in entries {
+            writeln!(manifest, "{}={}", key, value)?;
+        }
+        fs::write(self.dir.join(MANIFEST_FILE), manifest)?;
+        Ok(self.frame_count)
+    }
+}
+
+// 16-bit PCM in a RIFF WAVE file, the sizes in its header filled in on `finish`
+pub(crate) struct WavWriter<W: Write + Seek> {
+    out: W,
+    // Bytes of samples so far
+    len: u32,
+    channels: u16,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub(crate) fn new(mut out: W, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        let block_align = channels * 2;
+        let mut header = Vec::with_capacity(WAV_HEADER_LEN as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(WAV_HEADER_LEN - 8).to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        // PCM
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        out.write_all(&header)?;
+        Ok(Self {
+            out,
+            len: 0,
+            channels,
+        })
+    }
+
+    // Append interleaved samples
+    pub(crate) fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        self.out.write_all(&bytes)?;
+        self.len += bytes.len() as u32;
+        Ok(())
+    }
+
+    // Fill in the sizes, returning the number of samples per channel
+    pub(crate) fn finish(mut self) -> io::Result<u64> {
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out
+            .write_all(&(WAV_HEADER_LEN - 8 + self.len).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(WAV_HEADER_LEN as u64 - 4))?;
+        self.out.write_all(&self.len.to_le_bytes())?;
+        self.out.flush()?;
+        Ok(self.len as u64 / (2 * self.channels.max(1) as u64))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_wav_writer() {
+        let mut out = Cursor::new(Vec::new());
+        let mut wav = WavWriter::new(&mut out, 48000, 2).unwrap();
+        wav.write(&[1, -1, 2, -2]).unwrap();
+        wav.write(&[3, -3]).unwrap();
+        assert_eq!(wav.finish().unwrap(), 3);
+
+        let data = out.into_inner();
+        assert_eq!(data.len(), 44 + 12);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(data[4..8], (36u32 + 12).to_le_bytes());
+        assert_eq!(data[22..24], 2u16.to_le_bytes());
+        assert_eq!(data[28..32], (48000u32 * 4).to_le_bytes());
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(data[40..44], 12u32.to_le_bytes());
+        assert_eq!(data[44..46], 1i16.to_le_bytes());
+        assert_eq!(data[54..56], (-3i16).to_le_bytes());
+    }
+}
@@ -0,0 +1,106 @@
+// Log of what happened where in the PPU's frame, for event viewers plotting CPU
+// writes and interrupts over the picture: PPU register writes, OAM DMA, NMIs,
+// IRQs and sprite 0 hits, each tagged with the scanline and dot it happened at.
+// Frames run from scanline 0 through the pre-render line.
+
+use crate::hook::Event;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PpuEventKind {
+    // A write to $2000-$2007, with the mirrors folded down, or to $4014
+    RegisterWrite { addr: u16, value: u8 },
+    Nmi,
+    Irq,
+    MapperIrq,
+    Sprite0Hit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PpuEvent {
+    pub scanline: u16,
+    pub dot: u16,
+    pub kind: PpuEventKind,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct EventLog {
+    current: Vec<PpuEvent>,
+    last: Vec<PpuEvent>,
+}
+
+impl EventLog {
+    pub(crate) fn push(&mut self, scanline: u16, dot: u16, kind: PpuEventKind) {
+        self.current.push(PpuEvent {
+            scanline,
+            dot,
+            kind,
+        });
+    }
+
+    pub(crate) fn write(&mut self, scanline: u16, dot: u16, addr: u16, value: u8) {
+        let addr = match addr {
+            0x2000..=0x3FFF => addr & 0x2007,
+            0x4014 => addr,
+            _ => return,
+        };
+        self.push(scanline, dot, PpuEventKind::RegisterWrite { addr, value });
+    }
+
+    pub(crate) fn event(&mut self, scanline: u16, dot: u16, event: Event) {
+        let kind = match event {
+            Event::Nmi => PpuEventKind::Nmi,
+            Event::Irq => PpuEventKind::Irq,
+            Event::MapperIrq => PpuEventKind::MapperIrq,
+            Event::Sprite0Hit => PpuEventKind::Sprite0Hit,
+            Event::FrameDone(_) | Event::StateLoaded | Event::Violation(_) => return,
+        };
+        self.push(scanline, dot, kind);
+    }
+
+    // The PPU started a new frame
+    pub(crate) fn next_frame(&mut self) {
+        std::mem::swap(&mut self.current, &mut self.last);
+        self.current.clear();
+    }
+
+    // Events of the last finished frame
+    pub(crate) fn last(&self) -> &[PpuEvent] {
+        &self.last
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_event_log() {
+        let mut log = EventLog::default();
+        log.write(10, 20, 0x2005, 0x80);
+        log.write(10, 30, 0x3FFE, 0x00);
+        log.write(10, 40, 0x4014, 0x02);
+        log.write(10, 50, 0x4015, 0x0F);
+        log.write(10, 60, 0x8000, 0x01);
+        log.event(30, 4, Event::Sprite0Hit);
+        log.event(241, 3, Event::FrameDone(0));
+        assert!(log.last().is_empty());
+        log.next_frame();
+
+        #[rustfmt::skip]
+        let expected = [
+            (10, 20, PpuEventKind::RegisterWrite { addr: 0x2005, value: 0x80 }),
+            (10, 30, PpuEventKind::RegisterWrite { addr: 0x2006, value: 0x00 }),
+            (10, 40, PpuEventKind::RegisterWrite { addr: 0x4014, value: 0x02 }),
+            (30, 4, PpuEventKind::Sprite0Hit),
+        ];
+        let events: Vec<(u16, u16, PpuEventKind)> = log
+            .last()
+            .iter()
+            .map(|e| (e.scanline, e.dot, e.kind))
+            .collect();
+        assert_eq!(events, expected);
+
+        log.next_frame();
+        assert!(log.last().is_empty());
+    }
+}
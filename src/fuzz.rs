@@ -0,0 +1,89 @@
+// Entry points for the fuzz targets under fuzz/, behind the "fuzzing" feature.
+// Each takes arbitrary bytes and must neither panic nor hang, whatever they are;
+// what parses is also loaded and run for a moment to exercise board setup.
+
+use crate::nes::{Nes, SystemBus};
+use crate::nsf::NsfPlayer;
+use crate::rom::FdsImage;
+use crate::{Cartridge, Emu, NsfFile};
+
+// Instructions run after inserting a cartridge
+const STEPS: usize = 1000;
+// CPU cycles an NSF tune plays for after INIT, about two calls of PLAY
+const NSF_CYCLES: u128 = 60_000;
+
+// An iNES, NES 2.0 or UNIF image
+pub fn rom(data: &[u8]) {
+    let cart = match Cartridge::from_bytes(data) {
+        Ok(cart) => cart,
+        Err(_) => return,
+    };
+    let _ = cart.info();
+    // what was parsed must load back the same
+    let ines = cart.to_ines();
+    if let Ok(again) = Cartridge::from_bytes(&ines) {
+        assert_eq!(again.sha1(), cart.sha1());
+    }
+
+    let mut emu = Emu::new();
+    emu.set_headless(true);
+    if emu.load_cartridge(cart).is_ok() {
+        for _ in 0..STEPS {
+            emu.step_instruction();
+        }
+    }
+}
+
+// An NSF or NSFe file
+pub fn nsf(data: &[u8]) {
+    let file = match NsfFile::from_bytes(data) {
+        Ok(file) => file,
+        Err(_) => return,
+    };
+    let mut nes = Nes::new();
+    let mut player = NsfPlayer::load(&mut nes, &file);
+    player.init::<SystemBus>(&mut nes, file.starting_song().saturating_sub(1));
+    player.run::<SystemBus>(&mut nes, NSF_CYCLES);
+}
+
+// A Famicom Disk System image, with or without the fwNES header
+pub fn fds(data: &[u8]) {
+    let _ = FdsImage::from_bytes(data);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Mangled versions of the ROMs at hand, as a smoke test of what the fuzz
+    // targets do at length
+    #[test]
+    fn test_mangled() {
+        let nestest = std::fs::read("roms/nestest.nes").unwrap();
+        let mut seed = 0x2545_F491_u32;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        };
+        for _ in 0..50 {
+            // whole, or cut short anywhere
+            let len = match next() % 2 {
+                0 => nestest.len(),
+                _ => next() as usize % nestest.len(),
+            }
+            .max(0x10);
+            let mut data = nestest[..len].to_vec();
+            for _ in 0..4 {
+                let i = next() as usize % 16;
+                data[i] = next() as u8;
+            }
+            rom(&data);
+            data[..4].copy_from_slice(b"NESM");
+            nsf(&data);
+            data[..4].copy_from_slice(b"FDS\x1A");
+            fds(&data);
+        }
+    }
+}
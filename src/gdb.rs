@@ -0,0 +1,350 @@
+// GDB remote serial protocol stub over TCP, behind the "gdb" feature, so that
+// debuggers and IDEs speaking it can attach to the 6502: read and write registers
+// and memory, step, continue and set breakpoints.
+//
+// There's no 6502 target description in GDB, so clients need to know the layout
+// of the "g" packet: A, X, Y, P, S and PC (little endian), one byte each but PC.
+// Memory goes through `Emu::peek` and `Emu::poke`, so reading has no side
+// effects and only RAM and PRG-RAM can be written.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use anyhow::Result;
+
+use crate::{Emu, Registers};
+
+// Instructions run between checks for an interrupt from the debugger
+const CHECK_INTERVAL: usize = 10_000;
+const INTERRUPT: u8 = 0x03;
+
+#[derive(Debug, Default)]
+pub struct GdbStub {
+    breakpoints: BTreeSet<u16>,
+}
+
+impl GdbStub {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // Wait for a debugger to connect on `addr`, then serve it until it detaches
+    // or disconnects. The emulator is paused meanwhile.
+    pub fn serve<A: ToSocketAddrs>(&mut self, emu: &mut Emu, addr: A) -> Result<()> {
+        let (stream, _) = TcpListener::bind(addr)?.accept()?;
+        self.serve_stream(emu, stream)
+    }
+
+    pub fn serve_stream(&mut self, emu: &mut Emu, mut stream: TcpStream) -> Result<()> {
+        stream.set_nodelay(true)?;
+        emu.pause();
+        while let Some(packet) = read_packet(&mut stream)? {
+            let reply = if let Some(addr) = packet.strip_prefix('c') {
+                if let Some(pc) = parse_hex(addr) {
+                    set_pc(emu, pc as u16);
+                }
+                Some(self.resume(emu, || interrupted(&stream)))
+            } else {
+                self.command(emu, &packet)
+            };
+            match reply {
+                Some(reply) => write_packet(&mut stream, &reply)?,
+                None => {
+                    write_packet(&mut stream, "OK")?;
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Reply to a packet other than continue, None to end the session
+    fn command(&mut self, emu: &mut Emu, packet: &str) -> Option<String> {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => "S05".to_string(),
+            Some(b'g') => {
+                let r = emu.nes.cpu.registers();
+                hex(&[r.a, r.x, r.y, r.p, r.s, r.pc as u8, (r.pc >> 8) as u8])
+            }
+            Some(b'G') => match unhex(&packet[1..]).as_deref() {
+                Some(&[a, x, y, p, s, pc_low, pc_high]) => {
+                    let pc = u16::from_le_bytes([pc_low, pc_high]);
+                    emu.nes.cpu.set_registers(Registers { a, x, y, s, p, pc });
+                    "OK".to_string()
+                }
+                _ => error(),
+            },
+            Some(b'm') => match parse_range(&packet[1..]) {
+                Some((addr, len)) => {
+                    let bytes: Vec<u8> = (0..len).map(|i| emu.peek(addr.wrapping_add(i))).collect();
+                    hex(&bytes)
+                }
+                None => error(),
+            },
+            Some(b'M') => {
+                let (range, data) = packet[1..].split_once(':')?;
+                match (parse_range(range), unhex(data)) {
+                    (Some((addr, len)), Some(data)) if data.len() == len as usize => {
+                        for (i, &v) in data.iter().enumerate() {
+                            emu.poke(addr.wrapping_add(i as u16), v);
+                        }
+                        "OK".to_string()
+                    }
+                    _ => error(),
+                }
+            }
+            Some(b's') => {
+                if let Some(pc) = parse_hex(&packet[1..]) {
+                    set_pc(emu, pc as u16);
+                }
+                emu.step_instruction();
+                "S05".to_string()
+            }
+            // software and hardware breakpoints alike
+            Some(b'Z') | Some(b'z') if packet[1..].starts_with(['0', '1']) => {
+                let addr = packet
+                    .get(3..)
+                    .and_then(|p| p.split(',').next())
+                    .and_then(parse_hex);
+                match addr {
+                    Some(addr) if packet.starts_with('Z') => {
+                        self.breakpoints.insert(addr as u16);
+                        "OK".to_string()
+                    }
+                    Some(addr) => {
+                        self.breakpoints.remove(&(addr as u16));
+                        "OK".to_string()
+                    }
+                    None => error(),
+                }
+            }
+            Some(b'D') | Some(b'k') => return None,
+            _ if packet.starts_with("qSupported") => "PacketSize=1000".to_string(),
+            _ if packet == "qAttached" => "1".to_string(),
+            // unsupported
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    // Run until a breakpoint, or until `interrupted` says the debugger wants to stop
+    fn resume<F: FnMut() -> bool>(&mut self, emu: &mut Emu, mut interrupted: F) -> String {
+        if emu.cartridge.is_none() {
+            return "S05".to_string();
+        }
+        loop {
+            for _ in 0..CHECK_INTERVAL {
+                emu.step_instruction();
+                if self.breakpoints.contains(&emu.nes.cpu.pc) {
+                    return "S05".to_string();
+                }
+            }
+            if interrupted() {
+                return "S02".to_string();
+            }
+        }
+    }
+}
+
+fn set_pc(emu: &mut Emu, pc: u16) {
+    let mut r = emu.nes.cpu.registers();
+    r.pc = pc;
+    emu.nes.cpu.set_registers(r);
+}
+
+fn interrupted(stream: &TcpStream) -> bool {
+    let mut b = [0];
+    let _ = stream.set_nonblocking(true);
+    let got = (&*stream).read(&mut b);
+    let _ = stream.set_nonblocking(false);
+    matches!(got, Ok(1) if b[0] == INTERRUPT)
+}
+
+// Next packet's contents, acknowledged, or None once the connection closes
+fn read_packet<S: Read + Write>(stream: &mut S) -> Result<Option<String>> {
+    loop {
+        // acks and interrupts while stopped are ignored
+        match read_byte(stream)? {
+            Some(b'$') => {}
+            Some(_) => continue,
+            None => return Ok(None),
+        }
+        let mut data = Vec::new();
+        loop {
+            match read_byte(stream)? {
+                Some(b'#') => break,
+                Some(b) => data.push(b),
+                None => return Ok(None),
+            }
+        }
+        let checksum = match (read_byte(stream)?, read_byte(stream)?) {
+            (Some(h), Some(l)) => unhex(std::str::from_utf8(&[h, l]).unwrap_or_default()),
+            _ => return Ok(None),
+        };
+        let sum = data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        if checksum.as_deref() == Some(&[sum]) {
+            stream.write_all(b"+")?;
+            let packet = String::from_utf8(data).map_err(|_| GdbError::new("non-ASCII packet"))?;
+            return Ok(Some(packet));
+        }
+        stream.write_all(b"-")?;
+    }
+}
+
+fn read_byte<R: Read>(stream: &mut R) -> io::Result<Option<u8>> {
+    let mut b = [0];
+    Ok(match stream.read(&mut b)? {
+        0 => None,
+        _ => Some(b[0]),
+    })
+}
+
+fn write_packet<W: Write>(stream: &mut W, data: &str) -> Result<()> {
+    let sum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+    write!(stream, "${}#{:02x}", data, sum)?;
+    Ok(())
+}
+
+fn error() -> String {
+    "E01".to_string()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok().filter(|&v| v <= 0xFFFF)
+}
+
+// "addr,len"
+fn parse_range(s: &str) -> Option<(u16, u16)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((parse_hex(addr)? as u16, parse_hex(len)? as u16))
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct GdbError {
+    msg: String,
+}
+
+impl GdbError {
+    pub(crate) fn new(msg: &str) -> Self {
+        Self {
+            msg: msg.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for GdbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "gdb error: {}", self.msg)
+    }
+}
+
+impl std::error::Error for GdbError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    // Both ends of a connection: what the debugger sent, and what the stub wrote
+    struct Pipe {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_packets() {
+        let mut pipe = Pipe {
+            input: Cursor::new(b"+$g#67\x03$m10,2#00$?#3f".to_vec()),
+            output: Vec::new(),
+        };
+        assert_eq!(read_packet(&mut pipe).unwrap().as_deref(), Some("g"));
+        // the bad checksum is refused
+        assert_eq!(read_packet(&mut pipe).unwrap().as_deref(), Some("?"));
+        assert_eq!(read_packet(&mut pipe).unwrap(), None);
+        assert_eq!(pipe.output, b"+-+");
+
+        let mut out = Vec::new();
+        write_packet(&mut out, "OK").unwrap();
+        assert_eq!(out, b"$OK#9a");
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        let mut stub = GdbStub::new();
+
+        #[rustfmt::skip]
+        let cases = [
+            ("G",      "G0102030405fdc0",  "OK"),
+            ("g",      "g",                "0102030405fdc0"),
+            ("M",      "M10,3:aabbcc",     "OK"),
+            ("m",      "m10,3",            "aabbcc"),
+            ("m ROM",  "mc000,3",          "4cf5c5"),
+            ("bad M",  "M10,3:aa",         "E01"),
+            ("Z0",     "Z0,c5f5,1",        "OK"),
+            ("bare Z0", "Z0",              "E01"),
+            ("bare z1", "z1,",             "E01"),
+            ("query",  "qSupported:xmlRegisters=i386", "PacketSize=1000"),
+            ("unknown", "vMustReplyEmpty", ""),
+        ];
+
+        for (name, packet, reply) in cases {
+            assert_eq!(stub.command(&mut emu, packet).unwrap(), reply, "{}", name);
+        }
+        assert_eq!(stub.command(&mut emu, "D"), None);
+    }
+
+    #[test]
+    fn test_resume() {
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        let mut stub = GdbStub::new();
+
+        // nestest's automation entry jumps to $C5F5
+        set_pc(&mut emu, 0xC000);
+        stub.command(&mut emu, "Z0,c5f5,1");
+        assert_eq!(stub.resume(&mut emu, || false), "S05");
+        assert_eq!(emu.nes.cpu.pc, 0xC5F5);
+
+        stub.command(&mut emu, "z0,c5f5,1");
+        assert_eq!(stub.command(&mut emu, "s").as_deref(), Some("S05"));
+        assert_ne!(emu.nes.cpu.pc, 0xC5F5);
+        assert_eq!(stub.resume(&mut emu, || true), "S02");
+    }
+}
@@ -0,0 +1,269 @@
+use std::fmt;
+use std::mem;
+use std::ops::RangeInclusive;
+
+use crate::cpu::Registers;
+use crate::nes::{self, Nes};
+use crate::self_check::Violation;
+use crate::trace;
+
+// Handle to a registered hook, for removing it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HookId(u32);
+
+// Notifications delivered to subscribers as they happen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    // The CPU took an NMI
+    Nmi,
+    // The CPU took an IRQ, from any source
+    Irq,
+    // The cartridge board asserted /IRQ
+    MapperIrq,
+    // The PPU detected sprite 0 hit this frame
+    Sprite0Hit,
+    // A frame completed, with its number
+    FrameDone(u64),
+    // A save state or rewind snapshot was restored
+    StateLoaded,
+    // An internal invariant broke, with self-checking on
+    Violation(Violation),
+}
+
+// The machine as seen from a hook
+pub struct Machine<'a> {
+    nes: &'a mut Nes,
+}
+
+impl Machine<'_> {
+    pub fn registers(&self) -> Registers {
+        self.nes.cpu.registers()
+    }
+
+    pub fn set_registers(&mut self, registers: Registers) {
+        self.nes.cpu.set_registers(registers);
+    }
+
+    // The 2KB of CPU RAM
+    pub fn ram(&self) -> &[u8] {
+        &self.nes.wram
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.nes.wram
+    }
+
+    // Side-effect-free access to the CPU address space, as `Emu::peek`/`Emu::poke`
+    pub fn peek(&self, addr: u16) -> u8 {
+        nes::peek(self.nes, addr)
+    }
+
+    pub fn poke(&mut self, addr: u16, value: u8) {
+        nes::poke(self.nes, addr, value);
+    }
+
+    // PPU frame count since power-on
+    pub fn frame(&self) -> u64 {
+        self.nes.ppu.frame
+    }
+
+    pub fn cpu_cycles(&self) -> u64 {
+        self.nes.cpu_cycles as u64
+    }
+
+    pub fn controller_state(&self, port: usize) -> u8 {
+        self.nes
+            .input
+            .pads
+            .controllers
+            .get(port)
+            .map_or(0, |c| c.state)
+    }
+
+    pub fn set_controller_state(&mut self, port: usize, state: u8) {
+        if let Some(c) = self.nes.input.pads.controllers.get_mut(port) {
+            c.state = state;
+        }
+    }
+}
+
+pub(crate) type ExecFn = Box<dyn FnMut(&mut Machine, u16) + Send>;
+// Returns the value to read or write instead
+pub(crate) type AccessFn = Box<dyn FnMut(&mut Machine, u16, u8) -> u8 + Send>;
+pub(crate) type FrameFn = Box<dyn FnMut(&mut Machine, u64) + Send>;
+pub(crate) type EventFn = Box<dyn FnMut(Event) + Send>;
+pub(crate) type PollFn = Box<dyn FnMut(&mut Machine) + Send>;
+
+pub(crate) struct Hook<F> {
+    id: HookId,
+    addrs: RangeInclusive<u16>,
+    f: F,
+}
+
+#[derive(Default)]
+pub(crate) struct Hooks {
+    next_id: u32,
+    pub(crate) exec: Vec<Hook<ExecFn>>,
+    pub(crate) read: Vec<Hook<AccessFn>>,
+    pub(crate) write: Vec<Hook<AccessFn>>,
+    pub(crate) frame: Vec<Hook<FrameFn>>,
+    pub(crate) event: Vec<Hook<EventFn>>,
+    pub(crate) poll: Vec<Hook<PollFn>>,
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("exec", &self.exec.len())
+            .field("read", &self.read.len())
+            .field("write", &self.write.len())
+            .field("frame", &self.frame.len())
+            .field("event", &self.event.len())
+            .field("poll", &self.poll.len())
+            .finish()
+    }
+}
+
+impl Hooks {
+    fn next_id(&mut self) -> HookId {
+        self.next_id += 1;
+        HookId(self.next_id)
+    }
+
+    pub(crate) fn add_exec(&mut self, addrs: RangeInclusive<u16>, f: ExecFn) -> HookId {
+        let id = self.next_id();
+        self.exec.push(Hook { id, addrs, f });
+        id
+    }
+
+    pub(crate) fn add_read(&mut self, addrs: RangeInclusive<u16>, f: AccessFn) -> HookId {
+        let id = self.next_id();
+        self.read.push(Hook { id, addrs, f });
+        id
+    }
+
+    pub(crate) fn add_write(&mut self, addrs: RangeInclusive<u16>, f: AccessFn) -> HookId {
+        let id = self.next_id();
+        self.write.push(Hook { id, addrs, f });
+        id
+    }
+
+    pub(crate) fn add_frame(&mut self, f: FrameFn) -> HookId {
+        let id = self.next_id();
+        self.frame.push(Hook {
+            id,
+            addrs: 0..=0xFFFF,
+            f,
+        });
+        id
+    }
+
+    pub(crate) fn add_event(&mut self, f: EventFn) -> HookId {
+        let id = self.next_id();
+        self.event.push(Hook {
+            id,
+            addrs: 0..=0xFFFF,
+            f,
+        });
+        id
+    }
+
+    pub(crate) fn add_poll(&mut self, f: PollFn) -> HookId {
+        let id = self.next_id();
+        self.poll.push(Hook {
+            id,
+            addrs: 0x4016..=0x4016,
+            f,
+        });
+        id
+    }
+
+    // Returns whether the hook was there
+    pub(crate) fn remove(&mut self, id: HookId) -> bool {
+        fn remove_from<F>(hooks: &mut Vec<Hook<F>>, id: HookId) -> bool {
+            let len = hooks.len();
+            hooks.retain(|h| h.id != id);
+            hooks.len() != len
+        }
+        remove_from(&mut self.exec, id)
+            || remove_from(&mut self.read, id)
+            || remove_from(&mut self.write, id)
+            || remove_from(&mut self.frame, id)
+            || remove_from(&mut self.event, id)
+            || remove_from(&mut self.poll, id)
+    }
+}
+
+// The hooks are taken out of `nes` while they run, so a hook can't reach itself
+// through the `Machine`.
+
+// Before the instruction at `pc` executes
+pub(crate) fn on_exec(nes: &mut Nes, pc: u16) {
+    let mut hooks = mem::take(&mut nes.hooks.exec);
+    for h in hooks.iter_mut().filter(|h| h.addrs.contains(&pc)) {
+        (h.f)(&mut Machine { nes }, pc);
+    }
+    nes.hooks.exec = hooks;
+}
+
+pub(crate) fn on_read(nes: &mut Nes, addr: u16, mut v: u8) -> u8 {
+    let mut hooks = mem::take(&mut nes.hooks.read);
+    for h in hooks.iter_mut().filter(|h| h.addrs.contains(&addr)) {
+        v = (h.f)(&mut Machine { nes }, addr, v);
+    }
+    nes.hooks.read = hooks;
+    v
+}
+
+pub(crate) fn on_write(nes: &mut Nes, addr: u16, mut v: u8) -> u8 {
+    let mut hooks = mem::take(&mut nes.hooks.write);
+    for h in hooks.iter_mut().filter(|h| h.addrs.contains(&addr)) {
+        v = (h.f)(&mut Machine { nes }, addr, v);
+    }
+    nes.hooks.write = hooks;
+    v
+}
+
+pub(crate) fn on_frame(nes: &mut Nes, frame: u64) {
+    let mut hooks = mem::take(&mut nes.hooks.frame);
+    for h in &mut hooks {
+        (h.f)(&mut Machine { nes }, frame);
+    }
+    nes.hooks.frame = hooks;
+}
+
+// When the game starts latching the controllers
+pub(crate) fn on_poll(nes: &mut Nes) {
+    let mut hooks = mem::take(&mut nes.hooks.poll);
+    for h in &mut hooks {
+        (h.f)(&mut Machine { nes });
+    }
+    nes.hooks.poll = hooks;
+}
+
+pub(crate) fn emit(nes: &mut Nes, event: Event) {
+    trace::event(event);
+    if let Some(log) = &mut nes.event_log {
+        log.event(nes.ppu.scanline, nes.ppu.dot, event);
+    }
+    for h in &mut nes.hooks.event {
+        (h.f)(event);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_remove() {
+        let mut hooks = Hooks::default();
+        let a = hooks.add_exec(0x8000..=0x8000, Box::new(|_, _| {}));
+        let b = hooks.add_frame(Box::new(|_, _| {}));
+        assert_ne!(a, b);
+        assert!(hooks.remove(b));
+        assert!(!hooks.remove(b));
+        assert_eq!(hooks.exec.len(), 1);
+        assert!(hooks.frame.is_empty());
+    }
+}
@@ -0,0 +1,576 @@
+use std::fmt;
+
+use anyhow::Result;
+
+use crate::hook;
+use crate::nes::Nes;
+use crate::ppu::{HEIGHT, WIDTH};
+use crate::state::{StateReader, StateWriter};
+use crate::video;
+
+// Standard controller buttons, in the order they are reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    A,
+    B,
+    Select,
+    Start,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Button {
+    // Bit of the button in a controller state byte
+    pub fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+// A peripheral plugged into a controller port, in place of the standard
+// controller. The bus only strobes and reads it, so frontends can add their own.
+pub trait InputDevice {
+    // $4016 write, with the OUT0-OUT2 lines in bits 0-2
+    fn strobe(&mut self, value: u8);
+    // $4016/$4017 read of `port` (0 or 1); bits 0-4 are the device's, the rest
+    // comes from open bus. In the expansion port only D1 of $4016 and D1-D4 of
+    // $4017 get through.
+    fn read(&mut self, port: usize) -> u8;
+    // What `read` would return, without side effects, for debuggers
+    fn peek(&self, port: usize) -> u8;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Controller {
+    // Buttons held, one bit per `Button`
+    pub(crate) state: u8,
+}
+
+// Four Score ID reported after the two controllers of each port, LSB first
+const FOUR_SCORE_SIGNATURE: [u8; 2] = [0x08, 0x04];
+
+// The standard controllers of both ports, optionally with the Four Score
+#[derive(Debug, Default)]
+pub(crate) struct Pads {
+    strobe: bool,
+    // Controllers 3 and 4 are only read through the Four Score
+    pub(crate) controllers: [Controller; 4],
+    pub(crate) four_score: bool,
+    // Bits still to be read from each port; reads past the end return 1
+    shift: [u32; 2],
+}
+
+impl Pads {
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.strobe);
+        w.write_bool(self.four_score);
+        for c in &self.controllers {
+            w.write_u8(c.state);
+        }
+        for &shift in &self.shift {
+            w.write_u32(shift);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.strobe = r.read_bool()?;
+        self.four_score = r.read_bool()?;
+        for c in &mut self.controllers {
+            c.state = r.read_u8()?;
+        }
+        for shift in &mut self.shift {
+            *shift = r.read_u32()?;
+        }
+        Ok(())
+    }
+
+    // Version 1 states had two controllers with 8-bit shift registers
+    pub(crate) fn load_state_v1(&mut self, r: &mut StateReader) -> Result<()> {
+        self.strobe = r.read_bool()?;
+        self.four_score = false;
+        for port in 0..2 {
+            self.controllers[port].state = r.read_u8()?;
+            self.shift[port] = r.read_u8()? as u32 | 0xFFFF_FF00;
+        }
+        Ok(())
+    }
+
+    // With the Four Score, each port sends its two controllers then the signature
+    fn latch(&mut self) {
+        for (port, signature) in FOUR_SCORE_SIGNATURE.iter().enumerate() {
+            let state = self.controllers[port].state as u32;
+            self.shift[port] = if self.four_score {
+                state
+                    | (self.controllers[port + 2].state as u32) << 8
+                    | (*signature as u32) << 16
+                    | 0xFF00_0000
+            } else {
+                state | 0xFFFF_FF00
+            };
+        }
+    }
+}
+
+impl InputDevice for Pads {
+    // bit 0 latches the button states while high
+    fn strobe(&mut self, value: u8) {
+        self.strobe = value & 1 != 0;
+        if self.strobe {
+            self.latch();
+        }
+    }
+
+    fn read(&mut self, port: usize) -> u8 {
+        if self.strobe {
+            self.latch();
+        }
+        let shift = &mut self.shift[port];
+        let v = (*shift & 1) as u8;
+        // the shift register's serial input is tied high, so reads past the 8
+        // buttons return 1 on official controllers
+        *shift = *shift >> 1 | 0x8000_0000;
+        v
+    }
+
+    fn peek(&self, port: usize) -> u8 {
+        let bits = if self.strobe {
+            self.controllers[port].state
+        } else {
+            self.shift[port] as u8
+        };
+        bits & 1
+    }
+}
+
+// Lines the Zapper's photodiode keeps seeing a pixel after the PPU drew it
+const LIGHT_LINES: u16 = 20;
+// Sum of the red, green and blue levels of a pixel bright enough to be seen
+const LIGHT_LEVEL: u32 = 0x200;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Zapper {
+    // Screen coordinates pointed at, or None when off screen
+    pub(crate) pointer: Option<(u16, u16)>,
+    pub(crate) trigger: bool,
+}
+
+impl Zapper {
+    // The pixel pointed at is bright and the PPU drew it a moment ago
+    fn sees_light(&self, nes: &Nes) -> bool {
+        let (x, y) = match self.pointer {
+            Some((x, y)) if (x as usize) < WIDTH && (y as usize) < HEIGHT => (x, y),
+            _ => return false,
+        };
+        let ppu = &nes.ppu;
+        let drawn = y < ppu.scanline || (y == ppu.scanline && x < ppu.dot);
+        if !drawn || y + LIGHT_LINES < ppu.scanline {
+            return false;
+        }
+        let rgb = video::to_rgb(ppu.buffer[y as usize * WIDTH + x as usize]);
+        let level = (rgb >> 16 & 0xFF) + (rgb >> 8 & 0xFF) + (rgb & 0xFF);
+        LIGHT_LEVEL <= level
+    }
+
+    // Bit 3 is low while light is seen, bit 4 high while the trigger is pulled
+    fn bits(&self, nes: &Nes) -> u8 {
+        let light = if self.sees_light(nes) { 0 } else { 0x08 };
+        let trigger = if self.trigger { 0x10 } else { 0 };
+        light | trigger
+    }
+}
+
+// The Vaus paddle reports a knob position from about 0x62 to 0xF2
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Vaus {
+    pub(crate) position: u8,
+    pub(crate) fire: bool,
+    // Knob bits still to be sent, MSB first
+    shift: u8,
+}
+
+impl InputDevice for Vaus {
+    // the knob position is latched while bit 0 is high
+    fn strobe(&mut self, value: u8) {
+        if value & 1 != 0 {
+            self.shift = self.position;
+        }
+    }
+
+    // Bit 4 is the knob position, inverted, and bit 3 the fire button
+    fn read(&mut self, port: usize) -> u8 {
+        let v = self.peek(port);
+        self.shift <<= 1;
+        v
+    }
+
+    fn peek(&self, _port: usize) -> u8 {
+        let data = if self.shift & 0x80 == 0 { 0x10 } else { 0 };
+        let fire = if self.fire { 0x08 } else { 0 };
+        data | fire
+    }
+}
+
+// Controllers 3 and 4 plugged into the Famicom's expansion port, each sending
+// its buttons on D1 of its own register
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct ExpansionPads {
+    strobe: bool,
+    // Bits still to be read for $4016 and $4017
+    shift: [u8; 2],
+}
+
+impl ExpansionPads {
+    fn strobe(&mut self, value: u8, controllers: &[Controller; 4]) {
+        self.strobe = value & 1 != 0;
+        if self.strobe {
+            self.latch(controllers);
+        }
+    }
+
+    fn latch(&mut self, controllers: &[Controller; 4]) {
+        self.shift = [controllers[2].state, controllers[3].state];
+    }
+
+    fn read(&mut self, port: usize, controllers: &[Controller; 4]) -> u8 {
+        if self.strobe {
+            self.latch(controllers);
+        }
+        let shift = &mut self.shift[port];
+        let v = *shift & 1;
+        *shift = *shift >> 1 | 0x80;
+        v << 1
+    }
+
+    fn peek(&self, port: usize, controllers: &[Controller; 4]) -> u8 {
+        let bits = if self.strobe {
+            controllers[port + 2].state
+        } else {
+            self.shift[port]
+        };
+        (bits & 1) << 1
+    }
+}
+
+// The Famicom Vaus plugs into the expansion port and sends fire on D1 of $4016
+// and the knob on D1 of $4017, instead of on D3 and D4
+fn famicom_vaus_bits(bits: u8, port: usize) -> u8 {
+    match port {
+        0 => bits >> 2 & 0x02,
+        _ => bits >> 3 & 0x02,
+    }
+}
+
+// Data lines the expansion port drives in $4016 and $4017
+const EXPANSION_LINES: [u8; 2] = [0x02, 0x1E];
+
+type Device = Box<dyn InputDevice + Send>;
+
+// What's in a controller port besides the standard controller. These are set
+// up by the frontend, which also feeds their input, so they aren't part of
+// save states.
+#[derive(Default)]
+pub(crate) enum Port {
+    #[default]
+    Controller,
+    // It looks at the picture, so it's read here rather than as an `InputDevice`
+    Zapper(Zapper),
+    Vaus(Vaus),
+    Device(Device),
+}
+
+impl fmt::Debug for Port {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Port::Controller => write!(f, "Controller"),
+            Port::Zapper(z) => f.debug_tuple("Zapper").field(z).finish(),
+            Port::Vaus(v) => f.debug_tuple("Vaus").field(v).finish(),
+            Port::Device(_) => write!(f, "Device"),
+        }
+    }
+}
+
+// What's in the Famicom's expansion port, which the NES doesn't have. Its
+// devices see the same strobe as the controllers and answer next to them, so
+// they work alongside whatever is in the ports. Like those, they aren't part of
+// save states.
+#[derive(Default)]
+pub(crate) enum Expansion {
+    #[default]
+    None,
+    Pads(ExpansionPads),
+    Vaus(Vaus),
+    // such as the Family BASIC keyboard
+    Device(Device),
+}
+
+impl fmt::Debug for Expansion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expansion::None => write!(f, "None"),
+            Expansion::Pads(p) => f.debug_tuple("Pads").field(p).finish(),
+            Expansion::Vaus(v) => f.debug_tuple("Vaus").field(v).finish(),
+            Expansion::Device(_) => write!(f, "Device"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Input {
+    pub(crate) pads: Pads,
+    pub(crate) ports: [Port; 2],
+    pub(crate) expansion: Expansion,
+    // Whether the game read a port during the frame being run, whether it did
+    // during the last one, and the frames since power-on it didn't (lag frames)
+    pub(crate) polled: bool,
+    pub(crate) lagged: bool,
+    pub(crate) lag_frames: u64,
+}
+
+impl Input {
+    // At the end of a frame
+    pub(crate) fn end_frame(&mut self) {
+        self.lagged = !self.polled;
+        if self.lagged {
+            self.lag_frames += 1;
+        }
+        self.polled = false;
+    }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        self.pads.save_state(w);
+        w.write_bool(self.polled);
+        w.write_bool(self.lagged);
+        w.write_u64(self.lag_frames);
+    }
+
+    // States of older builds end after the controllers
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.pads.load_state(r)?;
+        if r.is_empty() {
+            self.polled = false;
+            self.lagged = false;
+            self.lag_frames = 0;
+            return Ok(());
+        }
+        self.polled = r.read_bool()?;
+        self.lagged = r.read_bool()?;
+        self.lag_frames = r.read_u64()?;
+        Ok(())
+    }
+}
+
+// $4016 write, seen by everything plugged in
+pub(crate) fn write_strobe(nes: &mut Nes, value: u8) {
+    // input polled now is latched right away
+    if value & 1 != 0 && !nes.input.pads.strobe && !nes.hooks.poll.is_empty() {
+        hook::on_poll(nes);
+    }
+    let input = &mut nes.input;
+    input.pads.strobe(value);
+    for port in &mut input.ports {
+        match port {
+            Port::Vaus(v) => v.strobe(value),
+            Port::Device(d) => d.strobe(value),
+            Port::Controller | Port::Zapper(_) => {}
+        }
+    }
+    match &mut input.expansion {
+        Expansion::None => {}
+        Expansion::Pads(p) => p.strobe(value, &input.pads.controllers),
+        Expansion::Vaus(v) => v.strobe(value),
+        Expansion::Device(d) => d.strobe(value),
+    }
+}
+
+// $4016/$4017 read of controller `port`. The port drives D0-D4 (the
+// controller only D0, the rest left low) and D5-D7 keep the last value on the
+// data bus, usually $40 from the address; games tell peripherals apart by these.
+pub(crate) fn read(nes: &mut Nes, port: usize) -> u8 {
+    nes.input.polled = true;
+    let bits = match &mut nes.input.ports[port] {
+        Port::Controller => nes.input.pads.read(port),
+        Port::Zapper(z) => {
+            let z = *z;
+            z.bits(nes)
+        }
+        Port::Vaus(v) => v.read(port),
+        Port::Device(d) => d.read(port),
+    };
+    let input = &mut nes.input;
+    let expansion = match &mut input.expansion {
+        Expansion::None => 0,
+        Expansion::Pads(p) => p.read(port, &input.pads.controllers),
+        // only the knob shifts
+        Expansion::Vaus(v) if port == 0 => famicom_vaus_bits(v.peek(port), port),
+        Expansion::Vaus(v) => famicom_vaus_bits(v.read(port), port),
+        Expansion::Device(d) => d.read(port) & EXPANSION_LINES[port],
+    };
+    (bits | expansion) & 0x1F | (nes.open_bus & 0xE0)
+}
+
+// What `read` would return, without shifting
+pub(crate) fn peek(nes: &Nes, port: usize) -> u8 {
+    let bits = match &nes.input.ports[port] {
+        Port::Controller => nes.input.pads.peek(port),
+        Port::Zapper(z) => z.bits(nes),
+        Port::Vaus(v) => v.peek(port),
+        Port::Device(d) => d.peek(port),
+    };
+    let input = &nes.input;
+    let expansion = match &input.expansion {
+        Expansion::None => 0,
+        Expansion::Pads(p) => p.peek(port, &input.pads.controllers),
+        Expansion::Vaus(v) => famicom_vaus_bits(v.peek(port), port),
+        Expansion::Device(d) => d.peek(port) & EXPANSION_LINES[port],
+    };
+    (bits | expansion) & 0x1F | (nes.open_bus & 0xE0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_buttons() {
+        let mut nes = Nes::new();
+        nes.input.pads.controllers[0].state = Button::A.mask() | Button::Start.mask();
+        nes.input.pads.controllers[1].state = Button::Right.mask();
+
+        write_strobe(&mut nes, 1);
+        write_strobe(&mut nes, 0);
+        let p1: Vec<u8> = (0..8).map(|_| read(&mut nes, 0) & 1).collect();
+        let p2: Vec<u8> = (0..8).map(|_| read(&mut nes, 1) & 1).collect();
+        assert_eq!(p1, [1, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(p2, [0, 0, 0, 0, 0, 0, 0, 1]);
+        // past the 8 buttons
+        assert_eq!(read(&mut nes, 0) & 1, 1);
+        assert_eq!(read(&mut nes, 1) & 1, 1);
+
+        // while strobe is high, reads keep returning A
+        write_strobe(&mut nes, 1);
+        assert_eq!(read(&mut nes, 0) & 1, 1);
+        assert_eq!(read(&mut nes, 0) & 1, 1);
+    }
+
+    #[test]
+    fn test_four_score() {
+        let mut nes = Nes::new();
+        nes.input.pads.four_score = true;
+        for (i, c) in nes.input.pads.controllers.iter_mut().enumerate() {
+            c.state = 1 << i;
+        }
+
+        write_strobe(&mut nes, 1);
+        write_strobe(&mut nes, 0);
+        let report = |nes: &mut Nes, port| {
+            (0..24).fold(0u32, |r, i| r | ((read(nes, port) & 1) as u32) << i)
+        };
+        // controllers 1 and 3, then the signature
+        assert_eq!(report(&mut nes, 0), 0x08_04_01);
+        assert_eq!(report(&mut nes, 1), 0x04_08_02);
+        assert_eq!(read(&mut nes, 0) & 1, 1);
+    }
+
+    #[test]
+    fn test_load_state_v1() {
+        let mut pads = Pads {
+            four_score: true,
+            ..Default::default()
+        };
+        let data = [0, 0x11, 0x01, 0x22, 0x80];
+        pads.load_state_v1(&mut StateReader::new(&data)).unwrap();
+        assert!(!pads.four_score);
+        assert_eq!(pads.controllers[1].state, 0x22);
+        assert_eq!(pads.shift, [0xFFFF_FF01, 0xFFFF_FF80]);
+    }
+
+    #[test]
+    fn test_zapper() {
+        let mut nes = Nes::new();
+        // a white pixel at (10, 100)
+        nes.ppu.buffer[100 * WIDTH + 10] = 0x30;
+        nes.input.ports[1] = Port::Zapper(Zapper {
+            pointer: Some((10, 100)),
+            trigger: true,
+        });
+
+        #[rustfmt::skip]
+        let cases = [
+            ("not drawn yet",   (10, 100), 99,  0,  0x18),
+            ("same line",       (10, 100), 100, 20, 0x10),
+            ("lines later",     (10, 100), 110, 0,  0x10),
+            ("faded",           (10, 100), 121, 0,  0x18),
+            ("dark pixel",      (11, 100), 101, 0,  0x18),
+            ("off screen",      (10, 300), 101, 0,  0x18),
+        ];
+
+        for (name, pointer, scanline, dot, expected) in cases {
+            if let Port::Zapper(z) = &mut nes.input.ports[1] {
+                z.pointer = Some(pointer);
+            }
+            nes.ppu.scanline = scanline;
+            nes.ppu.dot = dot;
+            assert_eq!(read(&mut nes, 1) & 0x1F, expected, "{}", name);
+            assert_eq!(peek(&nes, 1) & 0x1F, expected, "{}", name);
+        }
+
+        // port 0 is still a controller
+        assert_eq!(read(&mut nes, 0) & 0x18, 0);
+    }
+
+    #[test]
+    fn test_vaus() {
+        let mut nes = Nes::new();
+        nes.input.ports[1] = Port::Vaus(Vaus {
+            position: 0xA5,
+            fire: true,
+            ..Default::default()
+        });
+
+        write_strobe(&mut nes, 1);
+        write_strobe(&mut nes, 0);
+        let bits: Vec<u8> = (0..8).map(|_| read(&mut nes, 1) & 0x18).collect();
+        // 0xA5 inverted, MSB first, with the fire button held throughout
+        assert_eq!(bits, [8, 24, 8, 24, 24, 8, 24, 8]);
+        // the controller in port 0 isn't affected
+        assert_eq!(read(&mut nes, 0) & 0x18, 0);
+    }
+
+    #[test]
+    fn test_expansion_pads() {
+        let mut nes = Nes::new();
+        nes.input.expansion = Expansion::Pads(Default::default());
+        nes.input.pads.controllers[0].state = Button::B.mask();
+        nes.input.pads.controllers[2].state = Button::A.mask() | Button::Start.mask();
+        nes.input.pads.controllers[3].state = Button::Right.mask();
+
+        write_strobe(&mut nes, 1);
+        assert_eq!(peek(&nes, 0) & 0x03, 0x02);
+        write_strobe(&mut nes, 0);
+        let bits: Vec<u8> = (0..9).map(|_| read(&mut nes, 0) & 0x03).collect();
+        // controller 1 on D0, controller 3 on D1
+        assert_eq!(bits, [2, 1, 0, 2, 0, 0, 0, 0, 3]);
+        let bits: Vec<u8> = (0..8).map(|_| read(&mut nes, 1) & 0x02).collect();
+        assert_eq!(bits, [0, 0, 0, 0, 0, 0, 0, 2]);
+    }
+
+    #[test]
+    fn test_expansion_vaus() {
+        let mut nes = Nes::new();
+        nes.input.expansion = Expansion::Vaus(Vaus {
+            position: 0xA5,
+            fire: true,
+            ..Default::default()
+        });
+
+        write_strobe(&mut nes, 1);
+        write_strobe(&mut nes, 0);
+        // fire on $4016, which doesn't shift the knob
+        assert_eq!(read(&mut nes, 0) & 0x1E, 0x02);
+        assert_eq!(read(&mut nes, 0) & 0x1E, 0x02);
+        let bits: Vec<u8> = (0..8).map(|_| read(&mut nes, 1) & 0x1E).collect();
+        // 0xA5 inverted, MSB first
+        assert_eq!(bits, [0, 2, 0, 2, 2, 0, 2, 0]);
+    }
+}
@@ -3,18 +3,2454 @@ extern crate bitflags;
 
 extern crate anyhow;
 
+#[cfg(feature = "dump")]
+use std::fs::File;
+#[cfg(feature = "dump")]
+use std::io::BufWriter;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use anyhow::Result;
+
 #[cfg(test)]
 #[macro_use]
 extern crate assert_matches;
 
+mod apu;
+mod audio;
+mod cheat;
+mod config;
 mod cpu;
+#[cfg(test)]
+mod determinism;
+#[cfg(feature = "dump")]
+mod dump;
+mod event_log;
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+#[cfg(feature = "gdb")]
+mod gdb;
+mod hook;
+mod input;
+#[cfg(feature = "libretro")]
+mod libretro;
 mod mapper;
+mod memory_map;
+mod movie;
 mod nes;
+mod netplay;
+mod nsf;
+mod ppu;
+mod profiler;
+mod rewind;
 mod rom;
+#[cfg(feature = "lua")]
+mod script;
+mod search;
+mod self_check;
+mod state;
+mod symbols;
+mod test_rom;
+mod trace;
+mod video;
+
+pub use apu::{Channel, ChannelLevel};
+pub use audio::{AudioSink, SampleBuffer};
+pub use cheat::Cheat;
+pub use config::{Config, RamInit, Region};
+pub use cpu::{CallFrame, CallKind, Registers};
+pub use event_log::{PpuEvent, PpuEventKind};
+#[cfg(feature = "gdb")]
+pub use gdb::GdbStub;
+pub use hook::{Event, HookId, Machine};
+pub use input::{Button, InputDevice};
+pub use memory_map::{Backing, MemoryRange};
+pub use movie::{Movie, MovieFrame};
+pub use netplay::{InputPacket, Lockstep};
+pub use profiler::{HotSpot, Profile};
+pub use rewind::RewindConfig;
+pub use rom::{
+    Cartridge, FdsFile, FdsFileKind, FdsImage, FdsSide, NsfFile, NsfTrack, RomDatabase, RomError,
+    RomInfo,
+};
+#[cfg(feature = "lua")]
+pub use script::{GuiCommand, Script};
+pub use search::{Predicate, RamSearch, Watch, WatchList};
+pub use self_check::Violation;
+pub use state::{diff_states, StateDiff};
+pub use symbols::{Label, Symbols};
+pub use test_rom::{TestResult, TestRomMonitor};
+pub use video::{scale_frame, FrameBuffer, Scale, VideoSink};
+
+use apu::Apu;
+use audio::Resampler;
+use input::{Expansion, Port};
+use mapper::Mapper;
+use movie::MovieError;
+use nes::{Nes, SystemBus};
+use nsf::NsfPlayer;
+use rewind::Rewind;
+use video::VideoWorker;
+#[cfg(feature = "dump")]
+use video::Y4mWriter;
 
-pub struct Emu {}
+// One emulated console. There's no global state, so any number of instances can
+// run side by side, each on its own thread: `Emu` is Send, with sinks and hooks
+// required to be Send too. It isn't Sync; share one behind a Mutex instead.
+#[derive(Default)]
+pub struct Emu {
+    nes: Nes,
+    cartridge: Option<Cartridge>,
+    // A music file playing in place of a cartridge
+    nsf: Option<NsfPlayer>,
+    nsf_file: Option<NsfFile>,
+    // SHA-1 of the cartridge, tying save states to it
+    rom_hash: [u8; 20],
+    config: Config,
+
+    // The last frame as 0xFFRRGGBB, handed to the video sink
+    pixels: Vec<u32>,
+    video: Option<Box<dyn VideoSink + Send>>,
+    // Holds the video sink while frames are converted on a thread
+    video_worker: Option<VideoWorker>,
+    #[cfg(feature = "dump")]
+    video_dump: Option<Y4mWriter<BufWriter<File>>>,
+    #[cfg(feature = "dump")]
+    av_dump: Option<dump::AvDump>,
+    audio: Option<Box<dyn AudioSink + Send>>,
+    rewind: Option<Rewind>,
+    symbols: Symbols,
+
+    movie: Option<MovieState>,
+    // `reset` was called since the last frame started
+    reset_pending: bool,
+    // The current frame's input has been taken
+    frame_started: bool,
+    // Samples the last frame produced, for `FrameResult`
+    frame_samples: usize,
+    // `run_*` calls do nothing; only the `step_*` calls and `frame_advance` run
+    paused: bool,
+}
+
+enum MovieState {
+    Recording(Movie),
+    // The movie and the index of the next frame to play
+    Playing(Movie, usize),
+}
+
+// How long `step_over` and `step_out` run for a return before giving up
+pub const STEP_LIMIT_FRAMES: u64 = 600;
+
+// What happened during a `run_frame` call
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FrameResult {
+    // Number of the frame that was completed, counted from 0 since the ROM was loaded
+    pub frame: u64,
+    pub cpu_cycles: u64,
+    // Whether the frame was a lag frame, one where the game didn't read the
+    // controllers, and the lag frames since power-on including it
+    pub lag: bool,
+    pub lag_frames: u64,
+    // Whether the CPU took an NMI, and an IRQ from any source, during the call
+    pub nmi: bool,
+    pub irq: bool,
+    // Samples handed to the audio sink, channels interleaved; none when headless
+    pub audio_samples: usize,
+}
 
 impl Emu {
-    #[allow(dead_code)]
-    fn run_frame() {}
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_config(config: Config) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<()> {
+        self.load_cartridge(Cartridge::from_bytes(rom)?)
+    }
+
+    // With the `archive` feature, .zip and .gz files are accepted too
+    pub fn load_rom_path<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.load_cartridge(Cartridge::from_path(path)?)
+    }
+
+    // Power on a fresh console with `cart` inserted
+    pub fn load_cartridge(&mut self, cart: Cartridge) -> Result<()> {
+        let region = self.config.region(&cart);
+        let mut nes = Nes::new();
+        self.config.ram_init.fill(&mut nes.wram);
+        nes.debug_port = self.config.debug_port;
+        nes.load_cartridge(&cart, region)?;
+        if let Some(dip_switches) = self.config.dip_switches {
+            nes.mapper.set_dip_switches(dip_switches);
+        }
+        nes.audio = Resampler::new(
+            region.cpu_clock(),
+            self.config.sample_rate,
+            self.config.channels,
+        );
+        cpu::reset::<SystemBus>(&mut nes);
+        // codes and hooks stay on across power cycles of the same game, and its
+        // unread debug output stays too
+        if cart.sha1() == self.rom_hash {
+            nes.cheats = std::mem::take(&mut self.nes.cheats);
+            nes.hooks = std::mem::take(&mut self.nes.hooks);
+            nes.debug_output = std::mem::take(&mut self.nes.debug_output);
+        }
+
+        self.nes = nes;
+        self.rom_hash = cart.sha1();
+        if let Some(rewind) = &mut self.rewind {
+            rewind.clear();
+        }
+        self.movie = None;
+        self.reset_pending = false;
+        self.frame_started = false;
+        self.cartridge = Some(cart);
+        self.nsf = None;
+        self.nsf_file = None;
+        Ok(())
+    }
+
+    // Play an NSF, NSF2 or NSFe tune in place of a cartridge, from the top of its
+    // playlist: the NSFe one, or every song from the header's starting one.
+    // `run_frame` plays it, moving to the next song once one with a known length
+    // has played and faded out, and stopping after the last.
+    pub fn load_nsf(&mut self, file: NsfFile) {
+        let region = if file.header.pal {
+            Region::Pal
+        } else {
+            Region::Ntsc
+        };
+        let mut nes = Nes::new();
+        nes.region = region;
+        nes.apu = Apu::new(region);
+        nes.audio = Resampler::new(
+            region.cpu_clock(),
+            self.config.sample_rate,
+            self.config.channels,
+        );
+        let mut player = NsfPlayer::load(&mut nes, &file);
+        if let Some(position) = player.position() {
+            player.start::<SystemBus>(&mut nes, position);
+        }
+
+        self.nes = nes;
+        self.rom_hash = [0; 20];
+        if let Some(rewind) = &mut self.rewind {
+            rewind.clear();
+        }
+        self.movie = None;
+        self.reset_pending = false;
+        self.frame_started = false;
+        self.cartridge = None;
+        self.nsf = Some(player);
+        self.nsf_file = Some(file);
+    }
+
+    pub fn nsf_file(&self) -> Option<&NsfFile> {
+        self.nsf_file.as_ref()
+    }
+
+    // Songs of the tune in the order they play, 0-origin
+    pub fn nsf_playlist(&self) -> &[u8] {
+        self.nsf.as_ref().map_or(&[], |p| p.playlist())
+    }
+
+    // Index in `nsf_playlist` of the song playing, None once the last has ended
+    pub fn nsf_position(&self) -> Option<usize> {
+        self.nsf.as_ref()?.position()
+    }
+
+    // Song playing, 0-origin like the playlist's, None once the last has ended
+    pub fn nsf_song(&self) -> Option<u8> {
+        let player = self.nsf.as_ref()?;
+        player.position()?;
+        Some(player.song())
+    }
+
+    // Time the current song has played for, in milliseconds like `NsfTrack`'s
+    pub fn nsf_elapsed(&self) -> Option<u64> {
+        let player = self.nsf.as_ref()?;
+        player.position()?;
+        Some(player.elapsed(&self.nes) as u64)
+    }
+
+    // Play the song at `position` in the playlist from its start; past the end,
+    // playback stops
+    pub fn select_track(&mut self, position: usize) {
+        if let Some(player) = &mut self.nsf {
+            player.start::<SystemBus>(&mut self.nes, position);
+        }
+    }
+
+    // Skip to the next song, returning false when there's none
+    pub fn next_track(&mut self) -> bool {
+        let next = match self.nsf_position() {
+            Some(position) => position + 1,
+            None => return false,
+        };
+        self.select_track(next);
+        self.nsf_position().is_some()
+    }
+
+    // Go back a song, or to the start of the first one; after the last song has
+    // ended, play it again
+    pub fn previous_track(&mut self) {
+        let len = self.nsf_playlist().len();
+        let previous = match self.nsf_position() {
+            Some(position) => position.saturating_sub(1),
+            None => len.saturating_sub(1),
+        };
+        self.select_track(previous);
+    }
+
+    // Press the console's reset button: the CPU restarts through the reset vector
+    // and the APU is silenced, while RAM and the cartridge keep their state
+    pub fn reset(&mut self) {
+        if self.cartridge.is_none() {
+            return;
+        }
+        self.reset_pending = true;
+        self.reset_console();
+    }
+
+    fn reset_console(&mut self) {
+        let nes = &mut self.nes;
+        nes.ppu.reset();
+        nes.apu.reset();
+        nes.mapper.irq_ack();
+        nes.nmi = false;
+        nes.oam_dma = None;
+        cpu::reset::<SystemBus>(nes);
+    }
+
+    // Turn the console off and on again: RAM and the board's registers start over,
+    // only battery-backed memory survives
+    pub fn power_cycle(&mut self) -> Result<()> {
+        let cart = match self.cartridge.take() {
+            Some(cart) => cart,
+            None => return Ok(()),
+        };
+        let nvram = self.nes.mapper.nvram().map(|m| m.to_vec());
+        self.load_cartridge(cart)?;
+        if let (Some(saved), Some(m)) = (nvram, self.nes.mapper.nvram_mut()) {
+            m.copy_from_slice(&saved);
+        }
+        Ok(())
+    }
+
+    // Power on without battery-backed memory, the defined state movies start from
+    fn power_on_clean(&mut self) -> Result<()> {
+        let cart = self.cartridge.take().ok_or_else(no_cartridge)?;
+        // the Four Score stays plugged in; movies record it
+        let four_score = self.four_score();
+        self.load_cartridge(cart)?;
+        self.set_four_score(four_score);
+        Ok(())
+    }
+
+    // Power the console on afresh and record the controller input and resets of
+    // every following frame. Battery-backed memory is cleared so that playback
+    // doesn't depend on it; a frontend keeping saves should store it beforehand.
+    pub fn start_recording(&mut self) -> Result<()> {
+        self.power_on_clean()?;
+        self.movie = Some(MovieState::Recording(Movie::new(self.rom_hash)));
+        Ok(())
+    }
+
+    // Power the console on as `start_recording` does and replay `movie`. Controller
+    // input set meanwhile is overridden until the movie ends.
+    pub fn play_movie(&mut self, movie: Movie) -> Result<()> {
+        if self.cartridge.is_none() {
+            return Err(no_cartridge());
+        }
+        if movie.rom_hash() != &self.rom_hash {
+            return Err(MovieError::new("movie is for another ROM").into());
+        }
+        self.power_on_clean()?;
+        self.movie = Some(MovieState::Playing(movie, 0));
+        Ok(())
+    }
+
+    // Stop recording or playing, returning the movie
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        match self.movie.take()? {
+            MovieState::Recording(movie) | MovieState::Playing(movie, _) => Some(movie),
+        }
+    }
+
+    pub fn recording(&self) -> bool {
+        matches!(self.movie, Some(MovieState::Recording(_)))
+    }
+
+    // Whether a movie is playing and has frames left
+    pub fn playing(&self) -> bool {
+        matches!(&self.movie, Some(MovieState::Playing(movie, i)) if *i < movie.len())
+    }
+
+    // Region the loaded cartridge runs as
+    pub fn region(&self) -> Region {
+        self.nes.region
+    }
+
+    pub fn cartridge(&self) -> Option<&Cartridge> {
+        self.cartridge.as_ref()
+    }
+
+    // Press or release a button of the standard controller in `port` (0 or 1, or
+    // 2 and 3 with the Four Score or the expansion port's controllers)
+    pub fn set_button(&mut self, port: usize, button: Button, pressed: bool) {
+        if let Some(c) = self.nes.input.pads.controllers.get_mut(port) {
+            if pressed {
+                c.state |= button.mask();
+            } else {
+                c.state &= !button.mask();
+            }
+        }
+    }
+
+    // Set all buttons of the controller in `port` at once, one bit per `Button`
+    // from A in bit 0 to Right in bit 7
+    pub fn set_controller_state(&mut self, port: usize, state: u8) {
+        if let Some(c) = self.nes.input.pads.controllers.get_mut(port) {
+            c.state = state;
+        }
+    }
+
+    pub fn controller_state(&self, port: usize) -> u8 {
+        self.nes
+            .input
+            .pads
+            .controllers
+            .get(port)
+            .map_or(0, |c| c.state)
+    }
+
+    // Plug in the Four Score adapter, which adds controllers 3 and 4 behind
+    // ports 0 and 1
+    pub fn set_four_score(&mut self, enabled: bool) {
+        self.nes.input.pads.four_score = enabled;
+    }
+
+    pub fn four_score(&self) -> bool {
+        self.nes.input.pads.four_score
+    }
+
+    // Plug a Zapper into `port` in place of whatever is there, or go back to the
+    // controller
+    pub fn connect_zapper(&mut self, port: usize, connected: bool) {
+        self.connect_port(port, connected, || Port::Zapper(Default::default()));
+    }
+
+    // Aim the Zapper in `port` at screen coordinates, None for off screen, and
+    // pull or release the trigger. Light is sensed from the frame buffer, so it
+    // isn't in headless mode.
+    pub fn set_zapper(&mut self, port: usize, pointer: Option<(u16, u16)>, trigger: bool) {
+        if let Some(Port::Zapper(z)) = self.nes.input.ports.get_mut(port) {
+            z.pointer = pointer;
+            z.trigger = trigger;
+        }
+    }
+
+    // Plug an Arkanoid Vaus paddle into `port` in place of whatever is there, or
+    // go back to the controller
+    pub fn connect_vaus(&mut self, port: usize, connected: bool) {
+        self.connect_port(port, connected, || Port::Vaus(Default::default()));
+    }
+
+    // Turn the Vaus knob in `port` to `position` (Arkanoid uses about 0x62 to
+    // 0xF2) and press or release fire
+    pub fn set_vaus(&mut self, port: usize, position: u8, fire: bool) {
+        if let Some(Port::Vaus(v)) = self.nes.input.ports.get_mut(port) {
+            v.position = position;
+            v.fire = fire;
+        }
+    }
+
+    // Plug a custom device into `port` in place of whatever is there, until
+    // it's disconnected. Devices aren't part of save states.
+    pub fn connect_device<D: InputDevice + Send + 'static>(&mut self, port: usize, device: D) {
+        self.connect_port(port, true, || Port::Device(Box::new(device)));
+    }
+
+    // Go back to the controller in `port`
+    pub fn disconnect_device(&mut self, port: usize) {
+        self.connect_port(port, false, || Port::Controller);
+    }
+
+    // Plug two controllers into the Famicom's expansion port, or unplug them.
+    // They're controllers 3 and 4 of `set_controller_state`, read on D1 of
+    // $4016 and $4017 next to controllers 1 and 2.
+    pub fn connect_expansion_pads(&mut self, connected: bool) {
+        self.connect_expansion(connected, || Expansion::Pads(Default::default()));
+    }
+
+    // Plug the Famicom version of the Arkanoid Vaus into the expansion port, or
+    // unplug it
+    pub fn connect_expansion_vaus(&mut self, connected: bool) {
+        self.connect_expansion(connected, || Expansion::Vaus(Default::default()));
+    }
+
+    // Turn the knob of the Vaus in the expansion port and press or release fire
+    pub fn set_expansion_vaus(&mut self, position: u8, fire: bool) {
+        if let Expansion::Vaus(v) = &mut self.nes.input.expansion {
+            v.position = position;
+            v.fire = fire;
+        }
+    }
+
+    // Plug a custom device, such as a keyboard, into the expansion port until
+    // it's disconnected
+    pub fn connect_expansion_device<D: InputDevice + Send + 'static>(&mut self, device: D) {
+        self.connect_expansion(true, || Expansion::Device(Box::new(device)));
+    }
+
+    // Leave the expansion port empty, as on the NES
+    pub fn disconnect_expansion(&mut self) {
+        self.connect_expansion(false, || Expansion::None);
+    }
+
+    fn connect_expansion(&mut self, connected: bool, device: impl FnOnce() -> Expansion) {
+        self.nes.input.expansion = if connected { device() } else { Expansion::None };
+    }
+
+    fn connect_port(&mut self, port: usize, connected: bool, device: impl FnOnce() -> Port) {
+        if let Some(p) = self.nes.input.ports.get_mut(port) {
+            *p = if connected {
+                device()
+            } else {
+                Port::Controller
+            };
+        }
+    }
+
+    // Add a Game Genie code, enabled. Adding a code that's already there only
+    // enables it.
+    pub fn add_cheat(&mut self, code: &str) -> Result<()> {
+        let cheat = Cheat::from_game_genie(code)?;
+        match self
+            .nes
+            .cheats
+            .iter_mut()
+            .find(|c| c.code() == cheat.code())
+        {
+            Some(c) => c.enabled = true,
+            None => self.nes.cheats.push(cheat),
+        }
+        Ok(())
+    }
+
+    // Returns whether the code was there
+    pub fn remove_cheat(&mut self, code: &str) -> bool {
+        let len = self.nes.cheats.len();
+        self.nes
+            .cheats
+            .retain(|c| !c.code().eq_ignore_ascii_case(code));
+        self.nes.cheats.len() != len
+    }
+
+    // Returns whether the code was there
+    pub fn set_cheat_enabled(&mut self, code: &str, enabled: bool) -> bool {
+        match self
+            .nes
+            .cheats
+            .iter_mut()
+            .find(|c| c.code().eq_ignore_ascii_case(code))
+        {
+            Some(c) => {
+                c.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.nes.cheats
+    }
+
+    // The console's 2KB of CPU RAM, for `RamSearch`
+    pub fn ram(&self) -> &[u8] {
+        &self.nes.wram
+    }
+
+    // Read a byte of the CPU address space as the CPU would see it, but without
+    // advancing the clock or the side effects of reading registers
+    pub fn peek(&self, addr: u16) -> u8 {
+        nes::peek(&self.nes, addr)
+    }
+
+    // Write a byte of CPU RAM or cartridge PRG-RAM. Writes to registers and ROM
+    // are ignored.
+    pub fn poke(&mut self, addr: u16, value: u8) {
+        nes::poke(&mut self.nes, addr, value);
+    }
+
+    // What backs each range of the CPU address space with the current banks
+    pub fn cpu_memory_map(&self) -> Vec<MemoryRange> {
+        memory_map::cpu_map(&self.nes)
+    }
+
+    // What backs each range of the PPU address space with the current banks and
+    // mirroring
+    pub fn ppu_memory_map(&self) -> Vec<MemoryRange> {
+        memory_map::ppu_map(&self.nes)
+    }
+
+    // Call `f` with the PC before each instruction at an address in `addrs`
+    // executes. Changing the PC skips the instruction.
+    pub fn add_exec_hook<F>(&mut self, addrs: RangeInclusive<u16>, f: F) -> HookId
+    where
+        F: FnMut(&mut Machine, u16) + Send + 'static,
+    {
+        self.nes.hooks.add_exec(addrs, Box::new(f))
+    }
+
+    // Call `f` with the address and value of each CPU bus read in `addrs`, reading
+    // what it returns instead
+    pub fn add_read_hook<F>(&mut self, addrs: RangeInclusive<u16>, f: F) -> HookId
+    where
+        F: FnMut(&mut Machine, u16, u8) -> u8 + Send + 'static,
+    {
+        self.nes.hooks.add_read(addrs, Box::new(f))
+    }
+
+    // Call `f` with the address and value of each CPU bus write in `addrs`, writing
+    // what it returns instead
+    pub fn add_write_hook<F>(&mut self, addrs: RangeInclusive<u16>, f: F) -> HookId
+    where
+        F: FnMut(&mut Machine, u16, u8) -> u8 + Send + 'static,
+    {
+        self.nes.hooks.add_write(addrs, Box::new(f))
+    }
+
+    // Call `f` with the frame number as each frame completes, before it's delivered
+    pub fn add_frame_hook<F>(&mut self, f: F) -> HookId
+    where
+        F: FnMut(&mut Machine, u64) + Send + 'static,
+    {
+        self.nes.hooks.add_frame(Box::new(f))
+    }
+
+    // Call `f` each time the game starts latching the controllers, to set their
+    // state right when it's read rather than once a frame. Movies still record
+    // the state at the start of each frame.
+    pub fn add_poll_hook<F>(&mut self, f: F) -> HookId
+    where
+        F: FnMut(&mut Machine) + Send + 'static,
+    {
+        self.nes.hooks.add_poll(Box::new(f))
+    }
+
+    // Call `f` with every `Event` from now on; `remove_hook` unsubscribes
+    pub fn subscribe<F>(&mut self, f: F) -> HookId
+    where
+        F: FnMut(Event) + Send + 'static,
+    {
+        self.nes.hooks.add_event(Box::new(f))
+    }
+
+    // Returns whether the hook was there
+    pub fn remove_hook(&mut self, id: HookId) -> bool {
+        self.nes.hooks.remove(id)
+    }
+
+    // Deliver every finished frame to `sink`
+    pub fn set_video_sink<S: VideoSink + Send + 'static>(&mut self, sink: S) {
+        match &mut self.video_worker {
+            Some(worker) => worker.set_sink(Some(Box::new(sink))),
+            None => self.video = Some(Box::new(sink)),
+        }
+    }
+
+    // Convert frames and hand them to the video sink on a worker thread, while the
+    // next frame runs, or on the calling thread again with false. Threaded, the
+    // sink and `frame_buffer` get each frame during the `run_frame` after it.
+    pub fn set_threaded_video(&mut self, enabled: bool) {
+        match (enabled, self.video_worker.take()) {
+            (true, None) => self.video_worker = Some(VideoWorker::new(self.video.take())),
+            (false, Some(worker)) => self.video = worker.finish(&mut self.pixels),
+            (_, worker) => self.video_worker = worker,
+        }
+    }
+
+    pub fn threaded_video(&self) -> bool {
+        self.video_worker.is_some()
+    }
+
+    // Write every frame drawn from now on to a Y4M file at `path`, finishing any
+    // dump in progress. Headless frames aren't drawn, so they're left out.
+    #[cfg(feature = "dump")]
+    pub fn start_video_dump<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.stop_video_dump()?;
+        let file = BufWriter::new(File::create(path)?);
+        self.video_dump = Some(Y4mWriter::new(file, self.nes.region.frame_rate()));
+        Ok(())
+    }
+
+    // Finish the dump, failing with the first error writing it
+    #[cfg(feature = "dump")]
+    pub fn stop_video_dump(&mut self) -> Result<()> {
+        match self.video_dump.take() {
+            Some(dump) => dump.finish().map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    // Play `movie` from power-on to its last frame, recording into `dir`, created
+    // if missing: frames.rgb with the frames as raw 24-bit RGB, audio.wav with the
+    // sound, and manifest.txt describing both. Returns the number of frames.
+    #[cfg(feature = "dump")]
+    pub fn dump_movie<P: AsRef<Path>>(&mut self, movie: Movie, dir: P) -> Result<u64> {
+        let frames = movie.len();
+        // powering on drops headless mode, which is kept for after
+        let (headless, paused) = (self.headless(), self.paused);
+        self.play_movie(movie)?;
+        let info = dump::DumpInfo {
+            rom_hash: self.rom_hash,
+            width: ppu::WIDTH,
+            height: ppu::HEIGHT,
+            frame_rate: self.nes.region.frame_rate(),
+            sample_rate: self.config.sample_rate,
+            channels: self.config.channels,
+        };
+        self.av_dump = Some(dump::AvDump::create(dir.as_ref(), info)?);
+
+        self.set_headless(false);
+        self.paused = false;
+        for _ in 0..frames {
+            self.run_frame();
+        }
+        self.set_headless(headless);
+        self.paused = paused;
+        self.stop_movie();
+        self.av_dump.take().unwrap().finish()
+    }
+
+    // Deliver the samples of each frame to `sink`, at the configured rate and
+    // channel count
+    pub fn set_audio_sink<S: AudioSink + Send + 'static>(&mut self, sink: S) {
+        self.audio = Some(Box::new(sink));
+    }
+
+    // The last finished frame, 256x240 pixels as 0xFFRRGGBB; empty before the first one
+    pub fn frame_buffer(&self) -> &[u32] {
+        &self.pixels
+    }
+
+    // Run until the PPU finishes the current frame, at the start of VBlank.
+    // Does nothing without a cartridge or while paused.
+    pub fn run_frame(&mut self) -> FrameResult {
+        let start = self.nes.cpu_cycles;
+        let _span = trace::frame(self.nes.ppu.frame);
+        self.nes.took_nmi = false;
+        self.nes.took_irq = false;
+        self.frame_samples = 0;
+        if self.cartridge.is_some() && !self.paused {
+            while !self.step() {}
+        } else if self.nsf.is_some() && !self.paused {
+            self.nsf_frame();
+        }
+        FrameResult {
+            frame: self.nes.ppu.frame,
+            cpu_cycles: (self.nes.cpu_cycles - start) as u64,
+            lag: self.nes.input.lagged,
+            lag_frames: self.nes.input.lag_frames,
+            nmi: self.nes.took_nmi,
+            irq: self.nes.took_irq,
+            audio_samples: self.frame_samples,
+        }
+    }
+
+    // Run until the PPU reaches `dot` of `scanline`, stopping after the instruction
+    // during which it got there. Frames completed on the way are delivered as by
+    // `run_frame`. Does nothing for positions outside the frame or while paused.
+    pub fn run_until_scanline(&mut self, scanline: u16, dot: u16) {
+        if !self.paused {
+            self.run_until(scanline, dot);
+        }
+    }
+
+    fn run_until(&mut self, scanline: u16, dot: u16) {
+        if self.cartridge.is_none() || ppu::prerender_line(self.nes.region) < scanline || 340 < dot
+        {
+            return;
+        }
+        let target = ppu_position(scanline, dot);
+        loop {
+            let before = ppu_position(self.nes.ppu.scanline, self.nes.ppu.dot);
+            self.step();
+            nes::catch_up_ppu(&mut self.nes);
+            let after = ppu_position(self.nes.ppu.scanline, self.nes.ppu.dot);
+            let reached = if before <= after {
+                before < target && target <= after
+            } else {
+                // wrapped into the next frame
+                before < target || target <= after
+            };
+            if reached {
+                return;
+            }
+        }
+    }
+
+    // Run until VBlank starts, finishing the frame
+    pub fn run_to_vblank(&mut self) {
+        self.run_until_scanline(ppu::vblank_line(self.nes.region), 1);
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    // Pause and run exactly one frame, as `run_frame` would
+    pub fn frame_advance(&mut self) -> FrameResult {
+        self.paused = false;
+        let result = self.run_frame();
+        self.paused = true;
+        result
+    }
+
+    // Pause and execute one instruction, or take a pending interrupt. Returns the
+    // CPU cycles it took.
+    pub fn step_instruction(&mut self) -> u64 {
+        self.paused = true;
+        if self.cartridge.is_none() {
+            return 0;
+        }
+        let start = self.nes.cpu_cycles;
+        self.step();
+        nes::catch_up_ppu(&mut self.nes);
+        (self.nes.cpu_cycles - start) as u64
+    }
+
+    // Pause and run until the PPU reaches the start of the next scanline
+    pub fn step_scanline(&mut self) {
+        self.paused = true;
+        let next = (self.nes.ppu.scanline + 1) % (ppu::prerender_line(self.nes.region) + 1);
+        self.run_until(next, 0);
+    }
+
+    // Pause and execute one instruction, running through any subroutine it calls
+    // and any interrupt handler taken meanwhile. Returns whether they returned
+    // within `STEP_LIMIT_FRAMES` frames.
+    pub fn step_over(&mut self) -> bool {
+        let depth = self.nes.cpu.calls.depth();
+        self.step_instruction();
+        self.run_to_depth(depth)
+    }
+
+    // Pause and run until the innermost subroutine or interrupt handler returns.
+    // Returns whether it did within `STEP_LIMIT_FRAMES` frames, false outside any.
+    pub fn step_out(&mut self) -> bool {
+        self.paused = true;
+        match self.nes.cpu.calls.depth() {
+            0 => false,
+            depth => self.run_to_depth(depth - 1),
+        }
+    }
+
+    fn run_to_depth(&mut self, depth: usize) -> bool {
+        if self.cartridge.is_none() {
+            return false;
+        }
+        let start = self.nes.ppu.frame;
+        let mut returned = true;
+        while depth < self.nes.cpu.calls.depth() {
+            if start + STEP_LIMIT_FRAMES <= self.nes.ppu.frame {
+                returned = false;
+                break;
+            }
+            self.step();
+        }
+        nes::catch_up_ppu(&mut self.nes);
+        returned
+    }
+
+    // Count executed opcodes and the cycles spent at each instruction address from
+    // now on, starting over if already counting, or stop and drop the counts
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.nes.profile = if enabled {
+            Some(Default::default())
+        } else {
+            None
+        };
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.nes.profile.as_deref()
+    }
+
+    // Time left in ms on a countdown the cartridge keeps for players, for
+    // frontends to show: the Nintendo World Championships timer, full until the
+    // game starts it. None for other boards.
+    pub fn countdown_ms(&self) -> Option<u64> {
+        let cycles = self.nes.mapper.countdown()?;
+        Some(cycles as u64 * 1000 / self.nes.region.cpu_clock() as u64)
+    }
+
+    // Text the game wrote to `Config::debug_port` since the last call, with bytes
+    // that aren't UTF-8 replaced
+    pub fn take_debug_output(&mut self) -> String {
+        let output = std::mem::take(&mut self.nes.debug_output);
+        String::from_utf8_lossy(&output).into_owned()
+    }
+
+    // Run a test ROM reporting through $6000 until it finishes, for at most
+    // `timeout_frames` frames, pressing reset whenever it asks
+    pub fn run_test_rom(&mut self, timeout_frames: u32) -> TestResult {
+        let mut monitor = TestRomMonitor::new();
+        for _ in 0..timeout_frames {
+            self.run_frame();
+            if let Some(result) = monitor.poll(self) {
+                return result;
+            }
+        }
+        TestResult {
+            status: None,
+            text: test_rom::text(self),
+        }
+    }
+
+    // Measure each sound channel's output for visualizers, or stop with false
+    pub fn set_audio_metering(&mut self, enabled: bool) {
+        self.nes.meter = if enabled {
+            Some(Default::default())
+        } else {
+            None
+        };
+    }
+
+    // Levels, periods and volumes of the channels as of the last frame, empty
+    // unless metering
+    pub fn channel_levels(&self) -> &[ChannelLevel] {
+        match &self.nes.meter {
+            Some(meter) => &meter.levels,
+            None => &[],
+        }
+    }
+
+    // Log PPU register writes, interrupts and sprite 0 hits with the scanline and
+    // dot they happened at, or stop logging with false
+    pub fn set_ppu_event_log(&mut self, enabled: bool) {
+        self.nes.event_log = if enabled {
+            Some(Default::default())
+        } else {
+            None
+        };
+    }
+
+    // Logged events of the last frame the PPU finished, from scanline 0 through
+    // the pre-render line; empty without `set_ppu_event_log`
+    pub fn ppu_events(&self) -> &[PpuEvent] {
+        self.nes.event_log.as_ref().map_or(&[], |log| log.last())
+    }
+
+    // Check internal invariants after every instruction, reporting what breaks as
+    // `Event::Violation` to event hooks; slow, meant for developing boards
+    pub fn set_self_check(&mut self, enabled: bool) {
+        self.nes.self_check = if enabled {
+            Some(Default::default())
+        } else {
+            None
+        };
+    }
+
+    pub fn self_check(&self) -> bool {
+        self.nes.self_check.is_some()
+    }
+
+    // Name addresses with these labels from now on
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    // Name of the label covering `addr` with the current banks, followed by "+n"
+    // inside arrays
+    pub fn label(&self, addr: u16) -> Option<String> {
+        let backing = memory_map::cpu_backing(&self.nes, addr);
+        self.symbols
+            .lookup(backing, addr)
+            .map(|(label, offset)| match offset {
+                0 => label.name.clone(),
+                n => format!("{}+{}", label.name, n),
+            })
+    }
+
+    // The subroutines and interrupt handlers the CPU is in, outermost first
+    pub fn call_stack(&self) -> &[CallFrame] {
+        self.nes.cpu.calls.frames()
+    }
+
+    // Serialize the whole machine into a versioned snapshot of the loaded ROM
+    pub fn save_state(&self) -> Result<Vec<u8>> {
+        if self.cartridge.is_none() {
+            return Err(no_cartridge());
+        }
+        Ok(state::save_snapshot(&self.nes, &self.rom_hash))
+    }
+
+    // Restore a snapshot made by `save_state`. Snapshots of another ROM, region or
+    // a newer format are rejected, leaving the machine as it was.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<()> {
+        if self.cartridge.is_none() {
+            return Err(no_cartridge());
+        }
+        let backup = state::save_snapshot(&self.nes, &self.rom_hash);
+        if let Err(e) = state::load_snapshot(&mut self.nes, &self.rom_hash, data) {
+            state::load_snapshot(&mut self.nes, &self.rom_hash, &backup)?;
+            return Err(e);
+        }
+        hook::emit(&mut self.nes, Event::StateLoaded);
+        Ok(())
+    }
+
+    // Hash of the whole machine state, equal on two instances exactly when their
+    // save states are, for detecting netplay desyncs. 0 without a cartridge.
+    pub fn state_hash(&self) -> u64 {
+        self.save_state().map_or(0, |s| netplay::hash(&s))
+    }
+
+    // Keep snapshots of past frames for `rewind`, or stop with None
+    pub fn set_rewind(&mut self, config: Option<RewindConfig>) {
+        self.rewind = config.map(Rewind::new);
+    }
+
+    // Go back at least `frames` frames, as far as the kept snapshots allow.
+    // Returns how many frames were actually rewound.
+    pub fn rewind(&mut self, frames: u64) -> Result<u64> {
+        let rewind = match &mut self.rewind {
+            Some(rewind) => rewind,
+            None => {
+                return Err(state::StateError {
+                    msg: "rewind is disabled".to_string(),
+                }
+                .into())
+            }
+        };
+        let current = self.nes.ppu.frame;
+        match rewind.seek(current.saturating_sub(frames)) {
+            Some((frame, data)) => {
+                state::load_snapshot(&mut self.nes, &self.rom_hash, data)?;
+                // the snapshot was taken at the end of the frame, so the movie
+                // continues with the one after it
+                let next = frame as usize + 1;
+                match &mut self.movie {
+                    Some(MovieState::Recording(movie)) => movie.truncate(next),
+                    Some(MovieState::Playing(_, i)) => *i = next.min(*i),
+                    None => {}
+                }
+                self.frame_started = false;
+                hook::emit(&mut self.nes, Event::StateLoaded);
+                Ok(current - frame)
+            }
+            None => Ok(0),
+        }
+    }
+
+    // Headless mode skips drawing pixels and synthesizing audio while keeping the
+    // emulation exact, for test harnesses and fast-forward. Frames and samples
+    // aren't delivered to the sinks meanwhile.
+    pub fn set_headless(&mut self, headless: bool) {
+        self.nes.headless = headless;
+        self.nes.audio.flush();
+        self.nes.audio.buffer.clear();
+    }
+
+    pub fn headless(&self) -> bool {
+        self.nes.headless
+    }
+
+    // Current PPU position
+    pub fn scanline(&self) -> u16 {
+        self.nes.ppu.scanline
+    }
+
+    pub fn dot(&self) -> u16 {
+        self.nes.ppu.dot
+    }
+
+    // Execute one instruction, then hand the frame to the sinks if VBlank started.
+    // Returns whether it did, with the PPU caught up only then: callers stopping
+    // at any other point catch it up themselves.
+    fn step(&mut self) -> bool {
+        if !self.frame_started {
+            self.frame_started = true;
+            self.movie_frame();
+            // movies keep to the console's timing, which they depend on
+            self.nes.overclock_lines = if self.movie.is_none() || self.config.overclock_movies {
+                self.config.overclock_lines
+            } else {
+                0
+            };
+        }
+        let nes = &mut self.nes;
+        let before = nes.self_check.as_ref().map(|_| self_check::before(nes));
+        Self::cpu_step::<SystemBus>(nes);
+        if let Some(before) = before {
+            self_check::check(nes, self.cartridge.as_ref(), before);
+        }
+        if !nes.ppu.frame_ready {
+            return false;
+        }
+        nes.ppu.frame_ready = false;
+        self.end_frame();
+        true
+    }
+
+    // Hand the frame the PPU just finished to the hooks and sinks
+    fn end_frame(&mut self) {
+        let nes = &mut self.nes;
+        nes::catch_up_ppu(nes);
+        self.frame_started = false;
+        nes.input.end_frame();
+        // frames end with no batch of audio pending, in snapshots too
+        nes.audio.flush();
+        if let Some(player) = &self.nsf {
+            let volume = player.volume(nes);
+            if volume < 1.0 {
+                for s in &mut nes.audio.buffer {
+                    *s = (*s as f32 * volume) as i16;
+                }
+            }
+        }
+        if let Some(meter) = &mut nes.meter {
+            meter.end_frame(nes.apu.settings());
+        }
+        if !nes.hooks.frame.is_empty() {
+            hook::on_frame(nes, nes.ppu.frame);
+        }
+        hook::emit(nes, Event::FrameDone(nes.ppu.frame));
+        if let Some(rewind) = &mut self.rewind {
+            if rewind.due(nes.ppu.frame) {
+                rewind.push(nes.ppu.frame, state::save_snapshot(nes, &self.rom_hash));
+            }
+        }
+        if nes.headless {
+            return;
+        }
+        #[cfg(feature = "dump")]
+        if let Some(dump) = &mut self.video_dump {
+            dump.frame(&nes.ppu.buffer, ppu::WIDTH, ppu::HEIGHT);
+        }
+
+        match &mut self.video_worker {
+            Some(worker) => {
+                worker.submit(&nes.ppu.buffer, ppu::WIDTH, ppu::HEIGHT, &mut self.pixels)
+            }
+            None => {
+                video::convert(&nes.ppu.buffer, &mut self.pixels);
+                if let Some(sink) = &mut self.video {
+                    sink.frame(&self.pixels, ppu::WIDTH, ppu::HEIGHT);
+                }
+            }
+        }
+        if let Some(sink) = &mut self.audio {
+            sink.samples(&nes.audio.buffer);
+        }
+        #[cfg(feature = "dump")]
+        if let Some(dump) = &mut self.av_dump {
+            dump.frame(&nes.ppu.buffer, &nes.audio.buffer);
+        }
+        self.frame_samples = nes.audio.buffer.len();
+        nes.audio.buffer.clear();
+    }
+
+    // Play the tune until the PPU, idle but still keeping time, starts VBlank,
+    // so frames last as long as a cartridge's. The next song starts once this
+    // one is over.
+    fn nsf_frame(&mut self) {
+        let player = match &mut self.nsf {
+            Some(player) if player.position().is_some() => player,
+            _ => return,
+        };
+        let nes = &mut self.nes;
+        let fifths = if nes.region == Region::Pal { 16 } else { 15 };
+        while !nes.ppu.frame_ready {
+            let dots = nes.ppu.dots_to(nes.region, ppu::vblank_line(nes.region), 1);
+            player.run::<SystemBus>(nes, (dots * 5 / fifths).max(1) as u128);
+        }
+        nes.ppu.frame_ready = false;
+        self.end_frame();
+
+        if let Some(player) = &mut self.nsf {
+            if player.finished(&self.nes) {
+                let next = player.position().map_or(0, |p| p + 1);
+                player.start::<SystemBus>(&mut self.nes, next);
+            }
+        }
+    }
+
+    // Record or replay the input of the frame about to start
+    fn movie_frame(&mut self) {
+        let reset = std::mem::take(&mut self.reset_pending);
+        match &mut self.movie {
+            Some(MovieState::Recording(movie)) => {
+                let pads = &self.nes.input.pads;
+                let c = &pads.controllers;
+                movie.push(MovieFrame {
+                    controllers: [c[0].state, c[1].state, c[2].state, c[3].state],
+                    four_score: pads.four_score,
+                    reset,
+                });
+            }
+            Some(MovieState::Playing(movie, i)) => {
+                let frame = match movie.frames().get(*i) {
+                    Some(&frame) => frame,
+                    None => return,
+                };
+                *i += 1;
+                let pads = &mut self.nes.input.pads;
+                for (c, &state) in pads.controllers.iter_mut().zip(&frame.controllers) {
+                    c.state = state;
+                }
+                pads.four_score = frame.four_score;
+                if frame.reset {
+                    self.reset_console();
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+fn no_cartridge() -> anyhow::Error {
+    state::StateError {
+        msg: "no cartridge loaded".to_string(),
+    }
+    .into()
+}
+
+fn ppu_position(scanline: u16, dot: u16) -> u32 {
+    scanline as u32 * 341 + dot as u32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_run_frame() {
+        let mut emu = Emu::new();
+        assert_eq!(emu.run_frame().cpu_cycles, 0);
+
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        // reset lands at (0, 0); VBlank starts at line 241, dot 1
+        let result = emu.run_frame();
+        assert_eq!(result.frame, 0);
+        assert!((27385..27400).contains(&result.cpu_cycles), "{:?}", result);
+
+        let mut cycles = 0;
+        for frame in 1..=10 {
+            let result = emu.run_frame();
+            assert_eq!(result.frame, frame);
+            cycles += result.cpu_cycles;
+        }
+        // 262 lines of 341 dots, less one dot on odd rendered frames; each frame
+        // ends with the instruction VBlank started during
+        assert!((297_790..297_820).contains(&cycles), "{}", cycles);
+    }
+
+    #[test]
+    fn test_frame_result() {
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        // NMIs come on once the menu is set up
+        let results: Vec<FrameResult> = (0..8).map(|_| emu.run_frame()).collect();
+        assert!(!results[0].nmi);
+        let result = results[7];
+        assert!(result.nmi && !result.irq, "{:?}", result);
+        // 48000 Hz at 60.1 frames a second
+        assert!((797..=800).contains(&result.audio_samples), "{:?}", result);
+
+        // turn the APU frame IRQ on and let it through, which the menu never does
+        for (i, &b) in [0xA9, 0x00, 0x8D, 0x17, 0x40, 0x58, 0x4C, 0x06, 0x03]
+            .iter()
+            .enumerate()
+        {
+            emu.poke(0x0300 + i as u16, b);
+        }
+        let r = emu.nes.cpu.registers();
+        emu.nes.cpu.set_registers(Registers { pc: 0x0300, ..r });
+        // it first fires after a whole frame counter sequence
+        emu.run_frame();
+        let result = emu.run_frame();
+        assert!(result.nmi && result.irq, "{:?}", result);
+    }
+
+    #[test]
+    fn test_lag_frames() {
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        // the menu reads the controllers in its NMI handler, once it's set up
+        let results: Vec<FrameResult> = (0..8).map(|_| emu.run_frame()).collect();
+        assert!(results[0].lag);
+        assert!(!results[7].lag);
+        let lag = results[7].lag_frames;
+        assert_eq!(lag, results.iter().filter(|s| s.lag).count() as u64);
+
+        // turn NMIs off and spin
+        for (i, &b) in [0xA9, 0x00, 0x8D, 0x00, 0x20, 0x4C, 0x05, 0x03]
+            .iter()
+            .enumerate()
+        {
+            emu.poke(0x0300 + i as u16, b);
+        }
+        let r = emu.nes.cpu.registers();
+        emu.nes.cpu.set_registers(Registers { pc: 0x0300, ..r });
+        // the NMI of the frame just done still comes
+        let lag = emu.run_frame().lag_frames;
+        let state = emu.save_state().unwrap();
+        for n in 1..=3 {
+            let result = emu.run_frame();
+            assert!(result.lag);
+            assert_eq!(result.lag_frames, lag + n);
+        }
+        emu.load_state(&state).unwrap();
+        assert_eq!(emu.run_frame().lag_frames, lag + 1);
+    }
+
+    #[test]
+    fn test_run_until_scanline() {
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+
+        emu.run_until_scanline(100, 200);
+        // within one instruction (at most 7 CPU cycles = 21 dots) past the target
+        let pos = ppu_position(emu.scanline(), emu.dot());
+        assert!((ppu_position(100, 200)..ppu_position(100, 222)).contains(&pos));
+
+        // wraps into the next frame
+        emu.run_until_scanline(50, 0);
+        assert_eq!(emu.scanline(), 50);
+        assert_eq!(emu.nes.ppu.frame, 1);
+
+        emu.run_to_vblank();
+        assert_eq!(emu.scanline(), 241);
+        assert!((1..=22).contains(&emu.dot()));
+        // the frame was delivered, so the next run_frame runs a full frame
+        assert_eq!(emu.run_frame().frame, 2);
+
+        // out of range
+        emu.run_until_scanline(262, 0);
+        emu.run_until_scanline(0, 341);
+        assert_eq!(emu.scanline(), 241);
+    }
+
+    #[test]
+    fn test_ram_init() {
+        let mut emu = Emu::with_config(Config {
+            ram_init: RamInit::Random(1234),
+            ..Default::default()
+        });
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        let ram = emu.ram().to_vec();
+        assert!(ram.iter().any(|&b| b != 0));
+
+        // power cycles get the same contents again
+        emu.poke(0x0300, !ram[0x300]);
+        emu.power_cycle().unwrap();
+        assert_eq!(emu.ram(), &ram[..]);
+    }
+
+    #[test]
+    fn test_pause() {
+        let mut emu = Emu::new();
+        assert_eq!(emu.step_instruction(), 0);
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        emu.pause();
+        assert_eq!(emu.run_frame().cpu_cycles, 0);
+        emu.run_until_scanline(100, 0);
+        assert_eq!(emu.nes.cpu_cycles, 7);
+
+        // SEI at the reset vector
+        assert_eq!(emu.step_instruction(), 2);
+        assert_eq!(emu.nes.cpu.pc, 0xC005);
+
+        emu.step_scanline();
+        assert_eq!(emu.scanline(), 1);
+        assert!(emu.dot() <= 21);
+        emu.run_until_scanline(261, 0);
+        emu.resume();
+        emu.run_until_scanline(261, 0);
+        emu.step_scanline();
+        assert_eq!(emu.scanline(), 0);
+
+        assert_eq!(emu.frame_advance().frame, 1);
+        assert!(emu.paused());
+        assert_eq!(emu.run_frame().cpu_cycles, 0);
+        emu.resume();
+        assert_eq!(emu.run_frame().frame, 2);
+    }
+
+    #[test]
+    fn test_call_stack() {
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        assert!(!emu.step_out());
+        let mut r = emu.nes.cpu.registers();
+        r.pc = 0xC000;
+        emu.nes.cpu.set_registers(r);
+        let next_jsr = |emu: &mut Emu| {
+            while emu.peek(emu.nes.cpu.pc) != 0x20 {
+                emu.step_instruction();
+            }
+            emu.nes.cpu.pc
+        };
+
+        let jsr = next_jsr(&mut emu);
+        emu.step_instruction();
+        let frames = emu.call_stack();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].kind, CallKind::Jsr);
+        assert_eq!(frames[0].from, jsr);
+        assert_eq!(frames[0].to, emu.nes.cpu.pc);
+        assert_eq!(frames[0].return_addr, jsr + 3);
+        assert!(emu.step_out());
+        assert_eq!(emu.nes.cpu.pc, jsr + 3);
+        assert!(emu.call_stack().is_empty());
+
+        let jsr = next_jsr(&mut emu);
+        assert!(emu.step_over());
+        assert_eq!(emu.nes.cpu.pc, jsr + 3);
+        assert!(emu.call_stack().is_empty());
+    }
+
+    #[test]
+    fn test_profiling() {
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        assert!(emu.profile().is_none());
+        emu.set_profiling(true);
+        let mut cycles = 0;
+        for _ in 0..3 {
+            cycles += emu.run_frame().cpu_cycles;
+        }
+
+        let p = emu.profile().unwrap();
+        assert!(p.instructions() > 0);
+        assert_eq!(
+            p.instruction_cycles() + p.interrupt_cycles() + p.dma_cycles(),
+            cycles
+        );
+        assert_eq!(p.cycles_in(0x8000..=0xFFFF), p.instruction_cycles());
+        emu.set_profiling(false);
+        assert!(emu.profile().is_none());
+    }
+
+    #[test]
+    fn test_audio_metering() {
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        emu.run_frame();
+        assert!(emu.channel_levels().is_empty());
+
+        emu.set_audio_metering(true);
+        emu.run_frame();
+        let channels: Vec<Channel> = emu.channel_levels().iter().map(|l| l.channel).collect();
+        assert_eq!(channels[0], Channel::Pulse1);
+        assert_eq!(channels[4], Channel::Dmc);
+        // the menu is silent
+        assert!(emu.channel_levels().iter().all(|l| l.level == 0.0));
+
+        emu.set_audio_metering(false);
+        assert!(emu.channel_levels().is_empty());
+    }
+
+    #[test]
+    fn test_ppu_event_log() {
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        emu.set_ppu_event_log(true);
+        for _ in 0..5 {
+            emu.run_frame();
+        }
+
+        let events = emu.ppu_events();
+        assert!(events
+            .iter()
+            .any(|e| matches!(e.kind, PpuEventKind::RegisterWrite { addr: 0x2006, .. })));
+        assert!(events.iter().any(|e| e.kind == PpuEventKind::Nmi));
+        let positions: Vec<(u16, u16)> = events.iter().map(|e| (e.scanline, e.dot)).collect();
+        let mut sorted = positions.clone();
+        sorted.sort_unstable();
+        assert_eq!(positions, sorted);
+
+        emu.set_ppu_event_log(false);
+        assert!(emu.ppu_events().is_empty());
+    }
+
+    #[test]
+    fn test_label() {
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        let mut symbols = Symbols::new();
+        symbols
+            .add_nl("$C004#reset#\n$C005#after_sei#\n", Some(0))
+            .unwrap();
+        symbols.add_nl("$0200/100#oam#\n", None).unwrap();
+        emu.set_symbols(symbols);
+
+        // the 16KB bank 0 shows at both $8000 and $C000
+        assert_eq!(emu.label(0xC004).as_deref(), Some("reset"));
+        assert_eq!(emu.label(0x8004).as_deref(), Some("reset"));
+        assert_eq!(emu.label(0x0A05).as_deref(), Some("oam+5"));
+        assert_eq!(emu.label(0xC006), None);
+    }
+
+    #[test]
+    fn test_headless() {
+        let mut normal = Emu::new();
+        normal.load_rom_path("roms/nestest.nes").unwrap();
+        let mut headless = Emu::new();
+        headless.load_rom_path("roms/nestest.nes").unwrap();
+        headless.set_headless(true);
+
+        for _ in 0..30 {
+            // the same but for the samples, which headless mode skips
+            let result = normal.run_frame();
+            assert_ne!(result.audio_samples, 0);
+            let expected = FrameResult {
+                audio_samples: 0,
+                ..result
+            };
+            assert_eq!(headless.run_frame(), expected);
+        }
+        assert_eq!(normal.nes.cpu_cycles, headless.nes.cpu_cycles);
+        assert_eq!(normal.nes.wram, headless.nes.wram);
+        assert!(headless.frame_buffer().is_empty());
+        assert!(headless.nes.audio.buffer.is_empty());
+
+        // back to normal output
+        headless.set_headless(false);
+        headless.run_frame();
+        assert_eq!(headless.frame_buffer().len(), 256 * 240);
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut emu = Emu::new();
+        assert!(emu.save_state().is_err());
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        for _ in 0..20 {
+            emu.run_frame();
+        }
+        let state = emu.save_state().unwrap();
+        let frames: Vec<_> = (0..10)
+            .map(|_| {
+                emu.run_frame();
+                emu.frame_buffer().to_vec()
+            })
+            .collect();
+        let end = emu.save_state().unwrap();
+
+        // replaying from the snapshot gives the same frames and end state
+        emu.load_state(&state).unwrap();
+        for (i, frame) in frames.iter().enumerate() {
+            emu.run_frame();
+            assert_eq!(emu.frame_buffer(), &frame[..], "frame {}", i);
+        }
+        assert_eq!(emu.save_state().unwrap(), end);
+
+        // a rejected state leaves the machine untouched
+        let mut corrupted = state.clone();
+        corrupted.truncate(state.len() - 10);
+        assert!(emu.load_state(&corrupted).is_err());
+        assert_eq!(emu.save_state().unwrap(), end);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        assert!(emu.rewind(1).is_err());
+
+        emu.set_rewind(Some(RewindConfig {
+            interval: 2,
+            capacity: 10,
+            compress: true,
+        }));
+        let mut states = vec![];
+        for _ in 0..30 {
+            let frame = emu.run_frame().frame;
+            states.push((frame, emu.save_state().unwrap()));
+        }
+        assert_eq!(emu.nes.ppu.frame, 29);
+
+        // back to the snapshot at frame 24
+        assert_eq!(emu.rewind(5).unwrap(), 5);
+        assert_eq!(emu.save_state().unwrap(), states[24].1);
+        // runs on from there just the same
+        emu.run_frame();
+        assert_eq!(emu.save_state().unwrap(), states[25].1);
+
+        // only the 10 snapshots from frame 10 on were kept
+        assert_eq!(emu.rewind(100).unwrap(), 15);
+        assert_eq!(emu.save_state().unwrap(), states[10].1);
+    }
+
+    #[test]
+    fn test_movie() {
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        for _ in 0..5 {
+            emu.run_frame();
+        }
+        emu.start_recording().unwrap();
+        assert!(emu.recording());
+        for frame in 0..60 {
+            emu.set_button(0, Button::Down, frame % 8 < 2);
+            emu.set_button(0, Button::Start, frame == 40);
+            if frame == 20 {
+                emu.reset();
+            }
+            emu.run_frame();
+        }
+        let state = emu.save_state().unwrap();
+        let movie = emu.stop_movie().unwrap();
+        assert_eq!(movie.len(), 60);
+        assert!(movie.frames()[20].reset);
+        assert_eq!(
+            movie.frames()[1].controllers,
+            [Button::Down.mask(), 0, 0, 0]
+        );
+
+        let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        emu.set_controller_state(0, 0xFF);
+        emu.play_movie(movie.clone()).unwrap();
+        for _ in 0..60 {
+            assert!(emu.playing());
+            emu.run_frame();
+        }
+        assert!(!emu.playing());
+        assert_eq!(emu.save_state().unwrap(), state);
+
+        let mut other = Emu::new();
+        assert!(other.play_movie(movie.clone()).is_err());
+        other.load_rom_path("roms/nestest.nes").unwrap();
+        assert!(other.play_movie(movie).is_ok());
+    }
+
+    #[test]
+    fn test_movie_four_score() {
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        emu.set_four_score(true);
+        emu.start_recording().unwrap();
+        for frame in 0..30 {
+            emu.set_button(2, Button::A, frame % 4 < 2);
+            emu.set_button(3, Button::Left, frame >= 10);
+            emu.run_frame();
+        }
+        let state = emu.save_state().unwrap();
+        let movie = emu.stop_movie().unwrap();
+        assert!(movie.frames()[0].four_score);
+        assert_eq!(
+            movie.frames()[12].controllers,
+            [0, 0, Button::A.mask(), Button::Left.mask()]
+        );
+
+        let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        emu.set_four_score(false);
+        emu.set_controller_state(2, 0xFF);
+        emu.set_controller_state(3, 0xFF);
+        emu.play_movie(movie).unwrap();
+        for _ in 0..30 {
+            emu.run_frame();
+        }
+        assert!(emu.four_score());
+        assert_eq!(emu.controller_state(3), Button::Left.mask());
+        assert_eq!(emu.save_state().unwrap(), state);
+    }
+
+    #[test]
+    fn test_overclock() {
+        // 30 lines of 341 dots add 3410 cycles to the 29780.67 of a frame
+        let overclocked = 33180..33200;
+        let normal = 29770..29790;
+        for movies in [false, true] {
+            let mut emu = Emu::with_config(Config {
+                overclock_lines: 30,
+                overclock_movies: movies,
+                ..Default::default()
+            });
+            emu.load_rom_path("roms/nestest.nes").unwrap();
+            emu.run_frame();
+            let cycles = emu.run_frame().cpu_cycles;
+            assert!(overclocked.contains(&cycles), "{} {}", movies, cycles);
+
+            emu.start_recording().unwrap();
+            emu.run_frame();
+            let cycles = emu.run_frame().cpu_cycles;
+            let expected = if movies { &overclocked } else { &normal };
+            assert!(expected.contains(&cycles), "{} {}", movies, cycles);
+
+            emu.stop_movie();
+            emu.run_frame();
+            let cycles = emu.run_frame().cpu_cycles;
+            assert!(overclocked.contains(&cycles), "{} {}", movies, cycles);
+        }
+    }
+
+    #[test]
+    fn test_nsf_playlist() {
+        use std::sync::{Arc, Mutex};
+
+        struct Shared(Arc<Mutex<SampleBuffer>>);
+        impl AudioSink for Shared {
+            fn samples(&mut self, samples: &[i16]) {
+                self.0.lock().unwrap().samples(samples);
+            }
+        }
+
+        #[rustfmt::skip]
+        let tune = [
+            // INIT: a held pulse tone at full volume
+            0xA9, 0xBF, 0x8D, 0x00, 0x40, 0xA9, 0xFF, 0x8D, 0x02, 0x40,
+            0xA9, 0x08, 0x8D, 0x03, 0x40, 0x60,
+            // PLAY
+            0x60,
+        ];
+        let chunk = |id: &[u8], data: &[u8]| {
+            let mut chunk = (data.len() as u32).to_le_bytes().to_vec();
+            chunk.extend_from_slice(id);
+            chunk.extend_from_slice(data);
+            chunk
+        };
+        let ms = |times: [i32; 2]| [times[0].to_le_bytes(), times[1].to_le_bytes()].concat();
+        // NSF2: 2 songs, INIT at $8000 and PLAY at $8010, with NSFe metadata
+        let mut nsf = vec![0; 0x80];
+        nsf[..5].copy_from_slice(b"NESM\x1A");
+        nsf[0x05] = 2;
+        nsf[0x06] = 2;
+        nsf[0x07] = 1;
+        nsf[0x08..0x0A].copy_from_slice(&0x8000u16.to_le_bytes());
+        nsf[0x0A..0x0C].copy_from_slice(&0x8000u16.to_le_bytes());
+        nsf[0x0C..0x0E].copy_from_slice(&0x8010u16.to_le_bytes());
+        nsf[0x6E..0x70].copy_from_slice(&16_639u16.to_le_bytes());
+        nsf[0x7C] = 0x80;
+        nsf[0x7D] = tune.len() as u8;
+        nsf.extend_from_slice(&tune);
+        nsf.extend(chunk(b"time", &ms([200, 50])));
+        nsf.extend(chunk(b"fade", &ms([200, 0])));
+        nsf.extend(chunk(b"plst", &[1, 0]));
+
+        let mut emu = Emu::new();
+        let samples = Arc::new(Mutex::new(SampleBuffer::new()));
+        emu.set_audio_sink(Shared(samples.clone()));
+        emu.load_nsf(NsfFile::from_bytes(&nsf).unwrap());
+        assert_eq!(emu.nsf_playlist(), [1, 0]);
+        assert_eq!(emu.nsf_position(), Some(0));
+        assert_eq!(emu.nsf_song(), Some(1));
+
+        // 50ms of the second song, cut off partway through the fourth frame, then
+        // the first one with its fade
+        let mut peaks = Vec::new();
+        let mut positions = Vec::new();
+        for _ in 0..30 {
+            emu.run_frame();
+            let frame = samples.lock().unwrap().take();
+            peaks.push(frame.iter().map(|s| s.unsigned_abs()).max().unwrap_or(0));
+            positions.push(emu.nsf_position());
+        }
+        assert_eq!(positions[2], Some(0));
+        assert_eq!(positions[3], Some(1));
+        assert_eq!(peaks[3], 0);
+        assert_eq!(positions[27], Some(1));
+        assert_eq!(positions[28], None);
+        // full volume for 200ms, then fading
+        assert!(peaks[10] > 1000, "{:?}", peaks);
+        assert!(peaks[22] < peaks[10] / 2, "{:?}", peaks);
+        assert_eq!(peaks[29], 0);
+        assert_eq!(emu.run_frame().cpu_cycles, 0);
+        assert_eq!(emu.nsf_elapsed(), None);
+
+        emu.previous_track();
+        assert_eq!(emu.nsf_position(), Some(1));
+        assert!(emu.nsf_elapsed().unwrap() < 10);
+        emu.previous_track();
+        emu.previous_track();
+        assert_eq!(emu.nsf_position(), Some(0));
+        assert!(emu.next_track());
+        assert!(!emu.next_track());
+        assert_eq!(emu.nsf_position(), None);
+        assert_eq!(emu.nsf_song(), None);
+        emu.select_track(1);
+        assert_eq!(emu.nsf_position(), Some(1));
+        assert_eq!(emu.nsf_song(), Some(0));
+
+        // a cartridge takes over
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        assert!(emu.nsf_file().is_none());
+        assert!(emu.nsf_playlist().is_empty());
+    }
+
+    #[test]
+    fn test_debug_port() {
+        let mut emu = Emu::with_config(Config {
+            debug_port: Some(0x401A),
+            ..Default::default()
+        });
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        // print "Hi", then spin
+        for (i, &b) in [
+            0xA9, b'H', 0x8D, 0x1A, 0x40, 0xA9, b'i', 0x8D, 0x1A, 0x40, 0x4C, 0x0A, 0x03,
+        ]
+        .iter()
+        .enumerate()
+        {
+            emu.poke(0x0300 + i as u16, b);
+        }
+        let r = emu.nes.cpu.registers();
+        emu.nes.cpu.set_registers(Registers { pc: 0x0300, ..r });
+        emu.run_frame();
+        assert_eq!(emu.take_debug_output(), "Hi");
+        emu.run_frame();
+        assert_eq!(emu.take_debug_output(), "");
+
+        // off by default
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        emu.nes.cpu.set_registers(Registers { pc: 0x0300, ..r });
+        for (i, &b) in [0xA9, b'H', 0x8D, 0x1A, 0x40, 0x4C, 0x05, 0x03]
+            .iter()
+            .enumerate()
+        {
+            emu.poke(0x0300 + i as u16, b);
+        }
+        emu.run_frame();
+        assert_eq!(emu.take_debug_output(), "");
+    }
+
+    #[test]
+    fn test_countdown() {
+        // NES-EVENT with 256KB PRG-ROM, starting the timer through the serial
+        // port, then spinning
+        let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 0x10, 0x00, 0x90, 0x60];
+        rom.resize(16, 0);
+        let mut prg = vec![0xEA; 0x40000];
+        let mut program = vec![0xA9, 0x00];
+        for _ in 0..5 {
+            program.extend_from_slice(&[0x8D, 0x00, 0xA0]);
+        }
+        program.extend_from_slice(&[0x4C, 0x11, 0x80]);
+        prg[..program.len()].copy_from_slice(&program);
+        // the first 32KB is mapped at power-on
+        prg[0x7FFC..0x7FFE].copy_from_slice(&[0x00, 0x80]);
+        rom.extend_from_slice(&prg);
+
+        #[rustfmt::skip]
+        let cases = [
+            ("factory setting", None,     374_957),
+            ("all off",         Some(0),  299_965),
+            ("all on",          Some(15), 581_183),
+        ];
+
+        for (name, dip_switches, full) in cases {
+            let mut emu = Emu::with_config(Config {
+                dip_switches,
+                ..Default::default()
+            });
+            emu.load_rom(&rom).unwrap();
+            assert_eq!(emu.countdown_ms(), Some(full), "{}", name);
+            emu.run_frame();
+            let left = emu.countdown_ms().unwrap();
+            assert!((full - 17..full).contains(&left), "{}: {}", name, left);
+        }
+
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        assert_eq!(emu.countdown_ms(), None);
+    }
+
+    #[test]
+    fn test_cheats() {
+        use cpu::CpuBus;
+
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        let reset = SystemBus::read(&mut emu.nes, 0xFFFC);
+        assert!(emu.add_cheat("ZOYNKB").is_err());
+        // $FFFC:12
+        emu.add_cheat("zoynky").unwrap();
+        assert_eq!(emu.cheats()[0].addr(), 0xFFFC);
+        assert_eq!(SystemBus::read(&mut emu.nes, 0xFFFC), 0x12);
+
+        assert!(emu.set_cheat_enabled("ZOYNKY", false));
+        assert_eq!(SystemBus::read(&mut emu.nes, 0xFFFC), reset);
+        emu.add_cheat("ZOYNKY").unwrap();
+        assert_eq!(emu.cheats().len(), 1);
+        assert!(emu.cheats()[0].enabled());
+
+        // kept by a power cycle, dropped with the game
+        emu.power_cycle().unwrap();
+        assert_eq!(emu.cheats().len(), 1);
+        assert!(emu.remove_cheat("zoynky"));
+        assert!(!emu.remove_cheat("zoynky"));
+        emu.add_cheat("ZOYNKY").unwrap();
+        emu.load_rom(&nrom_with_battery()).unwrap();
+        assert!(emu.cheats().is_empty());
+    }
+
+    #[test]
+    fn test_events() {
+        use std::sync::{Arc, Mutex};
+
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let e = events.clone();
+        let id = emu.subscribe(move |event| e.lock().unwrap().push(event));
+        // nestest leaves the APU frame IRQ on, so enabling interrupts lets it in
+        emu.add_frame_hook(|m, frame| {
+            if frame == 0 {
+                let r = m.registers();
+                m.set_registers(Registers {
+                    p: r.p & !0x04,
+                    ..r
+                });
+            }
+        });
+
+        for _ in 0..3 {
+            emu.run_frame();
+        }
+        let state = emu.save_state().unwrap();
+        emu.load_state(&state).unwrap();
+        assert!(emu.remove_hook(id));
+        emu.run_frame();
+
+        let events = events.lock().unwrap();
+        assert_eq!(events[0], Event::FrameDone(0));
+        assert!(events.contains(&Event::FrameDone(1)));
+        assert!(events.contains(&Event::Irq));
+        assert!(events.contains(&Event::FrameDone(2)));
+        assert_eq!(events.last(), Some(&Event::StateLoaded));
+    }
+
+    #[test]
+    fn test_self_check() {
+        use std::sync::{Arc, Mutex};
+
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        emu.set_self_check(true);
+        assert!(emu.self_check());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let e = events.clone();
+        emu.subscribe(move |event| {
+            if let Event::Violation(v) = event {
+                e.lock().unwrap().push(v);
+            }
+        });
+        for _ in 0..10 {
+            emu.run_frame();
+        }
+        assert!(events.lock().unwrap().is_empty());
+
+        // PHA with the stack full
+        emu.poke(0x0300, 0x48);
+        let r = emu.nes.cpu.registers();
+        emu.nes.cpu.set_registers(Registers {
+            pc: 0x0300,
+            s: 0x00,
+            ..r
+        });
+        emu.step_instruction();
+        // an NMI may come first, pushing further
+        let events = events.lock().unwrap();
+        assert!(
+            matches!(events[..], [Violation::StackWrap { pc: 0x0300, .. }]),
+            "{:?}",
+            events
+        );
+
+        emu.set_self_check(false);
+        assert!(!emu.self_check());
+    }
+
+    #[test]
+    fn test_ram_search() {
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        emu.run_frame();
+
+        let mut search = RamSearch::new(emu.ram());
+        for v in 1..4 {
+            emu.poke(0x0123, v);
+            emu.poke(0x0124, 0x80 - v);
+            emu.run_frame();
+            search.filter(emu.ram(), Predicate::ChangedBy(1));
+        }
+        assert_eq!(search.candidates(), [0x0123]);
+
+        let mut watches = WatchList::new();
+        watches.add(Watch {
+            label: "counter".to_string(),
+            addr: 0x0123,
+            word: true,
+        });
+        assert_eq!(watches.values(&emu), [("counter", 0x7D03)]);
+        assert!(watches.remove("counter"));
+        assert!(watches.watches().is_empty());
+    }
+
+    #[test]
+    fn test_peek_poke() {
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        emu.run_frame();
+        let cycles = emu.nes.cpu_cycles;
+
+        // peeking the status doesn't clear VBlank or the write toggle
+        let status = emu.peek(0x2002);
+        assert_eq!(emu.peek(0x2002), status);
+        // SEI at the reset vector
+        assert_eq!(emu.peek(0xC004), 0x78);
+
+        emu.poke(0x0010, 0xAB);
+        assert_eq!(emu.peek(0x0810), 0xAB);
+        emu.poke(0x6000, 0xCD);
+        assert_eq!(emu.peek(0x6000), 0xCD);
+        emu.poke(0xC004, 0x00);
+        assert_eq!(emu.peek(0xC004), 0x78);
+        assert_eq!(emu.nes.cpu_cycles, cycles);
+    }
+
+    #[test]
+    fn test_hooks() {
+        use std::sync::{Arc, Mutex};
+
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        let l = log.clone();
+        emu.add_exec_hook(0xC004..=0xC004, move |m, pc| {
+            l.lock().unwrap().push(("exec", pc, m.registers().pc as u8));
+        });
+        // nestest's reset handler starts by reading $2002
+        let l = log.clone();
+        let read = emu.add_read_hook(0x2002..=0x2002, move |_, addr, v| {
+            l.lock().unwrap().push(("read", addr, v));
+            v
+        });
+        // keep a RAM byte from being written
+        let write = emu.add_write_hook(0x0000..=0x07FF, |m, addr, v| {
+            m.ram_mut()[0x7F0] = 0x55;
+            if addr == 0x7F0 {
+                0x55
+            } else {
+                v
+            }
+        });
+        let l = log.clone();
+        emu.add_frame_hook(move |m, frame| {
+            l.lock()
+                .unwrap()
+                .push(("frame", frame as u16, m.frame() as u8));
+            m.set_controller_state(1, 0xA5);
+        });
+        emu.run_frame();
+        emu.run_frame();
+
+        let log = log.lock().unwrap();
+        assert_eq!(log.iter().filter(|e| e.0 == "exec").count(), 1);
+        assert!(log.iter().any(|e| e.0 == "read"));
+        assert_eq!(log.last(), Some(&("frame", 1, 1)));
+        assert_eq!(emu.controller_state(1), 0xA5);
+
+        assert!(emu.remove_hook(read));
+        assert!(emu.remove_hook(write));
+        assert!(!emu.remove_hook(write));
+    }
+
+    // NROM with battery-backed PRG-RAM, looping at $8000
+    fn nrom_with_battery() -> Vec<u8> {
+        #[rustfmt::skip]
+        let mut rom = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0b0000_0010, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let mut prg = vec![0xEA; 0x4000];
+        // JMP $8000
+        prg[..3].copy_from_slice(&[0x4C, 0x00, 0x80]);
+        // reset vector
+        prg[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0x80]);
+        rom.extend_from_slice(&prg);
+        rom.resize(rom.len() + 0x2000, 0);
+        rom
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut emu = Emu::new();
+        emu.load_rom(&nrom_with_battery()).unwrap();
+        assert_eq!(emu.nes.cpu.pc, 0x8000);
+        assert_eq!(emu.nes.cpu.s, 0xFD);
+        emu.run_frame();
+
+        emu.nes.wram[0x10] = 0xAA;
+        emu.nes.mapper.write(0x6000, 0xBB);
+        emu.nes.apu.write_register(0x4015, 0x0F);
+        emu.nes.apu.write_register(0x4003, 0x08);
+        assert_eq!(emu.nes.apu.read_status() & 0x01, 0x01);
+
+        emu.reset();
+        assert_eq!(emu.nes.cpu.pc, 0x8000);
+        assert_eq!(emu.nes.cpu.s, 0xFA);
+        assert_eq!(emu.nes.wram[0x10], 0xAA);
+        assert_eq!(emu.nes.apu.read_status() & 0x01, 0x00);
+
+        emu.power_cycle().unwrap();
+        assert_eq!(emu.nes.cpu.s, 0xFD);
+        assert_eq!(emu.nes.wram[0x10], 0x00);
+        // battery-backed RAM is kept
+        assert_eq!(emu.nes.mapper.read(0x6000), Some(0xBB));
+    }
+
+    #[test]
+    fn test_set_button() {
+        use cpu::CpuBus;
+
+        let mut emu = Emu::new();
+        emu.set_button(0, Button::Start, true);
+        emu.set_button(0, Button::A, true);
+        emu.set_button(0, Button::A, false);
+        emu.set_button(1, Button::Left, true);
+        // no such port
+        emu.set_button(4, Button::B, true);
+        assert_eq!(emu.controller_state(0), 0b0000_1000);
+        assert_eq!(emu.controller_state(1), 0b0100_0000);
+
+        emu.set_controller_state(1, 0xFF);
+        SystemBus::write(&mut emu.nes, 0x4016, 1);
+        SystemBus::write(&mut emu.nes, 0x4016, 0);
+        let bits: Vec<u8> = (0..8)
+            .map(|_| SystemBus::read(&mut emu.nes, 0x4016) & 1)
+            .collect();
+        assert_eq!(bits, [0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(SystemBus::read(&mut emu.nes, 0x4017) & 1, 1);
+    }
+
+    #[test]
+    fn test_expansion_device() {
+        use cpu::CpuBus;
+
+        // a keyboard-like device holding all its lines high
+        struct Keys;
+        impl InputDevice for Keys {
+            fn strobe(&mut self, _value: u8) {}
+            fn read(&mut self, _port: usize) -> u8 {
+                0xFF
+            }
+            fn peek(&self, _port: usize) -> u8 {
+                0xFF
+            }
+        }
+
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        emu.set_controller_state(0, Button::A.mask());
+        emu.connect_expansion_device(Keys);
+        SystemBus::write(&mut emu.nes, 0x4016, 1);
+        SystemBus::write(&mut emu.nes, 0x4016, 0);
+        // D1 of $4016 and D1-D4 of $4017, next to the controllers
+        assert_eq!(SystemBus::read(&mut emu.nes, 0x4016) & 0x1F, 0x03);
+        assert_eq!(SystemBus::read(&mut emu.nes, 0x4017) & 0x1F, 0x1E);
+        assert_eq!(emu.peek(0x4017) & 0x1F, 0x1E);
+
+        emu.connect_expansion_pads(true);
+        emu.set_controller_state(3, Button::A.mask());
+        SystemBus::write(&mut emu.nes, 0x4016, 1);
+        SystemBus::write(&mut emu.nes, 0x4016, 0);
+        assert_eq!(SystemBus::read(&mut emu.nes, 0x4017) & 0x1F, 0x02);
+
+        emu.disconnect_expansion();
+        assert_eq!(SystemBus::read(&mut emu.nes, 0x4017) & 0x1F, 0);
+    }
+
+    #[test]
+    fn test_two_controllers() {
+        use cpu::CpuBus;
+
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        emu.set_controller_state(0, Button::A.mask() | Button::Select.mask());
+        emu.set_controller_state(1, Button::B.mask() | Button::Start.mask());
+        SystemBus::write(&mut emu.nes, 0x4016, 1);
+        SystemBus::write(&mut emu.nes, 0x4016, 0);
+
+        // each port shifts on its own reads only
+        let mut read = |addr| SystemBus::read(&mut emu.nes, addr) & 1;
+        assert_eq!([read(0x4016), read(0x4016)], [1, 0]);
+        assert_eq!([read(0x4017), read(0x4017)], [0, 1]);
+
+        // the second port's shift position is saved too
+        let state = emu.save_state().unwrap();
+        let mut read = |addr| SystemBus::read(&mut emu.nes, addr) & 1;
+        assert_eq!([read(0x4016), read(0x4017), read(0x4017)], [1, 0, 1]);
+        emu.load_state(&state).unwrap();
+        let mut read = |addr| SystemBus::read(&mut emu.nes, addr) & 1;
+        assert_eq!([read(0x4017), read(0x4017), read(0x4016)], [0, 1, 1]);
+    }
+
+    #[test]
+    fn test_poll_hook() {
+        use cpu::CpuBus;
+        use std::sync::atomic::{AtomicU8, Ordering};
+        use std::sync::Arc;
+
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        let polls = Arc::new(AtomicU8::new(0));
+        let p = polls.clone();
+        let id = emu.add_poll_hook(move |m| {
+            let n = p.fetch_add(1, Ordering::SeqCst) + 1;
+            m.set_controller_state(0, n);
+        });
+
+        // only the rising edge of the strobe polls
+        let read = |emu: &mut Emu| {
+            SystemBus::write(&mut emu.nes, 0x4016, 1);
+            SystemBus::write(&mut emu.nes, 0x4016, 1);
+            SystemBus::write(&mut emu.nes, 0x4016, 0);
+            (0..8).fold(0, |s, i| {
+                s | (SystemBus::read(&mut emu.nes, 0x4016) & 1) << i
+            })
+        };
+        assert_eq!(read(&mut emu), 1);
+        assert_eq!(read(&mut emu), 2);
+        assert_eq!(polls.load(Ordering::SeqCst), 2);
+
+        assert!(emu.remove_hook(id));
+        assert_eq!(read(&mut emu), 2);
+        assert_eq!(polls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_input_device() {
+        use cpu::CpuBus;
+
+        // reports how many times it was strobed, in bits 0-4
+        struct Counter(u8);
+        impl InputDevice for Counter {
+            fn strobe(&mut self, value: u8) {
+                self.0 += value & 1;
+            }
+            fn read(&mut self, _port: usize) -> u8 {
+                self.0 | 0xE0
+            }
+            fn peek(&self, _port: usize) -> u8 {
+                self.0
+            }
+        }
+
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        emu.set_controller_state(1, Button::A.mask());
+        emu.connect_device(1, Counter(0));
+        for _ in 0..3 {
+            SystemBus::write(&mut emu.nes, 0x4016, 1);
+            SystemBus::write(&mut emu.nes, 0x4016, 0);
+        }
+        // the upper bits stay open bus
+        assert_eq!(SystemBus::read(&mut emu.nes, 0x4017) & 0x1F, 3);
+        assert_eq!(emu.peek(0x4017) & 0x1F, 3);
+        assert_eq!(SystemBus::read(&mut emu.nes, 0x4016) & 1, 0);
+
+        emu.disconnect_device(1);
+        SystemBus::write(&mut emu.nes, 0x4016, 1);
+        SystemBus::write(&mut emu.nes, 0x4016, 0);
+        assert_eq!(SystemBus::read(&mut emu.nes, 0x4017) & 1, 1);
+    }
+
+    #[test]
+    fn test_threads() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Emu>();
+
+        let rom = std::fs::read("roms/nestest.nes").unwrap();
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let rom = rom.clone();
+                std::thread::spawn(move || {
+                    let mut emu = Emu::new();
+                    emu.load_rom(&rom).unwrap();
+                    emu.set_controller_state(0, Button::Start.mask());
+                    for _ in 0..10 + i {
+                        emu.run_frame();
+                    }
+                    emu.save_state().unwrap()
+                })
+            })
+            .collect();
+        let states: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        // instances don't affect each other
+        let mut emu = Emu::new();
+        emu.load_rom(&rom).unwrap();
+        emu.set_controller_state(0, Button::Start.mask());
+        for (i, state) in states.iter().enumerate() {
+            while emu.nes.ppu.frame < 9 + i as u64 {
+                emu.run_frame();
+            }
+            assert_eq!(&emu.save_state().unwrap(), state, "{}", i);
+        }
+    }
+
+    #[test]
+    fn test_video_sink() {
+        use std::sync::{Arc, Mutex};
+
+        struct Shared(Arc<Mutex<FrameBuffer>>);
+        impl VideoSink for Shared {
+            fn frame(&mut self, pixels: &[u32], width: usize, height: usize) {
+                self.0.lock().unwrap().frame(pixels, width, height);
+            }
+        }
+
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        let frames = Arc::new(Mutex::new(FrameBuffer::new()));
+        emu.set_video_sink(Shared(frames.clone()));
+        assert!(emu.frame_buffer().is_empty());
+
+        for _ in 0..3 {
+            emu.run_frame();
+        }
+        let frames = frames.lock().unwrap();
+        assert_eq!(frames.frames(), 3);
+        assert_eq!((frames.width(), frames.height()), (256, 240));
+        assert_eq!(frames.pixels(), emu.frame_buffer());
+        assert!(frames.pixels().iter().all(|p| p >> 24 == 0xFF));
+    }
+
+    #[test]
+    fn test_threaded_video() {
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Clone, Default)]
+        struct Shared(Arc<Mutex<Vec<Vec<u32>>>>);
+        impl VideoSink for Shared {
+            fn frame(&mut self, pixels: &[u32], _width: usize, _height: usize) {
+                self.0.lock().unwrap().push(pixels.to_vec());
+            }
+        }
+
+        let run = |threaded| {
+            let mut emu = Emu::new();
+            emu.load_rom_path("roms/nestest.nes").unwrap();
+            let frames = Shared::default();
+            emu.set_threaded_video(threaded);
+            emu.set_video_sink(frames.clone());
+            for _ in 0..8 {
+                emu.run_frame();
+            }
+            emu.set_threaded_video(false);
+            assert!(!emu.threaded_video());
+            let last = emu.frame_buffer().to_vec();
+            let frames = frames.0.lock().unwrap().clone();
+            (frames, last)
+        };
+        let (frames, last) = run(true);
+        assert_eq!(frames.len(), 8);
+        assert_eq!((frames, last), run(false));
+    }
+
+    #[cfg(feature = "dump")]
+    #[test]
+    fn test_video_dump() {
+        let path = std::env::temp_dir().join(format!("korones-{}.y4m", std::process::id()));
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        emu.start_video_dump(&path).unwrap();
+        emu.run_frame();
+        emu.set_headless(true);
+        emu.run_frame();
+        emu.set_headless(false);
+        emu.run_frame();
+        emu.stop_video_dump().unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let header = b"YUV4MPEG2 W256 H240 F60098:1000 Ip A1:1 C444\n";
+        assert!(data.starts_with(header));
+        let frame = b"FRAME\n".len() + 3 * ppu::WIDTH * ppu::HEIGHT;
+        assert_eq!(data.len(), header.len() + 2 * frame);
+    }
+
+    #[cfg(feature = "dump")]
+    #[test]
+    fn test_dump_movie() {
+        let dir = std::env::temp_dir().join(format!("korones-{}-av", std::process::id()));
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        emu.start_recording().unwrap();
+        for _ in 0..3 {
+            emu.run_frame();
+        }
+        let movie = emu.stop_movie().unwrap();
+        emu.set_headless(true);
+        assert_eq!(emu.dump_movie(movie, &dir).unwrap(), 3);
+        assert!(emu.headless());
+
+        let frames = std::fs::read(dir.join(dump::FRAMES_FILE)).unwrap();
+        let audio = std::fs::read(dir.join(dump::AUDIO_FILE)).unwrap();
+        let manifest = std::fs::read_to_string(dir.join(dump::MANIFEST_FILE)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(frames.len(), 3 * 3 * ppu::WIDTH * ppu::HEIGHT);
+        // 1/60 of a second of 48kHz mono is about 800 samples a frame
+        let samples = (audio.len() - 44) / 2;
+        assert!((2300..2500).contains(&samples), "{}", samples);
+        assert!(manifest.contains("frames=3\n"));
+        assert!(manifest.contains(&format!("samples={}\n", samples)));
+    }
+
+    #[test]
+    fn test_audio_sink() {
+        use std::sync::{Arc, Mutex};
+
+        struct Shared(Arc<Mutex<SampleBuffer>>);
+        impl AudioSink for Shared {
+            fn samples(&mut self, samples: &[i16]) {
+                self.0.lock().unwrap().samples(samples);
+            }
+        }
+
+        let mut emu = Emu::with_config(Config {
+            sample_rate: 44100,
+            channels: 2,
+            ..Default::default()
+        });
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        let samples = Arc::new(Mutex::new(SampleBuffer::new()));
+        emu.set_audio_sink(Shared(samples.clone()));
+
+        let mut cycles = 0;
+        for _ in 0..60 {
+            cycles += emu.run_frame().cpu_cycles;
+        }
+        let expected = cycles * 44100 / 1_789_773 * 2;
+        let n = samples.lock().unwrap().take().len() as u64;
+        assert!(expected - 2 <= n && n <= expected + 2, "{} {}", n, expected);
+    }
 }
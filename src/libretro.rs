@@ -0,0 +1,434 @@
+// libretro core API, so the crate built as a cdylib loads in RetroArch and other
+// libretro frontends. The frontend calls everything from one thread and drives
+// a single instance, kept in thread-local storage.
+#![allow(clippy::missing_safety_doc)]
+
+use std::cell::{Cell, RefCell};
+use std::os::raw::{c_char, c_uint, c_void};
+use std::ptr;
+use std::slice;
+
+use crate::mapper::Mapper;
+use crate::{AudioSink, Button, Config, Emu, Region, VideoSink};
+
+const API_VERSION: c_uint = 1;
+
+const ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+const PIXEL_FORMAT_XRGB8888: c_uint = 1;
+
+const DEVICE_JOYPAD: c_uint = 1;
+// RETRO_DEVICE_ID_JOYPAD_* for each `Button`, in `Button` order
+const JOYPAD_IDS: [(c_uint, Button); 8] = [
+    (8, Button::A),
+    (0, Button::B),
+    (2, Button::Select),
+    (3, Button::Start),
+    (4, Button::Up),
+    (5, Button::Down),
+    (6, Button::Left),
+    (7, Button::Right),
+];
+
+const MEMORY_SAVE_RAM: c_uint = 0;
+const MEMORY_SYSTEM_RAM: c_uint = 2;
+
+const REGION_NTSC: c_uint = 0;
+const REGION_PAL: c_uint = 1;
+
+const SAMPLE_RATE: u32 = 48000;
+
+#[repr(C)]
+pub struct SystemInfo {
+    library_name: *const c_char,
+    library_version: *const c_char,
+    valid_extensions: *const c_char,
+    need_fullpath: bool,
+    block_extract: bool,
+}
+
+#[repr(C)]
+pub struct GameGeometry {
+    base_width: c_uint,
+    base_height: c_uint,
+    max_width: c_uint,
+    max_height: c_uint,
+    aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct SystemTiming {
+    fps: f64,
+    sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct SystemAvInfo {
+    geometry: GameGeometry,
+    timing: SystemTiming,
+}
+
+#[repr(C)]
+pub struct GameInfo {
+    path: *const c_char,
+    data: *const c_void,
+    size: usize,
+    meta: *const c_char,
+}
+
+type EnvironmentFn = unsafe extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+type VideoRefreshFn =
+    unsafe extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+type AudioSampleFn = unsafe extern "C" fn(left: i16, right: i16);
+type AudioSampleBatchFn = unsafe extern "C" fn(data: *const i16, frames: usize) -> usize;
+type InputPollFn = unsafe extern "C" fn();
+type InputStateFn =
+    unsafe extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+
+thread_local! {
+    static EMU: RefCell<Option<Emu>> = const { RefCell::new(None) };
+    static ENVIRONMENT: Cell<Option<EnvironmentFn>> = const { Cell::new(None) };
+    static VIDEO_REFRESH: Cell<Option<VideoRefreshFn>> = const { Cell::new(None) };
+    static AUDIO_SAMPLE_BATCH: Cell<Option<AudioSampleBatchFn>> = const { Cell::new(None) };
+    static INPUT_POLL: Cell<Option<InputPollFn>> = const { Cell::new(None) };
+    static INPUT_STATE: Cell<Option<InputStateFn>> = const { Cell::new(None) };
+}
+
+fn with_emu<R>(default: R, f: impl FnOnce(&mut Emu) -> R) -> R {
+    EMU.with(|emu| emu.borrow_mut().as_mut().map_or(default, f))
+}
+
+// Frames go straight to the frontend's callback
+struct RetroVideo;
+
+impl VideoSink for RetroVideo {
+    fn frame(&mut self, pixels: &[u32], width: usize, height: usize) {
+        if let Some(cb) = VIDEO_REFRESH.with(Cell::get) {
+            unsafe {
+                cb(
+                    pixels.as_ptr() as *const c_void,
+                    width as c_uint,
+                    height as c_uint,
+                    width * 4,
+                )
+            };
+        }
+    }
+}
+
+// Interleaved stereo samples
+struct RetroAudio;
+
+impl AudioSink for RetroAudio {
+    fn samples(&mut self, samples: &[i16]) {
+        if let Some(cb) = AUDIO_SAMPLE_BATCH.with(Cell::get) {
+            let mut rest = samples;
+            while 2 <= rest.len() {
+                let written = unsafe { cb(rest.as_ptr(), rest.len() / 2) };
+                if written == 0 {
+                    break;
+                }
+                rest = &rest[(written * 2).min(rest.len())..];
+            }
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    EMU.with(|emu| emu.borrow_mut().take());
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut SystemInfo) {
+    *info = SystemInfo {
+        library_name: b"korones\0".as_ptr() as *const c_char,
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: b"nes\0".as_ptr() as *const c_char,
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut SystemAvInfo) {
+    let region = with_emu(Region::Ntsc, |emu| emu.region());
+    *info = SystemAvInfo {
+        geometry: GameGeometry {
+            base_width: 256,
+            base_height: 240,
+            max_width: 256,
+            max_height: 240,
+            aspect_ratio: 4.0 / 3.0,
+        },
+        timing: SystemTiming {
+            fps: region.frame_rate(),
+            sample_rate: SAMPLE_RATE as f64,
+        },
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(cb: EnvironmentFn) {
+    ENVIRONMENT.with(|c| c.set(Some(cb)));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(cb: VideoRefreshFn) {
+    VIDEO_REFRESH.with(|c| c.set(Some(cb)));
+}
+
+// Only the batch callback is used
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_cb: AudioSampleFn) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(cb: AudioSampleBatchFn) {
+    AUDIO_SAMPLE_BATCH.with(|c| c.set(Some(cb)));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(cb: InputPollFn) {
+    INPUT_POLL.with(|c| c.set(Some(cb)));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(cb: InputStateFn) {
+    INPUT_STATE.with(|c| c.set(Some(cb)));
+}
+
+// Both ports always have a standard controller
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    with_emu((), |emu| emu.reset());
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    if let Some(poll) = INPUT_POLL.with(Cell::get) {
+        unsafe { poll() };
+    }
+    let state = INPUT_STATE.with(Cell::get);
+    with_emu((), |emu| {
+        if let Some(state) = state {
+            for port in 0..2 {
+                for &(id, button) in &JOYPAD_IDS {
+                    let pressed = unsafe { state(port, DEVICE_JOYPAD, 0, id) } != 0;
+                    emu.set_button(port as usize, button, pressed);
+                }
+            }
+        }
+        emu.run_frame();
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    with_emu(0, |emu| emu.save_state().map_or(0, |s| s.len()))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    if data.is_null() {
+        return false;
+    }
+    with_emu(false, |emu| match emu.save_state() {
+        Ok(state) if state.len() <= size => {
+            ptr::copy_nonoverlapping(state.as_ptr(), data as *mut u8, state.len());
+            true
+        }
+        _ => false,
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    if data.is_null() {
+        return false;
+    }
+    let state = slice::from_raw_parts(data as *const u8, size);
+    with_emu(false, |emu| emu.load_state(state).is_ok())
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {
+    with_emu((), |emu| {
+        let codes: Vec<_> = emu.cheats().iter().map(|c| c.code().to_string()).collect();
+        for code in codes {
+            emu.remove_cheat(&code);
+        }
+    });
+}
+
+// Game Genie codes, several joined by '+' as frontends pass them
+#[no_mangle]
+pub unsafe extern "C" fn retro_cheat_set(_index: c_uint, enabled: bool, code: *const c_char) {
+    if code.is_null() {
+        return;
+    }
+    let code = std::ffi::CStr::from_ptr(code).to_string_lossy();
+    with_emu((), |emu| {
+        for code in code.split('+').map(str::trim) {
+            if emu.add_cheat(code).is_ok() {
+                emu.set_cheat_enabled(code, enabled);
+            }
+        }
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const GameInfo) -> bool {
+    if game.is_null() || (*game).data.is_null() {
+        return false;
+    }
+    let rom = slice::from_raw_parts((*game).data as *const u8, (*game).size);
+
+    if let Some(env) = ENVIRONMENT.with(Cell::get) {
+        let mut format = PIXEL_FORMAT_XRGB8888;
+        if !env(
+            ENVIRONMENT_SET_PIXEL_FORMAT,
+            &mut format as *mut c_uint as *mut c_void,
+        ) {
+            return false;
+        }
+    }
+
+    let mut emu = Emu::with_config(Config {
+        sample_rate: SAMPLE_RATE,
+        channels: 2,
+        ..Default::default()
+    });
+    if emu.load_rom(rom).is_err() {
+        return false;
+    }
+    emu.set_video_sink(RetroVideo);
+    emu.set_audio_sink(RetroAudio);
+    EMU.with(|e| *e.borrow_mut() = Some(emu));
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(
+    _game_type: c_uint,
+    _info: *const GameInfo,
+    _num_info: usize,
+) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    EMU.with(|emu| emu.borrow_mut().take());
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    match with_emu(Region::Ntsc, |emu| emu.region()) {
+        Region::Ntsc => REGION_NTSC,
+        _ => REGION_PAL,
+    }
+}
+
+// Battery-backed RAM for the frontend to persist, and CPU RAM for its cheat search.
+// The pointers stay valid until the game is unloaded.
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    with_emu(ptr::null_mut(), |emu| match id {
+        MEMORY_SAVE_RAM => emu
+            .nes
+            .mapper
+            .nvram_mut()
+            .map_or(ptr::null_mut(), |m| m.as_mut_ptr() as *mut c_void),
+        MEMORY_SYSTEM_RAM => emu.nes.wram.as_mut_ptr() as *mut c_void,
+        _ => ptr::null_mut(),
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    with_emu(0, |emu| match id {
+        MEMORY_SAVE_RAM => emu.nes.mapper.nvram().map_or(0, |m| m.len()),
+        MEMORY_SYSTEM_RAM => emu.nes.wram.len(),
+        _ => 0,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static FRAMES: AtomicUsize = AtomicUsize::new(0);
+    static SAMPLES: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe extern "C" fn video(_data: *const c_void, width: c_uint, height: c_uint, pitch: usize) {
+        assert_eq!((width, height, pitch), (256, 240, 1024));
+        FRAMES.fetch_add(1, Ordering::Relaxed);
+    }
+
+    unsafe extern "C" fn audio(_data: *const i16, frames: usize) -> usize {
+        SAMPLES.fetch_add(frames, Ordering::Relaxed);
+        frames
+    }
+
+    unsafe extern "C" fn input_state(
+        _port: c_uint,
+        _device: c_uint,
+        _index: c_uint,
+        id: c_uint,
+    ) -> i16 {
+        (id == 3) as i16
+    }
+
+    #[test]
+    fn test_core() {
+        let rom = std::fs::read("roms/nestest.nes").unwrap();
+        retro_set_video_refresh(video);
+        retro_set_audio_sample_batch(audio);
+        retro_set_input_state(input_state);
+        let game = GameInfo {
+            path: ptr::null(),
+            data: rom.as_ptr() as *const c_void,
+            size: rom.len(),
+            meta: ptr::null(),
+        };
+        unsafe {
+            assert!(retro_load_game(&game));
+            retro_run();
+            retro_run();
+        }
+        assert_eq!(FRAMES.load(Ordering::Relaxed), 2);
+        // 48000 Hz stereo, the first frame a bit short as it starts after reset
+        let samples = SAMPLES.load(Ordering::Relaxed);
+        assert!((1520..1550).contains(&samples), "{}", samples);
+        assert_eq!(
+            with_emu(0, |emu| emu.controller_state(1)),
+            Button::Start.mask()
+        );
+
+        let size = retro_serialize_size();
+        let mut state = vec![0u8; size];
+        unsafe {
+            assert!(retro_serialize(state.as_mut_ptr() as *mut c_void, size));
+            assert!(retro_unserialize(state.as_ptr() as *const c_void, size));
+            assert!(!retro_unserialize(state.as_ptr() as *const c_void, 3));
+            assert!(!retro_serialize(ptr::null_mut(), size));
+            assert!(!retro_unserialize(ptr::null(), 0));
+        }
+        assert_eq!(retro_get_memory_size(MEMORY_SYSTEM_RAM), 0x0800);
+        assert_eq!(retro_get_region(), REGION_NTSC);
+        retro_unload_game();
+        assert_eq!(retro_serialize_size(), 0);
+    }
+}
@@ -1,14 +1,524 @@
+use anyhow::Result;
+
+use crate::memory_map::Backing;
+use crate::nes::Mirroring;
+use crate::nsf::NsfMapper;
+use crate::rom::{Cartridge, RomError};
+use crate::state::{StateError, StateReader, StateWriter};
+
+mod discrete;
+mod mmc3;
+mod nrom;
+mod nwc;
+mod rambo1;
+mod unrom512;
+
+pub(crate) use discrete::{Discrete, DiscreteBoard};
+pub(crate) use mmc3::{Mmc3, Mmc3Board, Mmc3Irq};
+pub(crate) use nrom::Nrom;
+pub(crate) use nwc::Nwc;
+pub(crate) use rambo1::Rambo1;
+pub(crate) use unrom512::Unrom512;
+
 pub(crate) trait Mapper: std::fmt::Debug {
-    fn read(&mut self, addr: u16) -> u8;
+    // Returns None for addresses the board doesn't decode, leaving the CPU data bus
+    // floating (open bus).
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        self.peek(addr)
+    }
     fn write(&mut self, addr: u16, value: u8);
+
+    // What `read` would return, without its side effects. Boards whose reads change
+    // state override `read`.
+    fn peek(&self, addr: u16) -> Option<u8>;
+
+    // Offset into PRG-ROM of the byte the CPU sees at `addr` right now, None where
+    // it sees RAM, registers or nothing
+    fn prg_rom_offset(&self, _addr: u16) -> Option<usize> {
+        None
+    }
+    // Offset into PRG-RAM of the byte at `addr` right now
+    fn prg_ram_offset(&self, _addr: u16) -> Option<usize> {
+        None
+    }
+    // CHR-ROM or CHR-RAM behind a pattern table address right now
+    fn chr_backing(&self, _addr: u16) -> Option<Backing> {
+        None
+    }
+    // All of PRG-ROM, for the CPU to read the banks `prg_rom_offset` places
+    // straight out of, skipping `read`; boards must only return it if those
+    // reads have no side effects, and their bank registers must sit at $8000-$FFFF
+    fn prg_rom(&self) -> &[u8] {
+        &[]
+    }
+
+    // Change a byte of PRG-RAM for debugging; ROM and registers aren't affected
+    fn poke(&mut self, addr: u16, value: u8) {
+        if let (0x6000..=0x7FFF, Some(ram)) = (addr, self.prg_ram_mut()) {
+            if !ram.is_empty() {
+                let len = ram.len();
+                ram[(addr as usize - 0x6000) % len] = value;
+            }
+        }
+    }
+
+    // PPU pattern table space ($0000-$1FFF)
+    fn ppu_read(&mut self, addr: u16) -> u8;
+    fn ppu_write(&mut self, addr: u16, value: u8);
+
+    // Current nametable arrangement. Consulted on every nametable access, so boards
+    // switching mirroring at runtime (MMC1, MMC3, AxROM, ...) take effect immediately.
+    fn mirroring(&self) -> Mirroring;
+
+    // Discrete logic boards (UxROM, CNROM, AxROM, ...) latch register writes without
+    // disabling PRG-ROM output, so the CPU and the ROM drive the data bus at the same
+    // time and the latched value becomes the AND of both.
+    // Boards return true here when their (sub)mapper is known to have bus conflicts.
+    fn bus_conflicts(&self) -> bool {
+        false
+    }
+
+    // PRG-RAM mapped at $6000-$7FFF, if the board has any
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    // Non-volatile memory to persist across sessions: battery-backed RAM or flash
+    fn nvram(&self) -> Option<&[u8]> {
+        None
+    }
+    fn nvram_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    // Whether the board is asserting /IRQ (MMC3 scanline counter, VRC/FME-7 cycle counters, ...)
+    fn irq_pending(&self) -> bool {
+        false
+    }
+    // Release /IRQ, e.g. on reset
+    fn irq_ack(&mut self) {}
+
+    // Called once per CPU cycle (M2)
+    fn cpu_clock(&mut self) {}
+    // Called on each rising edge of PPU A12
+    fn ppu_a12_rise(&mut self) {}
+    // Whether the board follows the PPU's fetches into something the CPU sees
+    // mid-frame, like IRQs counted from A12, so the PPU can't fall behind while
+    // rendering
+    fn watches_ppu(&self) -> bool {
+        false
+    }
+
+    // Set the board's DIP switches, on boards that have them
+    fn set_dip_switches(&mut self, _value: u8) {}
+    // CPU cycles left on a countdown the board keeps for players, like the NWC
+    // cartridge's competition timer
+    fn countdown(&self) -> Option<u32> {
+        None
+    }
+
+    // Every board serializes all of its mutable state: registers, selected banks,
+    // IRQ counters and PRG-RAM/CHR-RAM contents. ROM contents are never included.
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()>;
+}
+
+// Resolve the value actually seen by the board for a CPU write to PRG-ROM space.
+pub(crate) fn bus_conflict<M: Mapper>(mapper: &M, addr: u16, value: u8) -> u8 {
+    if 0x8000 <= addr && mapper.bus_conflicts() {
+        value & mapper.peek(addr).unwrap_or(value)
+    } else {
+        value
+    }
+}
+
+// Mirroring saved by a board whose register only picks horizontal or vertical;
+// anything else would index past CIRAM
+pub(crate) fn read_hv_mirroring(r: &mut StateReader) -> Result<Mirroring> {
+    match r.read_u8()? {
+        0 => Ok(Mirroring::Horizontal),
+        1 => Ok(Mirroring::Vertical),
+        v => Err(StateError {
+            msg: format!("invalid mirroring {}", v),
+        }
+        .into()),
+    }
+}
+
+// Every implemented board, dispatched statically so that the CPU/PPU memory access
+// hot paths don't go through a virtual call.
+#[derive(Debug)]
+pub(crate) enum Board {
+    Empty(Empty),
+    Nsf(NsfMapper),
+    Nrom(Nrom),
+    Discrete(Discrete),
+    Mmc3(Mmc3),
+    Unrom512(Unrom512),
+    Nwc(Nwc),
+    Rambo1(Rambo1),
+}
+
+impl Board {
+    // Build the board a cartridge needs from its (sub)mapper number
+    pub(crate) fn new(cart: &Cartridge) -> Result<Self> {
+        // every board runs from PRG-ROM, and bank math divides by its size
+        if cart.prg_rom().is_empty() {
+            return Err(RomError::Malformed("no PRG-ROM".to_string()).into());
+        }
+        let prg_rom = cart.prg_rom().to_vec();
+        let chr_rom = cart.chr_rom().to_vec();
+        // NES 2.0 RAM sizes override each board's usual amount
+        let prg_ram = |default| cart.prg_ram_size().unwrap_or(default);
+        let chr_ram = |default| cart.chr_ram_size().unwrap_or(default);
+        let board = match cart.mapper() {
+            0 => Board::Nrom(Nrom::new(
+                prg_rom,
+                chr_rom,
+                prg_ram(0x2000),
+                chr_ram(0x2000),
+                cart.mirroring(),
+                cart.battery(),
+            )),
+            // NES 2.0 submapper 2 has the ROM fight register writes, 1 doesn't.
+            // Without one, conflicts are left out: games written for them only
+            // write values the ROM agrees with.
+            2 | 3 | 7 => {
+                let board = match cart.mapper() {
+                    2 => DiscreteBoard::Uxrom,
+                    3 => DiscreteBoard::Cnrom,
+                    _ => DiscreteBoard::Axrom,
+                };
+                Board::Discrete(Discrete::new(
+                    board,
+                    prg_rom,
+                    chr_rom,
+                    chr_ram(0x2000),
+                    cart.mirroring(),
+                    cart.submapper() == 2,
+                ))
+            }
+            4 | 118 | 119 => {
+                let board = match cart.mapper() {
+                    118 => Mmc3Board::Txsrom,
+                    119 => Mmc3Board::Tqrom,
+                    _ => Mmc3Board::Txrom,
+                };
+                let irq = match cart.submapper() {
+                    4 => Mmc3Irq::Old,
+                    _ => Mmc3Irq::New,
+                };
+                Board::Mmc3(Mmc3::new(
+                    board,
+                    irq,
+                    prg_rom,
+                    chr_rom,
+                    prg_ram(0x2000),
+                    chr_ram(0x2000),
+                    cart.battery(),
+                ))
+            }
+            // flag 6 bit 3 selects the one-screen register (or four-screen RAM with
+            // vertical mirroring), the battery bit a flash chip
+            30 => Board::Unrom512(Unrom512::new(
+                prg_rom,
+                chr_ram(0x8000),
+                cart.mirroring(),
+                cart.four_screen() && cart.mirroring() == Mirroring::Horizontal,
+                cart.battery(),
+            )),
+            64 => Board::Rambo1(Rambo1::new(prg_rom, chr_rom, chr_ram(0x2000))),
+            105 => Board::Nwc(Nwc::new(
+                prg_rom,
+                prg_ram(0x2000),
+                chr_ram(0x2000),
+                cart.battery(),
+            )),
+            n => return Err(RomError::UnsupportedMapper(n).into()),
+        };
+        Ok(board)
+    }
+}
+
+// Whether the cartridge carries 2KB of extra nametable RAM
+pub(crate) fn four_screen(cart: &Cartridge) -> bool {
+    match cart.mapper() {
+        // flag 6 bit 3 alone means the one-screen register on UNROM 512
+        30 => cart.four_screen() && cart.mirroring() == Mirroring::Vertical,
+        _ => cart.four_screen(),
+    }
+}
+
+macro_rules! dispatch {
+    ($self:ident, $m:ident => $e:expr) => {
+        match $self {
+            Board::Empty($m) => $e,
+            Board::Nsf($m) => $e,
+            Board::Nrom($m) => $e,
+            Board::Discrete($m) => $e,
+            Board::Mmc3($m) => $e,
+            Board::Unrom512($m) => $e,
+            Board::Nwc($m) => $e,
+            Board::Rambo1($m) => $e,
+        }
+    };
+}
+
+impl Mapper for Board {
+    #[inline]
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        dispatch!(self, m => m.read(addr))
+    }
+    #[inline]
+    fn write(&mut self, addr: u16, value: u8) {
+        dispatch!(self, m => m.write(addr, value))
+    }
+    fn peek(&self, addr: u16) -> Option<u8> {
+        dispatch!(self, m => m.peek(addr))
+    }
+    fn poke(&mut self, addr: u16, value: u8) {
+        dispatch!(self, m => m.poke(addr, value))
+    }
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        dispatch!(self, m => m.prg_rom_offset(addr))
+    }
+    fn prg_ram_offset(&self, addr: u16) -> Option<usize> {
+        dispatch!(self, m => m.prg_ram_offset(addr))
+    }
+    fn chr_backing(&self, addr: u16) -> Option<Backing> {
+        dispatch!(self, m => m.chr_backing(addr))
+    }
+    #[inline]
+    fn prg_rom(&self) -> &[u8] {
+        dispatch!(self, m => m.prg_rom())
+    }
+    #[inline]
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        dispatch!(self, m => m.ppu_read(addr))
+    }
+    #[inline]
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        dispatch!(self, m => m.ppu_write(addr, value))
+    }
+    #[inline]
+    fn mirroring(&self) -> Mirroring {
+        dispatch!(self, m => m.mirroring())
+    }
+    #[inline]
+    fn bus_conflicts(&self) -> bool {
+        dispatch!(self, m => m.bus_conflicts())
+    }
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        dispatch!(self, m => m.prg_ram_mut())
+    }
+    fn nvram(&self) -> Option<&[u8]> {
+        dispatch!(self, m => m.nvram())
+    }
+    fn nvram_mut(&mut self) -> Option<&mut [u8]> {
+        dispatch!(self, m => m.nvram_mut())
+    }
+    #[inline]
+    fn irq_pending(&self) -> bool {
+        dispatch!(self, m => m.irq_pending())
+    }
+    fn irq_ack(&mut self) {
+        dispatch!(self, m => m.irq_ack())
+    }
+    #[inline]
+    fn cpu_clock(&mut self) {
+        dispatch!(self, m => m.cpu_clock())
+    }
+    #[inline]
+    fn ppu_a12_rise(&mut self) {
+        dispatch!(self, m => m.ppu_a12_rise())
+    }
+    fn watches_ppu(&self) -> bool {
+        dispatch!(self, m => m.watches_ppu())
+    }
+    fn set_dip_switches(&mut self, value: u8) {
+        dispatch!(self, m => m.set_dip_switches(value))
+    }
+    fn countdown(&self) -> Option<u32> {
+        dispatch!(self, m => m.countdown())
+    }
+    fn save_state(&self, w: &mut StateWriter) {
+        dispatch!(self, m => m.save_state(w))
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        dispatch!(self, m => m.load_state(r))
+    }
 }
 
 #[derive(Debug)]
 pub(crate) struct Empty {}
 
 impl Mapper for Empty {
-    fn read(&mut self, _addr: u16) -> u8 {
-        0
+    fn peek(&self, _addr: u16) -> Option<u8> {
+        None
     }
     fn write(&mut self, _addr: u16, _value: u8) {}
+    fn ppu_read(&mut self, _addr: u16) -> u8 {
+        0
+    }
+    fn ppu_write(&mut self, _addr: u16, _value: u8) {}
+    fn mirroring(&self) -> Mirroring {
+        Mirroring::Horizontal
+    }
+    fn save_state(&self, _w: &mut StateWriter) {}
+    fn load_state(&mut self, _r: &mut StateReader) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Latch {
+        rom: u8,
+        conflicts: bool,
+    }
+
+    impl Mapper for Latch {
+        fn peek(&self, _addr: u16) -> Option<u8> {
+            Some(self.rom)
+        }
+        fn write(&mut self, _addr: u16, _value: u8) {}
+        fn ppu_read(&mut self, _addr: u16) -> u8 {
+            0
+        }
+        fn ppu_write(&mut self, _addr: u16, _value: u8) {}
+        fn mirroring(&self) -> Mirroring {
+            Mirroring::Vertical
+        }
+        fn bus_conflicts(&self) -> bool {
+            self.conflicts
+        }
+        fn save_state(&self, _w: &mut StateWriter) {}
+        fn load_state(&mut self, _r: &mut StateReader) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_new_board() {
+        #[rustfmt::skip]
+        let cases = [
+            ("NROM",         0, 0),
+            ("UxROM",        2, 0),
+            ("CNROM",        3, 2),
+            ("AxROM",        7, 1),
+            ("TxROM",        4, 0),
+            ("MMC3A",        4, 4),
+            ("TxSROM",     118, 0),
+            ("UNROM 512",   30, 0),
+            ("RAMBO-1",     64, 0),
+            ("NES-EVENT",  105, 0),
+        ];
+
+        for (name, mapper, submapper) in cases {
+            #[rustfmt::skip]
+            let mut rom = vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01,
+                (mapper as u8) << 4, (mapper as u8) & 0xF0 | 0b1000,
+                submapper << 4, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            ];
+            rom.resize(16 + 0xA000, 0);
+            let cart = Cartridge::from_bytes(&rom).unwrap();
+            assert_eq!(cart.submapper(), submapper, "{}", name);
+
+            let board = Board::new(&cart).unwrap();
+            match (mapper, board) {
+                (0, Board::Nrom(_))
+                | (2 | 3 | 7, Board::Discrete(_))
+                | (4 | 118, Board::Mmc3(_))
+                | (30, Board::Unrom512(_))
+                | (64, Board::Rambo1(_))
+                | (105, Board::Nwc(_)) => {}
+                (_, board) => panic!("{}: {:?}", name, board),
+            }
+        }
+
+        let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x00, 0xF0, 0xF0];
+        rom.resize(16 + 0x4000, 0);
+        let err = Board::new(&Cartridge::from_bytes(&rom).unwrap()).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&RomError::UnsupportedMapper(255)));
+    }
+
+    #[test]
+    fn test_odd_prg_rom_sizes() {
+        for mapper in [0u8, 2, 3, 4, 7, 30, 64, 105] {
+            // 4KB in NES 2.0's exponent notation, below every board's bank size
+            #[rustfmt::skip]
+            let mut rom = vec![
+                0x4E, 0x45, 0x53, 0x1A, 12 << 2, 0x00, mapper << 4, mapper & 0xF0 | 0b1000,
+                0x00, 0x0F, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            ];
+            rom.resize(16 + 0x1000, 0xEA);
+            let board = Board::new(&Cartridge::from_bytes(&rom).unwrap()).unwrap();
+            for addr in 0x8000..=0xFFFF {
+                assert_eq!(board.peek(addr), Some(0xEA), "mapper {}", mapper);
+            }
+        }
+
+        let rom = [0x4E, 0x45, 0x53, 0x1A, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert!(Board::new(&Cartridge::from_bytes(&rom).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_nes2_ram_sizes() {
+        #[rustfmt::skip]
+        let mut rom = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x02, 0x00, 0x42, 0x08,
+            // 32KB PRG-RAM + 8KB PRG-NVRAM, 16KB CHR-RAM
+            0x00, 0x00, 0x79, 0x08, 0x00, 0x00, 0x00, 0x00,
+        ];
+        rom.resize(16 + 0x8000, 0);
+        let cart = Cartridge::from_bytes(&rom).unwrap();
+
+        let mut board = Board::new(&cart).unwrap();
+        assert_eq!(board.prg_ram_mut().unwrap().len(), 0xA000);
+        assert_eq!(board.nvram().unwrap().len(), 0xA000);
+
+        // CHR-RAM beyond 8KB is reachable through the bank registers
+        board.write(0x8000, 2);
+        board.write(0x8001, 12);
+        board.ppu_write(0x1000, 0xAB);
+        assert_eq!(board.ppu_read(0x1000), 0xAB);
+        board.write(0x8001, 4);
+        assert_eq!(board.ppu_read(0x1000), 0x00);
+    }
+
+    #[test]
+    fn test_read_hv_mirroring() {
+        #[rustfmt::skip]
+        let cases = [
+            (0, Some(Mirroring::Horizontal)),
+            (1, Some(Mirroring::Vertical)),
+            (2, None),
+            (4, None),
+        ];
+
+        for (v, expected) in cases {
+            let buf = [v];
+            let mut r = StateReader::new(&buf);
+            assert_eq!(read_hv_mirroring(&mut r).ok(), expected, "{}", v);
+        }
+    }
+
+    #[test]
+    fn test_bus_conflict() {
+        #[rustfmt::skip]
+        let cases = [
+            ("no conflicts", false, 0b1010_1010, 0b0110_0110),
+            ("conflicts",    true,  0b1010_1010, 0b0010_0010),
+        ];
+
+        for (name, conflicts, rom, expected) in cases {
+            let m = Latch { rom, conflicts };
+            let v = bus_conflict(&m, 0x8000, 0b0110_0110);
+            assert_eq!(v, expected, "{}", name);
+        }
+    }
 }
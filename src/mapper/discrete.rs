@@ -0,0 +1,220 @@
+use super::*;
+
+// Boards whose only register is a latch written anywhere in $8000-$FFFF
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum DiscreteBoard {
+    // UxROM (mapper 2): 16KB switchable at $8000, the last 16KB fixed at $C000
+    Uxrom,
+    // CNROM (mapper 3): fixed PRG, 8KB switchable CHR
+    Cnrom,
+    // AxROM (mapper 7): 32KB switchable PRG, bit 4 picks the one-screen page
+    Axrom,
+}
+
+#[derive(Debug)]
+pub(crate) struct Discrete {
+    board: DiscreteBoard,
+    prg_rom: Vec<u8>,
+    // CHR-ROM, or CHR-RAM if the cartridge has no CHR-ROM
+    chr: Vec<u8>,
+    chr_writable: bool,
+    mirroring: Mirroring,
+    conflicts: bool,
+
+    bank: u8,
+}
+
+impl Discrete {
+    pub(crate) fn new(
+        board: DiscreteBoard,
+        prg_rom: Vec<u8>,
+        chr_rom: Vec<u8>,
+        chr_ram_size: usize,
+        mirroring: Mirroring,
+        conflicts: bool,
+    ) -> Self {
+        let chr_writable = chr_rom.is_empty();
+        let chr = if chr_writable {
+            vec![0; chr_ram_size.max(0x2000)]
+        } else {
+            chr_rom
+        };
+        Self {
+            board,
+            prg_rom,
+            chr,
+            chr_writable,
+            mirroring,
+            conflicts,
+            bank: 0,
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let len = self.prg_rom.len();
+        let offset = match (self.board, addr) {
+            (DiscreteBoard::Uxrom, 0x8000..=0xBFFF) => {
+                self.bank as usize * 0x4000 + (addr as usize & 0x3FFF)
+            }
+            (DiscreteBoard::Uxrom, _) => len.saturating_sub(0x4000) + (addr as usize & 0x3FFF),
+            (DiscreteBoard::Cnrom, _) => addr as usize - 0x8000,
+            (DiscreteBoard::Axrom, _) => {
+                (self.bank & 0x0F) as usize * 0x8000 + addr as usize - 0x8000
+            }
+        };
+        // images smaller than a bank wrap within themselves
+        offset % len
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let bank = match self.board {
+            DiscreteBoard::Cnrom => self.bank as usize,
+            _ => 0,
+        };
+        (bank * 0x2000 + (addr as usize & 0x1FFF)) % self.chr.len()
+    }
+}
+
+impl Mapper for Discrete {
+    fn peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000..=0xFFFF => Some(self.prg_rom[self.prg_offset(addr)]),
+            _ => None,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xFFFF => Some(self.prg_offset(addr)),
+            _ => None,
+        }
+    }
+
+    fn prg_rom(&self) -> &[u8] {
+        &self.prg_rom
+    }
+
+    fn chr_backing(&self, addr: u16) -> Option<Backing> {
+        let offset = self.chr_offset(addr);
+        Some(if self.chr_writable {
+            Backing::ChrRam(offset)
+        } else {
+            Backing::ChrRom(offset)
+        })
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        if addr >= 0x8000 {
+            self.bank = value;
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        if self.chr_writable {
+            let i = self.chr_offset(addr);
+            self.chr[i] = value;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.board {
+            DiscreteBoard::Axrom if self.bank & 0x10 == 0 => Mirroring::SingleScreenLower,
+            DiscreteBoard::Axrom => Mirroring::SingleScreenUpper,
+            _ => self.mirroring,
+        }
+    }
+
+    fn bus_conflicts(&self) -> bool {
+        self.conflicts
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        if self.chr_writable {
+            w.write_bytes(&self.chr);
+        }
+        w.write_u8(self.bank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        if self.chr_writable {
+            r.read_bytes_into(&mut self.chr)?;
+        }
+        self.bank = r.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Each 8KB of ROM is filled with its own bank number
+    fn rom(banks: usize) -> Vec<u8> {
+        (0..banks).flat_map(|b| vec![b as u8; 0x2000]).collect()
+    }
+
+    #[test]
+    fn test_banks() {
+        #[rustfmt::skip]
+        let cases = [
+            ("UxROM", DiscreteBoard::Uxrom, 0x03, [6, 14], 0),
+            ("CNROM", DiscreteBoard::Cnrom, 0x03, [0, 2],  3),
+            ("AxROM", DiscreteBoard::Axrom, 0x03, [12, 14], 0),
+        ];
+
+        for (name, board, bank, prg, chr) in cases {
+            let chr_rom = if board == DiscreteBoard::Cnrom {
+                rom(4)
+            } else {
+                Vec::new()
+            };
+            let mut m = Discrete::new(board, rom(16), chr_rom, 0x2000, Mirroring::Vertical, false);
+            m.write(0x8000, bank);
+            assert_eq!([m.read(0x8000), m.read(0xC000)], prg.map(Some), "{}", name);
+            assert_eq!(m.ppu_read(0x0000), chr, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_axrom_mirroring() {
+        let mut m = Discrete::new(
+            DiscreteBoard::Axrom,
+            rom(16),
+            Vec::new(),
+            0x2000,
+            Mirroring::Vertical,
+            false,
+        );
+        assert_eq!(m.mirroring(), Mirroring::SingleScreenLower);
+        m.write(0x8000, 0x10);
+        assert_eq!(m.mirroring(), Mirroring::SingleScreenUpper);
+    }
+
+    #[test]
+    fn test_bus_conflicts() {
+        #[rustfmt::skip]
+        let cases = [
+            ("no conflicts", false, 0x8000, 3),
+            // the ROM holds bank 1 at $A000 of bank 0
+            ("conflicts",    true,  0xA000, 1),
+        ];
+
+        for (name, conflicts, addr, expected) in cases {
+            let mut m = Discrete::new(
+                DiscreteBoard::Uxrom,
+                rom(16),
+                Vec::new(),
+                0x2000,
+                Mirroring::Vertical,
+                conflicts,
+            );
+            let value = bus_conflict(&m, addr, 3);
+            m.write(addr, value);
+            assert_eq!(m.read(0x8000), Some(expected * 2), "{}", name);
+        }
+    }
+}
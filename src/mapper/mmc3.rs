@@ -0,0 +1,505 @@
+use super::*;
+
+// Boards built around the MMC3
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Mmc3Board {
+    // TxROM (mapper 4)
+    Txrom,
+    // TxSROM (mapper 118): CHR bank bit 7 selects the CIRAM page of each nametable
+    Txsrom,
+    // TQROM (mapper 119): CHR bank bit 6 selects 8KB CHR-RAM instead of CHR-ROM
+    Tqrom,
+}
+
+// Revisions differ in when the scanline counter raises an IRQ
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Mmc3Irq {
+    // MMC3B/C (Sharp): whenever the counter is 0 after a clock
+    New,
+    // MMC3A (NEC), NES 2.0 submapper 4: only when decremented or reloaded to 0
+    Old,
+}
+
+#[derive(Debug)]
+pub(crate) struct Mmc3 {
+    board: Mmc3Board,
+    irq_revision: Mmc3Irq,
+
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    battery: bool,
+    // CHR-ROM, or CHR-RAM if the cartridge has no CHR-ROM
+    chr: Vec<u8>,
+    chr_writable: bool,
+    // TQROM has CHR-RAM alongside CHR-ROM
+    chr_ram: Vec<u8>,
+
+    bank_select: u8,
+    registers: [u8; 8],
+    mirroring: Mirroring,
+    prg_ram_enabled: bool,
+    prg_ram_write_protect: bool,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+impl Mmc3 {
+    pub(crate) fn new(
+        board: Mmc3Board,
+        irq_revision: Mmc3Irq,
+        prg_rom: Vec<u8>,
+        chr_rom: Vec<u8>,
+        prg_ram_size: usize,
+        chr_ram_size: usize,
+        battery: bool,
+    ) -> Self {
+        let chr_writable = chr_rom.is_empty();
+        let chr = if chr_writable {
+            vec![0; chr_ram_size.max(0x400)]
+        } else {
+            chr_rom
+        };
+        let chr_ram = if board == Mmc3Board::Tqrom {
+            vec![0; chr_ram_size.max(0x400)]
+        } else {
+            Vec::new()
+        };
+        Self {
+            board,
+            irq_revision,
+            prg_rom,
+            prg_ram: vec![0; prg_ram_size],
+            battery,
+            chr,
+            chr_writable,
+            chr_ram,
+            bank_select: 0,
+            registers: [0, 2, 4, 5, 6, 7, 0, 1],
+            mirroring: Mirroring::Vertical,
+            prg_ram_enabled: true,
+            prg_ram_write_protect: false,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let banks = self.prg_rom.len() / 0x2000;
+        let second_last = banks.saturating_sub(2);
+        let last = banks.saturating_sub(1);
+        let prg_mode = self.bank_select & 0x40 != 0;
+
+        let bank = match (addr, prg_mode) {
+            (0x8000..=0x9FFF, false) | (0xC000..=0xDFFF, true) => self.registers[6] as usize,
+            (0xA000..=0xBFFF, _) => self.registers[7] as usize,
+            (0x8000..=0x9FFF, true) | (0xC000..=0xDFFF, false) => second_last,
+            _ => last,
+        };
+        // images under 8KB wrap within themselves
+        ((bank % banks.max(1)) * 0x2000 + (addr as usize & 0x1FFF)) % self.prg_rom.len()
+    }
+
+    // Raw bank register selected for the 1KB CHR page containing `addr`,
+    // with the low bit already resolved for the 2KB registers R0/R1.
+    fn chr_register(&self, addr: u16) -> u8 {
+        let addr = if self.bank_select & 0x80 != 0 {
+            addr ^ 0x1000
+        } else {
+            addr
+        };
+        match addr / 0x400 {
+            0 => self.registers[0] & 0xFE,
+            1 => self.registers[0] | 1,
+            2 => self.registers[1] & 0xFE,
+            3 => self.registers[1] | 1,
+            n => self.registers[n as usize - 2],
+        }
+    }
+
+    fn chr_ram_selected(&self, bank: u8) -> bool {
+        self.board == Mmc3Board::Tqrom && bank & 0x40 != 0
+    }
+
+    fn chr_offset(&self, len: usize, bank: u8, addr: u16) -> usize {
+        (bank as usize * 0x400 + (addr as usize & 0x03FF)) % len
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled && !self.prg_ram.is_empty() => {
+                Some(self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()])
+            }
+            0x8000..=0xFFFF => Some(self.prg_rom[self.prg_offset(addr)]),
+            _ => None,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xFFFF => Some(self.prg_offset(addr)),
+            _ => None,
+        }
+    }
+
+    fn prg_rom(&self) -> &[u8] {
+        &self.prg_rom
+    }
+
+    fn prg_ram_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled && !self.prg_ram.is_empty() => {
+                Some((addr as usize - 0x6000) % self.prg_ram.len())
+            }
+            _ => None,
+        }
+    }
+
+    fn chr_backing(&self, addr: u16) -> Option<Backing> {
+        let bank = self.chr_register(addr);
+        Some(if self.chr_ram_selected(bank) {
+            Backing::ChrRam(self.chr_offset(self.chr_ram.len(), bank, addr))
+        } else if self.chr_writable {
+            Backing::ChrRam(self.chr_offset(self.chr.len(), bank, addr))
+        } else {
+            Backing::ChrRom(self.chr_offset(self.chr.len(), bank, addr))
+        })
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match (addr, addr & 1) {
+            (0x6000..=0x7FFF, _)
+                if self.prg_ram_enabled
+                    && !self.prg_ram_write_protect
+                    && !self.prg_ram.is_empty() =>
+            {
+                let i = (addr as usize - 0x6000) % self.prg_ram.len();
+                self.prg_ram[i] = value
+            }
+            (0x8000..=0x9FFF, 0) => self.bank_select = value,
+            (0x8000..=0x9FFF, _) => self.registers[self.bank_select as usize & 0x07] = value,
+            (0xA000..=0xBFFF, 0) => {
+                self.mirroring = if value & 1 == 0 {
+                    Mirroring::Vertical
+                } else {
+                    Mirroring::Horizontal
+                }
+            }
+            (0xA000..=0xBFFF, _) => {
+                self.prg_ram_enabled = value & 0x80 != 0;
+                self.prg_ram_write_protect = value & 0x40 != 0;
+            }
+            (0xC000..=0xDFFF, 0) => self.irq_latch = value,
+            (0xC000..=0xDFFF, _) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (0xE000..=0xFFFF, 0) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            (0xE000..=0xFFFF, _) => self.irq_enabled = true,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        let bank = self.chr_register(addr);
+        if self.chr_ram_selected(bank) {
+            self.chr_ram[self.chr_offset(self.chr_ram.len(), bank, addr)]
+        } else {
+            self.chr[self.chr_offset(self.chr.len(), bank, addr)]
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        let bank = self.chr_register(addr);
+        if self.chr_ram_selected(bank) {
+            let i = self.chr_offset(self.chr_ram.len(), bank, addr);
+            self.chr_ram[i] = value;
+        } else if self.chr_writable {
+            let i = self.chr_offset(self.chr.len(), bank, addr);
+            self.chr[i] = value;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.board {
+            Mmc3Board::Txsrom => {
+                let mut pages = [0; 4];
+                for (table, page) in pages.iter_mut().enumerate() {
+                    *page = self.chr_register(table as u16 * 0x400) >> 7;
+                }
+                Mirroring::Custom(pages)
+            }
+            _ => self.mirroring,
+        }
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn nvram(&self) -> Option<&[u8]> {
+        if self.battery {
+            Some(&self.prg_ram)
+        } else {
+            None
+        }
+    }
+
+    fn nvram_mut(&mut self) -> Option<&mut [u8]> {
+        if self.battery {
+            Some(&mut self.prg_ram)
+        } else {
+            None
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn irq_ack(&mut self) {
+        self.irq_pending = false;
+    }
+
+    fn ppu_a12_rise(&mut self) {
+        let counted = if self.irq_counter == 0 || self.irq_reload {
+            let reload = self.irq_reload;
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+            reload
+        } else {
+            self.irq_counter -= 1;
+            true
+        };
+        let fire = match self.irq_revision {
+            Mmc3Irq::New => true,
+            Mmc3Irq::Old => counted,
+        };
+        if self.irq_counter == 0 && self.irq_enabled && fire {
+            self.irq_pending = true;
+        }
+    }
+
+    fn watches_ppu(&self) -> bool {
+        true
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.prg_ram);
+        if self.chr_writable {
+            w.write_bytes(&self.chr);
+        }
+        w.write_bytes(&self.chr_ram);
+        w.write_u8(self.bank_select);
+        w.write_bytes(&self.registers);
+        w.write_u8(self.mirroring.into());
+        w.write_bool(self.prg_ram_enabled);
+        w.write_bool(self.prg_ram_write_protect);
+        w.write_u8(self.irq_latch);
+        w.write_u8(self.irq_counter);
+        w.write_bool(self.irq_reload);
+        w.write_bool(self.irq_enabled);
+        w.write_bool(self.irq_pending);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.read_bytes_into(&mut self.prg_ram)?;
+        if self.chr_writable {
+            r.read_bytes_into(&mut self.chr)?;
+        }
+        r.read_bytes_into(&mut self.chr_ram)?;
+        self.bank_select = r.read_u8()?;
+        r.read_bytes_into(&mut self.registers)?;
+        self.mirroring = read_hv_mirroring(r)?;
+        self.prg_ram_enabled = r.read_bool()?;
+        self.prg_ram_write_protect = r.read_bool()?;
+        self.irq_latch = r.read_u8()?;
+        self.irq_counter = r.read_u8()?;
+        self.irq_reload = r.read_bool()?;
+        self.irq_enabled = r.read_bool()?;
+        self.irq_pending = r.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Each 1KB of ROM is filled with its own bank number
+    fn rom(kb: usize) -> Vec<u8> {
+        (0..kb).flat_map(|b| vec![b as u8; 0x400]).collect()
+    }
+
+    #[test]
+    fn test_prg_banks() {
+        let mut m = Mmc3::new(
+            Mmc3Board::Txrom,
+            Mmc3Irq::New,
+            rom(64),
+            rom(8),
+            0x2000,
+            0x2000,
+            false,
+        );
+        m.write(0x8000, 6);
+        m.write(0x8001, 2);
+        m.write(0x8000, 7);
+        m.write(0x8001, 3);
+
+        assert_eq!(m.read(0x8000), Some(2 * 8));
+        assert_eq!(m.read(0xA000), Some(3 * 8));
+        assert_eq!(m.read(0xC000), Some(6 * 8));
+        assert_eq!(m.read(0xE000), Some(7 * 8));
+
+        // PRG mode 1 swaps $8000 and $C000
+        m.write(0x8000, 0x46);
+        assert_eq!(m.read(0x8000), Some(6 * 8));
+        assert_eq!(m.read(0xC000), Some(2 * 8));
+    }
+
+    #[test]
+    fn test_chr_banks() {
+        let mut m = Mmc3::new(
+            Mmc3Board::Txrom,
+            Mmc3Irq::New,
+            rom(64),
+            rom(256),
+            0x2000,
+            0x2000,
+            false,
+        );
+        m.write(0x8000, 0);
+        m.write(0x8001, 0x11);
+        m.write(0x8000, 2);
+        m.write(0x8001, 0x22);
+
+        assert_eq!(m.ppu_read(0x0000), 0x10);
+        assert_eq!(m.ppu_read(0x0400), 0x11);
+        assert_eq!(m.ppu_read(0x1000), 0x22);
+
+        // CHR A12 inversion
+        m.write(0x8000, 0x80);
+        assert_eq!(m.ppu_read(0x1000), 0x10);
+        assert_eq!(m.ppu_read(0x0000), 0x22);
+    }
+
+    #[test]
+    fn test_irq() {
+        let mut m = Mmc3::new(
+            Mmc3Board::Txrom,
+            Mmc3Irq::New,
+            rom(64),
+            rom(8),
+            0x2000,
+            0x2000,
+            false,
+        );
+        m.write(0xC000, 2);
+        m.write(0xC001, 0);
+        m.write(0xE001, 0);
+
+        m.ppu_a12_rise();
+        assert!(!m.irq_pending());
+        m.ppu_a12_rise();
+        assert!(!m.irq_pending());
+        m.ppu_a12_rise();
+        assert!(m.irq_pending());
+
+        // disabling acknowledges
+        m.write(0xE000, 0);
+        assert!(!m.irq_pending());
+    }
+
+    #[test]
+    fn test_irq_revision() {
+        #[rustfmt::skip]
+        let cases = [
+            ("new", Mmc3Irq::New, [true, true, true]),
+            ("old", Mmc3Irq::Old, [true, false, false]),
+        ];
+
+        for (name, revision, expected) in cases {
+            let mut m = Mmc3::new(
+                Mmc3Board::Txrom,
+                revision,
+                rom(64),
+                rom(8),
+                0x2000,
+                0x2000,
+                false,
+            );
+            // a latch of 0 keeps reloading the counter with 0
+            m.write(0xC000, 0);
+            m.write(0xC001, 0);
+            m.write(0xE001, 0);
+            for (i, pending) in expected.iter().enumerate() {
+                m.ppu_a12_rise();
+                assert_eq!(m.irq_pending(), *pending, "{} {}", name, i);
+                m.irq_ack();
+            }
+        }
+    }
+
+    #[test]
+    fn test_txsrom_mirroring() {
+        let mut m = Mmc3::new(
+            Mmc3Board::Txsrom,
+            Mmc3Irq::New,
+            rom(64),
+            rom(256),
+            0x2000,
+            0x2000,
+            false,
+        );
+        m.write(0x8000, 0);
+        m.write(0x8001, 0x80);
+        m.write(0x8000, 1);
+        m.write(0x8001, 0x00);
+        assert_eq!(m.mirroring(), Mirroring::Custom([1, 1, 0, 0]));
+
+        // the mirroring register is ignored
+        m.write(0xA000, 1);
+        assert_eq!(m.mirroring(), Mirroring::Custom([1, 1, 0, 0]));
+
+        m.write(0x8000, 0x80 | 3);
+        m.write(0x8001, 0x80);
+        assert_eq!(m.mirroring(), Mirroring::Custom([0, 1, 0, 0]));
+    }
+
+    #[test]
+    fn test_tqrom_chr_ram() {
+        let mut m = Mmc3::new(
+            Mmc3Board::Tqrom,
+            Mmc3Irq::New,
+            rom(64),
+            rom(64),
+            0x2000,
+            0x2000,
+            false,
+        );
+        m.write(0x8000, 2);
+        m.write(0x8001, 0x41);
+        m.write(0x8000, 3);
+        m.write(0x8001, 0x05);
+
+        m.ppu_write(0x1000, 0xAB);
+        assert_eq!(m.ppu_read(0x1000), 0xAB);
+        // CHR-ROM is read-only
+        m.ppu_write(0x1400, 0xAB);
+        assert_eq!(m.ppu_read(0x1400), 0x05);
+        assert_eq!(m.chr_backing(0x1000), Some(Backing::ChrRam(0x0400)));
+        assert_eq!(m.chr_backing(0x1400), Some(Backing::ChrRom(0x1400)));
+    }
+}
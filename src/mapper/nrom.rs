@@ -0,0 +1,171 @@
+use super::*;
+
+// Mapper 0: 16KB or 32KB PRG-ROM, 8KB CHR-ROM (or CHR-RAM) and hardwired mirroring.
+// PRG-RAM at $6000-$7FFF only exists on Family BASIC, but most emulators provide it.
+#[derive(Debug)]
+pub(crate) struct Nrom {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    mirroring: Mirroring,
+    battery: bool,
+}
+
+impl Nrom {
+    pub(crate) fn new(
+        prg_rom: Vec<u8>,
+        chr_rom: Vec<u8>,
+        prg_ram_size: usize,
+        chr_ram_size: usize,
+        mirroring: Mirroring,
+        battery: bool,
+    ) -> Self {
+        let chr_ram = chr_rom.is_empty();
+        Self {
+            prg_rom,
+            prg_ram: vec![0; prg_ram_size],
+            chr: if chr_ram {
+                vec![0; chr_ram_size.max(0x2000)]
+            } else {
+                chr_rom
+            },
+            chr_ram,
+            mirroring,
+            battery,
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                Some(self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()])
+            }
+            // 16KB boards mirror $8000-$BFFF at $C000-$FFFF
+            0x8000..=0xFFFF => Some(self.prg_rom[self.prg_rom_offset(addr)?]),
+            _ => None,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xFFFF => Some((addr as usize - 0x8000) % self.prg_rom.len()),
+            _ => None,
+        }
+    }
+
+    fn prg_rom(&self) -> &[u8] {
+        &self.prg_rom
+    }
+
+    fn prg_ram_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                Some((addr as usize - 0x6000) % self.prg_ram.len())
+            }
+            _ => None,
+        }
+    }
+
+    fn chr_backing(&self, addr: u16) -> Option<Backing> {
+        let offset = addr as usize % self.chr.len();
+        Some(if self.chr_ram {
+            Backing::ChrRam(offset)
+        } else {
+            Backing::ChrRom(offset)
+        })
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        if let 0x6000..=0x7FFF = addr {
+            if !self.prg_ram.is_empty() {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = value;
+            }
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[addr as usize % self.chr.len()]
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        if self.chr_ram {
+            let len = self.chr.len();
+            self.chr[addr as usize % len] = value;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        if self.prg_ram.is_empty() {
+            None
+        } else {
+            Some(&mut self.prg_ram)
+        }
+    }
+
+    fn nvram(&self) -> Option<&[u8]> {
+        if self.battery {
+            Some(&self.prg_ram)
+        } else {
+            None
+        }
+    }
+
+    fn nvram_mut(&mut self) -> Option<&mut [u8]> {
+        if self.battery {
+            Some(&mut self.prg_ram)
+        } else {
+            None
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.prg_ram);
+        if self.chr_ram {
+            w.write_bytes(&self.chr);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.read_bytes_into(&mut self.prg_ram)?;
+        if self.chr_ram {
+            r.read_bytes_into(&mut self.chr)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_prg_mirroring() {
+        #[rustfmt::skip]
+        let cases = [
+            ("NROM-128", 0x4000, 0xEE),
+            ("NROM-256", 0x8000, 0x00),
+        ];
+
+        for (name, size, expected) in cases {
+            let mut prg = vec![0; size];
+            prg[size - 1] = 0xEE;
+            let mut m = Nrom::new(prg, vec![], 0x2000, 0x2000, Mirroring::Vertical, false);
+            assert_eq!(m.read(0xFFFF), Some(0xEE), "{}", name);
+            assert_eq!(m.read(0xBFFF), Some(expected), "{}", name);
+
+            m.write(0x6123, 0x45);
+            assert_eq!(m.read(0x6123), Some(0x45), "{}", name);
+            // CHR-RAM when there's no CHR-ROM
+            m.ppu_write(0x1000, 0x67);
+            assert_eq!(m.ppu_read(0x1000), 0x67, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,376 @@
+use super::*;
+
+// Mapper 105: the Nintendo World Championships 1990 cartridge (NES-EVENT). An
+// MMC1 banks two 128KB PRG chips: the first in 32KB pages through its CHR bank 0
+// register, which the board repurposes, the second through its PRG register.
+// That register also holds the countdown which interrupts the game when
+// competition time is up, lengthened by four DIP switches.
+#[derive(Debug)]
+pub(crate) struct Nwc {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    battery: bool,
+    chr_ram: Vec<u8>,
+
+    // Serial port bits written so far, above a marker bit that reaches bit 0
+    // once four are in
+    shift: u8,
+    // CPU cycles since the last serial write, saturating; the MMC1 ignores a
+    // write on the cycle right after another, as read-modify-write instructions
+    // make
+    since_write: u8,
+    control: u8,
+    // $A000: I (bit 4) holds the timer at 0 while set, C (bit 3) selects the
+    // second chip, BB (bits 1-2) the first chip's 32KB page
+    board: u8,
+    prg_bank: u8,
+    // PRG stays on the first 32KB after power-on until I has been low, then high
+    unlock: u8,
+
+    dip_switches: u8,
+    // CPU cycles counted since I was last cleared
+    timer: u32,
+    irq_pending: bool,
+}
+
+// Timer length with all DIP switches off, 5 minutes
+const TIMER_BASE: u32 = 0x2000_0000;
+// What each step of the DIP switches adds, 18.75 seconds
+const TIMER_STEP: u32 = 0x0200_0000;
+// The setting used in the competition, for 6:15
+const COMPETITION_DIP_SWITCHES: u8 = 4;
+
+impl Nwc {
+    pub(crate) fn new(
+        prg_rom: Vec<u8>,
+        prg_ram_size: usize,
+        chr_ram_size: usize,
+        battery: bool,
+    ) -> Self {
+        Self {
+            prg_rom,
+            prg_ram: vec![0; prg_ram_size],
+            battery,
+            chr_ram: vec![0; chr_ram_size.max(0x2000)],
+            shift: 0x10,
+            since_write: u8::MAX,
+            control: 0x0C,
+            board: 0x10,
+            prg_bank: 0,
+            unlock: 0,
+            dip_switches: COMPETITION_DIP_SWITCHES,
+            timer: 0,
+            irq_pending: false,
+        }
+    }
+
+    fn timer_length(&self) -> u32 {
+        TIMER_BASE + (self.dip_switches & 0x0F) as u32 * TIMER_STEP
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let half = (addr as usize >> 14) & 1;
+        let bank = if self.unlock < 2 {
+            half
+        } else if self.board & 0x08 == 0 {
+            (self.board as usize >> 1 & 0x03) * 2 + half
+        } else {
+            // the MMC1's PRG modes, within the second chip
+            let reg = (self.prg_bank & 0x07) as usize;
+            let bank = match (self.control >> 2 & 0x03, addr) {
+                (0 | 1, _) => reg & 0x06 | half,
+                (2, 0x8000..=0xBFFF) => 0,
+                (2, _) => reg,
+                (_, 0x8000..=0xBFFF) => reg,
+                _ => 0x07,
+            };
+            0x08 | bank
+        };
+        (bank * 0x4000 + (addr as usize & 0x3FFF)) % self.prg_rom.len()
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_bank & 0x10 == 0 && !self.prg_ram.is_empty()
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x8000..=0x9FFF => self.control = value,
+            0xA000..=0xBFFF => {
+                self.board = value;
+                let high = value & 0x10 != 0;
+                match (self.unlock, high) {
+                    (0, false) | (1, true) => self.unlock += 1,
+                    _ => {}
+                }
+                if high {
+                    self.timer = 0;
+                    self.irq_pending = false;
+                }
+            }
+            // CHR bank 1 isn't connected
+            0xC000..=0xDFFF => {}
+            _ => self.prg_bank = value,
+        }
+    }
+}
+
+impl Mapper for Nwc {
+    fn peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                Some(self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()])
+            }
+            0x8000..=0xFFFF => Some(self.prg_rom[self.prg_offset(addr)]),
+            _ => None,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xFFFF => Some(self.prg_offset(addr)),
+            _ => None,
+        }
+    }
+
+    fn prg_rom(&self) -> &[u8] {
+        &self.prg_rom
+    }
+
+    fn prg_ram_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                Some((addr as usize - 0x6000) % self.prg_ram.len())
+            }
+            _ => None,
+        }
+    }
+
+    fn chr_backing(&self, addr: u16) -> Option<Backing> {
+        Some(Backing::ChrRam(addr as usize % self.chr_ram.len()))
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                let i = (addr as usize - 0x6000) % self.prg_ram.len();
+                self.prg_ram[i] = value;
+            }
+            0x8000..=0xFFFF => {
+                let consecutive = self.since_write == 1;
+                self.since_write = 0;
+                if value & 0x80 != 0 {
+                    self.shift = 0x10;
+                    self.control |= 0x0C;
+                } else if !consecutive {
+                    let done = self.shift & 1 != 0;
+                    self.shift = self.shift >> 1 | (value & 1) << 4;
+                    if done {
+                        let value = self.shift;
+                        self.shift = 0x10;
+                        self.write_register(addr, value);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr_ram[addr as usize % self.chr_ram.len()]
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        let i = addr as usize % self.chr_ram.len();
+        self.chr_ram[i] = value;
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0x03 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn nvram(&self) -> Option<&[u8]> {
+        if self.battery {
+            Some(&self.prg_ram)
+        } else {
+            None
+        }
+    }
+
+    fn nvram_mut(&mut self) -> Option<&mut [u8]> {
+        if self.battery {
+            Some(&mut self.prg_ram)
+        } else {
+            None
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn irq_ack(&mut self) {
+        self.irq_pending = false;
+    }
+
+    fn cpu_clock(&mut self) {
+        self.since_write = self.since_write.saturating_add(1);
+        if self.board & 0x10 == 0 && self.timer < self.timer_length() {
+            self.timer += 1;
+            if self.timer == self.timer_length() {
+                self.irq_pending = true;
+            }
+        }
+    }
+
+    fn set_dip_switches(&mut self, value: u8) {
+        self.dip_switches = value;
+    }
+
+    fn countdown(&self) -> Option<u32> {
+        Some(self.timer_length().saturating_sub(self.timer))
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.prg_ram);
+        w.write_bytes(&self.chr_ram);
+        w.write_u8(self.shift);
+        w.write_u8(self.since_write);
+        w.write_u8(self.control);
+        w.write_u8(self.board);
+        w.write_u8(self.prg_bank);
+        w.write_u8(self.unlock);
+        w.write_u32(self.timer);
+        w.write_bool(self.irq_pending);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.read_bytes_into(&mut self.prg_ram)?;
+        r.read_bytes_into(&mut self.chr_ram)?;
+        self.shift = r.read_u8()?;
+        self.since_write = r.read_u8()?;
+        self.control = r.read_u8()?;
+        self.board = r.read_u8()?;
+        self.prg_bank = r.read_u8()?;
+        self.unlock = r.read_u8()?;
+        self.timer = r.read_u32()?;
+        self.irq_pending = r.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Each 16KB of ROM is filled with its own bank number
+    fn rom(banks: usize) -> Vec<u8> {
+        (0..banks).flat_map(|b| vec![b as u8; 0x4000]).collect()
+    }
+
+    // Load a register through the serial port, a bit at a time
+    fn write_serial(m: &mut Nwc, addr: u16, value: u8) {
+        for i in 0..5 {
+            m.write(addr, value >> i & 1);
+        }
+    }
+
+    fn unlocked() -> Nwc {
+        let mut m = Nwc::new(rom(16), 0x2000, 0x2000, false);
+        write_serial(&mut m, 0xA000, 0x00);
+        write_serial(&mut m, 0xA000, 0x10);
+        m
+    }
+
+    #[test]
+    fn test_prg_banks() {
+        let mut m = Nwc::new(rom(16), 0x2000, 0x2000, false);
+        let banks = |m: &Nwc| [m.peek(0x8000).unwrap(), m.peek(0xC000).unwrap()];
+
+        // locked to the first 32KB until I has been low, then high
+        write_serial(&mut m, 0xA000, 0x04);
+        assert_eq!(banks(&m), [0, 1]);
+        write_serial(&mut m, 0xA000, 0x14);
+        assert_eq!(banks(&m), [4, 5]);
+
+        #[rustfmt::skip]
+        let cases = [
+            ("first chip",          0x0C, 0x06, 0x00, [6, 7]),
+            ("32KB mode",           0x00, 0x08, 0x03, [10, 11]),
+            ("fixed $8000",         0x08, 0x08, 0x03, [8, 11]),
+            ("fixed $C000",         0x0C, 0x08, 0x03, [11, 15]),
+            ("bank bit 3 ignored",  0x0C, 0x08, 0x0A, [10, 15]),
+        ];
+
+        for (name, control, board, prg_bank, expected) in cases {
+            write_serial(&mut m, 0x8000, control);
+            write_serial(&mut m, 0xA000, 0x10 | board);
+            write_serial(&mut m, 0xE000, prg_bank);
+            assert_eq!(banks(&m), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_serial_port() {
+        let mut m = unlocked();
+        write_serial(&mut m, 0x8000, 0x02);
+        assert_eq!(m.mirroring(), Mirroring::Vertical);
+
+        // a write on the cycle after another is ignored
+        m.write(0x8000, 1);
+        m.cpu_clock();
+        m.write(0x8000, 1);
+        for _ in 0..4 {
+            m.cpu_clock();
+            m.cpu_clock();
+            m.write(0x8000, 1);
+        }
+        assert_eq!(m.mirroring(), Mirroring::Horizontal);
+
+        // bit 7 starts over and fixes $C000 to the last bank
+        write_serial(&mut m, 0x8000, 0x00);
+        write_serial(&mut m, 0xA000, 0x18);
+        m.write(0x8000, 1);
+        m.write(0x8000, 0x80);
+        assert_eq!(m.peek(0xC000), Some(15));
+        write_serial(&mut m, 0x8000, 0x01);
+        assert_eq!(m.mirroring(), Mirroring::SingleScreenUpper);
+    }
+
+    #[test]
+    fn test_timer() {
+        let mut m = unlocked();
+        assert_eq!(m.countdown(), Some(TIMER_BASE + 4 * TIMER_STEP));
+        m.set_dip_switches(0);
+        // held while I is set; two cycles, so the next write isn't ignored
+        m.cpu_clock();
+        m.cpu_clock();
+        assert_eq!(m.countdown(), Some(TIMER_BASE));
+
+        write_serial(&mut m, 0xA000, 0x00);
+        m.cpu_clock();
+        assert_eq!(m.countdown(), Some(TIMER_BASE - 1));
+        m.timer = TIMER_BASE - 2;
+        m.cpu_clock();
+        assert!(!m.irq_pending());
+        m.cpu_clock();
+        assert!(m.irq_pending());
+        // it stops at 0
+        m.cpu_clock();
+        assert_eq!(m.countdown(), Some(0));
+
+        // setting I resets it and acknowledges
+        write_serial(&mut m, 0xA000, 0x10);
+        assert!(!m.irq_pending());
+        assert_eq!(m.countdown(), Some(TIMER_BASE));
+    }
+}
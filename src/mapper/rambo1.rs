@@ -0,0 +1,408 @@
+use super::*;
+
+// Mapper 64: Tengen's RAMBO-1, an MMC3 with a third switchable PRG bank, full
+// 1KB CHR banking for the low pattern table, and an IRQ counter that can count
+// CPU cycles instead of scanlines. Its counter also reloads and fires later than
+// the MMC3's, which Klax, Skull & Crossbones and Hard Drivin' depend on.
+#[derive(Debug)]
+pub(crate) struct Rambo1 {
+    prg_rom: Vec<u8>,
+    // CHR-ROM, or CHR-RAM if the cartridge has no CHR-ROM
+    chr: Vec<u8>,
+    chr_writable: bool,
+
+    bank_select: u8,
+    // R0-R9 and RF; RA-RE aren't connected
+    registers: [u8; 16],
+    mirroring: Mirroring,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    // $C001 bit 0: clock the counter every 4 CPU cycles rather than on A12
+    cycle_mode: bool,
+    prescaler: u8,
+    // Leaving cycle mode takes a while, so the prescaler clocks the counter once
+    // more
+    extra_clock: bool,
+    // CPU cycles until the IRQ is raised after the counter reached 0
+    irq_delay: u8,
+}
+
+// Cycles from the counter reaching 0 to /IRQ, in each mode
+const CYCLE_IRQ_DELAY: u8 = 1;
+const A12_IRQ_DELAY: u8 = 2;
+
+impl Rambo1 {
+    pub(crate) fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, chr_ram_size: usize) -> Self {
+        let chr_writable = chr_rom.is_empty();
+        let chr = if chr_writable {
+            vec![0; chr_ram_size.max(0x400)]
+        } else {
+            chr_rom
+        };
+        Self {
+            prg_rom,
+            chr,
+            chr_writable,
+            bank_select: 0,
+            registers: [0, 2, 4, 5, 6, 7, 0, 1, 1, 3, 0, 0, 0, 0, 0, 0],
+            mirroring: Mirroring::Vertical,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            cycle_mode: false,
+            prescaler: 0,
+            extra_clock: false,
+            irq_delay: 0,
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let banks = self.prg_rom.len() / 0x2000;
+        let prg_mode = self.bank_select & 0x40 != 0;
+        let r = &self.registers;
+
+        let bank = match (addr, prg_mode) {
+            (0x8000..=0x9FFF, false) | (0xA000..=0xBFFF, true) => r[6] as usize,
+            (0xA000..=0xBFFF, false) | (0xC000..=0xDFFF, true) => r[7] as usize,
+            (0xC000..=0xDFFF, false) | (0x8000..=0x9FFF, true) => r[15] as usize,
+            _ => banks.saturating_sub(1),
+        };
+        // images under 8KB wrap within themselves
+        ((bank % banks.max(1)) * 0x2000 + (addr as usize & 0x1FFF)) % self.prg_rom.len()
+    }
+
+    // Bank register selected for the 1KB CHR page containing `addr`, with the
+    // low bit resolved for R0/R1 when they select 2KB
+    fn chr_bank(&self, addr: u16) -> u8 {
+        let addr = if self.bank_select & 0x80 != 0 {
+            addr ^ 0x1000
+        } else {
+            addr
+        };
+        let r = &self.registers;
+        match (addr / 0x400, self.bank_select & 0x20 != 0) {
+            (0, false) => r[0] & 0xFE,
+            (1, false) => r[0] | 1,
+            (2, false) => r[1] & 0xFE,
+            (3, false) => r[1] | 1,
+            (0, true) => r[0],
+            (1, true) => r[8],
+            (2, true) => r[1],
+            (3, true) => r[9],
+            (n, _) => r[n as usize - 2],
+        }
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        (self.chr_bank(addr) as usize * 0x400 + (addr as usize & 0x03FF)) % self.chr.len()
+    }
+
+    // A reload counts one past the latch, two for latches above 1, and the
+    // counter then runs down from there to 0
+    fn clock_irq_counter(&mut self, delay: u8) {
+        if self.irq_reload {
+            let extra = if self.irq_latch <= 1 { 1 } else { 2 };
+            self.irq_counter = self.irq_latch.wrapping_add(extra);
+            self.irq_reload = false;
+        } else if self.irq_counter == 0 {
+            self.irq_counter = self.irq_latch.wrapping_add(1);
+        }
+        self.irq_counter = self.irq_counter.wrapping_sub(1);
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_delay = delay;
+        }
+    }
+}
+
+impl Mapper for Rambo1 {
+    fn peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000..=0xFFFF => Some(self.prg_rom[self.prg_offset(addr)]),
+            _ => None,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xFFFF => Some(self.prg_offset(addr)),
+            _ => None,
+        }
+    }
+
+    fn prg_rom(&self) -> &[u8] {
+        &self.prg_rom
+    }
+
+    fn chr_backing(&self, addr: u16) -> Option<Backing> {
+        let offset = self.chr_offset(addr);
+        Some(if self.chr_writable {
+            Backing::ChrRam(offset)
+        } else {
+            Backing::ChrRom(offset)
+        })
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match (addr, addr & 1) {
+            (0x8000..=0x9FFF, 0) => self.bank_select = value,
+            (0x8000..=0x9FFF, _) => self.registers[self.bank_select as usize & 0x0F] = value,
+            (0xA000..=0xBFFF, 0) => {
+                self.mirroring = if value & 1 == 0 {
+                    Mirroring::Vertical
+                } else {
+                    Mirroring::Horizontal
+                }
+            }
+            (0xC000..=0xDFFF, 0) => self.irq_latch = value,
+            (0xC000..=0xDFFF, _) => {
+                let cycle_mode = value & 1 != 0;
+                if self.cycle_mode && !cycle_mode {
+                    self.extra_clock = true;
+                }
+                if cycle_mode {
+                    self.prescaler = 0;
+                }
+                self.cycle_mode = cycle_mode;
+                self.irq_reload = true;
+            }
+            (0xE000..=0xFFFF, 0) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+                self.irq_delay = 0;
+            }
+            (0xE000..=0xFFFF, _) => self.irq_enabled = true,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        if self.chr_writable {
+            let i = self.chr_offset(addr);
+            self.chr[i] = value;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn irq_ack(&mut self) {
+        self.irq_pending = false;
+    }
+
+    fn cpu_clock(&mut self) {
+        if self.irq_delay != 0 {
+            self.irq_delay -= 1;
+            if self.irq_delay == 0 {
+                self.irq_pending = true;
+            }
+        }
+        if self.cycle_mode || self.extra_clock {
+            self.prescaler = (self.prescaler + 1) & 0x03;
+            if self.prescaler == 0 {
+                self.extra_clock = false;
+                self.clock_irq_counter(CYCLE_IRQ_DELAY);
+            }
+        }
+    }
+
+    fn ppu_a12_rise(&mut self) {
+        if !self.cycle_mode {
+            self.clock_irq_counter(A12_IRQ_DELAY);
+        }
+    }
+
+    fn watches_ppu(&self) -> bool {
+        true
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        if self.chr_writable {
+            w.write_bytes(&self.chr);
+        }
+        w.write_u8(self.bank_select);
+        w.write_bytes(&self.registers);
+        w.write_u8(self.mirroring.into());
+        w.write_u8(self.irq_latch);
+        w.write_u8(self.irq_counter);
+        w.write_bool(self.irq_reload);
+        w.write_bool(self.irq_enabled);
+        w.write_bool(self.irq_pending);
+        w.write_bool(self.cycle_mode);
+        w.write_u8(self.prescaler);
+        w.write_bool(self.extra_clock);
+        w.write_u8(self.irq_delay);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        if self.chr_writable {
+            r.read_bytes_into(&mut self.chr)?;
+        }
+        self.bank_select = r.read_u8()?;
+        r.read_bytes_into(&mut self.registers)?;
+        self.mirroring = read_hv_mirroring(r)?;
+        self.irq_latch = r.read_u8()?;
+        self.irq_counter = r.read_u8()?;
+        self.irq_reload = r.read_bool()?;
+        self.irq_enabled = r.read_bool()?;
+        self.irq_pending = r.read_bool()?;
+        self.cycle_mode = r.read_bool()?;
+        self.prescaler = r.read_u8()?;
+        self.extra_clock = r.read_bool()?;
+        self.irq_delay = r.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Each 1KB of ROM is filled with its own bank number
+    fn rom(kb: usize) -> Vec<u8> {
+        (0..kb).flat_map(|b| vec![b as u8; 0x400]).collect()
+    }
+
+    fn rambo1() -> Rambo1 {
+        Rambo1::new(rom(128), rom(256), 0x2000)
+    }
+
+    #[test]
+    fn test_prg_banks() {
+        let mut m = rambo1();
+        for (register, bank) in [(6, 2), (7, 3), (15, 4)] {
+            m.write(0x8000, register);
+            m.write(0x8001, bank);
+        }
+        let banks = |m: &Rambo1| -> Vec<u8> {
+            [0x8000, 0xA000, 0xC000, 0xE000]
+                .iter()
+                .map(|&a| m.peek(a).unwrap() / 8)
+                .collect()
+        };
+        assert_eq!(banks(&m), [2, 3, 4, 15]);
+
+        // PRG mode 1 rotates the three switchable banks
+        m.write(0x8000, 0x40);
+        assert_eq!(banks(&m), [4, 2, 3, 15]);
+    }
+
+    #[test]
+    fn test_chr_banks() {
+        let mut m = rambo1();
+        for (register, bank) in [(0, 0x10), (1, 0x20), (8, 0x31), (9, 0x41), (2, 0x50)] {
+            m.write(0x8000, register);
+            m.write(0x8001, bank);
+        }
+        let banks =
+            |m: &mut Rambo1| -> Vec<u8> { (0..5).map(|page| m.ppu_read(page * 0x400)).collect() };
+        assert_eq!(banks(&mut m), [0x10, 0x11, 0x20, 0x21, 0x50]);
+
+        // 1KB mode brings in R8 and R9
+        m.write(0x8000, 0x20);
+        assert_eq!(banks(&mut m), [0x10, 0x31, 0x20, 0x41, 0x50]);
+
+        // CHR A12 inversion
+        m.write(0x8000, 0xA0);
+        assert_eq!(m.ppu_read(0x1000), 0x10);
+        assert_eq!(m.ppu_read(0x1400), 0x31);
+        assert_eq!(m.ppu_read(0x0000), 0x50);
+    }
+
+    #[test]
+    fn test_irq_scanlines() {
+        #[rustfmt::skip]
+        let cases = [
+            ("latch 0", 0, 1),
+            ("latch 1", 1, 2),
+            ("latch 2", 2, 4),
+            ("latch 5", 5, 7),
+        ];
+
+        for (name, latch, rises) in cases {
+            let mut m = rambo1();
+            m.write(0xC000, latch);
+            m.write(0xC001, 0);
+            m.write(0xE001, 0);
+            for _ in 0..rises {
+                m.cpu_clock();
+                assert!(!m.irq_pending(), "{}", name);
+                m.ppu_a12_rise();
+            }
+            // raised 2 CPU cycles later
+            m.cpu_clock();
+            assert!(!m.irq_pending(), "{}", name);
+            m.cpu_clock();
+            assert!(m.irq_pending(), "{}", name);
+
+            // and again every latch + 1 scanlines
+            m.irq_ack();
+            for _ in 0..latch as usize + 1 {
+                m.ppu_a12_rise();
+            }
+            m.cpu_clock();
+            m.cpu_clock();
+            assert!(m.irq_pending(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_irq_cycles() {
+        let mut m = rambo1();
+        m.write(0xC000, 2);
+        m.write(0xC001, 1);
+        m.write(0xE001, 0);
+        // A12 doesn't count in this mode
+        m.ppu_a12_rise();
+
+        // clocked every 4 cycles, from 4 down, raised a cycle after reaching 0
+        for _ in 0..16 {
+            m.cpu_clock();
+        }
+        assert!(!m.irq_pending());
+        m.cpu_clock();
+        assert!(m.irq_pending());
+
+        // disabling acknowledges
+        m.write(0xE000, 0);
+        assert!(!m.irq_pending());
+    }
+
+    #[test]
+    fn test_leaving_cycle_mode() {
+        let mut m = rambo1();
+        m.write(0xC000, 8);
+        m.write(0xC001, 1);
+        for _ in 0..6 {
+            m.cpu_clock();
+        }
+        assert_eq!(m.irq_counter, 9);
+
+        // the prescaler still clocks the reload through
+        m.write(0xC001, 0);
+        m.cpu_clock();
+        assert!(m.irq_reload);
+        m.cpu_clock();
+        assert_eq!((m.irq_reload, m.irq_counter), (false, 9));
+        for _ in 0..8 {
+            m.cpu_clock();
+        }
+        assert_eq!(m.irq_counter, 9);
+        m.ppu_a12_rise();
+        assert_eq!(m.irq_counter, 8);
+    }
+}
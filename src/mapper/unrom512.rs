@@ -0,0 +1,293 @@
+use super::*;
+
+// Mapper 30: 16KB switchable + 16KB fixed PRG, 4 x 8KB CHR-RAM banks and an optional
+// one-screen mirroring select. Self-flashable boards carry an SST39SF040 instead of
+// a mask ROM, which the game can program through the JEDEC command sequence.
+#[derive(Debug)]
+pub(crate) struct Unrom512 {
+    prg: Vec<u8>,
+    chr_ram: Vec<u8>,
+    mirroring: Mirroring,
+    one_screen: bool,
+    flashable: bool,
+
+    bank: u8,
+    flash: Flash,
+    software_id: bool,
+}
+
+// JEDEC command sequence progress
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Flash {
+    Ready,
+    Unlock1,
+    Unlock2,
+    Program,
+    Erase1,
+    Erase2,
+    Erase3,
+}
+
+impl From<Flash> for u8 {
+    fn from(f: Flash) -> Self {
+        f as u8
+    }
+}
+
+impl From<u8> for Flash {
+    fn from(v: u8) -> Self {
+        match v {
+            1 => Flash::Unlock1,
+            2 => Flash::Unlock2,
+            3 => Flash::Program,
+            4 => Flash::Erase1,
+            5 => Flash::Erase2,
+            6 => Flash::Erase3,
+            _ => Flash::Ready,
+        }
+    }
+}
+
+impl Unrom512 {
+    pub(crate) fn new(
+        prg_rom: Vec<u8>,
+        chr_ram_size: usize,
+        mirroring: Mirroring,
+        one_screen: bool,
+        flashable: bool,
+    ) -> Self {
+        Self {
+            prg: prg_rom,
+            chr_ram: vec![0; chr_ram_size.max(0x2000)],
+            mirroring,
+            one_screen,
+            flashable,
+            bank: 0,
+            flash: Flash::Ready,
+            software_id: false,
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let banks = self.prg.len() / 0x4000;
+        let bank = match addr {
+            0x8000..=0xBFFF => (self.bank & 0x1F) as usize,
+            _ => banks.saturating_sub(1),
+        };
+        // images under 16KB wrap within themselves
+        ((bank % banks.max(1)) * 0x4000 + (addr as usize & 0x3FFF)) % self.prg.len()
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        (((self.bank >> 5) & 0x03) as usize * 0x2000 + (addr as usize & 0x1FFF))
+            % self.chr_ram.len()
+    }
+
+    fn write_flash(&mut self, addr: u16, value: u8) {
+        let offset = self.prg_offset(addr);
+        // flash address lines A0-A13 come from the CPU, A14-A18 from the bank register
+        let cmd_addr = offset & 0x7FFF;
+
+        if value == 0xF0 {
+            self.flash = Flash::Ready;
+            self.software_id = false;
+            return;
+        }
+
+        self.flash = match (self.flash, cmd_addr, value) {
+            (Flash::Ready, 0x5555, 0xAA) => Flash::Unlock1,
+            (Flash::Unlock1, 0x2AAA, 0x55) => Flash::Unlock2,
+            (Flash::Unlock2, 0x5555, 0xA0) => Flash::Program,
+            (Flash::Unlock2, 0x5555, 0x80) => Flash::Erase1,
+            (Flash::Unlock2, 0x5555, 0x90) => {
+                self.software_id = true;
+                Flash::Ready
+            }
+            (Flash::Program, _, _) => {
+                // programming can only clear bits
+                self.prg[offset] &= value;
+                Flash::Ready
+            }
+            (Flash::Erase1, 0x5555, 0xAA) => Flash::Erase2,
+            (Flash::Erase2, 0x2AAA, 0x55) => Flash::Erase3,
+            (Flash::Erase3, 0x5555, 0x10) => {
+                self.prg.iter_mut().for_each(|b| *b = 0xFF);
+                Flash::Ready
+            }
+            (Flash::Erase3, _, 0x30) => {
+                let sector = offset & !0x0FFF;
+                let end = (sector + 0x1000).min(self.prg.len());
+                self.prg[sector..end].iter_mut().for_each(|b| *b = 0xFF);
+                Flash::Ready
+            }
+            _ => Flash::Ready,
+        };
+    }
+}
+
+impl Mapper for Unrom512 {
+    fn peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000..=0xFFFF if self.software_id => {
+                // SST manufacturer ID / SST39SF040 device ID
+                Some(if addr & 1 == 0 { 0xBF } else { 0xB7 })
+            }
+            0x8000..=0xFFFF => Some(self.prg[self.prg_offset(addr)]),
+            _ => None,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xFFFF if !self.software_id => Some(self.prg_offset(addr)),
+            _ => None,
+        }
+    }
+
+    fn prg_rom(&self) -> &[u8] {
+        &self.prg
+    }
+
+    fn chr_backing(&self, addr: u16) -> Option<Backing> {
+        Some(Backing::ChrRam(self.chr_offset(addr)))
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x8000..=0xBFFF if self.flashable => self.write_flash(addr, value),
+            0x8000..=0xFFFF => self.bank = value,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr_ram[self.chr_offset(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        let i = self.chr_offset(addr);
+        self.chr_ram[i] = value;
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if !self.one_screen {
+            self.mirroring
+        } else if self.bank & 0x80 == 0 {
+            Mirroring::SingleScreenLower
+        } else {
+            Mirroring::SingleScreenUpper
+        }
+    }
+
+    // Non-flashable boards don't decode the register and ROM enables separately
+    fn bus_conflicts(&self) -> bool {
+        !self.flashable
+    }
+
+    fn nvram(&self) -> Option<&[u8]> {
+        if self.flashable {
+            Some(&self.prg)
+        } else {
+            None
+        }
+    }
+
+    fn nvram_mut(&mut self) -> Option<&mut [u8]> {
+        if self.flashable {
+            Some(&mut self.prg)
+        } else {
+            None
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        if self.flashable {
+            w.write_bytes(&self.prg);
+        }
+        w.write_bytes(&self.chr_ram);
+        w.write_u8(self.bank);
+        w.write_u8(self.flash.into());
+        w.write_bool(self.software_id);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        if self.flashable {
+            r.read_bytes_into(&mut self.prg)?;
+        }
+        r.read_bytes_into(&mut self.chr_ram)?;
+        self.bank = r.read_u8()?;
+        self.flash = Flash::from(r.read_u8()?);
+        self.software_id = r.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Each 16KB bank of a 512KB flash is filled with its own bank number
+    fn prg() -> Vec<u8> {
+        (0..32).flat_map(|b| vec![b as u8; 0x4000]).collect()
+    }
+
+    // Issue a JEDEC command byte at a flash address through the bank register
+    fn command(m: &mut Unrom512, flash_addr: usize, value: u8) {
+        m.write(0xC000, (flash_addr >> 14) as u8);
+        m.write(0x8000 | (flash_addr as u16 & 0x3FFF), value);
+    }
+
+    #[test]
+    fn test_banks() {
+        let mut m = Unrom512::new(prg(), 0x8000, Mirroring::Vertical, true, false);
+        m.write(0x8000, 0b1110_0011);
+
+        assert_eq!(m.read(0x8000), Some(3));
+        assert_eq!(m.read(0xC000), Some(31));
+        assert_eq!(m.mirroring(), Mirroring::SingleScreenUpper);
+
+        m.ppu_write(0x0010, 0x55);
+        assert_eq!(m.chr_ram[3 * 0x2000 + 0x10], 0x55);
+    }
+
+    #[test]
+    fn test_flash_program_and_erase() {
+        let mut m = Unrom512::new(prg(), 0x8000, Mirroring::Vertical, false, true);
+
+        // sector erase at bank 2
+        command(&mut m, 0x5555, 0xAA);
+        command(&mut m, 0x2AAA, 0x55);
+        command(&mut m, 0x5555, 0x80);
+        command(&mut m, 0x5555, 0xAA);
+        command(&mut m, 0x2AAA, 0x55);
+        m.write(0xC000, 2);
+        m.write(0x8123, 0x30);
+        assert_eq!(m.read(0x8000), Some(0xFF));
+        assert_eq!(m.read(0x8FFF), Some(0xFF));
+        assert_eq!(m.read(0x9000), Some(2));
+
+        // program a byte
+        command(&mut m, 0x5555, 0xAA);
+        command(&mut m, 0x2AAA, 0x55);
+        command(&mut m, 0x5555, 0xA0);
+        m.write(0xC000, 2);
+        m.write(0x8123, 0x3C);
+        assert_eq!(m.read(0x8123), Some(0x3C));
+
+        assert_eq!(m.nvram().unwrap()[2 * 0x4000 + 0x123], 0x3C);
+    }
+
+    #[test]
+    fn test_software_id() {
+        let mut m = Unrom512::new(prg(), 0x8000, Mirroring::Vertical, false, true);
+        command(&mut m, 0x5555, 0xAA);
+        command(&mut m, 0x2AAA, 0x55);
+        command(&mut m, 0x5555, 0x90);
+        assert_eq!(m.read(0x8000), Some(0xBF));
+        assert_eq!(m.read(0x8001), Some(0xB7));
+
+        m.write(0x8000, 0xF0);
+        // bank 1 is still selected from the last command
+        assert_eq!(m.read(0x8000), Some(1));
+    }
+}
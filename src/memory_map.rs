@@ -0,0 +1,182 @@
+// Description of what backs each range of the CPU and PPU address spaces right
+// now, following the board's current banks, PRG-RAM enable and mirroring. The
+// spaces are looked at a page (256 bytes) at a time, finer than any board
+// switches banks, and consecutive pages continuing the same memory are merged.
+
+use crate::mapper::Mapper;
+use crate::nes::Nes;
+use crate::ppu;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    // The console's 2KB of RAM, from an offset
+    Ram(usize),
+    PpuRegisters,
+    // APU, OAM DMA and controller registers
+    ApuIo,
+    PrgRom(usize),
+    PrgRam(usize),
+    // Answered by the board, but not from its memory (registers, IDs)
+    Cartridge,
+    // Nothing drives the data bus
+    OpenBus,
+    ChrRom(usize),
+    ChrRam(usize),
+    // Nametable RAM, in the console or on four-screen boards, from an offset
+    Nametable(usize),
+    Palette,
+}
+
+impl Backing {
+    fn offset(self) -> Option<usize> {
+        match self {
+            Backing::Ram(o)
+            | Backing::PrgRom(o)
+            | Backing::PrgRam(o)
+            | Backing::ChrRom(o)
+            | Backing::ChrRam(o)
+            | Backing::Nametable(o) => Some(o),
+            _ => None,
+        }
+    }
+
+    // Whether `next`, `len` bytes further on, picks up where this left off
+    fn continues(self, len: usize, next: Backing) -> bool {
+        if std::mem::discriminant(&self) != std::mem::discriminant(&next) {
+            return false;
+        }
+        match (self.offset(), next.offset()) {
+            (Some(a), Some(b)) => a + len == b,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRange {
+    pub start: u16,
+    pub end: u16,
+    pub backing: Backing,
+}
+
+pub(crate) fn cpu_map(nes: &Nes) -> Vec<MemoryRange> {
+    // $4020 splits the page of the APU registers from the cartridge
+    let starts = (0..=0xFF).map(|p| p << 8).chain(Some(0x4020));
+    map(starts, 0xFFFF, |addr| cpu_backing(nes, addr))
+}
+
+pub(crate) fn cpu_backing(nes: &Nes, addr: u16) -> Backing {
+    match addr {
+        0x0000..=0x1FFF => Backing::Ram(addr as usize & 0x07FF),
+        0x2000..=0x3FFF => Backing::PpuRegisters,
+        0x4000..=0x401F => Backing::ApuIo,
+        _ => {
+            if let Some(o) = nes.mapper.prg_rom_offset(addr) {
+                Backing::PrgRom(o)
+            } else if let Some(o) = nes.mapper.prg_ram_offset(addr) {
+                Backing::PrgRam(o)
+            } else if nes.mapper.peek(addr).is_some() {
+                Backing::Cartridge
+            } else {
+                Backing::OpenBus
+            }
+        }
+    }
+}
+
+pub(crate) fn ppu_map(nes: &Nes) -> Vec<MemoryRange> {
+    let mirroring = ppu::mirroring(nes);
+    map((0..=0x3F).map(|p| p << 8), 0x3FFF, |addr| match addr {
+        0x0000..=0x1FFF => nes.mapper.chr_backing(addr).unwrap_or(Backing::OpenBus),
+        0x2000..=0x3EFF => Backing::Nametable(ppu::nametable_addr(mirroring, addr)),
+        _ => Backing::Palette,
+    })
+}
+
+// Split a space ending at `last` into pieces at `starts`, in any order
+fn map<I, F>(starts: I, last: u16, backing: F) -> Vec<MemoryRange>
+where
+    I: Iterator<Item = u16>,
+    F: Fn(u16) -> Backing,
+{
+    let mut starts: Vec<u16> = starts.collect();
+    starts.sort_unstable();
+    let mut ranges: Vec<MemoryRange> = Vec::new();
+    for (i, &start) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).map_or(last, |&next| next - 1);
+        let b = backing(start);
+        match ranges.last_mut() {
+            Some(r)
+                if r.backing
+                    .continues(r.end as usize + 1 - r.start as usize, b) =>
+            {
+                r.end = end
+            }
+            _ => ranges.push(MemoryRange {
+                start,
+                end,
+                backing: b,
+            }),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Emu;
+
+    #[test]
+    fn test_cpu_map() {
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+
+        #[rustfmt::skip]
+        let expected = [
+            (0x0000, 0x07FF, Backing::Ram(0)),
+            (0x0800, 0x0FFF, Backing::Ram(0)),
+            (0x1000, 0x17FF, Backing::Ram(0)),
+            (0x1800, 0x1FFF, Backing::Ram(0)),
+            (0x2000, 0x3FFF, Backing::PpuRegisters),
+            (0x4000, 0x401F, Backing::ApuIo),
+            (0x4020, 0x5FFF, Backing::OpenBus),
+            (0x6000, 0x7FFF, Backing::PrgRam(0)),
+            // 16KB of PRG-ROM, mirrored
+            (0x8000, 0xBFFF, Backing::PrgRom(0)),
+            (0xC000, 0xFFFF, Backing::PrgRom(0)),
+        ];
+        let map: Vec<(u16, u16, Backing)> = emu
+            .cpu_memory_map()
+            .iter()
+            .map(|r| (r.start, r.end, r.backing))
+            .collect();
+        assert_eq!(map, expected);
+    }
+
+    #[test]
+    fn test_ppu_map() {
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+
+        // nestest is horizontally mirrored, which happens to put $2400-$2BFF
+        // in one piece of CIRAM
+        #[rustfmt::skip]
+        let expected = [
+            (0x0000, 0x1FFF, Backing::ChrRom(0)),
+            (0x2000, 0x23FF, Backing::Nametable(0)),
+            (0x2400, 0x2BFF, Backing::Nametable(0)),
+            (0x2C00, 0x2FFF, Backing::Nametable(0x400)),
+            (0x3000, 0x33FF, Backing::Nametable(0)),
+            (0x3400, 0x3BFF, Backing::Nametable(0)),
+            (0x3C00, 0x3EFF, Backing::Nametable(0x400)),
+            (0x3F00, 0x3FFF, Backing::Palette),
+        ];
+        let map: Vec<(u16, u16, Backing)> = emu
+            .ppu_memory_map()
+            .iter()
+            .map(|r| (r.start, r.end, r.backing))
+            .collect();
+        assert_eq!(map, expected);
+    }
+}
@@ -0,0 +1,191 @@
+use std::fmt;
+
+use anyhow::Result;
+
+use crate::state::{StateReader, StateWriter};
+
+// Movie file layout: "KNMV", u16 format version, SHA-1 of the ROM, u32 frame count,
+// then 5 bytes per frame: the states of all four controllers and a flags byte.
+// Version 1 only had the first two controllers, 3 bytes per frame.
+const MAGIC: &[u8; 4] = b"KNMV";
+const VERSION: u16 = 2;
+
+const FLAG_RESET: u8 = 1;
+const FLAG_FOUR_SCORE: u8 = 2;
+
+// Input of one frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MovieFrame {
+    // Buttons held on each standard controller, one bit per `Button`; 3 and 4
+    // are behind the Four Score or on the expansion port
+    pub controllers: [u8; 4],
+    // The Four Score was plugged in
+    pub four_score: bool,
+    // The reset button was pressed right before the frame
+    pub reset: bool,
+}
+
+// Controller input recorded frame by frame from power-on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    rom_hash: [u8; 20],
+    frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    pub(crate) fn new(rom_hash: [u8; 20]) -> Self {
+        Self {
+            rom_hash,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn rom_hash(&self) -> &[u8; 20] {
+        &self.rom_hash
+    }
+
+    pub fn frames(&self) -> &[MovieFrame] {
+        &self.frames
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub(crate) fn push(&mut self, frame: MovieFrame) {
+        self.frames.push(frame);
+    }
+
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.frames.truncate(len);
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.write_raw(MAGIC);
+        w.write_u16(VERSION);
+        w.write_raw(&self.rom_hash);
+        w.write_u32(self.frames.len() as u32);
+        for f in &self.frames {
+            w.write_raw(&f.controllers);
+            let mut flags = 0;
+            if f.reset {
+                flags |= FLAG_RESET;
+            }
+            if f.four_score {
+                flags |= FLAG_FOUR_SCORE;
+            }
+            w.write_u8(flags);
+        }
+        w.into_inner()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let mut r = StateReader::new(data);
+        if r.read_raw(4).ok() != Some(MAGIC) {
+            return Err(MovieError::new("not a movie").into());
+        }
+        let version = r.read_u16()?;
+        if VERSION < version {
+            return Err(MovieError::new(&format!("unsupported movie version {}", version)).into());
+        }
+        let mut rom_hash = [0; 20];
+        rom_hash.copy_from_slice(r.read_raw(20)?);
+
+        let len = r.read_u32()? as usize;
+        let pads = if version < 2 { 2 } else { 4 };
+        // don't trust the count for the allocation
+        let mut frames = Vec::with_capacity(len.min(data.len() / (pads + 1)));
+        for _ in 0..len {
+            let mut controllers = [0; 4];
+            controllers[..pads].copy_from_slice(r.read_raw(pads)?);
+            let flags = r.read_u8()?;
+            frames.push(MovieFrame {
+                controllers,
+                four_score: flags & FLAG_FOUR_SCORE != 0,
+                reset: flags & FLAG_RESET != 0,
+            });
+        }
+        Ok(Self { rom_hash, frames })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct MovieError {
+    msg: String,
+}
+
+impl MovieError {
+    pub(crate) fn new(msg: &str) -> Self {
+        Self {
+            msg: msg.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "movie error: {}", self.msg)
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut movie = Movie::new([7; 20]);
+        movie.push(MovieFrame {
+            controllers: [0x01, 0x80, 0x10, 0x08],
+            four_score: true,
+            reset: false,
+        });
+        movie.push(MovieFrame {
+            controllers: [0x00, 0x00, 0x00, 0x00],
+            four_score: false,
+            reset: true,
+        });
+        let bytes = movie.to_bytes();
+        assert_eq!(bytes.len(), 4 + 2 + 20 + 4 + 2 * 5);
+        assert_eq!(Movie::from_bytes(&bytes).unwrap(), movie);
+
+        let mut newer = bytes.clone();
+        newer[4] = 3;
+        #[rustfmt::skip]
+        let cases = [
+            ("bad magic", &b"KNSS"[..],              "movie error: not a movie"),
+            ("newer",     &newer[..],                "movie error: unsupported movie version 3"),
+            ("truncated", &bytes[..bytes.len() - 1], "save state error: unexpected end of state"),
+        ];
+
+        for (name, data, expected) in cases {
+            let err = Movie::from_bytes(data).unwrap_err();
+            assert_eq!(err.to_string(), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_version_1() {
+        let mut bytes = b"KNMV\x01\x00".to_vec();
+        bytes.extend([7; 20]);
+        bytes.extend(1u32.to_le_bytes());
+        bytes.extend([0x01, 0x80, FLAG_RESET]);
+
+        let movie = Movie::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            movie.frames(),
+            [MovieFrame {
+                controllers: [0x01, 0x80, 0x00, 0x00],
+                four_score: false,
+                reset: true,
+            }]
+        );
+    }
+}
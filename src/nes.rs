@@ -1,51 +1,693 @@
 use crate::cpu::{Cpu, CpuBus};
-use crate::mapper::{Empty, Mapper};
+use anyhow::Result;
+
+use crate::apu::{self, Apu, Meter};
+use crate::audio::Resampler;
+use crate::cheat::{self, Cheat};
+use crate::config::Region;
+use crate::event_log::EventLog;
+use crate::hook::{self, Event, Hooks};
+use crate::input::{self, Input};
+use crate::mapper::{self, bus_conflict, Board, Empty, Mapper};
+use crate::ppu::{self, Ppu};
+use crate::profiler::Profile;
+use crate::rom::Cartridge;
+use crate::self_check::SelfCheck;
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::trace;
 
 #[derive(Debug)]
 pub(crate) struct Nes {
     pub(crate) cpu: Cpu,
-    pub(crate) wram: [u8; 0x07FF],
+    pub(crate) wram: [u8; 0x0800],
     pub(crate) cpu_cycles: u128,
+    pub(crate) irq: Irq,
+    // Set on the PPU's NMI output going high, cleared when the CPU takes it
+    pub(crate) nmi: bool,
+    // Whether the CPU took an NMI and an IRQ, since `Emu` last cleared them
+    pub(crate) took_nmi: bool,
+    pub(crate) took_irq: bool,
+    // Page written to $4014, copied to OAM before the next CPU cycle
+    pub(crate) oam_dma: Option<u8>,
+    // Last value driven on the CPU data bus
+    pub(crate) open_bus: u8,
+
+    // 2KB of nametable RAM (CIRAM) inside the console, plus 2KB on the cartridge
+    // for four-screen boards
+    pub(crate) nametables: Vec<u8>,
+    // the cartridge's extra RAM overrides mapper mirroring control
+    pub(crate) four_screen: bool,
 
-    pub(crate) mapper: Box<dyn Mapper>,
+    pub(crate) region: Region,
+    pub(crate) ppu: Ppu,
+    pub(crate) apu: Apu,
+    pub(crate) audio: Resampler,
+    pub(crate) input: Input,
+    // Skip producing pixels and samples, which the game can't observe
+    pub(crate) headless: bool,
+    // Game Genie patches of PRG reads
+    pub(crate) cheats: Vec<Cheat>,
+    pub(crate) hooks: Hooks,
+    pub(crate) profile: Option<Box<Profile>>,
+    pub(crate) event_log: Option<Box<EventLog>>,
+    pub(crate) meter: Option<Box<Meter>>,
+    pub(crate) self_check: Option<Box<SelfCheck>>,
+    // Where the game prints, and what it printed, the oldest dropped past
+    // `DEBUG_OUTPUT_LIMIT`
+    pub(crate) debug_port: Option<u16>,
+    pub(crate) debug_output: Vec<u8>,
+    // PPU dots owed to the PPU in fifths, as PAL runs 3.2 dots per CPU cycle
+    ppu_fraction: u8,
+    // Whole dots the PPU is behind the CPU, run by `catch_up_ppu` once there are
+    // `ppu_slack` of them or something is about to look. Always 0 by the time
+    // `Emu` returns, so neither is part of save states.
+    ppu_debt: u32,
+    ppu_slack: u32,
+    // Scanlines added after the picture, where the CPU runs while the PPU and
+    // APU wait, and the dots of them left this frame
+    pub(crate) overclock_lines: u16,
+    pub(crate) overclock_idle: u32,
+
+    pub(crate) mapper: Board,
+    // Where each 4KB window of $8000-$FFFF starts in the board's PRG-ROM, for
+    // windows mapped to 4KB of it in a row; the rest are read through the board.
+    // Only writes to $8000-$FFFF refresh them, so work RAM stores stay cheap
+    prg_banks: [Option<usize>; 8],
+}
+
+impl Default for Nes {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Nes {
-    #[allow(dead_code)]
     pub(crate) fn new() -> Self {
         Self {
             cpu: Default::default(),
-            wram: [0; 0x07FF],
+            wram: [0; 0x0800],
             cpu_cycles: 0,
-            mapper: Box::new(Empty {}),
+            irq: Default::default(),
+            nmi: false,
+            took_nmi: false,
+            took_irq: false,
+            oam_dma: None,
+            open_bus: 0,
+            nametables: vec![0; 0x800],
+            four_screen: false,
+            region: Region::Ntsc,
+            ppu: Default::default(),
+            apu: Default::default(),
+            audio: Default::default(),
+            input: Default::default(),
+            headless: false,
+            cheats: Vec::new(),
+            hooks: Default::default(),
+            profile: None,
+            event_log: None,
+            meter: None,
+            self_check: None,
+            debug_port: None,
+            debug_output: Vec::new(),
+            ppu_fraction: 0,
+            ppu_debt: 0,
+            ppu_slack: 0,
+            overclock_lines: 0,
+            overclock_idle: 0,
+            mapper: Board::Empty(Empty {}),
+            prg_banks: [None; 8],
+        }
+    }
+
+    // Insert a cartridge, wiring up its board and nametable RAM
+    pub(crate) fn load_cartridge(&mut self, cart: &Cartridge, region: Region) -> Result<()> {
+        self.region = region;
+        self.ppu = Default::default();
+        self.ppu_slack = 0;
+        self.apu = Apu::new(region);
+        self.mapper = Board::new(cart)?;
+        self.refresh_prg_banks();
+        self.four_screen = mapper::four_screen(cart);
+        self.nametables = vec![0; if self.four_screen { 0x1000 } else { 0x800 }];
+        if let Some(trainer) = cart.trainer() {
+            self.load_trainer(trainer);
+        }
+        Ok(())
+    }
+
+    // Follow bank switches, and the board being replaced
+    pub(crate) fn refresh_prg_banks(&mut self) {
+        let len = self.mapper.prg_rom().len();
+        for (i, bank) in self.prg_banks.iter_mut().enumerate() {
+            let start = 0x8000 + i as u16 * 0x1000;
+            let offset = match self.mapper.prg_rom_offset(start) {
+                Some(offset)
+                    if offset + 0x1000 <= len
+                        && self.mapper.prg_rom_offset(start + 0xFFF) == Some(offset + 0xFFF) =>
+                {
+                    Some(offset)
+                }
+                _ => None,
+            };
+            if *bank != offset {
+                trace::bank_switch(start, offset);
+                *bank = offset;
+            }
+        }
+    }
+
+    // Machine-wide state outside the CPU, PPU, APU and RAM
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.region as u8);
+        w.write_u64(self.cpu_cycles as u64);
+        w.write_u8(self.irq.bits());
+        w.write_bool(self.nmi);
+        w.write_bool(self.oam_dma.is_some());
+        w.write_u8(self.oam_dma.unwrap_or(0));
+        w.write_u8(self.open_bus);
+        w.write_u8(self.ppu_fraction);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        if r.read_u8()? != self.region as u8 {
+            return Err(StateError {
+                msg: "state is for another region".to_string(),
+            }
+            .into());
+        }
+        self.cpu_cycles = r.read_u64()? as u128;
+        self.irq = Irq::from_bits_truncate(r.read_u8()?);
+        self.nmi = r.read_bool()?;
+        let dma = r.read_bool()?;
+        let page = r.read_u8()?;
+        self.oam_dma = if dma { Some(page) } else { None };
+        self.open_bus = r.read_u8()?;
+        self.ppu_fraction = r.read_u8()? % 5;
+        // the PPU is somewhere else now
+        self.ppu_slack = 0;
+        Ok(())
+    }
+
+    // Copy a 512-byte trainer to $7000-$71FF; must happen before reset
+    pub(crate) fn load_trainer(&mut self, trainer: &[u8]) {
+        if let Some(ram) = self.mapper.prg_ram_mut() {
+            if 0x1200 <= ram.len() {
+                ram[0x1000..0x1200].copy_from_slice(trainer);
+            }
         }
     }
 }
 
-#[derive(Debug)]
+bitflags! {
+    // Sources currently pulling the shared /IRQ line low
+    #[derive(Default)]
+    pub(crate) struct Irq: u8 {
+        const MAPPER = 1;
+        const FRAME_COUNTER = 1 << 1;
+        const DMC = 1 << 2;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub(crate) enum Mirroring {
     Horizontal,
     Vertical,
+    SingleScreenLower,
+    SingleScreenUpper,
+    // CIRAM page of each nametable, selected by the board
+    Custom([u8; 4]),
+    // four independent nametables using 2KB of cartridge RAM
+    FourScreen,
+}
+
+impl From<Mirroring> for u8 {
+    fn from(m: Mirroring) -> Self {
+        match m {
+            Mirroring::Horizontal => 0,
+            Mirroring::Vertical => 1,
+            Mirroring::SingleScreenLower => 2,
+            Mirroring::SingleScreenUpper => 3,
+            Mirroring::FourScreen => 4,
+            // not a register-selectable arrangement
+            Mirroring::Custom(_) => 0,
+        }
+    }
+}
+
+// Read the CPU address space as `SystemBus` does, without ticking or side effects
+pub(crate) fn peek(nes: &Nes, addr: u16) -> u8 {
+    let v = match addr {
+        0x0000..=0x1FFF => Some(nes.wram[addr as usize & 0x07FF]),
+        0x2000..=0x3FFF => Some(ppu::peek_register(nes, addr)),
+        0x4015 => return nes.apu.peek_status() | (nes.open_bus & 0x20),
+        0x4016 => Some(input::peek(nes, 0)),
+        0x4017 => Some(input::peek(nes, 1)),
+        0x4020..=0xFFFF => nes.mapper.peek(addr),
+        _ => None,
+    };
+    let v = v.unwrap_or(nes.open_bus);
+    if 0x8000 <= addr && !nes.cheats.is_empty() {
+        cheat::apply(&nes.cheats, addr, v)
+    } else {
+        v
+    }
+}
+
+// Change RAM or PRG-RAM; writes elsewhere are ignored
+pub(crate) fn poke(nes: &mut Nes, addr: u16, value: u8) {
+    match addr {
+        0x0000..=0x1FFF => nes.wram[addr as usize & 0x07FF] = value,
+        0x4020..=0xFFFF => nes.mapper.poke(addr, value),
+        _ => {}
+    }
 }
 
-pub(crate) struct Bus {}
+// The machine as the CPU sees it: the memory map, and a cycle of the PPU, APU
+// and board on every tick
+pub(crate) struct SystemBus {}
 
-impl CpuBus for Bus {
+// RAM and PRG-ROM take most accesses; without hooks or cheats to apply, they're
+// served straight from memory before the full decode
+impl CpuBus for SystemBus {
+    #[inline]
     fn read(nes: &mut Nes, addr: u16) -> u8 {
-        match addr {
-            0x0000..=0x07FF => nes.wram[addr as usize],
-            0x0800..=0x1FFF => nes.mapper.read(addr - 0x0800),
-            //TODO ppu, apu, controllers
-            _ => 0,
+        if nes.hooks.read.is_empty() && nes.cheats.is_empty() {
+            let v = match addr {
+                0x0000..=0x1FFF => Some(nes.wram[addr as usize & 0x07FF]),
+                0x8000..=0xFFFF => nes.prg_banks[(addr as usize >> 12) - 8]
+                    .map(|offset| nes.mapper.prg_rom()[offset + (addr as usize & 0xFFF)]),
+                _ => None,
+            };
+            if let Some(v) = v {
+                nes.open_bus = v;
+                return v;
+            }
         }
+        SystemBus::decode_read(nes, addr)
     }
 
+    #[inline]
     fn write(nes: &mut Nes, addr: u16, value: u8) {
+        if addr <= 0x1FFF && nes.hooks.write.is_empty() {
+            nes.open_bus = value;
+            nes.wram[addr as usize & 0x07FF] = value;
+            return;
+        }
+        SystemBus::decode_write(nes, addr, value)
+    }
+
+    fn tick(nes: &mut Nes) {
+        nes.cpu_cycles = nes.cpu_cycles.wrapping_add(1);
+
+        nes.ppu_fraction += if nes.region == Region::Pal { 16 } else { 15 };
+        let dots = (nes.ppu_fraction / 5) as u32;
+        nes.ppu_fraction %= 5;
+        if nes.overclock_idle != 0 {
+            // an extra scanline: time passes for the CPU and the board alone
+            let idle = dots.min(nes.overclock_idle);
+            nes.overclock_idle -= idle;
+            nes.ppu_debt += dots - idle;
+            nes.mapper.cpu_clock();
+            update_irq(nes);
+            return;
+        }
+        nes.ppu_debt += dots;
+        // events carry where the PPU is, so with anyone listening it keeps pace
+        if nes.ppu_slack <= nes.ppu_debt || nes.event_log.is_some() || !nes.hooks.event.is_empty() {
+            catch_up_ppu(nes);
+        }
+        apu::step(nes);
+        if !nes.headless {
+            let v = nes.apu.output();
+            nes.audio.push(v);
+        }
+        nes.mapper.cpu_clock();
+        update_irq(nes);
+    }
+
+    fn sync(nes: &mut Nes) {
+        catch_up_ppu(nes);
+    }
+}
+
+impl SystemBus {
+    // Everything past RAM and PRG-ROM may see or change what the PPU does, so it
+    // catches up first
+    #[inline(never)]
+    fn decode_read(nes: &mut Nes, addr: u16) -> u8 {
+        catch_up_ppu(nes);
+        let v = match addr {
+            // 2KB of RAM, mirrored three times
+            0x0000..=0x1FFF => nes.wram[addr as usize & 0x07FF],
+            0x2000..=0x3FFF => ppu::read_register(nes, addr),
+            // only bit 5 is left floating, and the read doesn't reach the data bus
+            0x4015 => return nes.apu.read_status() | (nes.open_bus & 0x20),
+            0x4016 => input::read(nes, 0),
+            0x4017 => input::read(nes, 1),
+            0x4020..=0x7FFF => nes.mapper.read(addr).unwrap_or(nes.open_bus),
+            0x8000..=0xFFFF => {
+                let v = match nes.prg_banks[(addr as usize >> 12) - 8] {
+                    Some(offset) => nes.mapper.prg_rom()[offset + (addr as usize & 0xFFF)],
+                    None => nes.mapper.read(addr).unwrap_or(nes.open_bus),
+                };
+                if nes.cheats.is_empty() {
+                    v
+                } else {
+                    cheat::apply(&nes.cheats, addr, v)
+                }
+            }
+            _ => nes.open_bus,
+        };
+        let v = if nes.hooks.read.is_empty() {
+            v
+        } else {
+            hook::on_read(nes, addr, v)
+        };
+        nes.open_bus = v;
+        v
+    }
+
+    #[inline(never)]
+    fn decode_write(nes: &mut Nes, addr: u16, value: u8) {
+        catch_up_ppu(nes);
+        let value = if nes.hooks.write.is_empty() {
+            value
+        } else {
+            hook::on_write(nes, addr, value)
+        };
+        nes.open_bus = value;
+        trace::register_write(nes, addr, value);
+        if let Some(log) = &mut nes.event_log {
+            log.write(nes.ppu.scanline, nes.ppu.dot, addr, value);
+        }
+        if nes.debug_port == Some(addr) {
+            debug_write(nes, value);
+        }
         match addr {
-            0x0000..=0x07FF => nes.wram[addr as usize] = value,
-            0x0800..=0x1FFF => nes.mapper.write(addr - 0x0800, value),
-            //TODO ppu, apu, controllers
+            0x0000..=0x1FFF => nes.wram[addr as usize & 0x07FF] = value,
+            0x2000..=0x3FFF => {
+                ppu::write_register(nes, addr, value);
+                // rendering may have started with a board watching it
+                nes.ppu_slack = 0;
+            }
+            0x4014 => nes.oam_dma = Some(value),
+            0x4016 => input::write_strobe(nes, value),
+            0x4000..=0x4013 | 0x4015 | 0x4017 => nes.apu.write_register(addr, value),
+            0x4020..=0x7FFF => nes.mapper.write(addr, value),
+            0x8000..=0xFFFF => {
+                let value = bus_conflict(&nes.mapper, addr, value);
+                nes.mapper.write(addr, value);
+                nes.refresh_prg_banks();
+            }
             _ => {}
         }
     }
 }
+
+// Keep the debug output to the most recent 64KB, for games printing with
+// nobody reading
+const DEBUG_OUTPUT_LIMIT: usize = 0x10000;
+
+fn debug_write(nes: &mut Nes, value: u8) {
+    if DEBUG_OUTPUT_LIMIT <= nes.debug_output.len() {
+        nes.debug_output.drain(..DEBUG_OUTPUT_LIMIT / 2);
+    }
+    nes.debug_output.push(value);
+}
+
+// Regions as the const parameter of `ppu::step`
+pub(crate) const NTSC: u8 = Region::Ntsc as u8;
+pub(crate) const PAL: u8 = Region::Pal as u8;
+pub(crate) const DENDY: u8 = Region::Dendy as u8;
+
+pub(crate) const fn region(id: u8) -> Region {
+    match id {
+        PAL => Region::Pal,
+        DENDY => Region::Dendy,
+        _ => Region::Ntsc,
+    }
+}
+
+// Run the dots the PPU is behind in one batch, then let the CPU see the IRQs
+// the board raised while they ran. Until the next batch, the PPU only reaches
+// the CPU through NMIs and the board's IRQs: it may fall behind up to where
+// either could change.
+pub(crate) fn catch_up_ppu(nes: &mut Nes) {
+    if nes.ppu_debt != 0 {
+        // the region picks the PPU's specialization once per batch, not per dot
+        match nes.region {
+            Region::Ntsc => run_ppu::<NTSC>(nes),
+            Region::Pal => run_ppu::<PAL>(nes),
+            Region::Dendy => run_ppu::<DENDY>(nes),
+        }
+        update_irq(nes);
+    }
+    nes.ppu_slack = if nes.mapper.watches_ppu() && nes.ppu.rendering() {
+        0
+    } else if nes.overclock_lines != 0 {
+        // the extra lines start right after the picture
+        let post_render = nes.ppu.dots_to(nes.region, ppu::HEIGHT as u16, 0);
+        nes.ppu.dots_to_vblank_change(nes.region).min(post_render)
+    } else {
+        nes.ppu.dots_to_vblank_change(nes.region)
+    };
+}
+
+fn run_ppu<const REGION: u8>(nes: &mut Nes) {
+    if nes.overclock_lines == 0 {
+        for _ in 0..std::mem::take(&mut nes.ppu_debt) {
+            ppu::step::<REGION>(nes);
+        }
+        return;
+    }
+    while nes.ppu_debt != 0 {
+        if nes.overclock_idle != 0 {
+            let idle = nes.ppu_debt.min(nes.overclock_idle);
+            nes.overclock_idle -= idle;
+            nes.ppu_debt -= idle;
+            continue;
+        }
+        nes.ppu_debt -= 1;
+        ppu::step::<REGION>(nes);
+        if nes.ppu.scanline == ppu::HEIGHT as u16 && nes.ppu.dot == 0 {
+            nes.overclock_idle = nes.overclock_lines as u32 * ppu::DOTS_PER_LINE as u32;
+        }
+    }
+}
+
+fn update_irq(nes: &mut Nes) {
+    let mapper_irq = nes.mapper.irq_pending();
+    if mapper_irq && !nes.irq.contains(Irq::MAPPER) {
+        hook::emit(nes, Event::MapperIrq);
+    }
+    nes.irq.set(Irq::MAPPER, mapper_irq);
+    nes.irq.set(Irq::FRAME_COUNTER, nes.apu.frame_irq);
+    nes.irq.set(Irq::DMC, nes.apu.dmc_irq());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::ppu::{read_nametable, write_nametable};
+
+    #[test]
+    fn test_four_screen() {
+        #[rustfmt::skip]
+        let mut rom = vec![
+            // MMC3, four-screen
+            0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0b0100_1000, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        rom.resize(16 + 0xA000, 0);
+        let cart = Cartridge::from_bytes(&rom).unwrap();
+
+        let mut nes = Nes::new();
+        nes.load_cartridge(&cart, Region::Ntsc).unwrap();
+        assert_eq!(nes.nametables.len(), 0x1000);
+
+        for table in 0..4 {
+            write_nametable(&mut nes, 0x2000 + table * 0x400, table as u8 + 1);
+        }
+        // the mapper's mirroring register is ignored
+        SystemBus::write(&mut nes, 0xA000, 1);
+        for table in 0..4 {
+            let v = read_nametable(&mut nes, 0x2000 + table * 0x400);
+            assert_eq!(v, table as u8 + 1, "{}", table);
+        }
+    }
+
+    #[test]
+    fn test_prg_banks() {
+        // MMC3 with 8 PRG banks, each byte holding its bank number
+        #[rustfmt::skip]
+        let mut rom = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x04, 0x01, 0x40, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        for bank in 0..8 {
+            rom.extend(std::iter::repeat_n(bank, 0x2000));
+        }
+        rom.resize(rom.len() + 0x2000, 0);
+        let cart = Cartridge::from_bytes(&rom).unwrap();
+        let mut nes = Nes::new();
+        nes.load_cartridge(&cart, Region::Ntsc).unwrap();
+        assert!(nes.prg_banks.iter().all(|b| b.is_some()));
+
+        let check = |nes: &mut Nes, name| {
+            for addr in (0x8000..=0xFFFF).step_by(0x800) {
+                let expected = nes.mapper.peek(addr).unwrap();
+                assert_eq!(
+                    SystemBus::read(nes, addr),
+                    expected,
+                    "{} ${:04X}",
+                    name,
+                    addr
+                );
+            }
+        };
+        check(&mut nes, "power-on");
+        // R6 = 5, then swap $8000 and $C000
+        SystemBus::write(&mut nes, 0x8000, 6);
+        SystemBus::write(&mut nes, 0x8001, 5);
+        assert_eq!(SystemBus::read(&mut nes, 0x8000), 5);
+        check(&mut nes, "R6");
+        SystemBus::write(&mut nes, 0x8000, 0x46);
+        assert_eq!(SystemBus::read(&mut nes, 0xC000), 5);
+        check(&mut nes, "swapped");
+    }
+
+    #[test]
+    fn test_clock_regions() {
+        #[rustfmt::skip]
+        let cases: [(Region, u64, u64); 3] = [
+            (Region::Ntsc,  29781, 262),
+            (Region::Pal,   33248, 312),
+            (Region::Dendy, 35464, 312),
+        ];
+
+        for (region, cycles, lines) in cases {
+            let mut nes = Nes::new();
+            nes.region = region;
+            SystemBus::tick_n(&mut nes, cycles.into());
+            SystemBus::sync(&mut nes);
+            let ppu = &nes.ppu;
+            let dots = (ppu.frame * lines + ppu.scanline as u64) * 341 + ppu.dot as u64;
+            let expected = match region {
+                Region::Pal => cycles * 16 / 5,
+                _ => cycles * 3,
+            };
+            assert_eq!(dots, expected, "{:?}", region);
+        }
+    }
+
+    #[test]
+    fn test_lazy_ppu() {
+        for lines in [0, 20] {
+            let mut lazy = Nes::new();
+            // with the event log on, the PPU keeps in step
+            let mut eager = Nes::new();
+            eager.event_log = Some(Default::default());
+            for nes in [&mut lazy, &mut eager] {
+                nes.overclock_lines = lines;
+                ppu::write_register(nes, 0x2000, 0x80);
+            }
+
+            let mut behind = false;
+            let mut nmis = Vec::new();
+            for cycle in 0..70000u32 {
+                SystemBus::tick(&mut lazy);
+                SystemBus::tick(&mut eager);
+                assert_eq!(lazy.nmi, eager.nmi, "{} {}", lines, cycle);
+                if lazy.nmi {
+                    nmis.push(cycle);
+                }
+                lazy.nmi = false;
+                eager.nmi = false;
+                behind |= lazy.ppu_debt > 0;
+            }
+            assert!(behind, "{}", lines);
+            SystemBus::sync(&mut lazy);
+            assert_eq!(lazy.ppu.scanline, eager.ppu.scanline, "{}", lines);
+            assert_eq!(lazy.ppu.dot, eager.ppu.dot, "{}", lines);
+            // and the APU waited through the same cycles
+            let apu = |nes: &Nes| {
+                let mut w = StateWriter::new();
+                nes.apu.save_state(&mut w);
+                w.into_inner()
+            };
+            assert_eq!(apu(&lazy), apu(&eager), "{}", lines);
+            // each extra line is another 113.67 CPU cycles per frame
+            let frame = nmis[1] - nmis[0];
+            let expected = (262 + lines as u32) * 341 / 3;
+            assert!(frame.abs_diff(expected) <= 1, "{} {}", lines, frame);
+        }
+    }
+
+    #[test]
+    fn test_ram_mirrors() {
+        let mut nes = Nes::new();
+        for hooked in [false, true] {
+            if hooked {
+                // through the full decode
+                nes.hooks.add_read(0x0000..=0xFFFF, Box::new(|_, _, v| v));
+                nes.hooks.add_write(0x0000..=0xFFFF, Box::new(|_, _, v| v));
+            }
+            let mirrors = [0x0000, 0x0800, 0x1000, 0x1800];
+            for (i, &mirror) in mirrors.iter().enumerate() {
+                let value = i as u8 + 1;
+                SystemBus::write(&mut nes, mirror + 0x0123, value);
+                assert_eq!(nes.wram[0x0123], value, "{} ${:04X}", hooked, mirror);
+                for &other in &mirrors {
+                    let v = SystemBus::read(&mut nes, other + 0x0123);
+                    assert_eq!(v, value, "{} ${:04X}", hooked, other);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_debug_port() {
+        let mut nes = Nes::new();
+        nes.debug_port = Some(0x401A);
+        for &b in b"ok" {
+            SystemBus::write(&mut nes, 0x401A, b);
+        }
+        SystemBus::write(&mut nes, 0x401B, b'!');
+        assert_eq!(nes.debug_output, b"ok");
+
+        // a game printing with nobody reading keeps the latest
+        for i in 0..DEBUG_OUTPUT_LIMIT {
+            SystemBus::write(&mut nes, 0x401A, i as u8);
+        }
+        assert!(nes.debug_output.len() <= DEBUG_OUTPUT_LIMIT);
+        assert_eq!(
+            nes.debug_output.last(),
+            Some(&((DEBUG_OUTPUT_LIMIT - 1) as u8))
+        );
+    }
+
+    #[test]
+    fn test_open_bus() {
+        let mut nes = Nes::new();
+        nes.wram[0x0123] = 0x5A;
+
+        assert_eq!(SystemBus::read(&mut nes, 0x0123), 0x5A);
+        // nothing decodes $5000 on an empty slot
+        assert_eq!(SystemBus::read(&mut nes, 0x5000), 0x5A);
+
+        SystemBus::write(&mut nes, 0x0123, 0x81);
+        assert_eq!(SystemBus::read(&mut nes, 0x8000), 0x81);
+
+        // controller reads drive D0-D4 only; `LDA $4016` leaves $40 on the bus
+        nes.input.pads.controllers[0].state = 0x01;
+        nes.input.ports[1] = input::Port::Zapper(Default::default());
+        SystemBus::write(&mut nes, 0x4016, 1);
+        SystemBus::write(&mut nes, 0x4016, 0x40);
+        assert_eq!(SystemBus::read(&mut nes, 0x4016), 0x41);
+        assert_eq!(SystemBus::read(&mut nes, 0x4016), 0x40);
+        // a Zapper seeing no light
+        nes.open_bus = 0x40;
+        assert_eq!(SystemBus::read(&mut nes, 0x4017), 0x48);
+    }
+}
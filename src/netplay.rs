@@ -0,0 +1,212 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+use anyhow::Result;
+
+use crate::{Emu, FrameResult};
+
+// One player's controller state for one frame, as sent to the other side
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputPacket {
+    // Netplay frame, counted from 0 when the session started
+    pub frame: u64,
+    pub port: u8,
+    pub state: u8,
+}
+
+impl InputPacket {
+    pub const SIZE: usize = 10;
+
+    // Little-endian frame, then port and state
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut b = [0; Self::SIZE];
+        b[..8].copy_from_slice(&self.frame.to_le_bytes());
+        b[8] = self.port;
+        b[9] = self.state;
+        b
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let b = <[u8; Self::SIZE]>::try_from(data)
+            .map_err(|_| NetplayError::new(&format!("packet of {} bytes", data.len())))?;
+        if 1 < b[8] {
+            return Err(NetplayError::new(&format!("invalid port {}", b[8])).into());
+        }
+        let mut frame = [0; 8];
+        frame.copy_from_slice(&b[..8]);
+        Ok(Self {
+            frame: u64::from_le_bytes(frame),
+            port: b[8],
+            state: b[9],
+        })
+    }
+}
+
+// Lockstep for two players: local input is scheduled `delay` frames ahead, which
+// gives the packets that long to arrive, and a frame only runs once both players'
+// input for it is known. Both sides start from the same power-on state.
+#[derive(Debug, Clone)]
+pub struct Lockstep {
+    local_port: u8,
+    // next frame to run
+    frame: u64,
+    // next frame to schedule local input for
+    local_frame: u64,
+    inputs: [BTreeMap<u64, u8>; 2],
+}
+
+impl Lockstep {
+    pub fn new(local_port: u8, delay: u32) -> Self {
+        let mut inputs = [BTreeMap::new(), BTreeMap::new()];
+        // the first frames run with nothing pressed
+        for input in &mut inputs {
+            input.extend((0..delay as u64).map(|f| (f, 0)));
+        }
+        Self {
+            local_port: local_port & 1,
+            frame: 0,
+            local_frame: delay as u64,
+            inputs,
+        }
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    // Schedule the local player's controller state, returning the packet to send
+    pub fn push_local(&mut self, state: u8) -> InputPacket {
+        let packet = InputPacket {
+            frame: self.local_frame,
+            port: self.local_port,
+            state,
+        };
+        self.inputs[self.local_port as usize].insert(self.local_frame, state);
+        self.local_frame += 1;
+        packet
+    }
+
+    // Take a packet from the other side; late or repeated packets are ignored
+    pub fn push_remote(&mut self, packet: InputPacket) {
+        let port = (packet.port & 1) as usize;
+        if port != self.local_port as usize && self.frame <= packet.frame {
+            self.inputs[port]
+                .entry(packet.frame)
+                .or_insert(packet.state);
+        }
+    }
+
+    // Whether both inputs for the next frame are there
+    pub fn ready(&self) -> bool {
+        self.inputs.iter().all(|i| i.contains_key(&self.frame))
+    }
+
+    // Run the next frame with both players' input, or None when waiting for it
+    pub fn advance(&mut self, emu: &mut Emu) -> Option<FrameResult> {
+        if !self.ready() {
+            return None;
+        }
+        for (port, input) in self.inputs.iter_mut().enumerate() {
+            let state = input.remove(&self.frame).unwrap_or(0);
+            emu.set_controller_state(port, state);
+        }
+        self.frame += 1;
+        Some(emu.run_frame())
+    }
+}
+
+// 64-bit FNV-1a
+pub(crate) fn hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xCBF2_9CE4_8422_2325, |h, &b| {
+        (h ^ b as u64).wrapping_mul(0x0000_0100_0000_01B3)
+    })
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct NetplayError {
+    msg: String,
+}
+
+impl NetplayError {
+    pub(crate) fn new(msg: &str) -> Self {
+        Self {
+            msg: msg.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for NetplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "netplay error: {}", self.msg)
+    }
+}
+
+impl std::error::Error for NetplayError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_packet() {
+        let packet = InputPacket {
+            frame: 0x0102_0304,
+            port: 1,
+            state: 0x88,
+        };
+        assert_eq!(InputPacket::from_bytes(&packet.to_bytes()).unwrap(), packet);
+
+        let mut bad_port = packet.to_bytes();
+        bad_port[8] = 2;
+        #[rustfmt::skip]
+        let cases = [
+            ("short",    &packet.to_bytes()[..9], "netplay error: packet of 9 bytes"),
+            ("bad port", &bad_port[..],           "netplay error: invalid port 2"),
+        ];
+
+        for (name, data, expected) in cases {
+            let err = InputPacket::from_bytes(data).unwrap_err();
+            assert_eq!(err.to_string(), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_hash() {
+        assert_eq!(hash(b""), 0xCBF2_9CE4_8422_2325);
+        assert_eq!(hash(b"a"), 0xAF63_DC4C_8601_EC8C);
+    }
+
+    #[test]
+    fn test_lockstep() {
+        let mut emu = [Emu::new(), Emu::new()];
+        for e in &mut emu {
+            e.load_rom_path("roms/nestest.nes").unwrap();
+        }
+        let mut sides = [Lockstep::new(0, 2), Lockstep::new(1, 2)];
+
+        // the delayed frames run without waiting
+        for (side, e) in sides.iter_mut().zip(&mut emu) {
+            assert!(side.advance(e).is_some());
+            assert!(side.advance(e).is_some());
+            assert!(side.advance(e).is_none());
+        }
+
+        for frame in 0..20u8 {
+            let p0 = sides[0].push_local(frame);
+            let p1 = sides[1].push_local(!frame);
+            // a late packet from before the current frame is dropped
+            sides[0].push_remote(InputPacket { frame: 0, ..p1 });
+            sides[0].push_remote(p1);
+            sides[1].push_remote(p0);
+            for (side, e) in sides.iter_mut().zip(&mut emu) {
+                if 0 < frame {
+                    assert!(side.advance(e).is_some());
+                }
+            }
+        }
+        assert_eq!(sides[0].frame(), 21);
+        assert_eq!(emu[0].controller_state(1), !18);
+        assert_eq!(emu[0].state_hash(), emu[1].state_hash());
+    }
+}
@@ -0,0 +1,476 @@
+use anyhow::Result;
+
+use crate::cpu::{push_stack_word, CpuBus};
+use crate::mapper::{Board, Mapper};
+use crate::nes::{Mirroring, Nes};
+use crate::rom::{Nsf2Flags, NsfFile, NsfTrack};
+use crate::state::{StateReader, StateWriter};
+use crate::Emu;
+
+bitflags! {
+    // Expansion sound chips used by the tune
+    #[derive(Default)]
+    pub(crate) struct Expansion: u8 {
+        const VRC6 = 1;
+        const VRC7 = 1 << 1;
+        const FDS = 1 << 2;
+        const MMC5 = 1 << 3;
+        const N163 = 1 << 4;
+        const S5B = 1 << 5;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct NsfHeader {
+    pub(crate) songs: u8,
+    pub(crate) starting_song: u8,
+    pub(crate) load_addr: u16,
+    pub(crate) init_addr: u16,
+    pub(crate) play_addr: u16,
+    // Play routine period in microseconds
+    pub(crate) play_speed_ntsc: u16,
+    pub(crate) play_speed_pal: u16,
+    pub(crate) bankswitch: [u8; 8],
+    pub(crate) pal: bool,
+    pub(crate) expansion: Expansion,
+}
+
+impl NsfHeader {
+    fn bankswitched(&self) -> bool {
+        self.bankswitch.iter().any(|&b| b != 0)
+    }
+}
+
+// CPU clock rates in Hz
+const NTSC_CPU_CLOCK: u128 = 1_789_773;
+const PAL_CPU_CLOCK: u128 = 1_662_607;
+
+// Unmapped address the driver returns to after INIT/PLAY
+const RETURN_ADDR: u16 = 0x5FF5;
+// Give up on routines which don't return within a second
+const MAX_ROUTINE_CYCLES: u128 = NTSC_CPU_CLOCK;
+
+#[derive(Debug)]
+pub(crate) struct NsfPlayer {
+    header: NsfHeader,
+    // empty without NSFe/NSF2 metadata
+    tracks: Vec<NsfTrack>,
+    flags: Nsf2Flags,
+    // Songs in the order they play, and the index of the current one in it, or
+    // its length once the last one has finished
+    playlist: Vec<u8>,
+    position: usize,
+    clock: u128,
+    song: u8,
+    play_period: u128,
+    next_play: u128,
+    // CPU cycle INIT was called at
+    started: u128,
+}
+
+impl NsfPlayer {
+    // Insert the tune into `nes` as a cartridge
+    pub(crate) fn new(nes: &mut Nes, header: NsfHeader, data: &[u8]) -> Self {
+        let (clock, speed) = if header.pal {
+            (PAL_CPU_CLOCK, header.play_speed_pal)
+        } else {
+            (NTSC_CPU_CLOCK, header.play_speed_ntsc)
+        };
+        let play_period = (clock * speed as u128 / 1_000_000).max(1);
+
+        nes.mapper = Board::Nsf(NsfMapper::new(&header, data));
+        nes.refresh_prg_banks();
+
+        let song = header.starting_song.saturating_sub(1) % header.songs.max(1);
+        Self {
+            playlist: (0..header.songs).collect(),
+            position: song as usize,
+            song,
+            header,
+            tracks: Vec::new(),
+            flags: Nsf2Flags::empty(),
+            clock,
+            play_period,
+            next_play: 0,
+            started: 0,
+        }
+    }
+
+    pub(crate) fn load(nes: &mut Nes, file: &NsfFile) -> Self {
+        let mut player = Self::new(nes, file.header.clone(), &file.data);
+        player.tracks = file.tracks.clone();
+        player.flags = file.flags;
+        // an NSFe playlist starts from its top, skipping songs that don't exist
+        let songs = player.header.songs;
+        if let Some(playlist) = &file.playlist {
+            let playlist: Vec<u8> = playlist.iter().copied().filter(|&s| s < songs).collect();
+            if !playlist.is_empty() {
+                player.playlist = playlist;
+                player.position = 0;
+            }
+        }
+        player
+    }
+
+    pub(crate) fn playlist(&self) -> &[u8] {
+        &self.playlist
+    }
+
+    // Index of the current song in the playlist, None once it has ended
+    pub(crate) fn position(&self) -> Option<usize> {
+        Some(self.position).filter(|&p| p < self.playlist.len())
+    }
+
+    // Start the song at `position` in the playlist, or end it past the last one
+    pub(crate) fn start<B: CpuBus>(&mut self, nes: &mut Nes, position: usize) {
+        self.position = position.min(self.playlist.len());
+        if let Some(&song) = self.playlist.get(self.position) {
+            self.init::<B>(nes, song);
+        }
+    }
+
+    // Metadata of the current song
+    pub(crate) fn track(&self) -> Option<&NsfTrack> {
+        self.tracks.get(self.song as usize)
+    }
+
+    // Time since INIT in milliseconds
+    pub(crate) fn elapsed(&self, nes: &Nes) -> u128 {
+        (nes.cpu_cycles - self.started) * 1000 / self.clock
+    }
+
+    // Whether the song has played for its length and fade-out
+    pub(crate) fn finished(&self, nes: &Nes) -> bool {
+        match self.track() {
+            Some(NsfTrack {
+                length: Some(length),
+                fade,
+                ..
+            }) => *length as u128 + fade.unwrap_or(0) as u128 <= self.elapsed(nes),
+            _ => false,
+        }
+    }
+
+    // Output volume for the fade-out: 1.0 until the song's length, then linearly down to 0
+    pub(crate) fn volume(&self, nes: &Nes) -> f32 {
+        let (length, fade) = match self.track() {
+            Some(NsfTrack {
+                length: Some(length),
+                fade,
+                ..
+            }) => (*length as u128, fade.unwrap_or(0) as u128),
+            _ => return 1.0,
+        };
+        let elapsed = self.elapsed(nes);
+        if elapsed < length {
+            1.0
+        } else if fade == 0 || length + fade <= elapsed {
+            0.0
+        } else {
+            1.0 - (elapsed - length) as f32 / fade as f32
+        }
+    }
+
+    pub(crate) fn song(&self) -> u8 {
+        self.song
+    }
+
+    // Reset the sound state and call INIT for the 0-origin `song`
+    pub(crate) fn init<B: CpuBus>(&mut self, nes: &mut Nes, song: u8) {
+        self.song = song % self.header.songs.max(1);
+
+        nes.wram.iter_mut().for_each(|b| *b = 0);
+        if let Board::Nsf(m) = &mut nes.mapper {
+            m.reset(&self.header);
+        }
+        for addr in 0x4000..=0x4013 {
+            B::write(nes, addr, 0);
+        }
+        B::write(nes, 0x4015, 0x0F);
+        // 4-step frame counter, IRQ inhibited
+        B::write(nes, 0x4017, 0x40);
+
+        nes.cpu.s = 0xFD;
+        nes.cpu.a = self.song;
+        nes.cpu.x = self.header.pal as u8;
+        self.started = nes.cpu_cycles;
+        call::<B>(nes, self.header.init_addr);
+
+        self.next_play = nes.cpu_cycles;
+    }
+
+    // Run `cycles` CPU cycles, calling PLAY at the tune's rate and idling in between
+    pub(crate) fn run<B: CpuBus>(&mut self, nes: &mut Nes, cycles: u128) {
+        let end = nes.cpu_cycles + cycles;
+        while nes.cpu_cycles < end {
+            if self.flags.contains(Nsf2Flags::NO_PLAY) {
+                B::tick_n(nes, end - nes.cpu_cycles);
+            } else if self.next_play <= nes.cpu_cycles {
+                self.next_play += self.play_period;
+                call::<B>(nes, self.header.play_addr);
+            } else {
+                let n = self.next_play.min(end) - nes.cpu_cycles;
+                B::tick_n(nes, n);
+            }
+        }
+        B::sync(nes);
+    }
+}
+
+// Call the routine at `addr` as if by JSR, running until it returns
+fn call<B: CpuBus>(nes: &mut Nes, addr: u16) {
+    push_stack_word::<B>(nes, RETURN_ADDR.wrapping_sub(1));
+    nes.cpu.pc = addr;
+
+    let limit = nes.cpu_cycles + MAX_ROUTINE_CYCLES;
+    while nes.cpu.pc != RETURN_ADDR && nes.cpu_cycles < limit {
+        Emu::cpu_step::<B>(nes);
+    }
+}
+
+// NSF bank switching: eight 4K banks at $8000-$FFFF selected by $5FF8-$5FFF.
+// FDS tunes have RAM at $6000-$DFFF instead, loaded from banks via $5FF6-$5FFF.
+#[derive(Debug)]
+pub(crate) struct NsfMapper {
+    prg: Vec<u8>,
+    banks: [u8; 10],
+    ram: Vec<u8>,
+    fds: bool,
+}
+
+impl NsfMapper {
+    fn new(header: &NsfHeader, data: &[u8]) -> Self {
+        let padding = if header.bankswitched() {
+            header.load_addr & 0x0FFF
+        } else {
+            header.load_addr.saturating_sub(0x8000)
+        } as usize;
+
+        let mut prg = vec![0; padding];
+        prg.extend_from_slice(data);
+        let len = (prg.len() + 0x0FFF) & !0x0FFF;
+        prg.resize(len.max(0x1000), 0);
+
+        let fds = header.expansion.contains(Expansion::FDS);
+        let mut m = Self {
+            prg,
+            banks: [0; 10],
+            ram: vec![0; if fds { 0x8000 } else { 0x2000 }],
+            fds,
+        };
+        m.reset(header);
+        m
+    }
+
+    fn reset(&mut self, header: &NsfHeader) {
+        self.ram.iter_mut().for_each(|b| *b = 0);
+
+        let banks = if header.bankswitched() {
+            header.bankswitch
+        } else {
+            [0, 1, 2, 3, 4, 5, 6, 7]
+        };
+        if self.fds {
+            // $6000-$7FFF gets the same initial banks as $E000-$FFFF
+            self.select_bank(0, banks[6]);
+            self.select_bank(1, banks[7]);
+        }
+        for (i, &b) in banks.iter().enumerate() {
+            self.select_bank(i + 2, b);
+        }
+    }
+
+    // `slot` 0-1 are $6000-$7FFF (FDS only), 2-9 are $8000-$FFFF
+    fn select_bank(&mut self, slot: usize, bank: u8) {
+        self.banks[slot] = bank;
+        if self.fds && slot < 8 {
+            let src = self.bank_offset(bank);
+            let dst = slot * 0x1000;
+            self.ram[dst..dst + 0x1000].copy_from_slice(&self.prg[src..src + 0x1000]);
+        }
+    }
+
+    fn bank_offset(&self, bank: u8) -> usize {
+        (bank as usize * 0x1000) % self.prg.len()
+    }
+}
+
+impl Mapper for NsfMapper {
+    fn peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0xDFFF if self.fds => Some(self.ram[addr as usize - 0x6000]),
+            0x6000..=0x7FFF => Some(self.ram[addr as usize - 0x6000]),
+            0x8000..=0xFFFF => Some(self.prg[self.prg_rom_offset(addr)?]),
+            _ => None,
+        }
+    }
+
+    fn prg_ram_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x6000..=0xDFFF if self.fds => Some(addr as usize - 0x6000),
+            0x6000..=0x7FFF => Some(addr as usize - 0x6000),
+            _ => None,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x6000..=0xDFFF if self.fds => None,
+            0x8000..=0xFFFF => {
+                let slot = (addr as usize - 0x8000) / 0x1000 + 2;
+                Some(self.bank_offset(self.banks[slot]) + (addr as usize & 0x0FFF))
+            }
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x5FF6..=0x5FF7 if self.fds => self.select_bank(addr as usize - 0x5FF6, value),
+            0x5FF8..=0x5FFF => self.select_bank(addr as usize - 0x5FF6, value),
+            0x6000..=0xDFFF if self.fds => self.ram[addr as usize - 0x6000] = value,
+            0x6000..=0x7FFF => self.ram[addr as usize - 0x6000] = value,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, _addr: u16) -> u8 {
+        0
+    }
+
+    fn ppu_write(&mut self, _addr: u16, _value: u8) {}
+
+    fn mirroring(&self) -> Mirroring {
+        Mirroring::Horizontal
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.ram[..0x2000])
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.banks);
+        w.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.read_bytes_into(&mut self.banks)?;
+        r.read_bytes_into(&mut self.ram)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nes::SystemBus;
+
+    fn header() -> NsfHeader {
+        NsfHeader {
+            songs: 3,
+            starting_song: 1,
+            load_addr: 0x8000,
+            init_addr: 0x8000,
+            play_addr: 0x8003,
+            play_speed_ntsc: 16_639,
+            play_speed_pal: 19_997,
+            bankswitch: [0; 8],
+            pal: false,
+            expansion: Expansion::empty(),
+        }
+    }
+
+    #[rustfmt::skip]
+    const TUNE: [u8; 7] = [
+        // INIT: STA $00; RTS
+        0x85, 0x00, 0x60,
+        // PLAY: INC $01; RTS
+        0xE6, 0x01, 0x60,
+        0x00,
+    ];
+
+    #[test]
+    fn test_init_and_play() {
+        let mut nes = Nes::new();
+        let mut player = NsfPlayer::new(&mut nes, header(), &TUNE);
+        assert_eq!(player.play_period, 29_780);
+
+        player.init::<SystemBus>(&mut nes, 2);
+        assert_eq!(nes.wram[0x00], 2);
+
+        player.run::<SystemBus>(&mut nes, player.play_period * 3);
+        assert_eq!(nes.wram[0x01], 3);
+    }
+
+    #[test]
+    fn test_timed_playback() {
+        let mut nes = Nes::new();
+        let mut player = NsfPlayer::new(&mut nes, header(), &TUNE);
+        #[rustfmt::skip]
+        let track = NsfTrack { title: None, length: Some(1000), fade: Some(500) };
+        player.tracks = vec![NsfTrack::default(), track];
+
+        player.init::<SystemBus>(&mut nes, 0);
+        player.run::<SystemBus>(&mut nes, NTSC_CPU_CLOCK * 2);
+        // no length, plays forever
+        assert!(!player.finished(&nes));
+        assert_eq!(player.volume(&nes), 1.0);
+
+        player.init::<SystemBus>(&mut nes, 1);
+        player.run::<SystemBus>(&mut nes, NTSC_CPU_CLOCK / 2);
+        assert_eq!(player.volume(&nes), 1.0);
+        player.run::<SystemBus>(&mut nes, NTSC_CPU_CLOCK * 3 / 4);
+        assert!((player.volume(&nes) - 0.5).abs() < 0.01);
+        assert!(!player.finished(&nes));
+        player.run::<SystemBus>(&mut nes, NTSC_CPU_CLOCK / 4);
+        assert!(player.finished(&nes));
+        assert_eq!(player.volume(&nes), 0.0);
+    }
+
+    #[test]
+    fn test_playlist() {
+        let mut header = header();
+        header.starting_song = 2;
+        let mut nes = Nes::new();
+        let mut player = NsfPlayer::new(&mut nes, header, &TUNE);
+        // without NSFe metadata, every song from the starting one
+        assert_eq!(player.playlist(), [0, 1, 2]);
+        assert_eq!(player.position(), Some(1));
+
+        player.start::<SystemBus>(&mut nes, 2);
+        assert_eq!((player.position(), player.song()), (Some(2), 2));
+        assert_eq!(nes.wram[0x00], 2);
+        player.start::<SystemBus>(&mut nes, 3);
+        assert_eq!(player.position(), None);
+    }
+
+    #[test]
+    fn test_no_play() {
+        let mut nes = Nes::new();
+        let mut player = NsfPlayer::new(&mut nes, header(), &TUNE);
+        player.flags = Nsf2Flags::NO_PLAY;
+
+        player.init::<SystemBus>(&mut nes, 0);
+        player.run::<SystemBus>(&mut nes, player.play_period * 3);
+        assert_eq!(nes.wram[0x01], 0);
+    }
+
+    #[test]
+    fn test_bankswitch() {
+        let mut header = header();
+        header.load_addr = 0x8100;
+        header.bankswitch = [1, 0, 0, 0, 0, 0, 0, 0];
+
+        let mut data = vec![0; 0x2000];
+        data[0x0000] = 0x12;
+        data[0x1000] = 0x34;
+
+        let mut nes = Nes::new();
+        let _ = NsfPlayer::new(&mut nes, header, &data);
+        assert_eq!(nes.mapper.read(0x8100), Some(0x34));
+        assert_eq!(nes.mapper.read(0x9100), Some(0x12));
+
+        nes.mapper.write(0x5FF8, 0);
+        assert_eq!(nes.mapper.read(0x8100), Some(0x12));
+    }
+}
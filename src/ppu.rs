@@ -0,0 +1,999 @@
+use anyhow::Result;
+
+use crate::config::Region;
+use crate::hook::{self, Event};
+use crate::mapper::Mapper;
+use crate::nes::{self, Mirroring, Nes};
+use crate::state::{StateReader, StateWriter};
+
+pub(crate) const WIDTH: usize = 256;
+pub(crate) const HEIGHT: usize = 240;
+
+pub(crate) const DOTS_PER_LINE: u16 = 341;
+// A12 has to stay low this long before a rise clocks the mapper, like the MMC3's
+// M2-based filter which ignores the short drops between sprite pattern fetches
+const A12_FILTER_DOTS: u64 = 10;
+
+// A pattern byte's bits each moved to the bottom of a nibble, bit 7 to the top
+// one, so a tile's two planes and palette combine into 8 pixels at once
+const PLANE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut bit = 0;
+        while bit < 8 {
+            table[byte] |= ((byte as u32 >> bit) & 1) << (4 * bit);
+            bit += 1;
+        }
+        byte += 1;
+    }
+    table
+};
+
+// The 16 bits of one shift register into the bottom of nibbles, as PLANE does
+fn spread(bits: u16) -> u64 {
+    (PLANE[bits as usize >> 8] as u64) << 32 | PLANE[bits as usize & 0xFF] as u64
+}
+
+// Bit `plane` of each nibble back into a shift register
+fn gather(pixels: u64, plane: u32) -> u16 {
+    (0..16).fold(0, |bits, i| {
+        bits | ((pixels >> (4 * i + plane)) as u16 & 1) << i
+    })
+}
+
+bitflags! {
+    // $2000
+    #[derive(Default)]
+    struct Ctrl: u8 {
+        const NAMETABLE = 0b11;
+        const INCREMENT = 1 << 2;
+        const SPRITE_TABLE = 1 << 3;
+        const BG_TABLE = 1 << 4;
+        const SPRITE_SIZE = 1 << 5;
+        const NMI = 1 << 7;
+    }
+}
+
+bitflags! {
+    // $2001
+    #[derive(Default)]
+    struct Mask: u8 {
+        const GREYSCALE = 1;
+        const BG_LEFT = 1 << 1;
+        const SPRITE_LEFT = 1 << 2;
+        const BG = 1 << 3;
+        const SPRITE = 1 << 4;
+        const EMPHASIS = 0b1110_0000;
+    }
+}
+
+bitflags! {
+    // $2002
+    #[derive(Default)]
+    struct Status: u8 {
+        const OVERFLOW = 1 << 5;
+        const SPRITE_ZERO_HIT = 1 << 6;
+        const VBLANK = 1 << 7;
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Ppu {
+    ctrl: Ctrl,
+    mask: Mask,
+    status: Status,
+    oam_addr: u8,
+    pub(crate) oam: [u8; 0x100],
+    palette: [u8; 0x20],
+
+    // Internal registers: current/temporary VRAM address, fine X scroll and write toggle
+    v: u16,
+    t: u16,
+    x: u8,
+    w: bool,
+    // $2007 read buffer
+    read_buffer: u8,
+    // Value left on the PPU data bus by the last register access
+    latch: u8,
+
+    pub(crate) scanline: u16,
+    pub(crate) dot: u16,
+    pub(crate) frame: u64,
+    odd_frame: bool,
+    // Total dots since power-on
+    cycles: u64,
+
+    // Background tile fetched for the next 8 pixels and the pixel shift registers
+    nametable_byte: u8,
+    attribute: u8,
+    pattern_low: u8,
+    pattern_high: u8,
+    // The next 16 background pixels, 4 bits each from the top: palette number
+    // and pattern bits, the shift registers side by side
+    bg_pixels: u64,
+
+    // Sprites found for the next line and the ones being drawn on this line
+    next_sprites: [u8; 32],
+    next_count: usize,
+    next_zero: bool,
+    sprite_count: usize,
+    sprite_zero: bool,
+    sprite_low: [u8; 8],
+    sprite_high: [u8; 8],
+    sprite_attr: [u8; 8],
+    sprite_x: [u8; 8],
+
+    a12: bool,
+    a12_low_since: u64,
+
+    // NMI output (VBlank flag AND NMI enable)
+    nmi_output: bool,
+    // Palette index | emphasis << 6 of each pixel
+    pub(crate) buffer: Vec<u16>,
+    // Set when a frame finished rendering, at the start of VBlank
+    pub(crate) frame_ready: bool,
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self {
+            ctrl: Ctrl::empty(),
+            mask: Mask::empty(),
+            status: Status::empty(),
+            oam_addr: 0,
+            oam: [0; 0x100],
+            palette: [0; 0x20],
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+            read_buffer: 0,
+            latch: 0,
+            scanline: 0,
+            dot: 0,
+            frame: 0,
+            odd_frame: false,
+            cycles: 0,
+            nametable_byte: 0,
+            attribute: 0,
+            pattern_low: 0,
+            pattern_high: 0,
+            bg_pixels: 0,
+            next_sprites: [0xFF; 32],
+            next_count: 0,
+            next_zero: false,
+            sprite_count: 0,
+            sprite_zero: false,
+            sprite_low: [0; 8],
+            sprite_high: [0; 8],
+            sprite_attr: [0; 8],
+            sprite_x: [0; 8],
+            a12: false,
+            a12_low_since: 0,
+            nmi_output: false,
+            buffer: vec![0; WIDTH * HEIGHT],
+            frame_ready: false,
+        }
+    }
+}
+
+impl Ppu {
+    // The reset line clears $2000/$2001 and the write toggle; VRAM, OAM and the
+    // status flags keep their contents
+    pub(crate) fn reset(&mut self) {
+        self.ctrl = Ctrl::empty();
+        self.mask = Mask::empty();
+        self.w = false;
+        self.read_buffer = 0;
+        self.odd_frame = false;
+        self.nmi_output = false;
+    }
+
+    // Dots from the current one until the VBlank flag next changes, where NMIs
+    // start and stop. On odd NTSC frames a skipped dot brings it one sooner.
+    pub(crate) fn dots_to_vblank_change(&self, region: Region) -> u32 {
+        self.dots_to(region, vblank_line(region), 1)
+            .min(self.dots_to(region, prerender_line(region), 1))
+    }
+
+    // Dots until the PPU is at `dot` of `scanline`, 0 when it's there
+    pub(crate) fn dots_to(&self, region: Region, scanline: u16, dot: u16) -> u32 {
+        let line = DOTS_PER_LINE as u32;
+        let frame = (prerender_line(region) as u32 + 1) * line;
+        let now = self.scanline as u32 * line + self.dot as u32;
+        (scanline as u32 * line + dot as u32 + frame - now) % frame
+    }
+
+    // The current and temporary VRAM addresses, for self-checking
+    pub(crate) fn vram_addresses(&self) -> (u16, u16) {
+        (self.v, self.t)
+    }
+
+    // Everything but the output pixels
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.ctrl.bits());
+        w.write_u8(self.mask.bits());
+        w.write_u8(self.status.bits());
+        w.write_u8(self.oam_addr);
+        w.write_bytes(&self.oam);
+        w.write_bytes(&self.palette);
+        w.write_u16(self.v);
+        w.write_u16(self.t);
+        w.write_u8(self.x);
+        w.write_bool(self.w);
+        w.write_u8(self.read_buffer);
+        w.write_u8(self.latch);
+
+        w.write_u16(self.scanline);
+        w.write_u16(self.dot);
+        w.write_u64(self.frame);
+        w.write_bool(self.odd_frame);
+        w.write_u64(self.cycles);
+
+        w.write_u8(self.nametable_byte);
+        w.write_u8(self.attribute);
+        w.write_u8(self.pattern_low);
+        w.write_u8(self.pattern_high);
+        for plane in 0..4 {
+            w.write_u16(gather(self.bg_pixels, plane));
+        }
+
+        w.write_bytes(&self.next_sprites);
+        w.write_u8(self.next_count as u8);
+        w.write_bool(self.next_zero);
+        w.write_u8(self.sprite_count as u8);
+        w.write_bool(self.sprite_zero);
+        w.write_bytes(&self.sprite_low);
+        w.write_bytes(&self.sprite_high);
+        w.write_bytes(&self.sprite_attr);
+        w.write_bytes(&self.sprite_x);
+
+        w.write_bool(self.a12);
+        w.write_u64(self.a12_low_since);
+        w.write_bool(self.nmi_output);
+        w.write_bool(self.frame_ready);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.ctrl = Ctrl::from_bits_truncate(r.read_u8()?);
+        self.mask = Mask::from_bits_truncate(r.read_u8()?);
+        self.status = Status::from_bits_truncate(r.read_u8()?);
+        self.oam_addr = r.read_u8()?;
+        r.read_bytes_into(&mut self.oam)?;
+        r.read_bytes_into(&mut self.palette)?;
+        self.v = r.read_u16()?;
+        self.t = r.read_u16()?;
+        self.x = r.read_u8()?;
+        self.w = r.read_bool()?;
+        self.read_buffer = r.read_u8()?;
+        self.latch = r.read_u8()?;
+
+        self.scanline = r.read_u16()?;
+        self.dot = r.read_u16()?;
+        self.frame = r.read_u64()?;
+        self.odd_frame = r.read_bool()?;
+        self.cycles = r.read_u64()?;
+
+        self.nametable_byte = r.read_u8()?;
+        self.attribute = r.read_u8()?;
+        self.pattern_low = r.read_u8()?;
+        self.pattern_high = r.read_u8()?;
+        self.bg_pixels = 0;
+        for plane in 0..4 {
+            self.bg_pixels |= spread(r.read_u16()?) << plane;
+        }
+
+        r.read_bytes_into(&mut self.next_sprites)?;
+        self.next_count = (r.read_u8()? as usize).min(8);
+        self.next_zero = r.read_bool()?;
+        self.sprite_count = (r.read_u8()? as usize).min(8);
+        self.sprite_zero = r.read_bool()?;
+        r.read_bytes_into(&mut self.sprite_low)?;
+        r.read_bytes_into(&mut self.sprite_high)?;
+        r.read_bytes_into(&mut self.sprite_attr)?;
+        r.read_bytes_into(&mut self.sprite_x)?;
+
+        self.a12 = r.read_bool()?;
+        self.a12_low_since = r.read_u64()?;
+        self.nmi_output = r.read_bool()?;
+        self.frame_ready = r.read_bool()?;
+        Ok(())
+    }
+
+    pub(crate) fn rendering(&self) -> bool {
+        self.mask.intersects(Mask::BG | Mask::SPRITE)
+    }
+
+    fn sprite_height(&self) -> u16 {
+        if self.ctrl.contains(Ctrl::SPRITE_SIZE) {
+            16
+        } else {
+            8
+        }
+    }
+
+    fn increment_x(&mut self) {
+        if self.v & 0x001F == 31 {
+            self.v &= !0x001F;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let mut y = (self.v & 0x03E0) >> 5;
+        if y == 29 {
+            y = 0;
+            self.v ^= 0x0800;
+        } else if y == 31 {
+            y = 0;
+        } else {
+            y += 1;
+        }
+        self.v = (self.v & !0x03E0) | (y << 5);
+    }
+
+    fn copy_x(&mut self) {
+        self.v = (self.v & !0x041F) | (self.t & 0x041F);
+    }
+
+    fn copy_y(&mut self) {
+        self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+    }
+
+    // The fetched tile's 8 pixels at once into the low half
+    fn load_bg_shifters(&mut self) {
+        let tile = PLANE[self.pattern_low as usize]
+            | PLANE[self.pattern_high as usize] << 1
+            | (0x1111_1111 * (self.attribute as u32 & 0x03)) << 2;
+        self.bg_pixels = (self.bg_pixels & !0xFFFF_FFFF) | tile as u64;
+    }
+
+    fn shift_bg(&mut self) {
+        self.bg_pixels <<= 4;
+    }
+
+    fn palette_index(&self, addr: u16) -> usize {
+        let i = addr as usize & 0x1F;
+        // $3F10/$3F14/$3F18/$3F1C mirror $3F00/$3F04/$3F08/$3F0C
+        if i & 0x13 == 0x10 {
+            i & !0x10
+        } else {
+            i
+        }
+    }
+}
+
+// Scanline VBlank starts on, and the pre-render line
+pub(crate) const fn vblank_line(region: Region) -> u16 {
+    match region {
+        Region::Dendy => 291,
+        _ => 241,
+    }
+}
+
+pub(crate) const fn prerender_line(region: Region) -> u16 {
+    match region {
+        Region::Ntsc => 261,
+        _ => 311,
+    }
+}
+
+// PPU memory access, tracking A12 for mapper scanline counters
+fn read_vram(nes: &mut Nes, addr: u16) -> u8 {
+    let addr = addr & 0x3FFF;
+    watch_a12(nes, addr);
+    match addr {
+        0x0000..=0x1FFF => nes.mapper.ppu_read(addr),
+        0x2000..=0x3EFF => read_nametable(nes, addr),
+        _ => nes.ppu.palette[nes.ppu.palette_index(addr)],
+    }
+}
+
+fn write_vram(nes: &mut Nes, addr: u16, value: u8) {
+    let addr = addr & 0x3FFF;
+    watch_a12(nes, addr);
+    match addr {
+        0x0000..=0x1FFF => nes.mapper.ppu_write(addr, value),
+        0x2000..=0x3EFF => write_nametable(nes, addr, value),
+        _ => {
+            let i = nes.ppu.palette_index(addr);
+            nes.ppu.palette[i] = value & 0x3F;
+        }
+    }
+}
+
+fn watch_a12(nes: &mut Nes, addr: u16) {
+    let a12 = addr & 0x1000 != 0;
+    let ppu = &mut nes.ppu;
+    if a12 && !ppu.a12 && A12_FILTER_DOTS <= ppu.cycles - ppu.a12_low_since {
+        nes.mapper.ppu_a12_rise();
+    }
+    if !a12 && ppu.a12 {
+        ppu.a12_low_since = ppu.cycles;
+    }
+    ppu.a12 = a12;
+}
+
+pub(crate) fn read_nametable(nes: &mut Nes, addr: u16) -> u8 {
+    let i = nametable_addr(mirroring(nes), addr);
+    nes.nametables[i]
+}
+
+pub(crate) fn write_nametable(nes: &mut Nes, addr: u16, value: u8) {
+    let i = nametable_addr(mirroring(nes), addr);
+    nes.nametables[i] = value;
+}
+
+pub(crate) fn mirroring(nes: &Nes) -> Mirroring {
+    if nes.four_screen {
+        Mirroring::FourScreen
+    } else {
+        nes.mapper.mirroring()
+    }
+}
+
+// Translate a PPU address in $2000-$3EFF into an offset of the nametable RAM
+pub(crate) fn nametable_addr(mirroring: Mirroring, addr: u16) -> usize {
+    let addr = (addr - 0x2000) % 0x1000;
+    let table = addr / 0x400;
+    let offset = addr % 0x400;
+    let page = match mirroring {
+        // $2000 = $2400, $2800 = $2C00
+        Mirroring::Horizontal => table / 2,
+        // $2000 = $2800, $2400 = $2C00
+        Mirroring::Vertical => table % 2,
+        Mirroring::SingleScreenLower => 0,
+        Mirroring::SingleScreenUpper => 1,
+        Mirroring::Custom(pages) => pages[table as usize] as u16 & 1,
+        Mirroring::FourScreen => table,
+    };
+    (page * 0x400 + offset) as usize
+}
+
+// CPU reads of $2000-$2007
+pub(crate) fn read_register(nes: &mut Nes, addr: u16) -> u8 {
+    let v = match addr & 7 {
+        2 => {
+            let ppu = &mut nes.ppu;
+            let v = ppu.status.bits() | (ppu.latch & 0x1F);
+            ppu.status.remove(Status::VBLANK);
+            ppu.w = false;
+            update_nmi(nes);
+            v
+        }
+        4 => nes.ppu.oam[nes.ppu.oam_addr as usize],
+        7 => {
+            let addr = nes.ppu.v & 0x3FFF;
+            let v = if 0x3F00 <= addr {
+                // palette reads are immediate; the buffer gets the nametable byte underneath
+                nes.ppu.read_buffer = read_vram(nes, addr - 0x1000);
+                read_vram(nes, addr) | (nes.ppu.latch & 0xC0)
+            } else {
+                let v = nes.ppu.read_buffer;
+                nes.ppu.read_buffer = read_vram(nes, addr);
+                v
+            };
+            increment_v(nes);
+            v
+        }
+        // write-only registers
+        _ => nes.ppu.latch,
+    };
+    nes.ppu.latch = v;
+    v
+}
+
+// What `read_register` would return, leaving the PPU as it is
+pub(crate) fn peek_register(nes: &Nes, addr: u16) -> u8 {
+    let ppu = &nes.ppu;
+    match addr & 7 {
+        2 => ppu.status.bits() | (ppu.latch & 0x1F),
+        4 => ppu.oam[ppu.oam_addr as usize],
+        7 if 0x3F00 <= ppu.v & 0x3FFF => {
+            ppu.palette[ppu.palette_index(ppu.v & 0x3FFF)] | (ppu.latch & 0xC0)
+        }
+        7 => ppu.read_buffer,
+        _ => ppu.latch,
+    }
+}
+
+// CPU writes to $2000-$2007
+pub(crate) fn write_register(nes: &mut Nes, addr: u16, value: u8) {
+    nes.ppu.latch = value;
+    let ppu = &mut nes.ppu;
+    match addr & 7 {
+        0 => {
+            ppu.ctrl = Ctrl::from_bits_truncate(value);
+            ppu.t = (ppu.t & !0x0C00) | ((value as u16 & 0b11) << 10);
+            update_nmi(nes);
+        }
+        1 => ppu.mask = Mask::from_bits_truncate(value),
+        3 => ppu.oam_addr = value,
+        4 => {
+            ppu.oam[ppu.oam_addr as usize] = value;
+            ppu.oam_addr = ppu.oam_addr.wrapping_add(1);
+        }
+        5 => {
+            if ppu.w {
+                ppu.t = (ppu.t & !0x73E0)
+                    | ((value as u16 & 0x07) << 12)
+                    | ((value as u16 & 0xF8) << 2);
+            } else {
+                ppu.t = (ppu.t & !0x001F) | (value as u16 >> 3);
+                ppu.x = value & 0x07;
+            }
+            ppu.w = !ppu.w;
+        }
+        6 => {
+            if ppu.w {
+                ppu.t = (ppu.t & 0xFF00) | value as u16;
+                ppu.v = ppu.t;
+                let v = ppu.v;
+                watch_a12(nes, v);
+            } else {
+                ppu.t = (ppu.t & 0x00FF) | ((value as u16 & 0x3F) << 8);
+            }
+            nes.ppu.w = !nes.ppu.w;
+        }
+        7 => {
+            let v = nes.ppu.v;
+            write_vram(nes, v, value);
+            increment_v(nes);
+        }
+        _ => {}
+    }
+}
+
+fn increment_v(nes: &mut Nes) {
+    let ppu = &mut nes.ppu;
+    let step = if ppu.ctrl.contains(Ctrl::INCREMENT) {
+        32
+    } else {
+        1
+    };
+    ppu.v = ppu.v.wrapping_add(step) & 0x7FFF;
+}
+
+fn update_nmi(nes: &mut Nes) {
+    let ppu = &mut nes.ppu;
+    let output = ppu.status.contains(Status::VBLANK) && ppu.ctrl.contains(Ctrl::NMI);
+    if output && !ppu.nmi_output {
+        nes.nmi = true;
+    }
+    ppu.nmi_output = output;
+}
+
+// Advance the PPU by one dot
+pub(crate) fn step<const REGION: u8>(nes: &mut Nes) {
+    let region = nes::region(REGION);
+    let line = nes.ppu.scanline;
+    let dot = nes.ppu.dot;
+    let prerender = prerender_line(region);
+    let visible = line < HEIGHT as u16;
+
+    if dot == 0 {
+        render_disabled_line(nes);
+    }
+
+    if nes.ppu.rendering() && (visible || line == prerender) {
+        if visible && (1..=256).contains(&dot) {
+            render_pixel(nes);
+        }
+        fetch(nes, line, dot, prerender);
+    }
+
+    if line == vblank_line(region) && dot == 1 {
+        nes.ppu.status.insert(Status::VBLANK);
+        nes.ppu.frame_ready = true;
+        update_nmi(nes);
+    }
+    if line == prerender && dot == 1 {
+        nes.ppu
+            .status
+            .remove(Status::VBLANK | Status::SPRITE_ZERO_HIT | Status::OVERFLOW);
+        update_nmi(nes);
+    }
+
+    let ppu = &mut nes.ppu;
+    ppu.cycles += 1;
+    ppu.dot += 1;
+    // NTSC skips the last dot of the pre-render line on odd frames while rendering
+    let skip = region == Region::Ntsc
+        && line == prerender
+        && dot == 339
+        && ppu.odd_frame
+        && ppu.rendering();
+    if DOTS_PER_LINE <= ppu.dot || skip {
+        ppu.dot = 0;
+        ppu.scanline += 1;
+        if prerender < ppu.scanline {
+            ppu.scanline = 0;
+            ppu.frame += 1;
+            ppu.odd_frame = !ppu.odd_frame;
+            if let Some(log) = &mut nes.event_log {
+                log.next_frame();
+            }
+        }
+    }
+}
+
+// Memory fetches and scroll updates of rendering lines. This and
+// `render_pixel` are kept inside each region's `step`, which is otherwise
+// split up to run noticeably slower.
+#[inline(always)]
+fn fetch(nes: &mut Nes, line: u16, dot: u16, prerender: u16) {
+    if (2..=257).contains(&dot) || (322..=337).contains(&dot) {
+        nes.ppu.shift_bg();
+    }
+
+    if (1..=256).contains(&dot) || (321..=336).contains(&dot) {
+        let v = nes.ppu.v;
+        let fine_y = (v >> 12) & 0x07;
+        let table = if nes.ppu.ctrl.contains(Ctrl::BG_TABLE) {
+            0x1000
+        } else {
+            0
+        };
+        match (dot - 1) % 8 {
+            0 => {
+                nes.ppu.load_bg_shifters();
+                nes.ppu.nametable_byte = read_vram(nes, 0x2000 | (v & 0x0FFF));
+            }
+            2 => {
+                let addr = 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+                let shift = ((v >> 4) & 0x04) | (v & 0x02);
+                nes.ppu.attribute = (read_vram(nes, addr) >> shift) & 0x03;
+            }
+            4 => {
+                let addr = table + nes.ppu.nametable_byte as u16 * 16 + fine_y;
+                nes.ppu.pattern_low = read_vram(nes, addr);
+            }
+            6 => {
+                let addr = table + nes.ppu.nametable_byte as u16 * 16 + fine_y + 8;
+                nes.ppu.pattern_high = read_vram(nes, addr);
+            }
+            7 => nes.ppu.increment_x(),
+            _ => {}
+        }
+    }
+
+    match dot {
+        256 => nes.ppu.increment_y(),
+        257 => {
+            nes.ppu.load_bg_shifters();
+            nes.ppu.copy_x();
+            if line != prerender {
+                evaluate_sprites(nes, line);
+            } else {
+                nes.ppu.next_count = 0;
+            }
+        }
+        280..=304 if line == prerender => nes.ppu.copy_y(),
+        // unused nametable fetches
+        338 | 340 => {
+            let v = nes.ppu.v;
+            read_vram(nes, 0x2000 | (v & 0x0FFF));
+        }
+        _ => {}
+    }
+
+    // sprite pattern fetches for the next line, 8 dots per slot
+    if (257..=320).contains(&dot) && (dot - 257) % 8 == 7 {
+        fetch_sprite(nes, line, ((dot - 257) / 8) as usize);
+    }
+}
+
+// Find the sprites on the line after `line`
+fn evaluate_sprites(nes: &mut Nes, line: u16) {
+    let ppu = &mut nes.ppu;
+    let height = ppu.sprite_height();
+    ppu.next_sprites = [0xFF; 32];
+    ppu.next_count = 0;
+    ppu.next_zero = false;
+    for i in 0..64 {
+        let y = ppu.oam[i * 4] as u16;
+        // OAM Y is one less than the sprite's top line
+        if !(y..y + height).contains(&line) {
+            continue;
+        }
+        if ppu.next_count == 8 {
+            ppu.status.insert(Status::OVERFLOW);
+            break;
+        }
+        let n = ppu.next_count;
+        ppu.next_sprites[n * 4..n * 4 + 4].copy_from_slice(&ppu.oam[i * 4..i * 4 + 4]);
+        if i == 0 {
+            ppu.next_zero = true;
+        }
+        ppu.next_count += 1;
+    }
+}
+
+fn fetch_sprite(nes: &mut Nes, line: u16, slot: usize) {
+    let ppu = &nes.ppu;
+    let sprite = &ppu.next_sprites[slot * 4..slot * 4 + 4];
+    let (y, tile, attr, x) = (sprite[0], sprite[1], sprite[2], sprite[3]);
+    let height = ppu.sprite_height();
+    // empty slots still fetch tile $FF
+    let row = if slot < ppu.next_count {
+        line.wrapping_sub(y as u16)
+    } else {
+        0
+    };
+    let row = if attr & 0x80 != 0 && slot < ppu.next_count {
+        height - 1 - row
+    } else {
+        row
+    };
+    let addr = if height == 16 {
+        let table = (tile as u16 & 1) * 0x1000;
+        let tile = (tile & 0xFE) as u16 + (row >= 8) as u16;
+        table + tile * 16 + (row & 7)
+    } else {
+        let table = if ppu.ctrl.contains(Ctrl::SPRITE_TABLE) {
+            0x1000
+        } else {
+            0
+        };
+        table + tile as u16 * 16 + row
+    };
+    let mut low = read_vram(nes, addr);
+    let mut high = read_vram(nes, addr + 8);
+
+    let ppu = &mut nes.ppu;
+    if slot < ppu.next_count {
+        if attr & 0x40 != 0 {
+            low = low.reverse_bits();
+            high = high.reverse_bits();
+        }
+        ppu.sprite_low[slot] = low;
+        ppu.sprite_high[slot] = high;
+        ppu.sprite_attr[slot] = attr;
+        ppu.sprite_x[slot] = x;
+    }
+    if slot == 7 {
+        ppu.sprite_count = ppu.next_count;
+        ppu.sprite_zero = ppu.next_zero;
+    }
+}
+
+#[inline(always)]
+fn render_pixel(nes: &mut Nes) {
+    let headless = nes.headless;
+    let ppu = &mut nes.ppu;
+    let x = ppu.dot as usize - 1;
+    let y = ppu.scanline as usize;
+
+    let bg = if ppu.mask.contains(Mask::BG) && (8 <= x || ppu.mask.contains(Mask::BG_LEFT)) {
+        let pixel = (ppu.bg_pixels >> (60 - 4 * ppu.x as u32)) as u8 & 0x0F;
+        if pixel & 0x03 == 0 {
+            0
+        } else {
+            pixel
+        }
+    } else {
+        0
+    };
+
+    let mut sprite = None;
+    if ppu.mask.contains(Mask::SPRITE) && (8 <= x || ppu.mask.contains(Mask::SPRITE_LEFT)) {
+        for i in 0..ppu.sprite_count {
+            let offset = x.wrapping_sub(ppu.sprite_x[i] as usize);
+            if 8 <= offset {
+                continue;
+            }
+            let bit = 0x80 >> offset;
+            let pattern =
+                (ppu.sprite_low[i] & bit != 0) as u8 | ((ppu.sprite_high[i] & bit != 0) as u8) << 1;
+            if pattern == 0 {
+                continue;
+            }
+            sprite = Some((i, 0x10 | (ppu.sprite_attr[i] & 0x03) << 2 | pattern));
+            break;
+        }
+    }
+
+    let mut hit = false;
+    let color = match sprite {
+        Some((i, sprite)) => {
+            if i == 0
+                && ppu.sprite_zero
+                && bg != 0
+                && x != 255
+                && !ppu.status.contains(Status::SPRITE_ZERO_HIT)
+            {
+                ppu.status.insert(Status::SPRITE_ZERO_HIT);
+                hit = true;
+            }
+            let behind = ppu.sprite_attr[i] & 0x20 != 0;
+            if bg != 0 && behind {
+                bg
+            } else {
+                sprite
+            }
+        }
+        None => bg,
+    };
+    if hit {
+        hook::emit(nes, Event::Sprite0Hit);
+    }
+    // only sprite 0 hit is visible to the game
+    if headless {
+        return;
+    }
+    let ppu = &mut nes.ppu;
+    let mut color = ppu.palette[ppu.palette_index(color as u16)];
+    if ppu.mask.contains(Mask::GREYSCALE) {
+        color &= 0x30;
+    }
+    let emphasis = (ppu.mask & Mask::EMPHASIS).bits() as u16;
+    ppu.buffer[y * WIDTH + x] = color as u16 | emphasis << 1;
+}
+
+// Pixels while rendering is disabled show the backdrop color, or the palette
+// entry VRAM address points to
+fn render_disabled_line(nes: &mut Nes) {
+    let ppu = &mut nes.ppu;
+    if nes.headless || ppu.rendering() || HEIGHT as u16 <= ppu.scanline {
+        return;
+    }
+    let i = if ppu.v & 0x3F00 == 0x3F00 {
+        ppu.palette_index(ppu.v)
+    } else {
+        0
+    };
+    let color = ppu.palette[i] as u16 | ((ppu.mask & Mask::EMPHASIS).bits() as u16) << 1;
+    let y = ppu.scanline as usize;
+    ppu.buffer[y * WIDTH..(y + 1) * WIDTH]
+        .iter_mut()
+        .for_each(|p| *p = color);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::nes::NTSC;
+
+    #[test]
+    fn test_nametable_addr() {
+        #[rustfmt::skip]
+        let cases = [
+            (Mirroring::Horizontal,        [0x0000, 0x0000, 0x0400, 0x0400]),
+            (Mirroring::Vertical,          [0x0000, 0x0400, 0x0000, 0x0400]),
+            (Mirroring::SingleScreenLower, [0x0000, 0x0000, 0x0000, 0x0000]),
+            (Mirroring::SingleScreenUpper, [0x0400, 0x0400, 0x0400, 0x0400]),
+            (Mirroring::FourScreen,        [0x0000, 0x0400, 0x0800, 0x0C00]),
+        ];
+
+        for (mirroring, expected) in cases {
+            for (i, base) in expected.iter().enumerate() {
+                let addr = 0x2000 + i as u16 * 0x400 + 0x123;
+                assert_eq!(
+                    nametable_addr(mirroring, addr),
+                    base + 0x123,
+                    "{:?}",
+                    mirroring
+                );
+                // $3000-$3EFF mirrors $2000-$2EFF
+                let addr = addr + 0x1000;
+                assert_eq!(
+                    nametable_addr(mirroring, addr),
+                    base + 0x123,
+                    "{:?}",
+                    mirroring
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_vram_access() {
+        let mut nes = Nes::new();
+        // $2006 takes the high byte first
+        write_register(&mut nes, 0x2006, 0x21);
+        write_register(&mut nes, 0x2006, 0x08);
+        write_register(&mut nes, 0x2007, 0x11);
+        write_register(&mut nes, 0x2007, 0x22);
+
+        write_register(&mut nes, 0x2006, 0x21);
+        write_register(&mut nes, 0x2006, 0x08);
+        // reads below the palette come through the buffer one read late
+        read_register(&mut nes, 0x2007);
+        assert_eq!(read_register(&mut nes, 0x2007), 0x11);
+        assert_eq!(read_register(&mut nes, 0x2007), 0x22);
+
+        // +32 increment
+        write_register(&mut nes, 0x2000, 0x04);
+        write_register(&mut nes, 0x2006, 0x3F);
+        write_register(&mut nes, 0x2006, 0x10);
+        write_register(&mut nes, 0x2007, 0x2A);
+        assert_eq!(nes.ppu.v, 0x3F30);
+        // $3F10 mirrors $3F00, and palette reads aren't buffered
+        write_register(&mut nes, 0x2006, 0x3F);
+        write_register(&mut nes, 0x2006, 0x00);
+        assert_eq!(read_register(&mut nes, 0x2007), 0x2A);
+    }
+
+    #[test]
+    fn test_scroll() {
+        let mut nes = Nes::new();
+        write_register(&mut nes, 0x2000, 0x03);
+        write_register(&mut nes, 0x2005, 0x7D);
+        write_register(&mut nes, 0x2005, 0x5E);
+        // fine Y 6, nametable 3, coarse Y 11, coarse X 15
+        assert_eq!(nes.ppu.t, 0x6D6F);
+        assert_eq!(nes.ppu.x, 0b101);
+
+        // reading $2002 resets the write toggle
+        write_register(&mut nes, 0x2005, 0x00);
+        read_register(&mut nes, 0x2002);
+        write_register(&mut nes, 0x2005, 0x08);
+        assert_eq!(nes.ppu.t & 0x001F, 0x01);
+    }
+
+    #[test]
+    fn test_bg_pixels() {
+        let mut ppu = Ppu {
+            pattern_low: 0b1010_0001,
+            pattern_high: 0b0110_0001,
+            attribute: 2,
+            ..Default::default()
+        };
+        ppu.load_bg_shifters();
+        assert_eq!(ppu.bg_pixels, 0x9AB8_888B);
+        for _ in 0..8 {
+            ppu.shift_bg();
+        }
+        ppu.pattern_low = 0xFF;
+        ppu.pattern_high = 0;
+        ppu.attribute = 1;
+        ppu.load_bg_shifters();
+        assert_eq!(ppu.bg_pixels, 0x9AB8_888B_5555_5555);
+
+        let planes = [0x8001, 0x00FF, 0xFFFF, 0x1234];
+        let pixels = (0..4).fold(0, |p, plane| p | spread(planes[plane]) << plane);
+        for (plane, &bits) in planes.iter().enumerate() {
+            assert_eq!(gather(pixels, plane as u32), bits, "{}", plane);
+        }
+    }
+
+    #[test]
+    fn test_vblank() {
+        let mut nes = Nes::new();
+        let vblank = 241 * 341 + 2;
+        for _ in 0..vblank {
+            step::<NTSC>(&mut nes);
+        }
+        assert!(nes.ppu.status.contains(Status::VBLANK));
+        assert!(nes.ppu.frame_ready);
+        // NMI disabled
+        assert!(!nes.nmi);
+
+        // enabling NMI during VBlank raises it immediately
+        write_register(&mut nes, 0x2000, 0x80);
+        assert!(nes.nmi);
+
+        nes.nmi = false;
+        assert_eq!(read_register(&mut nes, 0x2002) & 0x80, 0x80);
+        assert_eq!(read_register(&mut nes, 0x2002) & 0x80, 0x00);
+        // toggling NMI enable after the flag was read does nothing
+        write_register(&mut nes, 0x2000, 0x00);
+        write_register(&mut nes, 0x2000, 0x80);
+        assert!(!nes.nmi);
+
+        // the pre-render line ends the frame
+        for _ in vblank..341 * 262 {
+            step::<NTSC>(&mut nes);
+        }
+        assert_eq!((nes.ppu.frame, nes.ppu.scanline, nes.ppu.dot), (1, 0, 0));
+    }
+}
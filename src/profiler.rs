@@ -0,0 +1,202 @@
+// Counters for finding hot spots in 6502 code: instructions executed per opcode,
+// and CPU cycles per instruction address. Addresses in PRG-ROM are told apart by
+// the ROM offset behind them, so code in different banks at the same address
+// gets counted separately.
+//
+// Cycles the CPU spends entering interrupts or halted for OAM DMA are counted on
+// their own rather than charged to the instruction they interrupted.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use crate::cpu;
+
+// How many of the hottest opcodes and addresses `Display` lists
+const REPORT_LINES: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct HotSpot {
+    // Address of the instruction
+    pub pc: u16,
+    // Where in PRG-ROM it was, None when running from RAM
+    pub rom_offset: Option<usize>,
+    pub cycles: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Profile {
+    opcodes: [u64; 256],
+    cycles: HashMap<(u16, Option<usize>), u64>,
+    interrupt_cycles: u64,
+    dma_cycles: u64,
+    // DMA cycles within the instruction being executed
+    pending_dma: u64,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            opcodes: [0; 256],
+            cycles: HashMap::new(),
+            interrupt_cycles: 0,
+            dma_cycles: 0,
+            pending_dma: 0,
+        }
+    }
+}
+
+impl Profile {
+    pub(crate) fn instruction(
+        &mut self,
+        pc: u16,
+        rom_offset: Option<usize>,
+        opcode: u8,
+        cycles: u64,
+    ) {
+        self.opcodes[opcode as usize] += 1;
+        *self.cycles.entry((pc, rom_offset)).or_insert(0) += cycles - self.pending_dma;
+        self.pending_dma = 0;
+    }
+
+    pub(crate) fn interrupt(&mut self, cycles: u64) {
+        self.interrupt_cycles += cycles;
+    }
+
+    pub(crate) fn dma(&mut self, cycles: u64) {
+        self.dma_cycles += cycles;
+        self.pending_dma += cycles;
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.opcodes.iter().sum()
+    }
+
+    pub fn opcode_count(&self, opcode: u8) -> u64 {
+        self.opcodes[opcode as usize]
+    }
+
+    // Executed opcodes with their counts, most frequent first
+    pub fn hot_opcodes(&self) -> Vec<(u8, u64)> {
+        let mut opcodes: Vec<(u8, u64)> = (0..=0xFF)
+            .map(|op| (op, self.opcodes[op as usize]))
+            .filter(|&(_, n)| n != 0)
+            .collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        opcodes
+    }
+
+    // Cycles spent executing instructions, excluding interrupts and DMA
+    pub fn instruction_cycles(&self) -> u64 {
+        self.cycles.values().sum()
+    }
+
+    pub fn interrupt_cycles(&self) -> u64 {
+        self.interrupt_cycles
+    }
+
+    pub fn dma_cycles(&self) -> u64 {
+        self.dma_cycles
+    }
+
+    // Cycles spent in instructions at `addrs`, whichever banks were mapped there
+    pub fn cycles_in(&self, addrs: RangeInclusive<u16>) -> u64 {
+        self.cycles
+            .iter()
+            .filter(|((pc, _), _)| addrs.contains(pc))
+            .map(|(_, &n)| n)
+            .sum()
+    }
+
+    // Cycles spent in each PRG-ROM bank of `bank_size` bytes that ran code, by
+    // bank number
+    pub fn cycles_per_bank(&self, bank_size: usize) -> Vec<(usize, u64)> {
+        let mut banks: HashMap<usize, u64> = HashMap::new();
+        for (&(_, offset), &n) in &self.cycles {
+            if let Some(offset) = offset {
+                *banks.entry(offset / bank_size.max(1)).or_insert(0) += n;
+            }
+        }
+        let mut banks: Vec<(usize, u64)> = banks.into_iter().collect();
+        banks.sort_unstable();
+        banks
+    }
+
+    // Instruction addresses by the cycles spent there, hottest first
+    pub fn hot_spots(&self) -> Vec<HotSpot> {
+        let mut spots: Vec<HotSpot> = self
+            .cycles
+            .iter()
+            .map(|(&(pc, rom_offset), &cycles)| HotSpot {
+                pc,
+                rom_offset,
+                cycles,
+            })
+            .collect();
+        spots.sort_by(|a, b| b.cycles.cmp(&a.cycles).then(a.cmp(b)));
+        spots
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} instructions in {} cycles, {} cycles in interrupts, {} in DMA",
+            self.instructions(),
+            self.instruction_cycles(),
+            self.interrupt_cycles,
+            self.dma_cycles,
+        )?;
+        writeln!(f, "opcodes:")?;
+        for (op, n) in self.hot_opcodes().into_iter().take(REPORT_LINES) {
+            writeln!(f, "  ${:02X} {:<4}{:>12}", op, cpu::mnemonic(op), n)?;
+        }
+        writeln!(f, "addresses:")?;
+        for spot in self.hot_spots().into_iter().take(REPORT_LINES) {
+            match spot.rom_offset {
+                Some(offset) => write!(f, "  ${:04X} (ROM ${:06X})", spot.pc, offset)?,
+                None => write!(f, "  ${:04X}              ", spot.pc)?,
+            }
+            writeln!(f, "{:>12}", spot.cycles)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_profile() {
+        let mut p = Profile::default();
+        p.instruction(0x8000, Some(0x4000), 0xEA, 2);
+        p.instruction(0x8000, Some(0x0000), 0xEA, 2);
+        p.instruction(0x8001, Some(0x4001), 0xAD, 4);
+        p.instruction(0x0300, None, 0xAD, 4);
+        p.instruction(0x0300, None, 0xAD, 4);
+        p.interrupt(7);
+        p.dma(513);
+        p.instruction(0x0310, None, 0x8D, 517);
+
+        assert_eq!(p.instructions(), 6);
+        assert_eq!(p.opcode_count(0xAD), 3);
+        assert_eq!(p.hot_opcodes(), [(0xAD, 3), (0xEA, 2), (0x8D, 1)]);
+        assert_eq!(p.instruction_cycles(), 20);
+        assert_eq!(p.interrupt_cycles(), 7);
+        assert_eq!(p.dma_cycles(), 513);
+        assert_eq!(p.cycles_in(0x8000..=0xFFFF), 8);
+        assert_eq!(p.cycles_per_bank(0x4000), [(0, 2), (1, 6)]);
+        assert_eq!(
+            p.hot_spots()[0],
+            HotSpot {
+                pc: 0x0300,
+                rom_offset: None,
+                cycles: 8
+            }
+        );
+        assert_eq!(p.hot_spots().len(), 5);
+        assert!(p.to_string().contains("$AD LDA"));
+    }
+}
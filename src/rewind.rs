@@ -0,0 +1,189 @@
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RewindConfig {
+    // Frames between snapshots
+    pub interval: u32,
+    // Number of snapshots kept; the oldest are dropped
+    pub capacity: usize,
+    // Store all but the newest snapshot as differences to the next one, which
+    // takes a fraction of the memory but costs time when rewinding far
+    pub compress: bool,
+}
+
+impl Default for RewindConfig {
+    fn default() -> Self {
+        // 20 seconds at 60 fps
+        Self {
+            interval: 2,
+            capacity: 600,
+            compress: true,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    // PPU frame count the snapshot was taken at
+    frame: u64,
+    data: Vec<u8>,
+    // `data` is a delta against the next newer snapshot
+    delta: bool,
+}
+
+// Ring buffer of snapshots, newest last. Only the newest is always stored whole.
+#[derive(Debug)]
+pub(crate) struct Rewind {
+    pub(crate) config: RewindConfig,
+    entries: VecDeque<Entry>,
+}
+
+impl Rewind {
+    pub(crate) fn new(config: RewindConfig) -> Self {
+        Self {
+            config,
+            entries: VecDeque::new(),
+        }
+    }
+
+    // Whether a snapshot is due at `frame`
+    pub(crate) fn due(&self, frame: u64) -> bool {
+        frame.is_multiple_of(self.config.interval.max(1) as u64)
+    }
+
+    pub(crate) fn push(&mut self, frame: u64, data: Vec<u8>) {
+        if self.config.compress {
+            if let Some(newest) = self.entries.back_mut() {
+                if let Some(delta) = encode(&newest.data, &data) {
+                    newest.data = delta;
+                    newest.delta = true;
+                }
+            }
+        }
+        self.entries.push_back(Entry {
+            frame,
+            data,
+            delta: false,
+        });
+        while self.config.capacity.max(1) < self.entries.len() {
+            self.entries.pop_front();
+        }
+    }
+
+    // Drop every snapshot newer than the newest one taken at or before `frame`
+    // (or all but the oldest), and return that one whole, leaving it newest.
+    pub(crate) fn seek(&mut self, frame: u64) -> Option<(u64, &[u8])> {
+        let target = self
+            .entries
+            .iter()
+            .rposition(|e| e.frame <= frame)
+            .unwrap_or(0);
+        while target + 1 < self.entries.len() {
+            let newer = self.entries.pop_back()?;
+            let entry = self.entries.back_mut()?;
+            if entry.delta {
+                entry.data = decode(&entry.data, &newer.data);
+                entry.delta = false;
+            }
+        }
+        self.entries.back().map(|e| (e.frame, &e.data[..]))
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+// Delta encoding: `old` XOR `new` as runs of a u16 count of unchanged bytes, a u16
+// count of changed bytes and the XORed changed bytes. None when sizes differ.
+fn encode(old: &[u8], new: &[u8]) -> Option<Vec<u8>> {
+    if old.len() != new.len() {
+        return None;
+    }
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < old.len() {
+        let same = old[i..]
+            .iter()
+            .zip(&new[i..])
+            .take(u16::MAX as usize)
+            .take_while(|(a, b)| a == b)
+            .count();
+        i += same;
+        let start = i;
+        let changed = old[i..]
+            .iter()
+            .zip(&new[i..])
+            .take(u16::MAX as usize)
+            .take_while(|(a, b)| a != b)
+            .count();
+        i += changed;
+        out.extend_from_slice(&(same as u16).to_le_bytes());
+        out.extend_from_slice(&(changed as u16).to_le_bytes());
+        out.extend(old[start..i].iter().zip(&new[start..i]).map(|(a, b)| a ^ b));
+    }
+    Some(out)
+}
+
+fn decode(delta: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut out = new.to_vec();
+    let mut i = 0;
+    let mut pos = 0;
+    while pos + 4 <= delta.len() {
+        let same = u16::from_le_bytes([delta[pos], delta[pos + 1]]) as usize;
+        let changed = u16::from_le_bytes([delta[pos + 2], delta[pos + 3]]) as usize;
+        pos += 4;
+        i += same;
+        for (b, x) in out[i..i + changed]
+            .iter_mut()
+            .zip(&delta[pos..pos + changed])
+        {
+            *b ^= x;
+        }
+        i += changed;
+        pos += changed;
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_delta() {
+        let old: Vec<u8> = (0..200_000).map(|i| (i / 7) as u8).collect();
+        let mut new = old.clone();
+        new[0] ^= 1;
+        new[100] = 0;
+        new[70_000..70_010].iter_mut().for_each(|b| *b = !*b);
+        new[199_999] = 0xFF;
+
+        let delta = encode(&old, &new).unwrap();
+        assert!(delta.len() < 100, "{}", delta.len());
+        assert_eq!(decode(&delta, &new), old);
+        assert_eq!(encode(&old, &new[1..]), None);
+    }
+
+    #[test]
+    fn test_seek() {
+        for compress in [false, true] {
+            let mut rewind = Rewind::new(RewindConfig {
+                interval: 2,
+                capacity: 4,
+                compress,
+            });
+            for frame in (0..12).step_by(2) {
+                rewind.push(frame, vec![frame as u8; 16]);
+            }
+            // frames 0 and 2 were dropped
+            assert_eq!(rewind.entries.len(), 4);
+
+            assert_eq!(rewind.seek(9), Some((8, &[8; 16][..])), "{}", compress);
+            assert_eq!(rewind.entries.len(), 3);
+            // earlier than what's kept goes to the oldest
+            assert_eq!(rewind.seek(1), Some((4, &[4; 16][..])), "{}", compress);
+            assert_eq!(rewind.entries.len(), 1);
+        }
+    }
+}
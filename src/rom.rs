@@ -1,23 +1,328 @@
 use std::fmt;
+use std::fs::File;
 use std::io::BufReader;
 use std::io::Read;
+use std::path::Path;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 
+use crate::config::Region;
 use crate::nes::Mirroring;
 
-#[allow(dead_code)]
-pub(crate) fn parse(rom: &[u8]) -> Result<(Header, Vec<u8>)> {
+#[cfg(feature = "archive")]
+mod archive;
+mod db;
+mod fds;
+mod hash;
+mod info;
+mod nsf;
+mod unif;
+
+pub use db::RomDatabase;
+pub use fds::{FdsFile, FdsFileKind, FdsImage, FdsSide};
+pub use info::RomInfo;
+pub(crate) use nsf::Nsf2Flags;
+pub use nsf::{NsfFile, NsfTrack};
+
+use db::DbEntry;
+
+// An iNES/NES 2.0 ROM image split into its parts
+#[derive(Debug)]
+pub struct Cartridge {
+    pub(crate) header: Header,
+    pub(crate) trainer: Option<Vec<u8>>,
+    pub(crate) prg_rom: Vec<u8>,
+    pub(crate) chr_rom: Vec<u8>,
+
+    // of PRG-ROM + CHR-ROM
+    crc32: u32,
+    sha1: [u8; 20],
+    // matched ROM database entry
+    pub(crate) db_entry: Option<DbEntry>,
+    // problems found while loading that did not prevent it
+    warnings: Vec<String>,
+}
+
+impl Cartridge {
+    // Load an iNES/NES 2.0 or UNIF image
+    pub fn from_bytes(rom: &[u8]) -> Result<Self> {
+        if rom.starts_with(unif::MAGIC) {
+            unif::parse(rom)
+        } else {
+            parse(rom)
+        }
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        File::open(path)
+            .map_err(anyhow::Error::from)
+            .and_then(Self::from_reader)
+            .with_context(|| format!("failed to load {}", path.display()))
+    }
+
+    // Reads to the end, extracting a .zip/.gz archive if needed
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Self> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        #[cfg(feature = "archive")]
+        if archive::is_archive(&data) {
+            return Self::from_bytes(&archive::extract(&data)?);
+        }
+        Self::from_bytes(&data)
+    }
+
+    fn new(header: Header, trainer: Option<Vec<u8>>, prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Self {
+        let (crc32, sha1) = {
+            let data = [&prg_rom[..], &chr_rom[..]].concat();
+            (hash::crc32(&data), hash::sha1(&data))
+        };
+        Self {
+            header,
+            trainer,
+            prg_rom,
+            chr_rom,
+            crc32,
+            sha1,
+            db_entry: None,
+            warnings: Vec::new(),
+        }
+    }
+
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    // CRC-32 of PRG-ROM + CHR-ROM
+    pub fn crc32(&self) -> u32 {
+        self.crc32
+    }
+
+    // SHA-1 of PRG-ROM + CHR-ROM
+    pub fn sha1(&self) -> [u8; 20] {
+        self.sha1
+    }
+
+    // Region the game was made for, if known: from the ROM database,
+    // the NES 2.0 timing byte or the iNES 1.0 TV system flags, in that order.
+    pub fn region(&self) -> Option<Region> {
+        let timing = self
+            .db_entry
+            .as_ref()
+            .and_then(|e| e.timing)
+            .or_else(|| self.header.nes2.as_ref().map(|n| n.timing))
+            .or_else(|| self.header.unif.as_ref().map(|u| u.timing));
+        if let Some(timing) = timing {
+            return match timing {
+                Timing::Ntsc => Some(Region::Ntsc),
+                Timing::Pal => Some(Region::Pal),
+                Timing::Dendy => Some(Region::Dendy),
+                Timing::Multiple => None,
+            };
+        }
+        self.header.ines1.as_ref().and_then(|i| {
+            match i.tv_system {
+                0 if i.pal => Some(Region::Pal),
+                0 => Some(Region::Ntsc),
+                2 => Some(Region::Pal),
+                // dual compatible
+                _ => None,
+            }
+        })
+    }
+
+    // Correct the header from a matching database entry.
+    // Must be called before the cartridge is loaded; returns whether an entry matched.
+    pub fn apply_database(&mut self, db: &RomDatabase) -> bool {
+        let entry = match db.lookup(self.crc32, &self.sha1) {
+            Some(entry) => entry.clone(),
+            None => return false,
+        };
+        if let Some(mapper) = entry.mapper {
+            self.header.mapper = mapper;
+        }
+        if let Some(mirroring) = entry.mirroring {
+            self.header.mirroring = mirroring;
+        }
+        if let Some(four_screen) = entry.four_screen {
+            self.header.four_screen = four_screen;
+        }
+        self.db_entry = Some(entry);
+        true
+    }
+
+    // The canonical iNES header for the (possibly corrected) cartridge.
+    // NES 2.0 is used when the source was NES 2.0 or iNES 1.0 can't describe it.
+    pub fn ines_header(&self) -> [u8; 16] {
+        let h = &self.header;
+        let db_timing = self.db_entry.as_ref().and_then(|e| e.timing);
+        let timing = db_timing
+            .or_else(|| h.nes2.as_ref().map(|n| n.timing))
+            .or_else(|| h.unif.as_ref().map(|u| u.timing));
+
+        let mut b = [0; 16];
+        b[..4].copy_from_slice(&[0x4E, 0x45, 0x53, 0x1A]);
+        b[6] = (h.mapper as u8) << 4
+            | (h.four_screen as u8) << 3
+            | (h.trainer as u8) << 2
+            | (h.battery as u8) << 1
+            | (h.mirroring == Mirroring::Vertical) as u8;
+        b[7] = (h.mapper as u8) & 0xF0 | (h.playchoice10 as u8) << 1 | h.vs_unisystem as u8;
+
+        if h.nes2.is_some() || h.mapper > 0xFF || timing == Some(Timing::Dendy) {
+            let (prg_msb, prg_lsb) = nes2_rom_size_bytes(h.prg_rom_size, 0x4000);
+            let (chr_msb, chr_lsb) = nes2_rom_size_bytes(h.chr_rom_size, 0x2000);
+            b[4] = prg_lsb;
+            b[5] = chr_lsb;
+            b[9] = chr_msb << 4 | prg_msb;
+            b[7] = b[7] & 0xF0 | 0b1000;
+            match &h.nes2 {
+                Some(n) => {
+                    b[7] |= match n.console {
+                        Console::Nes => 0,
+                        Console::VsSystem(t) => {
+                            b[13] = t;
+                            1
+                        }
+                        Console::Playchoice10 => 2,
+                        Console::Extended(t) => {
+                            b[13] = t;
+                            3
+                        }
+                    };
+                    b[10] = nes2_ram_shift(n.prg_nvram_size) << 4 | nes2_ram_shift(n.prg_ram_size);
+                    b[11] = nes2_ram_shift(n.chr_nvram_size) << 4 | nes2_ram_shift(n.chr_ram_size);
+                    b[14] = n.misc_roms;
+                    b[15] = n.expansion_device;
+                }
+                None => {
+                    b[7] |= (h.playchoice10 as u8) << 1 | h.vs_unisystem as u8;
+                    // the iNES 1.0 defaults: 8KB of PRG-RAM, 8KB of CHR-RAM without CHR-ROM
+                    b[10] = if h.battery { 7 << 4 } else { 7 };
+                    if h.chr_rom_size == 0 {
+                        b[11] = 7;
+                    }
+                }
+            }
+            b[8] = h.submapper << 4 | (h.mapper >> 8) as u8 & 0x0F;
+            b[12] = match timing.unwrap_or(Timing::Ntsc) {
+                Timing::Ntsc => 0,
+                Timing::Pal => 1,
+                Timing::Multiple => 2,
+                Timing::Dendy => 3,
+            };
+        } else {
+            b[4] = (h.prg_rom_size / 0x4000) as u8;
+            b[5] = (h.chr_rom_size / 0x2000) as u8;
+            if let Some(i) = &h.ines1 {
+                // 0 means 8KB
+                b[8] = if i.prg_ram_size == 0x2000 {
+                    0
+                } else {
+                    (i.prg_ram_size / 0x2000) as u8
+                };
+                b[9] = i.pal as u8;
+                b[10] = i.tv_system | (!i.prg_ram as u8) << 4 | (i.bus_conflicts as u8) << 5;
+            }
+            match db_timing.or(timing) {
+                Some(Timing::Pal) => {
+                    b[9] = 1;
+                    b[10] = b[10] & !0b11 | 2;
+                }
+                Some(Timing::Ntsc) => {
+                    b[9] = 0;
+                    b[10] &= !0b11;
+                }
+                Some(Timing::Multiple) => b[10] = b[10] & !0b11 | 1,
+                _ => {}
+            }
+        }
+        b
+    }
+
+    // A repaired ROM file: the canonical header followed by the ROM data
+    pub fn to_ines(&self) -> Vec<u8> {
+        let header = self.ines_header();
+        // sizes that can't be expressed exactly are rounded up, so pad to match
+        let (prg_size, chr_size) = if header[7] & 0b1100 == 0b1000 {
+            (
+                nes2_rom_size(header[9] & 0x0F, header[4], 0x4000),
+                nes2_rom_size(header[9] >> 4, header[5], 0x2000),
+            )
+        } else {
+            (header[4] as usize * 0x4000, header[5] as usize * 0x2000)
+        };
+
+        let mut rom = header.to_vec();
+        if let Some(trainer) = &self.trainer {
+            rom.extend(trainer);
+        }
+        let start = rom.len();
+        rom.extend(&self.prg_rom);
+        rom.resize(start + prg_size, 0);
+        rom.extend(&self.chr_rom);
+        rom.resize(start + prg_size + chr_size, 0);
+        rom
+    }
+
+    // iNES mapper number
+    pub fn mapper(&self) -> u16 {
+        self.header.mapper
+    }
+
+    pub fn submapper(&self) -> u8 {
+        self.header.submapper
+    }
+
+    pub(crate) fn mirroring(&self) -> Mirroring {
+        self.header.mirroring
+    }
+
+    pub(crate) fn four_screen(&self) -> bool {
+        self.header.four_screen
+    }
+
+    // PRG-RAM + PRG-NVRAM in bytes as declared by NES 2.0, None leaves it to the board
+    pub(crate) fn prg_ram_size(&self) -> Option<usize> {
+        let n = self.header.nes2.as_ref()?;
+        Some(n.prg_ram_size + n.prg_nvram_size)
+    }
+
+    // CHR-RAM + CHR-NVRAM in bytes as declared by NES 2.0, None leaves it to the board
+    pub(crate) fn chr_ram_size(&self) -> Option<usize> {
+        let n = self.header.nes2.as_ref()?;
+        Some(n.chr_ram_size + n.chr_nvram_size)
+    }
+
+    pub fn battery(&self) -> bool {
+        self.header.battery
+    }
+
+    pub fn trainer(&self) -> Option<&[u8]> {
+        self.trainer.as_deref()
+    }
+
+    pub fn prg_rom(&self) -> &[u8] {
+        &self.prg_rom
+    }
+
+    pub fn chr_rom(&self) -> &[u8] {
+        &self.chr_rom
+    }
+}
+
+pub(crate) fn parse(rom: &[u8]) -> Result<Cartridge> {
+    if rom.len() < 16 {
+        return Err(RomError::TruncatedHeader.into());
+    }
     let mut cur = BufReader::new(rom);
 
     // validate magic number
-    let _ = {
+    {
         let mut buf = [0; 4];
         cur.read_exact(&mut buf)?;
         if buf != [0x4E, 0x45, 0x53, 0x1A] {
-            Err(ParseError {
-                msg: "invalid magic number".to_string(),
-            })
+            Err(RomError::InvalidMagic)
         } else {
             Ok(())
         }
@@ -34,76 +339,343 @@ pub(crate) fn parse(rom: &[u8]) -> Result<(Header, Vec<u8>)> {
         buf[0]
     };
     // flag 6
-    let mirroring = {
+    let flag6 = {
         let mut buf = [0; 1];
         cur.read_exact(&mut buf)?;
-        let b = buf[0];
-        if b & 1 == 0 {
-            Mirroring::Horizontal
-        } else {
-            Mirroring::Vertical
-        }
+        buf[0]
     };
+    let mirroring = if flag6 & 1 == 0 {
+        Mirroring::Horizontal
+    } else {
+        Mirroring::Vertical
+    };
+    let battery = flag6 & 0b10 != 0;
+    let trainer = flag6 & 0b100 != 0;
+    let four_screen = flag6 & 0b1000 != 0;
 
-    // skip flag 7, 8, 9, 10
-    {
-        let mut buf = [0; 4];
+    // flag 7
+    let flag7 = {
+        let mut buf = [0; 1];
         cur.read_exact(&mut buf)?;
-    }
+        buf[0]
+    };
 
-    // validate unused padding
-    {
-        let mut buf = [0; 4];
+    // byte 8-15
+    let ext = {
+        let mut buf = [0; 8];
         cur.read_exact(&mut buf)?;
-        if buf != [0; 4] {
-            Err(ParseError {
-                msg: "invalid padding".to_string(),
-            })
-        } else {
-            Ok(())
+        buf
+    };
+
+    let vs_unisystem = flag7 & 0b01 != 0;
+    let playchoice10 = flag7 & 0b10 != 0;
+    let mapper = (flag7 & 0xF0 | flag6 >> 4) as u16;
+
+    let (mapper, submapper, prg_rom_size, chr_rom_size, ines1, nes2) = if flag7 & 0b1100 == 0b1000 {
+        let mapper = ((ext[0] & 0x0F) as u16) << 8 | mapper;
+        let prg_rom_size = nes2_rom_size(ext[1] & 0x0F, prg_rom_size, 0x4000);
+        let chr_rom_size = nes2_rom_size(ext[1] >> 4, chr_rom_size, 0x2000);
+        let nes2 = Nes2 {
+            prg_ram_size: nes2_ram_size(ext[2] & 0x0F),
+            prg_nvram_size: nes2_ram_size(ext[2] >> 4),
+            chr_ram_size: nes2_ram_size(ext[3] & 0x0F),
+            chr_nvram_size: nes2_ram_size(ext[3] >> 4),
+            timing: match ext[4] & 0b11 {
+                0 => Timing::Ntsc,
+                1 => Timing::Pal,
+                2 => Timing::Multiple,
+                _ => Timing::Dendy,
+            },
+            console: match flag7 & 0b11 {
+                0 => Console::Nes,
+                1 => Console::VsSystem(ext[5]),
+                2 => Console::Playchoice10,
+                _ => Console::Extended(ext[5] & 0x0F),
+            },
+            misc_roms: ext[6] & 0b11,
+            expansion_device: ext[7] & 0x3F,
+        };
+        (
+            mapper,
+            ext[0] >> 4,
+            prg_rom_size,
+            chr_rom_size,
+            None,
+            Some(nes2),
+        )
+    } else {
+        // validate unused padding (byte 11-15)
+        if ext[3..] != [0; 5] {
+            return Err(RomError::Malformed("invalid padding".to_string()).into());
         }
-    }?;
+        let ines1 = Ines1 {
+            // 0 infers 8KB for compatibility
+            prg_ram_size: ext[0].max(1) as usize * 0x2000,
+            pal: ext[1] & 1 != 0,
+            tv_system: ext[2] & 0b11,
+            prg_ram: ext[2] & 0b1_0000 == 0,
+            bus_conflicts: ext[2] & 0b10_0000 != 0,
+        };
+        (
+            mapper,
+            0,
+            prg_rom_size as usize * 0x4000,
+            chr_rom_size as usize * 0x2000,
+            Some(ines1),
+            None,
+        )
+    };
+
+    // check the declared sizes before allocating and reading them
+    let mut remaining = rom.len() - 16;
+    let trainer_size = if trainer { 512 } else { 0 };
+    if remaining < trainer_size {
+        return Err(RomError::TruncatedTrainer {
+            expected: trainer_size,
+            found: remaining,
+        }
+        .into());
+    }
+    remaining -= trainer_size;
+    if remaining < prg_rom_size {
+        return Err(RomError::TruncatedPrg {
+            expected: prg_rom_size,
+            found: remaining,
+        }
+        .into());
+    }
+    remaining -= prg_rom_size;
+    if remaining < chr_rom_size {
+        return Err(RomError::TruncatedChr {
+            expected: chr_rom_size,
+            found: remaining,
+        }
+        .into());
+    }
+    remaining -= chr_rom_size;
+
+    // 512-byte trainer, placed at $7000-$71FF
+    let trainer_data = if trainer {
+        let mut buf = vec![0; 512];
+        cur.read_exact(&mut buf)?;
+        Some(buf)
+    } else {
+        None
+    };
+
+    let mut prg_rom = vec![0; prg_rom_size];
+    cur.read_exact(&mut prg_rom)?;
+    let mut chr_rom = vec![0; chr_rom_size];
+    cur.read_exact(&mut chr_rom)?;
 
-    let mut buf = Vec::new();
-    cur.read_to_end(&mut buf)?;
+    // PlayChoice-10 and NES 2.0 miscellaneous ROMs legitimately follow CHR-ROM
+    let misc_roms = playchoice10 || nes2.as_ref().is_some_and(|n| n.misc_roms != 0);
 
-    Ok((
+    let mut cart = Cartridge::new(
         Header {
+            mapper,
+            submapper,
             prg_rom_size,
             chr_rom_size,
             mirroring,
+            battery,
+            trainer,
+            four_screen,
+            vs_unisystem,
+            playchoice10,
+            ines1,
+            nes2,
+            unif: None,
         },
-        buf,
-    ))
+        trainer_data,
+        prg_rom,
+        chr_rom,
+    );
+    if remaining != 0 && !misc_roms {
+        cart.warnings.push(format!(
+            "{} bytes of trailing data after CHR-ROM",
+            remaining
+        ));
+    }
+    Ok(cart)
+}
+
+// ROM size in bytes from the NES 2.0 LSB byte and MSB nibble
+fn nes2_rom_size(msb: u8, lsb: u8, unit: usize) -> usize {
+    if msb == 0x0F {
+        // exponent-multiplier notation: 2^E * (MM * 2 + 1)
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0b11) as usize * 2 + 1;
+        2usize.saturating_pow(exponent).saturating_mul(multiplier)
+    } else {
+        ((msb as usize) << 8 | lsb as usize) * unit
+    }
+}
+
+// NES 2.0 MSB nibble and LSB byte for a ROM size, the inverse of nes2_rom_size
+fn nes2_rom_size_bytes(size: usize, unit: usize) -> (u8, u8) {
+    let units = size / unit;
+    if size.is_multiple_of(unit) && units < 0xF00 {
+        return ((units >> 8) as u8, units as u8);
+    }
+    for multiplier in 0..4 {
+        let m = multiplier * 2 + 1;
+        if size.is_multiple_of(m) && (size / m).is_power_of_two() {
+            let exponent = (size / m).trailing_zeros() as u8;
+            if exponent < 64 {
+                return (0x0F, exponent << 2 | multiplier as u8);
+            }
+        }
+    }
+    // not representable; round up to whole units
+    let units = size.div_ceil(unit).min(0xEFF);
+    ((units >> 8) as u8, units as u8)
+}
+
+// NES 2.0 shift count for a RAM size, rounded up
+fn nes2_ram_shift(size: usize) -> u8 {
+    if size == 0 {
+        0
+    } else {
+        (1..=15).find(|s| 64 << s >= size).unwrap_or(15)
+    }
+}
+
+// RAM size in bytes from a NES 2.0 shift count
+fn nes2_ram_size(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    } else {
+        64 << shift
+    }
 }
 
 #[derive(Debug)]
 pub(crate) struct Header {
-    prg_rom_size: u8,
-    chr_rom_size: u8,
+    mapper: u16,
+    // NES 2.0 only, 0 otherwise
+    submapper: u8,
+    // in bytes
+    prg_rom_size: usize,
+    chr_rom_size: usize,
     mirroring: Mirroring,
+    // battery-backed PRG-RAM or other persistent memory
+    battery: bool,
+    trainer: bool,
+    // ignore mirroring and provide 4KB of nametable RAM
+    four_screen: bool,
+    vs_unisystem: bool,
+    playchoice10: bool,
+    // flag 8-10 of iNES 1.0, None for NES 2.0
+    ines1: Option<Ines1>,
+    // NES 2.0 extensions, None for iNES 1.0
+    nes2: Option<Nes2>,
+    // set for images loaded from UNIF
+    unif: Option<Unif>,
 }
 
-#[derive(Clone, Debug)]
-pub(crate) struct ParseError {
-    msg: String,
+#[derive(Debug)]
+pub(crate) struct Unif {
+    board: String,
+    timing: Timing,
+}
+
+// Rarely used and often unreliable
+#[derive(Debug)]
+pub(crate) struct Ines1 {
+    // in bytes
+    prg_ram_size: usize,
+    pal: bool,
+    // flag 10: 0 = NTSC, 2 = PAL, 1/3 = dual compatible
+    tv_system: u8,
+    // PRG-RAM present at $6000-$7FFF
+    prg_ram: bool,
+    bus_conflicts: bool,
+}
+
+#[derive(Debug)]
+pub(crate) struct Nes2 {
+    // in bytes
+    prg_ram_size: usize,
+    prg_nvram_size: usize,
+    chr_ram_size: usize,
+    chr_nvram_size: usize,
+    timing: Timing,
+    console: Console,
+    misc_roms: u8,
+    expansion_device: u8,
+}
+
+// CPU/PPU timing the ROM was made for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Timing {
+    Ntsc,
+    Pal,
+    Multiple,
+    Dendy,
 }
 
-impl fmt::Display for ParseError {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Console {
+    Nes,
+    // PPU type and hardware type byte
+    VsSystem(u8),
+    Playchoice10,
+    // extended console type
+    Extended(u8),
+}
+
+// Why a ROM image could not be loaded
+#[derive(Clone, Debug, PartialEq)]
+pub enum RomError {
+    InvalidMagic,
+    UnsupportedVersion(u8),
+    TruncatedHeader,
+    TruncatedTrainer { expected: usize, found: usize },
+    TruncatedPrg { expected: usize, found: usize },
+    TruncatedChr { expected: usize, found: usize },
+    UnsupportedMapper(u16),
+    UnsupportedBoard(String),
+    NoRomInArchive,
+    // structurally invalid in a format-specific way
+    Malformed(String),
+}
+
+impl fmt::Display for RomError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "iNES file parse error: {}", self.msg)
+        match self {
+            RomError::InvalidMagic => write!(f, "invalid magic number"),
+            RomError::UnsupportedVersion(v) => write!(f, "unsupported format version {}", v),
+            RomError::TruncatedHeader => write!(f, "truncated header"),
+            RomError::TruncatedTrainer { expected, found } => write!(
+                f,
+                "truncated trainer: header declares {} bytes, found {}",
+                expected, found
+            ),
+            RomError::TruncatedPrg { expected, found } => write!(
+                f,
+                "truncated PRG-ROM: header declares {} bytes, found {}",
+                expected, found
+            ),
+            RomError::TruncatedChr { expected, found } => write!(
+                f,
+                "truncated CHR-ROM: header declares {} bytes, found {}",
+                expected, found
+            ),
+            RomError::UnsupportedMapper(n) => write!(f, "unsupported mapper {}", n),
+            RomError::UnsupportedBoard(name) => write!(f, "unsupported board {}", name),
+            RomError::NoRomInArchive => write!(f, "no .nes file in archive"),
+            RomError::Malformed(msg) => write!(f, "{}", msg),
+        }
     }
 }
 
-impl std::error::Error for ParseError {}
+impl std::error::Error for RomError {}
 
 #[cfg(test)]
 mod test {
     use super::*;
 
-    use std::fs::File;
-    use std::path::Path;
-
     #[test]
     fn test_parse() {
         let root = env!("CARGO_MANIFEST_DIR");
@@ -117,14 +689,291 @@ mod test {
 
         assert_matches!(
             result,
-            Ok((
-                Header {
-                    prg_rom_size: 1,
-                    chr_rom_size: 1,
+            Ok(Cartridge {
+                header: Header {
+                    mapper: 0,
+                    submapper: 0,
+                    prg_rom_size: 0x4000,
+                    chr_rom_size: 0x2000,
                     mirroring: Mirroring::Horizontal,
+                    battery: false,
+                    trainer: false,
+                    four_screen: false,
+                    vs_unisystem: false,
+                    playchoice10: false,
+                    ines1: Some(Ines1 {
+                        prg_ram_size: 0x2000,
+                        pal: false,
+                        tv_system: 0,
+                        prg_ram: true,
+                        bus_conflicts: false,
+                    }),
+                    nes2: None,
+                    unif: None,
                 },
-                _
-            ))
-        )
+                trainer: None,
+                ..
+            })
+        );
+        let cart = result.unwrap();
+        assert_eq!(cart.prg_rom.len(), 0x4000);
+        assert_eq!(cart.chr_rom.len(), 0x2000);
+    }
+
+    #[test]
+    fn test_from_path() {
+        let root = env!("CARGO_MANIFEST_DIR");
+        let path = Path::new(root).join("roms/nestest.nes");
+
+        let cart = Cartridge::from_path(&path).unwrap();
+        assert_eq!(cart.prg_rom().len(), 0x4000);
+
+        let cart = Cartridge::from_reader(File::open(&path).unwrap()).unwrap();
+        assert_eq!(cart.chr_rom().len(), 0x2000);
+
+        let mut rom = std::fs::read(&path).unwrap();
+        rom[0] = b'X';
+        let err = Cartridge::from_reader(&rom[..]).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&RomError::InvalidMagic));
+
+        let err = Cartridge::from_path("missing.nes").unwrap_err();
+        assert_eq!(err.to_string(), "failed to load missing.nes");
+    }
+
+    #[test]
+    fn test_parse_trainer() {
+        #[rustfmt::skip]
+        let mut rom = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x01, 0x00, 0b0000_0100, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        rom.extend((0..512).map(|i| i as u8));
+        rom.extend(vec![0xEA; 0x4000]);
+
+        let cart = parse(&rom).unwrap();
+        assert!(cart.header.trainer);
+        let trainer = cart.trainer().unwrap();
+        assert_eq!(trainer.len(), 512);
+        assert_eq!(trainer[0x1FF], 0xFF);
+        assert_eq!(cart.prg_rom(), &[0xEA; 0x4000][..]);
+        assert!(cart.chr_rom().is_empty());
+    }
+
+    #[test]
+    fn test_parse_size_mismatch() {
+        #[rustfmt::skip]
+        let header = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+
+        #[rustfmt::skip]
+        let cases = [
+            (0x4000, RomError::TruncatedPrg { expected: 0x8000, found: 0x4000 }),
+            (0x8000 + 0x1000, RomError::TruncatedChr { expected: 0x2000, found: 0x1000 }),
+        ];
+        for (len, want) in cases.iter() {
+            let mut rom = header.clone();
+            rom.resize(16 + len, 0);
+            let err = parse(&rom).unwrap_err();
+            assert_eq!(err.downcast_ref::<RomError>(), Some(want), "{}", len);
+        }
+        let err = parse(&header[..8]).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&RomError::TruncatedHeader));
+
+        let mut rom = header.clone();
+        rom.resize(16 + 0xA000 + 0x10, 0);
+        let cart = parse(&rom).unwrap();
+        assert_eq!(cart.warnings(), ["16 bytes of trailing data after CHR-ROM"]);
+
+        // a NES 2.0 exponent size must fail rather than allocate
+        let mut rom = header;
+        rom[7] = 0b1000;
+        rom[9] = 0x0F;
+        rom[4] = 0b1111_1100;
+        assert!(parse(&rom).is_err());
+    }
+
+    #[test]
+    fn test_parse_ines_flags() {
+        #[rustfmt::skip]
+        let mut rom = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01,
+            // mapper low nibble 1, battery
+            0b0001_0010,
+            // mapper high nibble 4, PlayChoice-10
+            0b0100_0010,
+            // PRG-RAM 16KB, PAL, no PRG-RAM, bus conflicts
+            0x02, 0x01, 0b0011_0010,
+            0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        rom.extend(vec![0; 0x8000 + 0x2000]);
+
+        let header = parse(&rom).unwrap().header;
+        assert_eq!(header.mapper, 0x41);
+        assert!(header.battery);
+        assert!(!header.four_screen);
+        assert!(header.playchoice10);
+        assert!(header.nes2.is_none());
+
+        let ines1 = header.ines1.unwrap();
+        assert_eq!(ines1.prg_ram_size, 0x4000);
+        assert!(ines1.pal);
+        assert_eq!(ines1.tv_system, 2);
+        assert!(!ines1.prg_ram);
+        assert!(ines1.bus_conflicts);
+    }
+
+    #[test]
+    fn test_region() {
+        #[rustfmt::skip]
+        let cases = [
+            ("iNES flag 9",       0b0000_0000, 0x01, 0x00, 0x00, Some(Region::Pal)),
+            ("iNES flag 10 PAL",  0b0000_0000, 0x00, 0x02, 0x00, Some(Region::Pal)),
+            ("iNES flag 10 dual", 0b0000_0000, 0x00, 0x01, 0x00, None),
+            ("iNES NTSC",         0b0000_0000, 0x00, 0x00, 0x00, Some(Region::Ntsc)),
+            ("NES 2.0 Dendy",     0b0000_1000, 0x00, 0x00, 0x03, Some(Region::Dendy)),
+            ("NES 2.0 multiple",  0b0000_1000, 0x00, 0x00, 0x02, None),
+        ];
+
+        for (name, flag7, b9, b10, b12, expected) in cases {
+            #[rustfmt::skip]
+            let mut rom = vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x00, 0x00, flag7,
+                0x00, b9, b10, 0x00, b12, 0x00, 0x00, 0x00,
+            ];
+            rom.extend(vec![0; 0x4000]);
+
+            let cart = parse(&rom).unwrap();
+            assert_eq!(cart.region(), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_parse_nes2() {
+        #[rustfmt::skip]
+        let mut rom = vec![
+            0x4E, 0x45, 0x53, 0x1A,
+            // PRG LSB, CHR 2^2 * 3 bytes
+            0x02, 0b0000_1001,
+            // mapper low nibble 4, four-screen, vertical
+            0b0100_1001,
+            // mapper middle nibble 7, NES 2.0, Vs. System
+            0b0111_1001,
+            // submapper 3, mapper high nibble 1
+            0x31,
+            // PRG MSB 1, CHR in exponent notation
+            0xF1,
+            // PRG-RAM 8KB, PRG-NVRAM 32KB
+            0x97,
+            // CHR-RAM 8KB
+            0x07,
+            // PAL
+            0x01,
+            0x12, 0x01, 0x05,
+        ];
+        rom.extend(vec![0; 0x102 * 0x4000 + 12]);
+
+        let header = parse(&rom).unwrap().header;
+        assert_eq!(header.mapper, 0x174);
+        assert_eq!(header.submapper, 3);
+        assert!(header.four_screen);
+        assert!(header.vs_unisystem);
+        assert_eq!(header.mirroring, Mirroring::Vertical);
+        assert_eq!(header.prg_rom_size, 0x102 * 0x4000);
+        assert_eq!(header.chr_rom_size, 12);
+
+        let nes2 = header.nes2.unwrap();
+        assert_eq!(nes2.prg_ram_size, 0x2000);
+        assert_eq!(nes2.prg_nvram_size, 0x8000);
+        assert_eq!(nes2.chr_ram_size, 0x2000);
+        assert_eq!(nes2.chr_nvram_size, 0);
+        assert_eq!(nes2.timing, Timing::Pal);
+        assert_eq!(nes2.console, Console::VsSystem(0x12));
+        assert_eq!(nes2.misc_roms, 1);
+        assert_eq!(nes2.expansion_device, 5);
+    }
+
+    #[test]
+    fn test_apply_database() {
+        #[rustfmt::skip]
+        let mut rom = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x01, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        rom.extend(vec![0xEA; 0x4000]);
+
+        let mut cart = parse(&rom).unwrap();
+        assert_eq!(cart.crc32(), hash::crc32(&[0xEA; 0x4000]));
+
+        let db = RomDatabase::parse(&format!(
+            "{:08x} mapper=2 mirroring=vertical region=pal",
+            cart.crc32()
+        ))
+        .unwrap();
+        assert!(cart.apply_database(&db));
+        assert_eq!(cart.header.mapper, 2);
+        assert_eq!(cart.header.mirroring, Mirroring::Vertical);
+        assert_eq!(cart.db_entry.as_ref().unwrap().timing, Some(Timing::Pal));
+        assert_eq!(cart.region(), Some(Region::Pal));
+
+        let mut cart = parse(&rom).unwrap();
+        assert!(!cart.apply_database(&RomDatabase::new()));
+        assert_eq!(cart.header.mapper, 0);
+    }
+
+    #[test]
+    fn test_ines_header() {
+        let root = env!("CARGO_MANIFEST_DIR");
+        let rom = std::fs::read(Path::new(root).join("roms/nestest.nes")).unwrap();
+        let cart = parse(&rom).unwrap();
+        assert_eq!(cart.to_ines(), rom);
+
+        // the database corrections end up in the exported header
+        #[rustfmt::skip]
+        let mut rom = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x01, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        rom.extend(vec![0xEA; 0x4000]);
+        let mut cart = parse(&rom).unwrap();
+        let db = RomDatabase::parse(&format!(
+            "{:08x} mapper=66 mirroring=vertical region=pal",
+            cart.crc32()
+        ))
+        .unwrap();
+        cart.apply_database(&db);
+        #[rustfmt::skip]
+        assert_eq!(cart.ines_header(), [
+            0x4E, 0x45, 0x53, 0x1A, 0x01, 0x00, 0x21, 0x40,
+            0x00, 0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]);
+        let fixed = parse(&cart.to_ines()).unwrap();
+        assert_eq!(fixed.header.mapper, 66);
+        assert_eq!(fixed.region(), Some(Region::Pal));
+
+        // a mapper above 255 needs NES 2.0
+        cart.header.mapper = 0x123;
+        let header = cart.ines_header();
+        assert_eq!(header[7] & 0b1100, 0b1000);
+        assert_eq!(parse(&cart.to_ines()).unwrap().header.mapper, 0x123);
+    }
+
+    #[test]
+    fn test_nes2_sizes_round_trip() {
+        #[rustfmt::skip]
+        let cases = [
+            (0x4000, 0x4000, (0x00, 0x01)),
+            (0x102 * 0x4000, 0x4000, (0x01, 0x02)),
+            (12, 0x2000, (0x0F, 0b0000_1001)),
+            (0x7000, 0x2000, (0x0F, 12 << 2 | 3)),
+        ];
+        for (size, unit, bytes) in cases.iter() {
+            assert_eq!(nes2_rom_size_bytes(*size, *unit), *bytes, "{}", size);
+            assert_eq!(nes2_rom_size(bytes.0, bytes.1, *unit), *size, "{}", size);
+        }
+        for size in [0, 0x80, 0x2000, 0x8000] {
+            assert_eq!(nes2_ram_size(nes2_ram_shift(size)), size, "{}", size);
+        }
     }
 }
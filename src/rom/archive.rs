@@ -0,0 +1,70 @@
+use super::*;
+
+use std::io::Cursor;
+
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const GZIP_MAGIC: &[u8] = b"\x1F\x8B";
+
+pub(super) fn is_archive(data: &[u8]) -> bool {
+    data.starts_with(ZIP_MAGIC) || data.starts_with(GZIP_MAGIC)
+}
+
+// Decompress a .gz file, or pick the first .nes entry of a .zip file
+pub(super) fn extract(data: &[u8]) -> Result<Vec<u8>> {
+    let mut rom = Vec::new();
+    if data.starts_with(GZIP_MAGIC) {
+        GzDecoder::new(data).read_to_end(&mut rom)?;
+        return Ok(rom);
+    }
+
+    let mut zip = ZipArchive::new(Cursor::new(data))?;
+    let index = zip
+        .file_names()
+        .position(|name| name.is_ok_and(|n| n.to_ascii_lowercase().ends_with(".nes")))
+        .ok_or(RomError::NoRomInArchive)?;
+    zip.by_index(index)?.read_to_end(&mut rom)?;
+    Ok(rom)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    #[test]
+    fn test_extract_gzip() {
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(b"NES\x1Arom").unwrap();
+        let data = gz.finish().unwrap();
+
+        assert!(is_archive(&data));
+        assert_eq!(extract(&data).unwrap(), b"NES\x1Arom");
+    }
+
+    #[test]
+    fn test_extract_zip() {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default();
+        for (name, data) in [
+            ("readme.txt", "text"),
+            ("b.NES", "first"),
+            ("a.nes", "second"),
+        ] {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(data.as_bytes()).unwrap();
+        }
+        let data = zip.finish().unwrap().into_inner();
+
+        assert!(is_archive(&data));
+        assert_eq!(extract(&data).unwrap(), b"first");
+    }
+}
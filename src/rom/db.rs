@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::Result;
+
+use super::*;
+
+// Header corrections for known ROM images, keyed by the CRC-32 or SHA-1 of PRG-ROM + CHR-ROM.
+//
+// The text format has one entry per line: the hash in hex followed by `key=value` overrides.
+//
+//     # a dump with a broken header
+//     0123abcd mapper=0 mirroring=vertical region=ntsc
+//
+// `mirroring` is one of horizontal/vertical/four-screen and `region` one of
+// ntsc/pal/dendy/multiple.
+#[derive(Debug, Default)]
+pub struct RomDatabase {
+    by_crc32: HashMap<u32, DbEntry>,
+    by_sha1: HashMap<[u8; 20], DbEntry>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct DbEntry {
+    pub(crate) mapper: Option<u16>,
+    pub(crate) mirroring: Option<Mirroring>,
+    pub(crate) four_screen: Option<bool>,
+    pub(crate) timing: Option<Timing>,
+}
+
+impl RomDatabase {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut db = Self::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            db.parse_line(line)
+                .map_err(|msg| DatabaseError { line: i + 1, msg })?;
+        }
+        Ok(db)
+    }
+
+    fn parse_line(&mut self, line: &str) -> std::result::Result<(), String> {
+        let mut tokens = line.split_whitespace();
+        let hash = tokens.next().unwrap_or_default();
+
+        let mut entry = DbEntry::default();
+        for token in tokens {
+            let (key, value) = token
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got {:?}", token))?;
+            match key {
+                "mapper" => {
+                    let n = value
+                        .parse()
+                        .map_err(|_| format!("invalid mapper {:?}", value))?;
+                    entry.mapper = Some(n);
+                }
+                "mirroring" => match value {
+                    "horizontal" => entry.mirroring = Some(Mirroring::Horizontal),
+                    "vertical" => entry.mirroring = Some(Mirroring::Vertical),
+                    "four-screen" => entry.four_screen = Some(true),
+                    _ => return Err(format!("invalid mirroring {:?}", value)),
+                },
+                "region" => {
+                    entry.timing = Some(match value {
+                        "ntsc" => Timing::Ntsc,
+                        "pal" => Timing::Pal,
+                        "dendy" => Timing::Dendy,
+                        "multiple" => Timing::Multiple,
+                        _ => return Err(format!("invalid region {:?}", value)),
+                    })
+                }
+                _ => return Err(format!("unknown key {:?}", key)),
+            }
+        }
+        if entry.mirroring.is_some() {
+            entry.four_screen = Some(false);
+        }
+
+        match hash.len() {
+            8 => {
+                let crc = u32::from_str_radix(hash, 16)
+                    .map_err(|_| format!("invalid CRC-32 {:?}", hash))?;
+                self.by_crc32.insert(crc, entry);
+            }
+            40 => {
+                let mut sha1 = [0; 20];
+                for (i, b) in sha1.iter_mut().enumerate() {
+                    *b = u8::from_str_radix(&hash[i * 2..i * 2 + 2], 16)
+                        .map_err(|_| format!("invalid SHA-1 {:?}", hash))?;
+                }
+                self.by_sha1.insert(sha1, entry);
+            }
+            _ => return Err(format!("invalid hash {:?}", hash)),
+        }
+        Ok(())
+    }
+
+    pub(crate) fn lookup(&self, crc32: u32, sha1: &[u8; 20]) -> Option<&DbEntry> {
+        self.by_sha1.get(sha1).or_else(|| self.by_crc32.get(&crc32))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct DatabaseError {
+    line: usize,
+    msg: String,
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ROM database error at line {}: {}", self.line, self.msg)
+    }
+}
+
+impl std::error::Error for DatabaseError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let db = RomDatabase::parse(
+            "
+            # comment
+            cbf43926 mapper=4 mirroring=vertical region=pal
+            a9993e364706816aba3e25717850c26c9cd0d89d mirroring=four-screen # trailing
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            db.lookup(0xCBF4_3926, &[0; 20]),
+            Some(&DbEntry {
+                mapper: Some(4),
+                mirroring: Some(Mirroring::Vertical),
+                four_screen: Some(false),
+                timing: Some(Timing::Pal),
+            })
+        );
+        assert_eq!(
+            db.lookup(0, &hash::sha1(b"abc")),
+            Some(&DbEntry {
+                four_screen: Some(true),
+                ..Default::default()
+            })
+        );
+        assert_eq!(db.lookup(0, &[0; 20]), None);
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let err = RomDatabase::parse("\ncbf43926 mapper=x").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ROM database error at line 2: invalid mapper \"x\""
+        );
+    }
+}
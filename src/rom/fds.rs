@@ -0,0 +1,247 @@
+use super::*;
+
+pub(super) const MAGIC: &[u8] = b"FDS\x1A";
+// Disk info block of a headerless image
+const DISK_MAGIC: &[u8] = b"\x01*NINTENDO-HVC*";
+
+const HEADER_SIZE: usize = 16;
+pub(crate) const SIDE_SIZE: usize = 65500;
+
+// A Famicom Disk System image
+#[derive(Debug)]
+pub struct FdsImage {
+    pub sides: Vec<FdsSide>,
+}
+
+#[derive(Debug)]
+pub struct FdsSide {
+    pub manufacturer: u8,
+    pub game_name: [u8; 3],
+    pub revision: u8,
+    pub side_number: u8,
+    pub disk_number: u8,
+    // Files with an ID up to this are loaded at boot
+    pub boot_file: u8,
+    pub files: Vec<FdsFile>,
+    // The whole side as stored on disk, for the drive to read and write
+    pub raw: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct FdsFile {
+    pub number: u8,
+    pub id: u8,
+    pub name: [u8; 8],
+    pub load_addr: u16,
+    pub kind: FdsFileKind,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FdsFileKind {
+    Prg,
+    Chr,
+    Nametable,
+}
+
+impl FdsImage {
+    // Load a headered (fwNES) or headerless image
+    pub fn from_bytes(image: &[u8]) -> Result<Self> {
+        parse(image)
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        std::fs::read(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|image| Self::from_bytes(&image))
+            .with_context(|| format!("failed to load {}", path.display()))
+    }
+}
+
+fn error(msg: String) -> anyhow::Error {
+    RomError::Malformed(msg).into()
+}
+
+pub(super) fn parse(image: &[u8]) -> Result<FdsImage> {
+    let (count, disk) = if image.starts_with(MAGIC) {
+        if image.len() < HEADER_SIZE {
+            return Err(RomError::TruncatedHeader.into());
+        }
+        (image[4] as usize, &image[HEADER_SIZE..])
+    } else if image.starts_with(DISK_MAGIC) {
+        (image.len().div_ceil(SIDE_SIZE), image)
+    } else {
+        return Err(RomError::InvalidMagic.into());
+    };
+    if count == 0 {
+        return Err(error("FDS image has no disk sides".to_string()));
+    }
+    if disk.len() < count * SIDE_SIZE {
+        return Err(error(format!(
+            "truncated FDS image: {} sides need {} bytes, got {}",
+            count,
+            count * SIDE_SIZE,
+            disk.len()
+        )));
+    }
+
+    let sides = disk
+        .chunks_exact(SIDE_SIZE)
+        .take(count)
+        .enumerate()
+        .map(|(i, side)| parse_side(side).map_err(|e| error(format!("side {}: {}", i, e))))
+        .collect::<Result<_>>()?;
+    Ok(FdsImage { sides })
+}
+
+// A side is a sequence of blocks: disk info (1), file amount (2),
+// then a file header (3) and file data (4) for each file
+fn parse_side(side: &[u8]) -> Result<FdsSide> {
+    if !side.starts_with(DISK_MAGIC) {
+        return Err(error("missing disk info block".to_string()));
+    }
+    let info = &side[..56];
+    let mut rest = &side[56..];
+
+    let file_count = match rest {
+        [2, n, ..] => *n,
+        _ => return Err(error("missing file amount block".to_string())),
+    };
+    rest = &rest[2..];
+
+    let mut files = Vec::with_capacity(file_count as usize);
+    // Some games hide files past the declared amount, so read every file header present
+    while let [3, ..] = rest {
+        let h = rest
+            .get(..16)
+            .ok_or_else(|| error("truncated file header block".to_string()))?;
+        let size = u16::from_le_bytes([h[13], h[14]]) as usize;
+        let kind = match h[15] {
+            0 => FdsFileKind::Prg,
+            1 => FdsFileKind::Chr,
+            2 => FdsFileKind::Nametable,
+            n => return Err(error(format!("invalid file type {}", n))),
+        };
+        let data = match rest.get(16..17 + size) {
+            Some([4, data @ ..]) => data,
+            Some(_) => return Err(error(format!("missing data block for file {}", h[1]))),
+            None => return Err(error(format!("truncated data block for file {}", h[1]))),
+        };
+
+        let mut name = [0; 8];
+        name.copy_from_slice(&h[3..11]);
+        files.push(FdsFile {
+            number: h[1],
+            id: h[2],
+            name,
+            load_addr: u16::from_le_bytes([h[11], h[12]]),
+            kind,
+            data: data.to_vec(),
+        });
+        rest = &rest[17 + size..];
+    }
+    if files.len() < file_count as usize {
+        return Err(error(format!(
+            "expected {} files, found {}",
+            file_count,
+            files.len()
+        )));
+    }
+
+    Ok(FdsSide {
+        manufacturer: info[15],
+        game_name: [info[16], info[17], info[18]],
+        revision: info[20],
+        side_number: info[21],
+        disk_number: info[22],
+        boot_file: info[25],
+        files,
+        raw: side.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn side(files: &[(u8, FdsFileKind, &[u8])]) -> Vec<u8> {
+        let mut side = DISK_MAGIC.to_vec();
+        side.extend([0xA4, b'T', b'S', b'T', b' ', 0, 0, 0, 0, 0, 1]);
+        side.resize(56, 0);
+        side.extend([2, files.len() as u8]);
+        for (i, (id, kind, data)) in files.iter().enumerate() {
+            side.extend([3, i as u8, *id]);
+            side.extend(b"FILE0000");
+            side.extend(0x6000u16.to_le_bytes());
+            side.extend((data.len() as u16).to_le_bytes());
+            side.push(*kind as u8);
+            side.push(4);
+            side.extend(*data);
+        }
+        side.resize(SIDE_SIZE, 0);
+        side
+    }
+
+    #[test]
+    fn test_parse_fds() {
+        let a = side(&[
+            (0, FdsFileKind::Prg, &[1, 2, 3]),
+            (1, FdsFileKind::Chr, &[4; 16]),
+        ]);
+        let b = side(&[]);
+
+        let mut headered = MAGIC.to_vec();
+        headered.push(2);
+        headered.resize(HEADER_SIZE, 0);
+        headered.extend(&a);
+        headered.extend(&b);
+        let headerless = [a, b].concat();
+
+        for image in [headered, headerless] {
+            let fds = FdsImage::from_bytes(&image).unwrap();
+            assert_eq!(fds.sides.len(), 2);
+
+            let side = &fds.sides[0];
+            assert_eq!(side.manufacturer, 0xA4);
+            assert_eq!(&side.game_name, b"TST");
+            assert_eq!(side.boot_file, 1);
+            assert_eq!(side.files.len(), 2);
+            assert_eq!(side.files[0].kind, FdsFileKind::Prg);
+            assert_eq!(side.files[0].load_addr, 0x6000);
+            assert_eq!(side.files[0].data, vec![1, 2, 3]);
+            assert_eq!(side.files[1].kind, FdsFileKind::Chr);
+            assert_eq!(side.files[1].data, vec![4; 16]);
+            assert_eq!(side.raw.len(), SIDE_SIZE);
+            assert!(fds.sides[1].files.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_parse_truncated() {
+        let mut image = MAGIC.to_vec();
+        image.push(2);
+        image.resize(HEADER_SIZE, 0);
+        image.extend(side(&[]));
+        assert!(FdsImage::from_bytes(&image).is_err());
+
+        // The file size runs past the end of the side
+        let mut disk = side(&[(0, FdsFileKind::Prg, &[0])]);
+        disk[56 + 2 + 13..56 + 2 + 15].copy_from_slice(&0xFFFFu16.to_le_bytes());
+        assert!(FdsImage::from_bytes(&disk).is_err());
+    }
+
+    #[test]
+    fn test_from_path() {
+        let path = std::env::temp_dir().join(format!("korones-{}.fds", std::process::id()));
+        std::fs::write(&path, side(&[(0, FdsFileKind::Prg, &[1, 2, 3])])).unwrap();
+        let fds = crate::FdsImage::from_path(&path);
+        std::fs::remove_file(&path).unwrap();
+        let fds = fds.unwrap();
+        assert_eq!(fds.sides.len(), 1);
+        assert_eq!(fds.sides[0].files[0].data, vec![1, 2, 3]);
+
+        let err = crate::FdsImage::from_path(&path).unwrap_err();
+        assert!(err.to_string().starts_with("failed to load"), "{}", err);
+    }
+}
@@ -0,0 +1,133 @@
+use super::*;
+
+// Summary of a loaded cartridge for "ROM properties" style displays
+#[derive(Debug, Clone, PartialEq)]
+pub struct RomInfo {
+    // "iNES", "NES 2.0" or "UNIF"
+    pub format: &'static str,
+    pub mapper: u16,
+    pub submapper: u8,
+    // common board name, or the UNIF board name
+    pub mapper_name: String,
+    // in bytes
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub mirroring: &'static str,
+    pub battery: bool,
+    // None if the cartridge runs on any region
+    pub region: Option<Region>,
+    pub crc32: u32,
+    pub sha1: [u8; 20],
+}
+
+#[rustfmt::skip]
+const MAPPER_NAMES: [(u16, &str); 32] = [
+    (0, "NROM"), (1, "MMC1"), (2, "UxROM"), (3, "CNROM"), (4, "MMC3"), (5, "MMC5"),
+    (7, "AxROM"), (9, "MMC2"), (10, "MMC4"), (11, "Color Dreams"), (13, "CPROM"),
+    (19, "Namco 163"), (21, "VRC4"), (22, "VRC2"), (23, "VRC2/VRC4"), (24, "VRC6"),
+    (25, "VRC4"), (26, "VRC6"), (30, "UNROM 512"), (34, "BNROM/NINA-001"),
+    (64, "RAMBO-1"), (66, "GxROM"), (69, "FME-7"), (71, "Camerica"), (79, "NINA-03/06"),
+    (85, "VRC7"), (105, "NES-EVENT"), (118, "TxSROM"), (119, "TQROM"), (180, "UNROM (AND)"),
+    (206, "DxROM"), (210, "Namco 175/340"),
+];
+
+pub(super) fn mapper_name(mapper: u16) -> &'static str {
+    MAPPER_NAMES
+        .iter()
+        .find(|(n, _)| *n == mapper)
+        .map_or("unknown", |(_, name)| name)
+}
+
+impl Cartridge {
+    pub fn info(&self) -> RomInfo {
+        let h = &self.header;
+        let format = if h.nes2.is_some() {
+            "NES 2.0"
+        } else if h.unif.is_some() {
+            "UNIF"
+        } else {
+            "iNES"
+        };
+        let mapper_name = match &h.unif {
+            Some(unif) => unif.board.clone(),
+            None => mapper_name(h.mapper).to_string(),
+        };
+        let mirroring = if h.four_screen {
+            "four-screen"
+        } else {
+            match h.mirroring {
+                Mirroring::Horizontal => "horizontal",
+                Mirroring::Vertical => "vertical",
+                _ => "mapper-controlled",
+            }
+        };
+        RomInfo {
+            format,
+            mapper: h.mapper,
+            submapper: h.submapper,
+            mapper_name,
+            prg_rom_size: h.prg_rom_size,
+            chr_rom_size: h.chr_rom_size,
+            mirroring,
+            battery: h.battery,
+            region: self.region(),
+            crc32: self.crc32,
+            sha1: self.sha1,
+        }
+    }
+}
+
+impl fmt::Display for RomInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Format:    {}", self.format)?;
+        writeln!(
+            f,
+            "Mapper:    {}.{} ({})",
+            self.mapper, self.submapper, self.mapper_name
+        )?;
+        writeln!(f, "PRG-ROM:   {} KB", self.prg_rom_size / 1024)?;
+        if self.chr_rom_size == 0 {
+            writeln!(f, "CHR-ROM:   none (CHR-RAM)")?;
+        } else {
+            writeln!(f, "CHR-ROM:   {} KB", self.chr_rom_size / 1024)?;
+        }
+        writeln!(f, "Mirroring: {}", self.mirroring)?;
+        writeln!(f, "Battery:   {}", if self.battery { "yes" } else { "no" })?;
+        match self.region {
+            Some(region) => writeln!(f, "Region:    {:?}", region)?,
+            None => writeln!(f, "Region:    any")?,
+        }
+        writeln!(f, "CRC-32:    {:08X}", self.crc32)?;
+        write!(f, "SHA-1:     ")?;
+        self.sha1.iter().try_for_each(|b| write!(f, "{:02X}", b))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_info() {
+        #[rustfmt::skip]
+        let mut rom = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x02, 0x00, 0b0100_0011, 0x00,
+            0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        rom.resize(16 + 0x8000, 0);
+        let info = Cartridge::from_bytes(&rom).unwrap().info();
+
+        assert_eq!(info.format, "iNES");
+        assert_eq!(info.mapper_name, "MMC3");
+        assert_eq!(info.prg_rom_size, 0x8000);
+        assert_eq!(info.mirroring, "vertical");
+        assert!(info.battery);
+        assert_eq!(info.region, Some(Region::Pal));
+
+        let text = info.to_string();
+        assert!(text.contains("Mapper:    4.0 (MMC3)"), "{}", text);
+        assert!(text.contains("CHR-ROM:   none (CHR-RAM)"), "{}", text);
+        let crc32 = format!("CRC-32:    {:08X}", info.crc32);
+        assert!(text.contains(&crc32), "{}", text);
+    }
+}
@@ -0,0 +1,491 @@
+use super::*;
+
+use std::convert::TryFrom;
+
+use crate::nsf::{Expansion, NsfHeader};
+
+pub(super) const MAGIC: &[u8] = b"NESM\x1A";
+pub(super) const NSFE_MAGIC: &[u8] = b"NSFE";
+
+// Default play periods in microseconds when an NSFe has no RATE chunk
+const NTSC_PLAY_SPEED: u16 = 16_639;
+const PAL_PLAY_SPEED: u16 = 19_997;
+
+bitflags! {
+    // NSF2 feature flags (header byte $7C)
+    #[derive(Default)]
+    pub(crate) struct Nsf2Flags: u8 {
+        const IRQ = 1 << 4;
+        // PLAY is never called; the tune runs from INIT or IRQs
+        const NO_PLAY = 1 << 5;
+        const NON_RETURNING_INIT = 1 << 6;
+        // NSFe chunks follow the program data
+        const METADATA = 1 << 7;
+    }
+}
+
+// An NSF, NSF2 or NSFe music file
+#[derive(Debug)]
+pub struct NsfFile {
+    // 0 for NSFe
+    pub(crate) version: u8,
+    pub(crate) header: NsfHeader,
+    pub(crate) title: String,
+    pub(crate) artist: String,
+    pub(crate) copyright: String,
+    pub(crate) ripper: String,
+    // NTSC and PAL compatible
+    pub(crate) dual: bool,
+    pub(crate) flags: Nsf2Flags,
+    // one per song
+    pub(crate) tracks: Vec<NsfTrack>,
+    // order to play the songs in, if given
+    pub(crate) playlist: Option<Vec<u8>>,
+    pub(crate) data: Vec<u8>,
+}
+
+// Per-song metadata from NSFe or NSF2
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NsfTrack {
+    pub title: Option<String>,
+    // in milliseconds
+    pub length: Option<u32>,
+    pub fade: Option<u32>,
+}
+
+impl NsfFile {
+    pub fn from_bytes(nsf: &[u8]) -> Result<Self> {
+        parse(nsf)
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn artist(&self) -> &str {
+        &self.artist
+    }
+
+    pub fn copyright(&self) -> &str {
+        &self.copyright
+    }
+
+    pub fn ripper(&self) -> &str {
+        &self.ripper
+    }
+
+    // 1 or 2 for NSF and NSF2 files, 0 for NSFe
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    // Whether the tune plays on both NTSC and PAL consoles
+    pub fn dual_region(&self) -> bool {
+        self.dual
+    }
+
+    // 0-origin `song`
+    pub fn track(&self, song: u8) -> Option<&NsfTrack> {
+        self.tracks.get(song as usize)
+    }
+
+    pub fn songs(&self) -> u8 {
+        self.header.songs
+    }
+
+    // 1-origin
+    pub fn starting_song(&self) -> u8 {
+        self.header.starting_song
+    }
+}
+
+fn error(msg: &str) -> anyhow::Error {
+    RomError::Malformed(msg.to_string()).into()
+}
+
+// Null-terminated string field
+fn text(b: &[u8]) -> String {
+    let end = b.iter().position(|&c| c == 0).unwrap_or(b.len());
+    String::from_utf8_lossy(&b[..end]).into_owned()
+}
+
+pub(super) fn parse(nsf: &[u8]) -> Result<NsfFile> {
+    if nsf.starts_with(NSFE_MAGIC) {
+        return parse_nsfe(nsf);
+    }
+    if !nsf.starts_with(MAGIC) {
+        return Err(RomError::InvalidMagic.into());
+    }
+    if nsf.len() < 0x80 {
+        return Err(RomError::TruncatedHeader.into());
+    }
+    let h = &nsf[..0x80];
+    let word = |i: usize| u16::from_le_bytes([h[i], h[i + 1]]);
+
+    let version = h[0x05];
+    if !(1..=2).contains(&version) {
+        return Err(RomError::UnsupportedVersion(version).into());
+    }
+    let songs = h[0x06];
+    if songs == 0 {
+        return Err(error("NSF has no songs"));
+    }
+    let load_addr = word(0x08);
+    if load_addr < 0x6000 {
+        return Err(error("invalid NSF load address"));
+    }
+
+    let mut bankswitch = [0; 8];
+    bankswitch.copy_from_slice(&h[0x70..0x78]);
+
+    // NSF2 may declare the program length, followed by metadata
+    let data = &nsf[0x80..];
+    let flags = if 2 <= version {
+        Nsf2Flags::from_bits_truncate(h[0x7C])
+    } else {
+        Nsf2Flags::empty()
+    };
+    let len = u32::from_le_bytes([h[0x7D], h[0x7E], h[0x7F], 0]) as usize;
+    let (data, metadata) = if 2 <= version && len != 0 {
+        let program = data.get(..len).ok_or(RomError::TruncatedPrg {
+            expected: len,
+            found: data.len(),
+        })?;
+        (program, &data[len..])
+    } else {
+        (data, &[][..])
+    };
+
+    let mut file = NsfFile {
+        version,
+        header: NsfHeader {
+            songs,
+            starting_song: h[0x07].max(1),
+            load_addr,
+            init_addr: word(0x0A),
+            play_addr: word(0x0C),
+            play_speed_ntsc: word(0x6E),
+            play_speed_pal: word(0x78),
+            bankswitch,
+            pal: h[0x7A] & 0b11 == 0b01,
+            expansion: Expansion::from_bits_truncate(h[0x7B]),
+        },
+        title: text(&h[0x0E..0x2E]),
+        artist: text(&h[0x2E..0x4E]),
+        copyright: text(&h[0x4E..0x6E]),
+        ripper: String::new(),
+        dual: h[0x7A] & 0b10 != 0,
+        flags,
+        tracks: vec![NsfTrack::default(); songs as usize],
+        playlist: None,
+        data: data.to_vec(),
+    };
+    if flags.contains(Nsf2Flags::METADATA) {
+        for (id, chunk) in chunks(metadata)? {
+            match id {
+                b"NEND" => break,
+                b"INFO" | b"DATA" | b"BANK" | b"RATE" => {}
+                _ => metadata_chunk(&mut file, id, chunk)?,
+            }
+        }
+    }
+    Ok(file)
+}
+
+// NSFe-style chunks: 32-bit length, 4-byte ID, then data
+fn chunks(mut rest: &[u8]) -> Result<Vec<(&[u8; 4], &[u8])>> {
+    let mut chunks = Vec::new();
+    while !rest.is_empty() {
+        if rest.len() < 8 {
+            return Err(error("truncated NSFe chunk header"));
+        }
+        let len = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let id = <&[u8; 4]>::try_from(&rest[4..8]).unwrap();
+        let data = rest.get(8..8 + len).ok_or_else(|| {
+            RomError::Malformed(format!(
+                "truncated NSFe chunk {}",
+                String::from_utf8_lossy(id)
+            ))
+        })?;
+        chunks.push((id, data));
+        rest = &rest[8 + len..];
+    }
+    Ok(chunks)
+}
+
+// Chunks describing the music rather than the program, shared by NSFe and NSF2
+fn metadata_chunk(file: &mut NsfFile, id: &[u8; 4], data: &[u8]) -> Result<()> {
+    let strings = || data.split(|&b| b == 0).map(text);
+    let times = || {
+        data.chunks_exact(4)
+            .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            // negative means unspecified
+            .map(|ms| u32::try_from(ms).ok())
+    };
+    match id {
+        b"auth" => {
+            let mut s = strings();
+            file.title = s.next().unwrap_or_default();
+            file.artist = s.next().unwrap_or_default();
+            file.copyright = s.next().unwrap_or_default();
+            file.ripper = s.next().unwrap_or_default();
+        }
+        b"tlbl" => {
+            for (track, title) in file.tracks.iter_mut().zip(strings()) {
+                track.title = Some(title);
+            }
+        }
+        b"time" => {
+            for (track, ms) in file.tracks.iter_mut().zip(times()) {
+                track.length = ms;
+            }
+        }
+        b"fade" => {
+            for (track, ms) in file.tracks.iter_mut().zip(times()) {
+                track.fade = ms;
+            }
+        }
+        b"plst" => file.playlist = Some(data.to_vec()),
+        // chunks starting with an uppercase letter must be understood
+        [c, ..] if c.is_ascii_uppercase() => {
+            return Err(RomError::Malformed(format!(
+                "unsupported NSFe chunk {}",
+                String::from_utf8_lossy(id)
+            ))
+            .into())
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn parse_nsfe(nsfe: &[u8]) -> Result<NsfFile> {
+    let chunks = chunks(&nsfe[NSFE_MAGIC.len()..])?;
+
+    let info = chunks
+        .iter()
+        .find(|(id, _)| id == &b"INFO")
+        .map(|(_, data)| *data)
+        .ok_or_else(|| error("missing NSFe INFO chunk"))?;
+    if info.len() < 8 {
+        return Err(error("truncated NSFe INFO chunk"));
+    }
+    let word = |b: &[u8], i: usize| u16::from_le_bytes([b[i], b[i + 1]]);
+    let songs = info.get(8).copied().unwrap_or(1);
+    if songs == 0 {
+        return Err(error("NSF has no songs"));
+    }
+    let load_addr = word(info, 0);
+    if load_addr < 0x6000 {
+        return Err(error("invalid NSF load address"));
+    }
+
+    let mut file = NsfFile {
+        version: 0,
+        header: NsfHeader {
+            songs,
+            // 0-origin in NSFe
+            starting_song: info.get(9).copied().unwrap_or(0).saturating_add(1),
+            load_addr,
+            init_addr: word(info, 2),
+            play_addr: word(info, 4),
+            play_speed_ntsc: NTSC_PLAY_SPEED,
+            play_speed_pal: PAL_PLAY_SPEED,
+            bankswitch: [0; 8],
+            pal: info[6] & 0b11 == 0b01,
+            expansion: Expansion::from_bits_truncate(info[7]),
+        },
+        title: String::new(),
+        artist: String::new(),
+        copyright: String::new(),
+        ripper: String::new(),
+        dual: info[6] & 0b10 != 0,
+        flags: Nsf2Flags::empty(),
+        tracks: vec![NsfTrack::default(); songs as usize],
+        playlist: None,
+        data: Vec::new(),
+    };
+
+    let mut data = None;
+    for (id, chunk) in chunks {
+        match id {
+            b"INFO" => {}
+            b"DATA" => data = Some(chunk.to_vec()),
+            b"BANK" => {
+                let n = chunk.len().min(8);
+                file.header.bankswitch[..n].copy_from_slice(&chunk[..n]);
+            }
+            b"RATE" => {
+                if chunk.len() >= 2 {
+                    file.header.play_speed_ntsc = word(chunk, 0);
+                }
+                if chunk.len() >= 4 {
+                    file.header.play_speed_pal = word(chunk, 2);
+                }
+            }
+            b"NEND" => break,
+            _ => metadata_chunk(&mut file, id, chunk)?,
+        }
+    }
+    file.data = data.ok_or_else(|| error("missing NSFe DATA chunk"))?;
+    Ok(file)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    pub(crate) fn nsf_header() -> Vec<u8> {
+        let mut h = vec![0; 0x80];
+        h[..5].copy_from_slice(MAGIC);
+        h[0x05] = 1;
+        h[0x06] = 5;
+        h[0x07] = 2;
+        h[0x08..0x0A].copy_from_slice(&0x8000u16.to_le_bytes());
+        h[0x0A..0x0C].copy_from_slice(&0x8003u16.to_le_bytes());
+        h[0x0C..0x0E].copy_from_slice(&0x8006u16.to_le_bytes());
+        h[0x0E..0x13].copy_from_slice(b"Title");
+        h[0x2E..0x34].copy_from_slice(b"Artist");
+        h[0x4E..0x52].copy_from_slice(b"2021");
+        h[0x6E..0x70].copy_from_slice(&16_639u16.to_le_bytes());
+        h[0x78..0x7A].copy_from_slice(&19_997u16.to_le_bytes());
+        h[0x7A] = 0b10;
+        h[0x7B] = 0b0000_0101;
+        h
+    }
+
+    #[test]
+    fn test_parse_nsf() {
+        let mut nsf = nsf_header();
+        nsf.extend([0x60; 0x10]);
+
+        let file = NsfFile::from_bytes(&nsf).unwrap();
+        assert_eq!(file.version(), 1);
+        assert_eq!(file.title(), "Title");
+        assert_eq!(file.artist(), "Artist");
+        assert_eq!(file.copyright(), "2021");
+        assert_eq!(file.songs(), 5);
+        assert_eq!(file.starting_song(), 2);
+        assert!(file.dual_region());
+        assert!(!file.header.pal);
+        assert_eq!(file.header.load_addr, 0x8000);
+        assert_eq!(file.header.init_addr, 0x8003);
+        assert_eq!(file.header.play_addr, 0x8006);
+        assert_eq!(file.header.play_speed_ntsc, 16_639);
+        assert_eq!(file.header.expansion, Expansion::VRC6 | Expansion::FDS);
+        assert_eq!(file.data, vec![0x60; 0x10]);
+    }
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_le_bytes().to_vec();
+        chunk.extend(id);
+        chunk.extend(data);
+        chunk
+    }
+
+    fn metadata() -> Vec<u8> {
+        [
+            chunk(b"auth", b"Game\0Composer\0(c) 2021\0Ripper\0"),
+            chunk(b"tlbl", b"Intro\0Stage 1\0"),
+            chunk(
+                b"time",
+                &[&150_000i32.to_le_bytes()[..], &(-1i32).to_le_bytes()].concat(),
+            ),
+            chunk(
+                b"fade",
+                &[&2_000i32.to_le_bytes()[..], &0i32.to_le_bytes()].concat(),
+            ),
+            chunk(b"plst", &[1, 0]),
+            chunk(b"NEND", &[]),
+        ]
+        .concat()
+    }
+
+    fn check_metadata(file: &NsfFile) {
+        assert_eq!(file.title(), "Game");
+        assert_eq!(file.artist(), "Composer");
+        assert_eq!(file.copyright(), "(c) 2021");
+        assert_eq!(file.ripper(), "Ripper");
+        #[rustfmt::skip]
+        assert_eq!(file.track(0), Some(&NsfTrack {
+            title: Some("Intro".to_string()), length: Some(150_000), fade: Some(2_000),
+        }));
+        #[rustfmt::skip]
+        assert_eq!(file.track(1), Some(&NsfTrack {
+            title: Some("Stage 1".to_string()), length: None, fade: Some(0),
+        }));
+        assert_eq!(file.playlist, Some(vec![1, 0]));
+    }
+
+    #[test]
+    fn test_parse_nsfe() {
+        #[rustfmt::skip]
+        let info = [
+            0x00, 0x80, 0x03, 0x80, 0x06, 0x80,
+            // dual, VRC6
+            0b10, 0b1,
+            // 2 songs, starting at the second
+            2, 1,
+        ];
+        let nsfe = |extra: Vec<u8>| {
+            [
+                NSFE_MAGIC.to_vec(),
+                chunk(b"INFO", &info),
+                chunk(b"BANK", &[0, 1, 2, 3]),
+                chunk(b"RATE", &10_000u16.to_le_bytes()),
+                chunk(b"DATA", &[0x60; 4]),
+                extra,
+                metadata(),
+            ]
+            .concat()
+        };
+
+        let file = NsfFile::from_bytes(&nsfe(Vec::new())).unwrap();
+        assert_eq!(file.version(), 0);
+        assert_eq!(file.songs(), 2);
+        assert_eq!(file.starting_song(), 2);
+        assert_eq!(file.header.init_addr, 0x8003);
+        assert_eq!(file.header.bankswitch, [0, 1, 2, 3, 0, 0, 0, 0]);
+        assert_eq!(file.header.play_speed_ntsc, 10_000);
+        assert_eq!(file.header.play_speed_pal, PAL_PLAY_SPEED);
+        assert_eq!(file.header.expansion, Expansion::VRC6);
+        assert!(file.dual_region());
+        assert_eq!(file.data, vec![0x60; 4]);
+        check_metadata(&file);
+
+        // unknown chunks are skipped unless they're mandatory
+        assert!(NsfFile::from_bytes(&nsfe(chunk(b"xtra", &[1]))).is_ok());
+        assert!(NsfFile::from_bytes(&nsfe(chunk(b"XTRA", &[1]))).is_err());
+    }
+
+    #[test]
+    fn test_parse_nsf2_metadata() {
+        let mut nsf = nsf_header();
+        nsf[0x05] = 2;
+        nsf[0x06] = 2;
+        nsf[0x7C] = (Nsf2Flags::METADATA | Nsf2Flags::NO_PLAY).bits();
+        nsf[0x7D] = 0x10;
+        nsf.extend([0x60; 0x10]);
+        nsf.extend(metadata());
+
+        let file = NsfFile::from_bytes(&nsf).unwrap();
+        assert_eq!(file.flags, Nsf2Flags::METADATA | Nsf2Flags::NO_PLAY);
+        assert_eq!(file.data, vec![0x60; 0x10]);
+        check_metadata(&file);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        let err = NsfFile::from_bytes(b"NESM\x1A").unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&RomError::TruncatedHeader));
+
+        let mut nsf = nsf_header();
+        nsf[0x05] = 3;
+        let err = NsfFile::from_bytes(&nsf).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&RomError::UnsupportedVersion(3)));
+
+        let mut nsf = nsf_header();
+        nsf[0x06] = 0;
+        assert!(NsfFile::from_bytes(&nsf).is_err());
+    }
+}
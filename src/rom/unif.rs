@@ -0,0 +1,218 @@
+use super::*;
+
+pub(super) const MAGIC: &[u8] = b"UNIF";
+
+// Board names (without the NES-/HVC-/UNL- prefix) and the iNES mapper implementing
+// them, limited to boards `Board::new` supports
+#[rustfmt::skip]
+const BOARDS: [(&str, u16); 24] = [
+    ("NROM", 0), ("NROM-128", 0), ("NROM-256", 0), ("RROM", 0),
+    ("UNROM", 2), ("UOROM", 2),
+    ("CNROM", 3),
+    ("TBROM", 4), ("TEROM", 4), ("TFROM", 4), ("TGROM", 4), ("TKROM", 4),
+    ("TLROM", 4), ("TNROM", 4), ("TSROM", 4),
+    ("AMROM", 7), ("ANROM", 7), ("AOROM", 7),
+    ("UNROM-512-8", 30), ("UNROM-512-16", 30), ("UNROM-512-32", 30),
+    ("TKSROM", 118), ("TLSROM", 118), ("TQROM", 119),
+];
+
+fn mapper_for(board: &str) -> Option<u16> {
+    let name = ["NES-", "HVC-", "UNL-", "BMC-"]
+        .iter()
+        .find_map(|prefix| board.strip_prefix(prefix))
+        .unwrap_or(board);
+    BOARDS
+        .iter()
+        .find(|(b, _)| *b == name)
+        .map(|(_, mapper)| *mapper)
+}
+
+fn error(msg: String) -> anyhow::Error {
+    RomError::Malformed(msg).into()
+}
+
+// UNIF: a 32-byte header followed by chunks of 4-byte ID, 32-bit length and data
+pub(super) fn parse(rom: &[u8]) -> Result<Cartridge> {
+    if !rom.starts_with(MAGIC) {
+        return Err(RomError::InvalidMagic.into());
+    }
+    if rom.len() < 32 {
+        return Err(RomError::TruncatedHeader.into());
+    }
+
+    let mut board = None;
+    let mut prg = vec![Vec::new(); 16];
+    let mut chr = vec![Vec::new(); 16];
+    let mut mirroring = Mirroring::Horizontal;
+    let mut four_screen = false;
+    let mut battery = false;
+    let mut timing = Timing::Ntsc;
+
+    let mut rest = &rom[32..];
+    while !rest.is_empty() {
+        if rest.len() < 8 {
+            return Err(error("truncated UNIF chunk header".to_string()));
+        }
+        let id = &rest[0..4];
+        let len = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+        // the length can't be trusted not to overflow on 32-bit targets
+        let end = 8usize.checked_add(len);
+        let data = end.and_then(|end| rest.get(8..end)).ok_or_else(|| {
+            error(format!(
+                "truncated UNIF chunk {}",
+                String::from_utf8_lossy(id)
+            ))
+        })?;
+        rest = &rest[8 + len..];
+
+        let hex = |c: u8| (c as char).to_digit(16).map(|n| n as usize);
+        match id {
+            b"MAPR" => {
+                let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+                board = Some(String::from_utf8_lossy(&data[..end]).into_owned());
+            }
+            [b'P', b'R', b'G', n] => {
+                if let Some(i) = hex(*n) {
+                    prg[i] = data.to_vec();
+                }
+            }
+            [b'C', b'H', b'R', n] => {
+                if let Some(i) = hex(*n) {
+                    chr[i] = data.to_vec();
+                }
+            }
+            b"MIRR" => match data.first() {
+                Some(0) => mirroring = Mirroring::Horizontal,
+                Some(1) => mirroring = Mirroring::Vertical,
+                Some(2) => mirroring = Mirroring::SingleScreenLower,
+                Some(3) => mirroring = Mirroring::SingleScreenUpper,
+                Some(4) => four_screen = true,
+                // controlled by the mapper
+                _ => {}
+            },
+            b"BATR" => battery = true,
+            b"TVCI" => {
+                timing = match data.first() {
+                    Some(1) => Timing::Pal,
+                    Some(2) => Timing::Multiple,
+                    _ => Timing::Ntsc,
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let board = board.ok_or_else(|| error("missing MAPR chunk".to_string()))?;
+    let mapper = mapper_for(&board).ok_or_else(|| RomError::UnsupportedBoard(board.clone()))?;
+
+    let prg_rom = prg.concat();
+    let chr_rom = chr.concat();
+    Ok(Cartridge::new(
+        Header {
+            mapper,
+            submapper: 0,
+            prg_rom_size: prg_rom.len(),
+            chr_rom_size: chr_rom.len(),
+            mirroring,
+            battery,
+            trainer: false,
+            four_screen,
+            vs_unisystem: false,
+            playchoice10: false,
+            ines1: None,
+            nes2: None,
+            unif: Some(Unif { board, timing }),
+        },
+        None,
+        prg_rom,
+        chr_rom,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut v = id.to_vec();
+        v.extend((data.len() as u32).to_le_bytes());
+        v.extend(data);
+        v
+    }
+
+    #[test]
+    fn test_parse_unif() {
+        let mut rom = b"UNIF".to_vec();
+        rom.extend(7u32.to_le_bytes());
+        rom.extend([0; 24]);
+        rom.extend(chunk(b"MAPR", b"NES-TLROM\0"));
+        rom.extend(chunk(b"PRG1", &[2; 0x4000]));
+        rom.extend(chunk(b"PRG0", &[1; 0x4000]));
+        rom.extend(chunk(b"CHR0", &[3; 0x2000]));
+        rom.extend(chunk(b"MIRR", &[1]));
+        rom.extend(chunk(b"BATR", &[1]));
+        rom.extend(chunk(b"TVCI", &[1]));
+
+        let cart = Cartridge::from_bytes(&rom).unwrap();
+        assert_eq!(cart.header.mapper, 4);
+        assert_eq!(cart.header.mirroring, Mirroring::Vertical);
+        assert!(cart.header.battery);
+        assert_eq!(cart.region(), Some(Region::Pal));
+        assert_eq!(cart.prg_rom().len(), 0x8000);
+        assert_eq!(cart.prg_rom()[0], 1);
+        assert_eq!(cart.prg_rom()[0x4000], 2);
+        assert_eq!(cart.chr_rom(), &[3; 0x2000][..]);
+    }
+
+    #[test]
+    fn test_unsupported_board() {
+        let mut rom = b"UNIF".to_vec();
+        rom.extend([0; 28]);
+        rom.extend(chunk(b"MAPR", b"UNL-UNKNOWN\0"));
+
+        let err = Cartridge::from_bytes(&rom).unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&RomError::UnsupportedBoard("UNL-UNKNOWN".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_supported_boards() {
+        for (name, _) in BOARDS {
+            let mut rom = b"UNIF".to_vec();
+            rom.extend([0; 28]);
+            rom.extend(chunk(b"MAPR", format!("NES-{}\0", name).as_bytes()));
+            rom.extend(chunk(b"PRG0", &[0; 0x8000]));
+            rom.extend(chunk(b"CHR0", &[0; 0x2000]));
+
+            let cart = Cartridge::from_bytes(&rom).unwrap();
+            assert!(crate::mapper::Board::new(&cart).is_ok(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_malformed() {
+        #[rustfmt::skip]
+        let cases: [(&str, &[u8]); 4] = [
+            ("truncated chunk header", b"PRG0\x00\x40"),
+            ("truncated chunk",        b"PRG0\x00\x40\x00\x00\x00\x00"),
+            ("overflowing length",     b"PRG0\xFF\xFF\xFF\xFF\x00\x00"),
+            ("missing MAPR",           b"BATR\x00\x00\x00\x00"),
+        ];
+
+        for (name, chunks) in cases {
+            let mut rom = b"UNIF".to_vec();
+            rom.extend([0; 28]);
+            rom.extend(chunks);
+
+            let err = Cartridge::from_bytes(&rom).unwrap_err();
+            assert!(
+                matches!(err.downcast_ref(), Some(RomError::Malformed(_))),
+                "{}: {}",
+                name,
+                err
+            );
+        }
+    }
+}
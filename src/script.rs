@@ -0,0 +1,367 @@
+// Lua scripting with a subset of FCEUX's API, behind the "lua" feature, so
+// existing NES scripts run with little or no change:
+//
+//   memory.readbyte(addr), memory.readbytesigned(addr), memory.readword(addr),
+//   memory.writebyte(addr, value)
+//   joypad.get(player), joypad.set(player, {A=true, left=false, ...})
+//   emu.frameadvance(), emu.framecount(), emu.registerbefore(f), emu.registerafter(f)
+//   gui.text(x, y, text), gui.pixel(x, y, color), gui.line(x1, y1, x2, y2, color),
+//   gui.box(x1, y1, x2, y2, color)
+//
+// The script runs as a coroutine which `emu.frameadvance` yields, and each
+// `Script::frame` resumes it up to the next one before running the frame. Writes
+// go through `Emu::poke`, so only RAM and PRG-RAM can be changed. Drawing is left
+// to the frontend, which gets the gui calls as `GuiCommand`s. `emu.framecount`
+// numbers frames as `FrameResult::frame` does.
+
+use std::cell::RefCell;
+use std::fmt;
+
+use anyhow::Result;
+use mlua::{Function, Lua, Table, Thread, ThreadStatus};
+
+use crate::input::Button;
+use crate::{Emu, FrameResult};
+
+// FCEUX's names of the buttons in joypad tables
+const BUTTONS: [(&str, Button); 8] = [
+    ("A", Button::A),
+    ("B", Button::B),
+    ("select", Button::Select),
+    ("start", Button::Start),
+    ("up", Button::Up),
+    ("down", Button::Down),
+    ("left", Button::Left),
+    ("right", Button::Right),
+];
+
+const BEFORE: &str = "korones.before";
+const AFTER: &str = "korones.after";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GuiCommand {
+    Text {
+        x: i32,
+        y: i32,
+        text: String,
+    },
+    Pixel {
+        x: i32,
+        y: i32,
+        color: Option<String>,
+    },
+    Line {
+        x1: i32,
+        y1: i32,
+        x2: i32,
+        y2: i32,
+        color: Option<String>,
+    },
+    Box {
+        x1: i32,
+        y1: i32,
+        x2: i32,
+        y2: i32,
+        color: Option<String>,
+    },
+}
+
+type GuiHandler = Box<dyn FnMut(GuiCommand)>;
+
+pub struct Script {
+    lua: Lua,
+    main: Thread,
+    gui: RefCell<Option<GuiHandler>>,
+}
+
+impl Script {
+    // Compile `source` without running any of it yet
+    pub fn new(source: &str) -> Result<Self> {
+        let lua = Lua::new();
+        let main = lua
+            .load(source)
+            .into_function()
+            .and_then(|f| lua.create_thread(f))
+            .map_err(script_error)?;
+        let emu = lua.create_table().map_err(script_error)?;
+        let register = |name: &'static str| {
+            lua.create_function(move |lua, f: Option<Function>| {
+                lua.set_named_registry_value(name, f)
+            })
+        };
+        let install = || -> mlua::Result<()> {
+            let frameadvance: Function = lua.load("coroutine.yield").eval()?;
+            emu.set("frameadvance", frameadvance)?;
+            emu.set("registerbefore", register(BEFORE)?)?;
+            emu.set("registerafter", register(AFTER)?)?;
+            let globals = lua.globals();
+            globals.set("emu", emu)?;
+            globals.set("memory", lua.create_table()?)?;
+            globals.set("joypad", lua.create_table()?)?;
+            globals.set("gui", lua.create_table()?)?;
+            Ok(())
+        };
+        install().map_err(script_error)?;
+        Ok(Self {
+            lua,
+            main,
+            gui: RefCell::new(None),
+        })
+    }
+
+    // Receive the gui calls of the script
+    pub fn on_gui<F: FnMut(GuiCommand) + 'static>(&mut self, f: F) {
+        *self.gui.get_mut() = Some(Box::new(f));
+    }
+
+    // Whether the main chunk is yet to finish; callbacks it registered keep
+    // running either way
+    pub fn running(&self) -> bool {
+        self.main.status() == ThreadStatus::Resumable
+    }
+
+    // Run the script up to its next `emu.frameadvance`, then the frame with the
+    // callbacks registered around it
+    pub fn frame(&mut self, emu: &mut Emu) -> Result<FrameResult> {
+        let emu = RefCell::new(emu);
+        let draw = |command: GuiCommand| {
+            if let Some(f) = self.gui.borrow_mut().as_mut() {
+                f(command);
+            }
+        };
+        let lua = &self.lua;
+        lua.scope(|scope| {
+            let globals = lua.globals();
+
+            let memory: Table = globals.get("memory")?;
+            memory.set(
+                "readbyte",
+                scope.create_function(|_, addr: u16| Ok(emu.borrow().peek(addr)))?,
+            )?;
+            memory.set(
+                "readbytesigned",
+                scope.create_function(|_, addr: u16| Ok(emu.borrow().peek(addr) as i8))?,
+            )?;
+            memory.set(
+                "readword",
+                scope.create_function(|_, addr: u16| {
+                    let emu = emu.borrow();
+                    Ok(u16::from_le_bytes([
+                        emu.peek(addr),
+                        emu.peek(addr.wrapping_add(1)),
+                    ]))
+                })?,
+            )?;
+            memory.set(
+                "writebyte",
+                scope.create_function(|_, (addr, value): (u16, u8)| {
+                    emu.borrow_mut().poke(addr, value);
+                    Ok(())
+                })?,
+            )?;
+
+            let joypad: Table = globals.get("joypad")?;
+            joypad.set(
+                "get",
+                scope.create_function(|lua, player: usize| {
+                    let state = emu.borrow().controller_state(player.wrapping_sub(1));
+                    let buttons = lua.create_table()?;
+                    for (name, button) in BUTTONS {
+                        buttons.set(name, state & button.mask() != 0)?;
+                    }
+                    Ok(buttons)
+                })?,
+            )?;
+            joypad.set(
+                "set",
+                scope.create_function(|_, (player, buttons): (usize, Table)| {
+                    let port = player.wrapping_sub(1);
+                    let mut emu = emu.borrow_mut();
+                    // buttons left out keep their state
+                    let mut state = emu.controller_state(port);
+                    for (name, button) in BUTTONS {
+                        match buttons.get::<Option<bool>>(name)? {
+                            Some(true) => state |= button.mask(),
+                            Some(false) => state &= !button.mask(),
+                            None => {}
+                        }
+                    }
+                    emu.set_controller_state(port, state);
+                    Ok(())
+                })?,
+            )?;
+
+            let emu_table: Table = globals.get("emu")?;
+            emu_table.set(
+                "framecount",
+                scope.create_function(|_, ()| Ok(emu.borrow().nes.ppu.frame))?,
+            )?;
+
+            let gui: Table = globals.get("gui")?;
+            gui.set(
+                "text",
+                scope.create_function(|_, (x, y, text): (i32, i32, String)| {
+                    draw(GuiCommand::Text { x, y, text });
+                    Ok(())
+                })?,
+            )?;
+            gui.set(
+                "pixel",
+                scope.create_function(|_, (x, y, color): (i32, i32, Option<String>)| {
+                    draw(GuiCommand::Pixel { x, y, color });
+                    Ok(())
+                })?,
+            )?;
+            type Shape = (i32, i32, i32, i32, Option<String>);
+            gui.set(
+                "line",
+                scope.create_function(|_, (x1, y1, x2, y2, color): Shape| {
+                    draw(GuiCommand::Line {
+                        x1,
+                        y1,
+                        x2,
+                        y2,
+                        color,
+                    });
+                    Ok(())
+                })?,
+            )?;
+            gui.set(
+                "box",
+                scope.create_function(|_, (x1, y1, x2, y2, color): Shape| {
+                    draw(GuiCommand::Box {
+                        x1,
+                        y1,
+                        x2,
+                        y2,
+                        color,
+                    });
+                    Ok(())
+                })?,
+            )?;
+
+            if let Some(f) = lua.named_registry_value::<Option<Function>>(BEFORE)? {
+                f.call::<()>(())?;
+            }
+            if self.main.status() == ThreadStatus::Resumable {
+                self.main.resume::<()>(())?;
+            }
+            let result = emu.borrow_mut().run_frame();
+            if let Some(f) = lua.named_registry_value::<Option<Function>>(AFTER)? {
+                f.call::<()>(())?;
+            }
+            Ok(result)
+        })
+        .map_err(script_error)
+    }
+}
+
+fn script_error(e: mlua::Error) -> anyhow::Error {
+    ScriptError::new(&e.to_string()).into()
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct ScriptError {
+    msg: String,
+}
+
+impl ScriptError {
+    pub(crate) fn new(msg: &str) -> Self {
+        Self {
+            msg: msg.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "script error: {}", self.msg)
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::rc::Rc;
+
+    fn emu() -> Emu {
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        emu
+    }
+
+    #[test]
+    fn test_memory_and_joypad() {
+        let mut emu = emu();
+        let mut script = Script::new(
+            r#"
+            memory.writebyte(0x0010, 0xFE)
+            memory.writebyte(0x0011, memory.readbytesigned(0x0010) + 3)
+            joypad.set(1, {A = true, start = true})
+            emu.frameadvance()
+            local pad = joypad.get(1)
+            memory.writebyte(0x0012, pad.start and 1 or 0)
+            joypad.set(1, {start = false})
+            memory.writebyte(0x0013, emu.framecount())
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(script.frame(&mut emu).unwrap().frame, 0);
+        assert_eq!([emu.peek(0x0010), emu.peek(0x0011)], [0xFE, 0x01]);
+        assert!(script.running());
+        script.frame(&mut emu).unwrap();
+        assert!(!script.running());
+        assert_eq!([emu.peek(0x0012), emu.peek(0x0013)], [1, 0]);
+        assert_eq!(emu.controller_state(0), Button::A.mask());
+    }
+
+    #[test]
+    fn test_callbacks_and_gui() {
+        let mut emu = emu();
+        let mut script = Script::new(
+            r#"
+            emu.registerafter(function()
+                gui.text(8, 16, "frame " .. emu.framecount())
+                gui.box(0, 0, 10, 10, "red")
+            end)
+            "#,
+        )
+        .unwrap();
+        let commands = Rc::new(RefCell::new(Vec::new()));
+        let c = commands.clone();
+        script.on_gui(move |command| c.borrow_mut().push(command));
+
+        script.frame(&mut emu).unwrap();
+        script.frame(&mut emu).unwrap();
+        let commands = commands.borrow();
+        assert_eq!(commands.len(), 4);
+        assert_eq!(
+            commands[2],
+            GuiCommand::Text {
+                x: 8,
+                y: 16,
+                text: "frame 1".to_string()
+            }
+        );
+        assert_eq!(
+            commands[3],
+            GuiCommand::Box {
+                x1: 0,
+                y1: 0,
+                x2: 10,
+                y2: 10,
+                color: Some("red".to_string())
+            }
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(Script::new("this isn't lua").is_err());
+        let mut script = Script::new("memory.readbyte()").unwrap();
+        assert!(script.frame(&mut emu()).is_err());
+    }
+}
@@ -0,0 +1,179 @@
+use crate::Emu;
+
+// How a RAM byte has to compare for its address to stay a candidate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Predicate {
+    // against a value
+    Equal(u8),
+    NotEqual(u8),
+    GreaterThan(u8),
+    LessThan(u8),
+    // against the byte at the previous `filter` (or `new`)
+    Increased,
+    Decreased,
+    Changed,
+    Unchanged,
+    // went from the previous value by exactly this much, wrapping
+    ChangedBy(i16),
+}
+
+impl Predicate {
+    fn matches(self, previous: u8, current: u8) -> bool {
+        match self {
+            Predicate::Equal(v) => current == v,
+            Predicate::NotEqual(v) => current != v,
+            Predicate::GreaterThan(v) => v < current,
+            Predicate::LessThan(v) => current < v,
+            Predicate::Increased => previous < current,
+            Predicate::Decreased => current < previous,
+            Predicate::Changed => current != previous,
+            Predicate::Unchanged => current == previous,
+            Predicate::ChangedBy(d) => current == previous.wrapping_add(d as u8),
+        }
+    }
+}
+
+// Narrowing down which CPU RAM byte holds a value, comparing snapshots of RAM
+// taken across frames
+#[derive(Debug, Clone)]
+pub struct RamSearch {
+    previous: Vec<u8>,
+    candidates: Vec<u16>,
+}
+
+impl RamSearch {
+    // Start with every address of `ram` (usually `Emu::ram`) as a candidate
+    pub fn new(ram: &[u8]) -> Self {
+        Self {
+            previous: ram.to_vec(),
+            candidates: (0..ram.len() as u16).collect(),
+        }
+    }
+
+    // Keep the candidates whose byte in `ram` satisfies `predicate`, and remember
+    // `ram` for the next comparison
+    pub fn filter(&mut self, ram: &[u8], predicate: Predicate) {
+        let previous = &self.previous;
+        self.candidates.retain(|&a| {
+            let a = a as usize;
+            a < ram.len() && predicate.matches(previous[a], ram[a])
+        });
+        self.previous = ram.to_vec();
+    }
+
+    pub fn candidates(&self) -> &[u16] {
+        &self.candidates
+    }
+
+    // Byte at `addr` as of the last snapshot
+    pub fn previous(&self, addr: u16) -> Option<u8> {
+        self.previous.get(addr as usize).copied()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watch {
+    pub label: String,
+    pub addr: u16,
+    // 16-bit watches read a little-endian word at `addr`
+    pub word: bool,
+}
+
+impl Watch {
+    // Current value, read with `Emu::peek`
+    pub fn value(&self, emu: &Emu) -> u16 {
+        let lo = emu.peek(self.addr) as u16;
+        if self.word {
+            lo | (emu.peek(self.addr.wrapping_add(1)) as u16) << 8
+        } else {
+            lo
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WatchList {
+    watches: Vec<Watch>,
+}
+
+impl WatchList {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn add(&mut self, watch: Watch) {
+        self.watches.push(watch);
+    }
+
+    // Returns whether a watch had that label
+    pub fn remove(&mut self, label: &str) -> bool {
+        let len = self.watches.len();
+        self.watches.retain(|w| w.label != label);
+        self.watches.len() != len
+    }
+
+    pub fn watches(&self) -> &[Watch] {
+        &self.watches
+    }
+
+    // Labels and current values, in the order added
+    pub fn values<'a>(&'a self, emu: &Emu) -> Vec<(&'a str, u16)> {
+        self.watches
+            .iter()
+            .map(|w| (&w.label[..], w.value(emu)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_predicates() {
+        #[rustfmt::skip]
+        let cases = [
+            (Predicate::Equal(5),        5,    5,    true),
+            (Predicate::Equal(5),        5,    6,    false),
+            (Predicate::NotEqual(5),     5,    6,    true),
+            (Predicate::GreaterThan(5),  0,    6,    true),
+            (Predicate::LessThan(5),     0,    5,    false),
+            (Predicate::Increased,       3,    4,    true),
+            (Predicate::Decreased,       3,    4,    false),
+            (Predicate::Changed,         3,    3,    false),
+            (Predicate::Unchanged,       3,    3,    true),
+            (Predicate::ChangedBy(-1),   0,    0xFF, true),
+            (Predicate::ChangedBy(16),   0xF8, 0x08, true),
+            (Predicate::ChangedBy(1),    3,    5,    false),
+        ];
+
+        for (predicate, previous, current, expected) in cases {
+            assert_eq!(
+                predicate.matches(previous, current),
+                expected,
+                "{:?}",
+                predicate
+            );
+        }
+    }
+
+    #[test]
+    fn test_search() {
+        let mut ram = vec![0u8; 16];
+        ram[3] = 9;
+        ram[7] = 9;
+        let mut search = RamSearch::new(&ram);
+        search.filter(&ram, Predicate::Equal(9));
+        assert_eq!(search.candidates(), [3, 7]);
+
+        ram[3] = 8;
+        ram[7] = 10;
+        search.filter(&ram, Predicate::Decreased);
+        assert_eq!(search.candidates(), [3]);
+        assert_eq!(search.previous(3), Some(8));
+
+        // a smaller RAM drops what's out of range
+        search.filter(&ram[..2], Predicate::Unchanged);
+        assert!(search.candidates().is_empty());
+    }
+}
@@ -0,0 +1,143 @@
+// Checks of invariants after every instruction, for developing boards: the
+// stack pointer wrapping around page 1, the PPU's VRAM address registers growing
+// past 15 bits and banks mapped past the end of the cartridge's memory. Each
+// violation is delivered once as `Event::Violation` when it appears, not again
+// for every instruction it persists through.
+
+use crate::hook::{self, Event};
+use crate::mapper::Mapper;
+use crate::memory_map::Backing;
+use crate::nes::Nes;
+use crate::rom::Cartridge;
+
+const TXS: u8 = 0x9A;
+const LAS: u8 = 0xBB;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    // The instruction at `pc` pushed below $0100 or pulled above $01FF, leaving
+    // the stack pointer at `s`
+    StackWrap { pc: u16, s: u8 },
+    // The PPU's current or temporary VRAM address has bit 15 set
+    PpuAddress { v: u16, t: u16 },
+    // The board maps `addr` to `offset` into its memory, past the end of it
+    PrgRomBank { addr: u16, offset: usize },
+    PrgRamBank { addr: u16, offset: usize },
+    ChrRomBank { addr: u16, offset: usize },
+}
+
+// Violations seen after the last instruction
+#[derive(Debug, Default)]
+pub(crate) struct SelfCheck {
+    active: Vec<Violation>,
+}
+
+// What the checks need from before the instruction
+#[derive(Clone, Copy)]
+pub(crate) struct Before {
+    pc: u16,
+    s: u8,
+    opcode: u8,
+}
+
+pub(crate) fn before(nes: &Nes) -> Before {
+    Before {
+        pc: nes.cpu.pc,
+        s: nes.cpu.s,
+        opcode: crate::nes::peek(nes, nes.cpu.pc),
+    }
+}
+
+// Check the machine after the instruction that started at `before`, emitting the
+// violations that weren't there after the previous one
+pub(crate) fn check(nes: &mut Nes, cart: Option<&Cartridge>, before: Before) {
+    if nes.self_check.is_none() {
+        return;
+    }
+    crate::nes::catch_up_ppu(nes);
+    let mut found = Vec::new();
+
+    // pushes and pulls move S by at most three, anything else is a wrap
+    let moved = nes.cpu.s as i16 - before.s as i16;
+    let wrapped = moved.abs() > 3 && before.opcode != TXS && before.opcode != LAS;
+    if wrapped {
+        found.push(Violation::StackWrap {
+            pc: before.pc,
+            s: nes.cpu.s,
+        });
+    }
+
+    let (v, t) = nes.ppu.vram_addresses();
+    if v > 0x7FFF || t > 0x7FFF {
+        found.push(Violation::PpuAddress { v, t });
+    }
+
+    if let Some(cart) = cart {
+        banks(nes, cart, &mut found);
+    }
+
+    let check = nes.self_check.as_mut().unwrap();
+    let new: Vec<Violation> = found
+        .iter()
+        .filter(|v| !check.active.contains(v))
+        .copied()
+        .collect();
+    check.active = found;
+    for violation in new {
+        hook::emit(nes, Event::Violation(violation));
+    }
+}
+
+// The first and last byte of every page, as the memory map looks at them
+fn banks(nes: &mut Nes, cart: &Cartridge, found: &mut Vec<Violation>) {
+    let prg_ram = nes.mapper.prg_ram_mut().map_or(0, |ram| ram.len());
+    let pages = |range: std::ops::Range<u32>| range.flat_map(|p| [p << 8, p << 8 | 0xFF]);
+
+    for addr in pages(0x60..0x100).map(|a| a as u16) {
+        if let Some(offset) = nes.mapper.prg_rom_offset(addr) {
+            if offset >= cart.prg_rom.len() {
+                found.push(Violation::PrgRomBank { addr, offset });
+            }
+        }
+        if let Some(offset) = nes.mapper.prg_ram_offset(addr) {
+            if offset >= prg_ram {
+                found.push(Violation::PrgRamBank { addr, offset });
+            }
+        }
+    }
+    for addr in pages(0x00..0x20).map(|a| a as u16) {
+        if let Some(Backing::ChrRom(offset)) = nes.mapper.chr_backing(addr) {
+            if offset >= cart.chr_rom.len() {
+                found.push(Violation::ChrRomBank { addr, offset });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stack_wrap() {
+        let mut nes = Nes::new();
+        nes.self_check = Some(Default::default());
+        // PHA at S=0
+        let pha = Before {
+            pc: 0x0000,
+            s: 0x00,
+            opcode: 0x48,
+        };
+        nes.cpu.s = 0xFF;
+        check(&mut nes, None, pha);
+        assert_eq!(
+            nes.self_check.as_ref().unwrap().active,
+            vec![Violation::StackWrap { pc: 0, s: 0xFF }]
+        );
+
+        // TXS can put S anywhere
+        let txs = Before { opcode: TXS, ..pha };
+        check(&mut nes, None, txs);
+        assert!(nes.self_check.as_ref().unwrap().active.is_empty());
+    }
+}
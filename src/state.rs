@@ -0,0 +1,185 @@
+use std::fmt;
+
+use anyhow::Result;
+
+mod diff;
+mod snapshot;
+
+pub use diff::{diff_states, StateDiff};
+pub(crate) use snapshot::{load_snapshot, save_snapshot};
+
+// Little-endian binary encoding for save states
+#[derive(Debug, Default)]
+pub(crate) struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub(crate) fn new() -> Self {
+        Default::default()
+    }
+
+    pub(crate) fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub(crate) fn write_u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub(crate) fn write_bool(&mut self, v: bool) {
+        self.write_u8(v as u8);
+    }
+
+    pub(crate) fn write_u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub(crate) fn write_u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub(crate) fn write_u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    // Length-prefixed byte sequence (RAM contents, bank registers, ...)
+    pub(crate) fn write_bytes(&mut self, v: &[u8]) {
+        self.write_u32(v.len() as u32);
+        self.buf.extend_from_slice(v);
+    }
+
+    // Bytes without a length prefix, for fixed-size fields like magics and tags
+    pub(crate) fn write_raw(&mut self, v: &[u8]) {
+        self.buf.extend_from_slice(v);
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct StateReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() - self.pos < n {
+            return Err(StateError {
+                msg: "unexpected end of state".to_string(),
+            }
+            .into());
+        }
+        let v = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(v)
+    }
+
+    pub(crate) fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn read_bool(&mut self) -> Result<bool> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub(crate) fn read_u16(&mut self) -> Result<u16> {
+        let mut b = [0; 2];
+        b.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(b))
+    }
+
+    pub(crate) fn read_u32(&mut self) -> Result<u32> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+
+    pub(crate) fn read_u64(&mut self) -> Result<u64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    pub(crate) fn read_raw(&mut self, n: usize) -> Result<&'a [u8]> {
+        self.take(n)
+    }
+
+    // Read a length-prefixed byte sequence of any length
+    pub(crate) fn read_bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.read_u32()? as usize;
+        self.take(len)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pos == self.buf.len()
+    }
+
+    // Read a length-prefixed byte sequence into `dst`, which must have the same length
+    pub(crate) fn read_bytes_into(&mut self, dst: &mut [u8]) -> Result<()> {
+        let len = self.read_u32()? as usize;
+        if len != dst.len() {
+            return Err(StateError {
+                msg: format!("size mismatch: expected {}, got {}", dst.len(), len),
+            }
+            .into());
+        }
+        dst.copy_from_slice(self.take(len)?);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct StateError {
+    pub(crate) msg: String,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "save state error: {}", self.msg)
+    }
+}
+
+impl std::error::Error for StateError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut w = StateWriter::new();
+        w.write_u8(0x12);
+        w.write_bool(true);
+        w.write_u16(0x3456);
+        w.write_u32(0x789A_BCDE);
+        w.write_u64(0x0102_0304_0506_0708);
+        w.write_bytes(&[1, 2, 3]);
+        let buf = w.into_inner();
+
+        let mut r = StateReader::new(&buf);
+        assert_eq!(r.read_u8().unwrap(), 0x12);
+        assert!(r.read_bool().unwrap());
+        assert_eq!(r.read_u16().unwrap(), 0x3456);
+        assert_eq!(r.read_u32().unwrap(), 0x789A_BCDE);
+        assert_eq!(r.read_u64().unwrap(), 0x0102_0304_0506_0708);
+        let mut bytes = [0; 3];
+        r.read_bytes_into(&mut bytes).unwrap();
+        assert_eq!(bytes, [1, 2, 3]);
+
+        assert!(r.read_u8().is_err());
+    }
+
+    #[test]
+    fn test_size_mismatch() {
+        let mut w = StateWriter::new();
+        w.write_bytes(&[1, 2, 3]);
+        let buf = w.into_inner();
+
+        let mut bytes = [0; 4];
+        assert!(StateReader::new(&buf).read_bytes_into(&mut bytes).is_err());
+    }
+}
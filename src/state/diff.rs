@@ -0,0 +1,189 @@
+// Field by field comparison of snapshots, for finding where two runs that
+// should have stayed identical parted: netplay desyncs, replays that don't.
+
+use super::snapshot::{self, CPU, RAM, SYSTEM, VRAM};
+use super::*;
+
+// Named fields at the start of a chunk, with their sizes in bytes
+type Fields = &'static [(&'static str, usize)];
+
+#[rustfmt::skip]
+const CPU_FIELDS: Fields = &[("A", 1), ("X", 1), ("Y", 1), ("S", 1), ("P", 1), ("PC", 2)];
+#[rustfmt::skip]
+const SYSTEM_FIELDS: Fields = &[
+    ("region", 1), ("cpu_cycles", 8), ("irq", 1), ("nmi", 1),
+    ("oam_dma", 1), ("oam_dma_page", 1), ("open_bus", 1), ("ppu_fraction", 1),
+];
+
+// A difference between two snapshots: a register or field, or a run of
+// consecutive differing bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateDiff {
+    // Chunk the difference is in: "SYS", "CPU", "RAM", "VRAM", "PPU", "APU", ...
+    pub section: String,
+    pub field: Option<&'static str>,
+    // The address for RAM and VRAM, otherwise the offset into the chunk
+    pub offset: usize,
+    // Bytes of each side, little endian for fields; a side is shorter where its
+    // chunk ends early
+    pub left: Vec<u8>,
+    pub right: Vec<u8>,
+}
+
+impl fmt::Display for StateDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex = |bytes: &[u8]| {
+            let s: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            if s.is_empty() {
+                "-".to_string()
+            } else {
+                s.join(" ")
+            }
+        };
+        match self.field {
+            Some(field) => write!(f, "{} {}", self.section, field)?,
+            None if self.section == "RAM" || self.section == "VRAM" => {
+                write!(f, "{} ${:04X}", self.section, self.offset)?
+            }
+            None => write!(f, "{} +{}", self.section, self.offset)?,
+        }
+        write!(f, ": {} != {}", hex(&self.left), hex(&self.right))
+    }
+}
+
+// Compare two snapshots of the same ROM made by `Emu::save_state`, listing every
+// difference in the order of the snapshot: SYS, CPU, RAM, VRAM, PPU, APU, INPT,
+// MAPR and AOUT chunks
+pub fn diff_states(left: &[u8], right: &[u8]) -> Result<Vec<StateDiff>> {
+    let (a, b) = (snapshot::parse(left)?, snapshot::parse(right)?);
+    if a.rom_hash != b.rom_hash {
+        return Err(StateError {
+            msg: "states are of different ROMs".to_string(),
+        }
+        .into());
+    }
+    let mut tags: Vec<&[u8]> = a.chunks.iter().map(|&(tag, _)| tag).collect();
+    for &(tag, _) in &b.chunks {
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    let mut diffs = Vec::new();
+    for tag in tags {
+        let section = String::from_utf8_lossy(tag).trim_end().to_string();
+        let (mut a, mut b) = (payload(&a.chunks, tag), payload(&b.chunks, tag));
+        let fields = if tag == CPU {
+            CPU_FIELDS
+        } else if tag == SYSTEM {
+            SYSTEM_FIELDS
+        } else {
+            &[]
+        };
+        // memory chunks hold a length-prefixed block, addressed from 0
+        let mut offset = 0;
+        if tag == RAM || tag == VRAM {
+            (a, b) = (
+                a.get(4..).unwrap_or_default(),
+                b.get(4..).unwrap_or_default(),
+            );
+        }
+
+        for &(field, len) in fields {
+            let (fa, fb) = (slice(a, offset, len), slice(b, offset, len));
+            if fa != fb {
+                diffs.push(StateDiff {
+                    section: section.clone(),
+                    field: Some(field),
+                    offset,
+                    left: fa.to_vec(),
+                    right: fb.to_vec(),
+                });
+            }
+            offset += len;
+        }
+
+        let start = offset;
+        let mut run: Option<usize> = None;
+        for i in start..=a.len().max(b.len()) {
+            let differs = i < a.len().max(b.len()) && a.get(i) != b.get(i);
+            match (run, differs) {
+                (None, true) => run = Some(i),
+                (Some(from), false) => {
+                    diffs.push(StateDiff {
+                        section: section.clone(),
+                        field: None,
+                        offset: from,
+                        left: slice(a, from, i - from).to_vec(),
+                        right: slice(b, from, i - from).to_vec(),
+                    });
+                    run = None;
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(diffs)
+}
+
+// Payload of the chunk tagged `tag`, empty if there's none
+fn payload<'a>(chunks: &[(&'a [u8], &'a [u8])], tag: &[u8]) -> &'a [u8] {
+    chunks
+        .iter()
+        .find(|&&(t, _)| t == tag)
+        .map_or(&[], |&(_, p)| p)
+}
+
+// Up to `len` bytes from `offset`, fewer past the end
+fn slice(data: &[u8], offset: usize, len: usize) -> &[u8] {
+    let start = offset.min(data.len());
+    &data[start..(offset + len).min(data.len())]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Emu;
+
+    #[test]
+    fn test_diff_states() {
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        let mut r = emu.nes.cpu.registers();
+        r.pc = 0xC000;
+        emu.nes.cpu.set_registers(r);
+        let before = emu.save_state().unwrap();
+        assert_eq!(diff_states(&before, &before).unwrap(), vec![]);
+
+        emu.poke(0x0010, 0xAA);
+        emu.poke(0x0811, 0xBB);
+        emu.poke(0x0300, 0xCC);
+        r.pc = 0xC123;
+        emu.nes.cpu.set_registers(r);
+        let after = emu.save_state().unwrap();
+
+        let diffs: Vec<String> = diff_states(&before, &after)
+            .unwrap()
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        #[rustfmt::skip]
+        let expected = [
+            "CPU PC: 00 C0 != 23 C1",
+            "RAM $0010: 00 00 != AA BB",
+            "RAM $0300: 00 != CC",
+        ];
+        assert_eq!(diffs, expected);
+    }
+
+    #[test]
+    fn test_diff_errors() {
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        let state = emu.save_state().unwrap();
+        let mut other = state.clone();
+        other[6] ^= 1;
+
+        assert!(diff_states(&state, b"KNSX").is_err());
+        assert!(diff_states(&state, &other).is_err());
+    }
+}
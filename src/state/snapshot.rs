@@ -0,0 +1,186 @@
+use super::*;
+
+use crate::mapper::Mapper;
+use crate::nes::Nes;
+
+// Snapshot layout:
+//   "KNSS", u16 format version, SHA-1 of the ROM,
+//   then chunks of a 4-byte tag and a length-prefixed payload.
+// Readers skip chunks they don't know, so adding a chunk doesn't need a new
+// version; changing an existing chunk's layout does.
+const MAGIC: &[u8; 4] = b"KNSS";
+const VERSION: u16 = 3;
+
+pub(super) const SYSTEM: &[u8; 4] = b"SYS ";
+pub(super) const CPU: &[u8; 4] = b"CPU ";
+pub(super) const RAM: &[u8; 4] = b"RAM ";
+pub(super) const VRAM: &[u8; 4] = b"VRAM";
+const PPU: &[u8; 4] = b"PPU ";
+const APU: &[u8; 4] = b"APU ";
+const INPUT: &[u8; 4] = b"INPT";
+const MAPPER: &[u8; 4] = b"MAPR";
+// Optional, missing from states of older builds
+const AUDIO: &[u8; 4] = b"AOUT";
+const OVERCLOCK: &[u8; 4] = b"OVCK";
+
+fn error(msg: &str) -> anyhow::Error {
+    StateError {
+        msg: msg.to_string(),
+    }
+    .into()
+}
+
+pub(crate) fn save_snapshot(nes: &Nes, rom_hash: &[u8; 20]) -> Vec<u8> {
+    let mut w = StateWriter::new();
+    w.write_raw(MAGIC);
+    w.write_u16(VERSION);
+    w.write_raw(rom_hash);
+
+    let mut chunk = |tag: &[u8; 4], save: &dyn Fn(&mut StateWriter)| {
+        let mut c = StateWriter::new();
+        save(&mut c);
+        w.write_raw(tag);
+        w.write_bytes(&c.into_inner());
+    };
+    chunk(SYSTEM, &|w| nes.save_state(w));
+    chunk(CPU, &|w| nes.cpu.save_state(w));
+    chunk(RAM, &|w| w.write_bytes(&nes.wram));
+    chunk(VRAM, &|w| w.write_bytes(&nes.nametables));
+    chunk(PPU, &|w| nes.ppu.save_state(w));
+    chunk(APU, &|w| nes.apu.save_state(w));
+    chunk(INPUT, &|w| nes.input.save_state(w));
+    chunk(MAPPER, &|w| nes.mapper.save_state(w));
+    chunk(AUDIO, &|w| nes.audio.save_state(w));
+    chunk(OVERCLOCK, &|w| w.write_u32(nes.overclock_idle));
+    w.into_inner()
+}
+
+pub(super) struct Parsed<'a> {
+    pub(super) version: u16,
+    pub(super) rom_hash: &'a [u8],
+    // Tags and payloads in the order they were saved
+    pub(super) chunks: Vec<(&'a [u8], &'a [u8])>,
+}
+
+// Split a snapshot into its chunks, checking the header
+pub(super) fn parse(data: &[u8]) -> Result<Parsed<'_>> {
+    let mut r = StateReader::new(data);
+    if r.read_raw(4).ok() != Some(MAGIC) {
+        return Err(error("not a save state"));
+    }
+    let version = r.read_u16()?;
+    if VERSION < version {
+        return Err(error(&format!("unsupported state version {}", version)));
+    }
+    let rom_hash = r.read_raw(20)?;
+
+    let mut chunks = Vec::new();
+    while !r.is_empty() {
+        let tag = r.read_raw(4)?;
+        chunks.push((tag, r.read_bytes()?));
+    }
+    Ok(Parsed {
+        version,
+        rom_hash,
+        chunks,
+    })
+}
+
+// Restore a snapshot of the same ROM. `nes` may be partially overwritten when
+// this fails on a corrupted chunk.
+pub(crate) fn load_snapshot(nes: &mut Nes, rom_hash: &[u8; 20], data: &[u8]) -> Result<()> {
+    let Parsed {
+        version,
+        rom_hash: hash,
+        chunks,
+    } = parse(data)?;
+    if hash != rom_hash {
+        return Err(error("state is for another ROM"));
+    }
+    let find = |tag: &[u8; 4]| {
+        chunks
+            .iter()
+            .find(|(t, _)| t == tag)
+            .map(|(_, payload)| StateReader::new(payload))
+            .ok_or_else(|| {
+                error(&format!(
+                    "missing chunk {}",
+                    String::from_utf8_lossy(tag).trim_end()
+                ))
+            })
+    };
+    // check every chunk is there before touching anything
+    for tag in [SYSTEM, CPU, RAM, VRAM, PPU, APU, INPUT, MAPPER] {
+        find(tag)?;
+    }
+
+    nes.load_state(&mut find(SYSTEM)?)?;
+    nes.cpu.load_state(&mut find(CPU)?)?;
+    if version < 3 {
+        // RAM used to be a byte short
+        find(RAM)?.read_bytes_into(&mut nes.wram[..0x07FF])?;
+    } else {
+        find(RAM)?.read_bytes_into(&mut nes.wram)?;
+    }
+    find(VRAM)?.read_bytes_into(&mut nes.nametables)?;
+    nes.ppu.load_state(&mut find(PPU)?)?;
+    nes.apu.load_state(&mut find(APU)?)?;
+    if version < 2 {
+        nes.input.pads.load_state_v1(&mut find(INPUT)?)?;
+    } else {
+        nes.input.load_state(&mut find(INPUT)?)?;
+    }
+    nes.mapper.load_state(&mut find(MAPPER)?)?;
+    nes.refresh_prg_banks();
+    if let Ok(mut r) = find(AUDIO) {
+        nes.audio.load_state(&mut r)?;
+    }
+    nes.overclock_idle = match find(OVERCLOCK) {
+        Ok(mut r) => r.read_u32()?,
+        Err(_) => 0,
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_errors() {
+        let hash = [1; 20];
+        let state = save_snapshot(&Nes::new(), &hash);
+        assert!(load_snapshot(&mut Nes::new(), &hash, &state).is_ok());
+
+        let mut newer = state.clone();
+        newer[4] = 4;
+        let mut truncated = state.clone();
+        truncated.truncate(state.len() - 1);
+        // drop the trailing overclock and audio chunks, which may be missing, and
+        // the mapper one before them (the empty board saves nothing)
+        let mut optional = state.clone();
+        optional.truncate(state.len() - 12 - 8 - 26);
+        let mut missing = optional.clone();
+        missing.truncate(optional.len() - 8);
+        let mut unknown = state.clone();
+        unknown.extend_from_slice(b"XTRA\x01\x00\x00\x00\xFF");
+
+        #[rustfmt::skip]
+        let cases = [
+            ("bad magic", &b"KNSX"[..],   hash,    Some("not a save state")),
+            ("newer",     &newer[..],     hash,    Some("unsupported state version 4")),
+            ("other rom", &state[..],     [2; 20], Some("state is for another ROM")),
+            ("truncated", &truncated[..], hash,    Some("unexpected end of state")),
+            ("optional",  &optional[..],  hash,    None),
+            ("missing",   &missing[..],   hash,    Some("missing chunk MAPR")),
+            ("unknown",   &unknown[..],   hash,    None),
+        ];
+
+        for (name, data, hash, expected) in cases {
+            let result = load_snapshot(&mut Nes::new(), &hash, data);
+            let msg = result.err().map(|e| e.to_string());
+            let expected = expected.map(|m| format!("save state error: {}", m));
+            assert_eq!(msg, expected, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,299 @@
+// Labels from FCEUX (.nl) and Mesen (.mlb) symbol files, for naming addresses
+// in debuggers. Labels are kept by what they name rather than by CPU address:
+// PRG-ROM labels by ROM offset, so they follow bank switching, and RAM labels by
+// offset into RAM, so they cover the mirrors.
+//
+// FCEUX keeps one file per 16KB PRG-ROM bank, game.nes.<bank in hex>.nl, plus
+// game.nes.ram.nl for everything below $8000. Lines are "$ADDR#name#comment",
+// where ADDR may be followed by "/LEN" for arrays.
+//
+// Mesen keeps one file with lines "TYPE:ADDR[-END]:name[:comment]", TYPE being P
+// (PRG-ROM), R (RAM), S or W (PRG-RAM) or G (registers), or their Mesen 2 names.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::memory_map::Backing;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Label {
+    pub name: String,
+    pub comment: String,
+    // Bytes it covers, more than 1 for arrays
+    pub len: usize,
+}
+
+// What a label names
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Space {
+    Ram,
+    PrgRom,
+    PrgRam,
+    // Registers and anything else named by its CPU address
+    Cpu,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+    labels: BTreeMap<(Space, usize), Label>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // Add the labels of an FCEUX or Mesen file, telling them apart by name
+    pub fn load_path<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let text = std::fs::read_to_string(path)?;
+        if name.ends_with(".mlb") {
+            return self.add_mlb(&text);
+        }
+        let bank = match name.strip_suffix(".nl").and_then(|n| n.rsplit('.').next()) {
+            Some("ram") => None,
+            Some(bank) => Some(usize::from_str_radix(bank, 16).map_err(|_| {
+                SymbolError::new(&format!("{} has no bank number", path.display()))
+            })?),
+            None => {
+                return Err(SymbolError::new(&format!(
+                    "{} isn't an .nl or .mlb file",
+                    path.display()
+                ))
+                .into())
+            }
+        };
+        self.add_nl(&text, bank)
+    }
+
+    // Add the labels of an FCEUX file: the one of a 16KB PRG-ROM `bank`, or the
+    // RAM one with None
+    pub fn add_nl(&mut self, text: &str, bank: Option<usize>) -> Result<()> {
+        let mut last: Option<(Space, usize)> = None;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.trim().is_empty() {
+                continue;
+            }
+            // continuation of a multi-line comment
+            if let Some(more) = line.strip_prefix('\\') {
+                if let Some(label) = last.and_then(|key| self.labels.get_mut(&key)) {
+                    label.comment.push('\n');
+                    label.comment.push_str(more);
+                }
+                continue;
+            }
+            let invalid = || SymbolError::new(&format!("invalid line {}: {}", i + 1, line));
+            let mut fields = line.splitn(3, '#');
+            let addr = fields.next().and_then(|a| a.strip_prefix('$'));
+            let (addr, len) = match addr.map(|a| a.split_once('/').unwrap_or((a, "1"))) {
+                Some((addr, len)) => (hex(addr), hex(len)),
+                None => (None, None),
+            };
+            let (addr, len) = match (addr, len) {
+                (Some(addr), Some(len)) if addr <= 0xFFFF => (addr as u16, len.max(1)),
+                _ => return Err(invalid().into()),
+            };
+            let name = fields.next().ok_or_else(invalid)?;
+            let comment = fields.next().unwrap_or_default();
+
+            let key = match (bank, addr) {
+                (Some(bank), 0x8000..=0xFFFF) => {
+                    (Space::PrgRom, bank * 0x4000 + (addr as usize & 0x3FFF))
+                }
+                (_, 0x0000..=0x1FFF) => (Space::Ram, addr as usize & 0x07FF),
+                (_, 0x6000..=0x7FFF) => (Space::PrgRam, addr as usize - 0x6000),
+                _ => (Space::Cpu, addr as usize),
+            };
+            self.insert(key, name, comment, len);
+            last = Some(key);
+        }
+        Ok(())
+    }
+
+    // Add the labels of a Mesen file
+    pub fn add_mlb(&mut self, text: &str) -> Result<()> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.trim().is_empty() {
+                continue;
+            }
+            let invalid = || SymbolError::new(&format!("invalid line {}: {}", i + 1, line));
+            let mut fields = line.splitn(4, ':');
+            let space = match fields.next() {
+                Some("P") | Some("NesPrgRom") => Space::PrgRom,
+                Some("R") | Some("NesInternalRam") => Space::Ram,
+                Some("S") | Some("W") | Some("NesSaveRam") | Some("NesWorkRam") => Space::PrgRam,
+                Some("G") | Some("NesMemory") => Space::Cpu,
+                // labels of memory not on the CPU side, like CHR
+                Some(_) => continue,
+                None => return Err(invalid().into()),
+            };
+            let range = fields.next().ok_or_else(invalid)?;
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            let (start, end) = match (hex(start), hex(end)) {
+                (Some(start), Some(end)) if start <= end => (start, end),
+                _ => return Err(invalid().into()),
+            };
+            let name = fields.next().unwrap_or_default();
+            let comment = fields.next().unwrap_or_default().replace("\\n", "\n");
+            self.insert((space, start), name, &comment, end - start + 1);
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, key: (Space, usize), name: &str, comment: &str, len: usize) {
+        // lines with only a comment name nothing
+        if name.is_empty() {
+            return;
+        }
+        self.labels.insert(
+            key,
+            Label {
+                name: name.to_string(),
+                comment: comment.to_string(),
+                len,
+            },
+        );
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    // The label covering what backs `addr`, with how far into it `addr` is
+    pub(crate) fn lookup(&self, backing: Backing, addr: u16) -> Option<(&Label, usize)> {
+        let key = match backing {
+            Backing::Ram(o) => (Space::Ram, o),
+            Backing::PrgRom(o) => (Space::PrgRom, o),
+            Backing::PrgRam(o) => (Space::PrgRam, o),
+            _ => (Space::Cpu, addr as usize),
+        };
+        let (&(space, start), label) = self.labels.range(..=key).next_back()?;
+        if space == key.0 && key.1 < start + label.len {
+            Some((label, key.1 - start))
+        } else {
+            None
+        }
+    }
+}
+
+fn hex(s: &str) -> Option<usize> {
+    usize::from_str_radix(s.trim(), 16).ok()
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct SymbolError {
+    msg: String,
+}
+
+impl SymbolError {
+    pub(crate) fn new(msg: &str) -> Self {
+        Self {
+            msg: msg.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "symbol error: {}", self.msg)
+    }
+}
+
+impl std::error::Error for SymbolError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn name(symbols: &Symbols, backing: Backing, addr: u16) -> Option<(String, usize)> {
+        symbols
+            .lookup(backing, addr)
+            .map(|(l, offset)| (l.name.clone(), offset))
+    }
+
+    #[test]
+    fn test_nl() {
+        let mut symbols = Symbols::new();
+        symbols
+            .add_nl(
+                "$0010#counter#frames\n$0300/10#buffer#\n$2000#PPUCTRL#\n",
+                None,
+            )
+            .unwrap();
+        symbols
+            .add_nl(
+                "$C000#Reset#entry\n\\second line\n$8000##comment only\n",
+                Some(3),
+            )
+            .unwrap();
+        assert_eq!(symbols.len(), 4);
+
+        #[rustfmt::skip]
+        let cases = [
+            ("RAM",              Backing::Ram(0x10),        0x0810, Some(("counter", 0))),
+            ("array",            Backing::Ram(0x30F),       0x030F, Some(("buffer", 15))),
+            ("past the array",   Backing::Ram(0x310),       0x0310, None),
+            ("register",         Backing::PpuRegisters,     0x2000, Some(("PPUCTRL", 0))),
+            ("bank 3",           Backing::PrgRom(0xC000),   0xC000, Some(("Reset", 0))),
+            ("other bank",       Backing::PrgRom(0x4000),   0xC000, None),
+        ];
+
+        for (case, backing, addr, expected) in cases {
+            let expected = expected.map(|(n, o)| (n.to_string(), o));
+            assert_eq!(name(&symbols, backing, addr), expected, "{}", case);
+        }
+        let (reset, _) = symbols.lookup(Backing::PrgRom(0xC000), 0xC000).unwrap();
+        assert_eq!(reset.comment, "entry\nsecond line");
+
+        assert!(symbols.add_nl("C000#Reset#\n", Some(0)).is_err());
+        assert!(symbols.add_nl("$C000\n", Some(0)).is_err());
+    }
+
+    #[test]
+    fn test_mlb() {
+        let mut symbols = Symbols::new();
+        symbols
+            .add_mlb(
+                "P:7FFA:NmiVector:vectors\\nat the end\r\n\
+                 R:0000-0003:pointers\n\
+                 S:0100:save\n\
+                 G:4016:JOY1\n\
+                 NesChrRom:0000:tiles\n",
+            )
+            .unwrap();
+        assert_eq!(symbols.len(), 4);
+
+        #[rustfmt::skip]
+        let cases = [
+            ("PRG-ROM",   Backing::PrgRom(0x7FFA),  0xFFFA, Some(("NmiVector", 0))),
+            ("range",     Backing::Ram(0x02),       0x0002, Some(("pointers", 2))),
+            ("PRG-RAM",   Backing::PrgRam(0x100),   0x6100, Some(("save", 0))),
+            ("register",  Backing::ApuIo,           0x4016, Some(("JOY1", 0))),
+            ("unnamed",   Backing::Ram(0x04),       0x0004, None),
+        ];
+
+        for (case, backing, addr, expected) in cases {
+            let expected = expected.map(|(n, o)| (n.to_string(), o));
+            assert_eq!(name(&symbols, backing, addr), expected, "{}", case);
+        }
+        let (nmi, _) = symbols.lookup(Backing::PrgRom(0x7FFA), 0xFFFA).unwrap();
+        assert_eq!(nmi.comment, "vectors\nat the end");
+
+        assert!(symbols.add_mlb("P:zz:bad\n").is_err());
+        assert!(symbols.add_mlb("P:0010-0001:backwards\n").is_err());
+    }
+}
@@ -0,0 +1,370 @@
+// Results of test ROMs following blargg's convention, for harnesses checking
+// CPU, PPU, APU and mapper behavior alike. The ROMs report through PRG-RAM:
+// $6001-$6003 hold DE B0 61 once $6000 is valid, $6000 is $80 while running,
+// $81 when the ROM wants a reset, and the result code (0 for passed) at the
+// end. $6004 holds the text output, zero-terminated.
+//
+// The suites aren't redistributable, so the tests running them are ignored
+// unless run with `cargo test -- --ignored` after unpacking them into roms/.
+// Older suites such as sprite_hit_tests and sprite_overflow_tests predate the
+// convention and only leave their result code in $F8, 1 for passed.
+
+use crate::Emu;
+
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const RUNNING: u8 = 0x80;
+const NEEDS_RESET: u8 = 0x81;
+// A reset is asked for at least 100ms ahead
+const RESET_DELAY_FRAMES: u32 = 8;
+
+// What a test ROM reported
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestResult {
+    // Result code, 0 for passed; None if the ROM never finished
+    pub status: Option<u8>,
+    pub text: String,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.status == Some(0)
+    }
+}
+
+// Watches a test ROM between frames, pressing reset when it asks
+#[derive(Debug, Default)]
+pub struct TestRomMonitor {
+    // Frames until the reset the ROM asked for
+    reset_in: Option<u32>,
+}
+
+impl TestRomMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Check on the ROM after a frame, returning its result once it's done
+    pub fn poll(&mut self, emu: &mut Emu) -> Option<TestResult> {
+        let signature = [emu.peek(0x6001), emu.peek(0x6002), emu.peek(0x6003)];
+        if signature != SIGNATURE {
+            return None;
+        }
+        match emu.peek(0x6000) {
+            RUNNING => None,
+            NEEDS_RESET => {
+                let frames = self.reset_in.unwrap_or(RESET_DELAY_FRAMES);
+                if frames == 0 {
+                    self.reset_in = None;
+                    emu.reset();
+                } else {
+                    self.reset_in = Some(frames - 1);
+                }
+                None
+            }
+            status => Some(TestResult {
+                status: Some(status),
+                text: text(emu),
+            }),
+        }
+    }
+}
+
+// The text output so far
+pub(crate) fn text(emu: &Emu) -> String {
+    let bytes: Vec<u8> = (0x6004..=0x7FFF)
+        .map(|a| emu.peek(a))
+        .take_while(|&b| b != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    const TIMEOUT_FRAMES: u32 = 60 * 60;
+    const LEGACY_RESULT: u16 = 0x00F8;
+    const LEGACY_PASSED: u8 = 1;
+
+    fn run(rom: &[u8]) -> TestResult {
+        let mut emu = Emu::new();
+        emu.load_rom(rom).unwrap();
+        emu.set_headless(true);
+        emu.run_test_rom(TIMEOUT_FRAMES)
+    }
+
+    // Result code of a test ROM reporting through $F8, 0 if it never finished
+    pub(crate) fn run_legacy(rom: &[u8]) -> u8 {
+        let mut emu = Emu::new();
+        emu.load_rom(rom).unwrap();
+        emu.set_headless(true);
+        for _ in 0..TIMEOUT_FRAMES {
+            emu.run_frame();
+            match emu.peek(LEGACY_RESULT) {
+                0 => {}
+                status => return status,
+            }
+        }
+        0
+    }
+
+    // Run every ROM of a suite under roms/, reporting all failures at once
+    pub(crate) fn assert_pass(dir: &str, roms: &[&str]) {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("roms").join(dir);
+        let failures: Vec<String> = roms
+            .iter()
+            .filter_map(|name| {
+                let rom = std::fs::read(dir.join(name))
+                    .unwrap_or_else(|e| panic!("{}: {}", dir.join(name).display(), e));
+                match run(&rom) {
+                    TestResult {
+                        status: Some(0), ..
+                    } => None,
+                    TestResult {
+                        status: Some(status),
+                        text,
+                    } => Some(format!("{}: {:#04X}\n{}", name, status, text.trim())),
+                    TestResult { status: None, text } => {
+                        Some(format!("{}: timed out\n{}", name, text.trim()))
+                    }
+                }
+            })
+            .collect();
+        assert!(failures.is_empty(), "\n{}", failures.join("\n\n"));
+    }
+
+    pub(crate) fn assert_pass_legacy(dir: &str, roms: &[&str]) {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("roms").join(dir);
+        let failures: Vec<String> = roms
+            .iter()
+            .filter_map(|name| {
+                let rom = std::fs::read(dir.join(name))
+                    .unwrap_or_else(|e| panic!("{}: {}", dir.join(name).display(), e));
+                match run_legacy(&rom) {
+                    LEGACY_PASSED => None,
+                    0 => Some(format!("{}: timed out", name)),
+                    status => Some(format!("{}: failed #{}", name, status)),
+                }
+            })
+            .collect();
+        assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+    }
+
+    // NROM with PRG-RAM whose program writes `report` to $6000 and stops
+    fn reporting_rom(report: &[u8]) -> Vec<u8> {
+        #[rustfmt::skip]
+        let mut rom = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0b0000_0010, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let mut prg = vec![0xEA; 0x4000];
+        #[rustfmt::skip]
+        let program = [
+            0xA2, 0x00,                 // LDX #0
+            0xBD, 0x20, 0x80,           // LDA $8020,X
+            0x9D, 0x00, 0x60,           // STA $6000,X
+            0xE8,                       // INX
+            0xE0, report.len() as u8,   // CPX #len
+            0xD0, 0xF5,                 // BNE $8002
+            0x4C, 0x0D, 0x80,           // JMP $800D
+        ];
+        prg[..program.len()].copy_from_slice(&program);
+        prg[0x20..0x20 + report.len()].copy_from_slice(report);
+        prg[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0x80]);
+        rom.extend_from_slice(&prg);
+        rom.resize(rom.len() + 0x2000, 0);
+        rom
+    }
+
+    // NROM whose program stores `status` to $F8 and stops
+    fn legacy_rom(status: u8) -> Vec<u8> {
+        let mut rom = reporting_rom(&[]);
+        #[rustfmt::skip]
+        let program = [
+            0xA9, status,               // LDA #status
+            0x85, 0xF8,                 // STA $F8
+            0x4C, 0x04, 0x80,           // JMP $8004
+        ];
+        rom[0x10..0x10 + program.len()].copy_from_slice(&program);
+        rom
+    }
+
+    #[test]
+    fn test_run() {
+        #[rustfmt::skip]
+        let cases: [(&str, &[u8], Option<u8>, &str); 4] = [
+            ("passed", b"\x00\xDE\xB0\x61Passed\n\x00", Some(0), "Passed\n"),
+            ("failed", b"\x03\xDE\xB0\x61Failed #3\x00", Some(3), "Failed #3"),
+            ("running", b"\x80\xDE\xB0\x61Running\x00", None, "Running"),
+            ("no signature", b"\x00\x00\x00\x00\x00", None, ""),
+        ];
+
+        for (name, report, status, text) in cases {
+            let result = run(&reporting_rom(report));
+            assert_eq!(result.status, status, "{}", name);
+            assert_eq!(result.text, text, "{}", name);
+            assert_eq!(result.passed(), status == Some(0), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_monitor_reset() {
+        let mut emu = Emu::new();
+        emu.load_rom(&reporting_rom(b"\x81\xDE\xB0\x61")).unwrap();
+        emu.set_headless(true);
+        let mut monitor = TestRomMonitor::new();
+        for _ in 0..=RESET_DELAY_FRAMES {
+            emu.run_frame();
+            assert_eq!(emu.nes.cpu.pc, 0x800D);
+            assert_eq!(monitor.poll(&mut emu), None);
+        }
+        // back at the reset vector
+        assert_eq!(emu.nes.cpu.pc, 0x8000);
+    }
+
+    #[test]
+    fn test_run_legacy() {
+        #[rustfmt::skip]
+        let cases = [
+            ("passed", 1),
+            ("failed", 4),
+        ];
+
+        for (name, status) in cases {
+            assert_eq!(run_legacy(&legacy_rom(status)), status, "{}", name);
+        }
+    }
+
+    // nestest's automated mode, started at $C000, leaves the number of the first
+    // failing official test in $02 and unofficial one in $03
+    #[test]
+    fn test_nestest() {
+        let mut emu = Emu::new();
+        emu.load_rom_path("roms/nestest.nes").unwrap();
+        let mut r = emu.nes.cpu.registers();
+        r.pc = 0xC000;
+        emu.nes.cpu.set_registers(r);
+        // it ends with an RTS from the bottom of the stack, after 8991 instructions
+        for _ in 0..10_000 {
+            if emu.nes.cpu.pc == 0x0001 {
+                break;
+            }
+            emu.step_instruction();
+        }
+        assert_eq!(emu.nes.cpu.pc, 0x0001);
+        assert_eq!([emu.peek(0x0002), emu.peek(0x0003)], [0, 0]);
+    }
+
+    #[test]
+    #[ignore = "needs instr_test-v5 in roms/"]
+    fn test_instr_test() {
+        assert_pass(
+            "instr_test-v5/rom_singles",
+            &[
+                "01-basics.nes",
+                "02-implied.nes",
+                "03-immediate.nes",
+                "04-zero_page.nes",
+                "05-zp_xy.nes",
+                "06-absolute.nes",
+                "07-abs_xy.nes",
+                "08-ind_x.nes",
+                "09-ind_y.nes",
+                "10-branches.nes",
+                "11-stack.nes",
+                "12-jmp_jsr.nes",
+                "13-rts.nes",
+                "14-rti.nes",
+                "15-brk.nes",
+                "16-special.nes",
+            ],
+        );
+    }
+
+    #[test]
+    #[ignore = "needs instr_timing in roms/"]
+    fn test_instr_timing() {
+        assert_pass(
+            "instr_timing/rom_singles",
+            &["1-instr_timing.nes", "2-branch_timing.nes"],
+        );
+    }
+
+    #[test]
+    #[ignore = "needs cpu_interrupts_v2 in roms/"]
+    fn test_cpu_interrupts() {
+        assert_pass(
+            "cpu_interrupts_v2/rom_singles",
+            &[
+                "1-cli_latency.nes",
+                "2-nmi_and_brk.nes",
+                "3-nmi_and_irq.nes",
+                "4-irq_and_dma.nes",
+                "5-branch_delays_irq.nes",
+            ],
+        );
+    }
+
+    // One ROM per implemented mapper, named for its mapper number, PRG and CHR
+    // sizes (CR for CHR-RAM) and fixed mirroring
+    #[test]
+    #[ignore = "needs holy mapperel in roms/"]
+    fn test_holy_mapperel() {
+        assert_pass(
+            "holy_mapperel",
+            &[
+                "M0_P32K_C8K_V.nes",
+                "M0_P32K_CR8K_V.nes",
+                "M2_P128K_V.nes",
+                "M2_P128K_CR8K_V.nes",
+                "M3_P32K_C32K_H.nes",
+                "M4_P128K_CR8K.nes",
+                "M4_P256K_C256K.nes",
+                "M7_P128K.nes",
+                "M7_P128K_CR8K.nes",
+                "M30_P512K_CR32K.nes",
+                "M64_P64K_C64K_V.nes",
+                "M105_P256K.nes",
+                "M118_P128K_C64K.nes",
+                "M119_P128K_C64K.nes",
+            ],
+        );
+    }
+
+    #[test]
+    #[ignore = "needs sprite_hit_tests_2005.10.05 in roms/"]
+    fn test_sprite_hit() {
+        assert_pass_legacy(
+            "sprite_hit_tests_2005.10.05",
+            &[
+                "01.basics.nes",
+                "02.alignment.nes",
+                "03.corners.nes",
+                "04.flip.nes",
+                "05.left_clip.nes",
+                "06.right_edge.nes",
+                "07.screen_bottom.nes",
+                "08.double_height.nes",
+                "09.timing_basics.nes",
+                "10.timing_order.nes",
+                "11.edge_timing.nes",
+            ],
+        );
+    }
+
+    #[test]
+    #[ignore = "needs sprite_overflow_tests in roms/"]
+    fn test_sprite_overflow() {
+        assert_pass_legacy(
+            "sprite_overflow_tests",
+            &[
+                "1.Basics.nes",
+                "2.Details.nes",
+                "3.Timing.nes",
+                "4.Obscure.nes",
+                "5.Emulator.nes",
+            ],
+        );
+    }
+}
@@ -0,0 +1,149 @@
+// `tracing` instrumentation, behind the "tracing" feature. Without it every
+// function here is an empty inline one, so call sites need no cfg of their own.
+//
+// Frames are spans at DEBUG, hook events and bank switches at DEBUG and register
+// writes at TRACE, under the korones::frame, korones::event, korones::ppu,
+// korones::apu and korones::mapper targets.
+
+use crate::hook::Event;
+#[cfg(feature = "tracing")]
+use crate::mapper::Mapper;
+use crate::nes::Nes;
+
+#[cfg(feature = "tracing")]
+pub(crate) type FrameSpan = tracing::span::EnteredSpan;
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct FrameSpan;
+
+#[cfg(feature = "tracing")]
+pub(crate) fn frame(frame: u64) -> FrameSpan {
+    tracing::debug_span!(target: "korones::frame", "frame", frame).entered()
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub(crate) fn frame(_frame: u64) -> FrameSpan {
+    FrameSpan
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn event(event: Event) {
+    tracing::debug!(target: "korones::event", ?event);
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub(crate) fn event(_event: Event) {}
+
+// A CPU write to a PPU, APU/IO or cartridge register. Stores to the board's
+// PRG-RAM are data, not registers, and would drown out the rest.
+#[cfg(feature = "tracing")]
+pub(crate) fn register_write(nes: &Nes, addr: u16, value: u8) {
+    match addr {
+        0x2000..=0x3FFF => {
+            tracing::trace!(target: "korones::ppu", addr = addr & 0x2007, value, "register write")
+        }
+        0x4000..=0x401F => {
+            tracing::trace!(target: "korones::apu", addr, value, "register write")
+        }
+        0x4020..=0xFFFF if nes.mapper.prg_ram_offset(addr).is_none() => {
+            tracing::trace!(target: "korones::mapper", addr, value, "register write")
+        }
+        _ => {}
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub(crate) fn register_write(_nes: &Nes, _addr: u16, _value: u8) {}
+
+// The 4KB window at `addr` now shows PRG-ROM from `offset`, or is read through
+// the board if None
+#[cfg(feature = "tracing")]
+pub(crate) fn bank_switch(addr: u16, offset: Option<usize>) {
+    tracing::debug!(target: "korones::mapper", addr, ?offset, "bank switch");
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub(crate) fn bank_switch(_addr: u16, _offset: Option<usize>) {}
+
+#[cfg(all(test, feature = "tracing"))]
+mod test {
+    use super::*;
+    use crate::config::Region;
+    use crate::cpu::CpuBus;
+    use crate::nes::SystemBus;
+    use crate::rom::Cartridge;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Metadata, Subscriber};
+
+    // Collects the messages of korones::mapper events
+    struct Collector(Arc<Mutex<Vec<String>>>);
+
+    struct Message(String);
+
+    impl Visit for Message {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                self.0 = format!("{:?}", value);
+            }
+        }
+    }
+
+    impl Subscriber for Collector {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, _span: &Attributes<'_>) -> Id {
+            Id::from_u64(1)
+        }
+        fn record(&self, _span: &Id, _values: &Record<'_>) {}
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+        fn event(&self, event: &tracing::Event<'_>) {
+            if event.metadata().target() == "korones::mapper" {
+                let mut message = Message(String::new());
+                event.record(&mut message);
+                self.0.lock().unwrap().push(message.0);
+            }
+        }
+        fn enter(&self, _span: &Id) {}
+        fn exit(&self, _span: &Id) {}
+    }
+
+    #[test]
+    fn test_mapper_events() {
+        // MMC3 with 8 PRG banks and PRG-RAM
+        #[rustfmt::skip]
+        let mut rom = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x04, 0x01, 0x40, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        rom.resize(16 + 0x12000, 0);
+        let cart = Cartridge::from_bytes(&rom).unwrap();
+        let mut nes = Nes::new();
+        nes.load_cartridge(&cart, Region::Ntsc).unwrap();
+
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        tracing::subscriber::with_default(Collector(messages.clone()), || {
+            SystemBus::write(&mut nes, 0x6000, 1);
+            // R6 = 5 switches $8000-$9FFF, two 4KB windows
+            SystemBus::write(&mut nes, 0x8000, 6);
+            SystemBus::write(&mut nes, 0x8001, 5);
+            // the same bank again switches nothing
+            SystemBus::write(&mut nes, 0x8001, 5);
+        });
+        assert_eq!(
+            *messages.lock().unwrap(),
+            [
+                "register write",
+                "register write",
+                "bank switch",
+                "bank switch",
+                "register write",
+            ]
+        );
+    }
+}
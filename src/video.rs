@@ -0,0 +1,151 @@
+mod scale;
+mod worker;
+#[cfg(feature = "dump")]
+mod y4m;
+
+pub use scale::{scale_frame, Scale};
+pub(crate) use worker::VideoWorker;
+#[cfg(feature = "dump")]
+pub(crate) use y4m::Y4mWriter;
+
+// Receives each finished frame as 0xFFRRGGBB pixels, row by row
+pub trait VideoSink {
+    fn frame(&mut self, pixels: &[u32], width: usize, height: usize);
+}
+
+// A sink which keeps a copy of the last frame
+#[derive(Debug, Clone, Default)]
+pub struct FrameBuffer {
+    pixels: Vec<u32>,
+    width: usize,
+    height: usize,
+    frames: u64,
+}
+
+impl FrameBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Empty until the first frame arrives
+    pub fn pixels(&self) -> &[u32] {
+        &self.pixels
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // Number of frames received
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+}
+
+impl VideoSink for FrameBuffer {
+    fn frame(&mut self, pixels: &[u32], width: usize, height: usize) {
+        self.pixels.clear();
+        self.pixels.extend_from_slice(pixels);
+        self.width = width;
+        self.height = height;
+        self.frames += 1;
+    }
+}
+
+const ROW: usize = 256;
+
+// 2C02 colors
+#[rustfmt::skip]
+const PALETTE: [u32; 64] = [
+    0x626262, 0x001FB2, 0x2404C8, 0x5200B2, 0x730076, 0x800024, 0x730B00, 0x522800,
+    0x244400, 0x005700, 0x005C00, 0x005324, 0x003C76, 0x000000, 0x000000, 0x000000,
+    0xABABAB, 0x0D57FF, 0x4B30FF, 0x8A13FF, 0xBC08D6, 0xD21269, 0xC72E00, 0x9D5400,
+    0x607B00, 0x209800, 0x00A300, 0x009942, 0x007DB4, 0x000000, 0x000000, 0x000000,
+    0xFFFFFF, 0x53AEFF, 0x9085FF, 0xD365FF, 0xFF57FF, 0xFF5DCF, 0xFF7757, 0xFA9E00,
+    0xBDC700, 0x7AE700, 0x43F611, 0x26EF7E, 0x2CD5F6, 0x4E4E4E, 0x000000, 0x000000,
+    0xFFFFFF, 0xB6E1FF, 0xCED1FF, 0xE9C3FF, 0xFFBCFF, 0xFFBDF4, 0xFFC6C3, 0xFFD59A,
+    0xE9E681, 0xCEF481, 0xB6FB9A, 0xA9FAC3, 0xA9F0F4, 0xB8B8B8, 0x000000, 0x000000,
+];
+
+// Every PPU pixel (palette index | emphasis << 6) as 0xFFRRGGBB. Emphasized
+// channels keep their level while the others are dimmed.
+const RGBA: [u32; 512] = {
+    let mut table = [0; 512];
+    let mut pixel = 0;
+    while pixel < 512 {
+        let rgb = PALETTE[pixel & 0x3F];
+        // emphasis bits are red, green, blue from the lowest
+        let emphasis = pixel >> 6;
+        let mut argb = 0xFF00_0000;
+        let mut channel = 0;
+        while channel < 3 {
+            let shift = 16 - 8 * channel;
+            let c = (rgb >> shift) & 0xFF;
+            let c = if emphasis == 0 || emphasis & (1 << channel) != 0 {
+                c
+            } else {
+                c * 3 / 4
+            };
+            argb |= c << shift;
+            channel += 1;
+        }
+        table[pixel] = argb;
+        pixel += 1;
+    }
+    table
+};
+
+pub(crate) fn to_rgb(pixel: u16) -> u32 {
+    RGBA[pixel as usize & 0x1FF]
+}
+
+// A row of pixels at a time, which the compiler unrolls and keeps free of
+// bounds checks
+pub(crate) fn convert(buffer: &[u16], out: &mut Vec<u32>) {
+    out.resize(buffer.len(), 0);
+    let mut rows = out.chunks_exact_mut(ROW).zip(buffer.chunks_exact(ROW));
+    for (out, pixels) in &mut rows {
+        for (o, &p) in out.iter_mut().zip(pixels) {
+            *o = RGBA[p as usize & 0x1FF];
+        }
+    }
+    let start = buffer.len() / ROW * ROW;
+    for (o, &p) in out[start..].iter_mut().zip(&buffer[start..]) {
+        *o = RGBA[p as usize & 0x1FF];
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_to_rgb() {
+        #[rustfmt::skip]
+        let cases = [
+            ("black",          0x0F,              0xFF00_0000),
+            ("white",          0x30,              0xFFFF_FFFF),
+            ("red emphasis",   0x30 | 0b001 << 6, 0xFFFF_BFBF),
+            ("all emphasis",   0x30 | 0b111 << 6, 0xFFFF_FFFF),
+        ];
+
+        for (name, pixel, expected) in cases {
+            assert_eq!(to_rgb(pixel), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_convert() {
+        let buffer: Vec<u16> = (0..600).map(|i| (i * 7) as u16 & 0x1FF).collect();
+        let mut out = vec![1; 3];
+        convert(&buffer, &mut out);
+        assert_eq!(out.len(), buffer.len());
+        for (i, (&p, &o)) in buffer.iter().zip(&out).enumerate() {
+            assert_eq!(o, to_rgb(p), "{}", i);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+// Enlarging frames for display, nearest neighbor: whole copies of each pixel,
+// optionally widened to the 8:7 pixels of NTSC televisions.
+
+// How to enlarge a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scale {
+    // Copies of each pixel down and, before aspect correction, across
+    pub factor: usize,
+    // Widen pixels to 8:7, as televisions showed them
+    pub aspect: bool,
+}
+
+impl Scale {
+    pub fn new(factor: usize, aspect: bool) -> Self {
+        Self { factor, aspect }
+    }
+
+    // The largest factor, at least 1, at which a `width` x `height` frame fits in
+    // a `max_width` x `max_height` window
+    pub fn fit(
+        width: usize,
+        height: usize,
+        max_width: usize,
+        max_height: usize,
+        aspect: bool,
+    ) -> Self {
+        let mut scale = Self::new(1, aspect);
+        if width == 0 || height == 0 {
+            return scale;
+        }
+        loop {
+            let next = Self::new(scale.factor + 1, aspect);
+            let (w, h) = next.output_size(width, height);
+            if max_width < w || max_height < h {
+                return scale;
+            }
+            scale = next;
+        }
+    }
+
+    // Size of a `width` x `height` frame scaled, widths rounding to the nearest
+    // pixel under aspect correction
+    pub fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+        let w = width * self.factor;
+        let w = if self.aspect { (w * 8 + 3) / 7 } else { w };
+        (w, height * self.factor)
+    }
+}
+
+// Scale `pixels`, a `width` x `height` frame as delivered to a `VideoSink`, into
+// `out`, which is resized to `scale.output_size`
+pub fn scale_frame(pixels: &[u32], width: usize, height: usize, scale: Scale, out: &mut Vec<u32>) {
+    let (out_width, out_height) = scale.output_size(width, height);
+    out.resize(out_width * out_height, 0);
+    if out.is_empty() {
+        return;
+    }
+    let rows = pixels.chunks_exact(width).take(height);
+    for (row, band) in rows.zip(out.chunks_exact_mut(out_width * scale.factor)) {
+        let (first, rest) = band.split_at_mut(out_width);
+        for (x, o) in first.iter_mut().enumerate() {
+            *o = row[x * width / out_width];
+        }
+        for copy in rest.chunks_exact_mut(out_width) {
+            copy.copy_from_slice(first);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scale_frame() {
+        let mut out = vec![9; 5];
+        scale_frame(&[1, 2, 3, 4], 2, 2, Scale::new(2, false), &mut out);
+        #[rustfmt::skip]
+        let expected = [
+            1, 1, 2, 2,
+            1, 1, 2, 2,
+            3, 3, 4, 4,
+            3, 3, 4, 4,
+        ];
+        assert_eq!(out, expected);
+
+        // 7 pixels widen to 8, one of them twice as wide
+        let row: Vec<u32> = (0..7).collect();
+        scale_frame(&row, 7, 1, Scale::new(1, true), &mut out);
+        assert_eq!(out, [0, 0, 1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_output_size() {
+        #[rustfmt::skip]
+        let cases = [
+            ("1x",          Scale::new(1, false), (256, 240)),
+            ("3x",          Scale::new(3, false), (768, 720)),
+            ("1x aspect",   Scale::new(1, true),  (293, 240)),
+            ("2x aspect",   Scale::new(2, true),  (585, 480)),
+        ];
+
+        for (name, scale, expected) in cases {
+            assert_eq!(scale.output_size(256, 240), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_fit() {
+        #[rustfmt::skip]
+        let cases = [
+            ("exact",       (768, 720),   false, 3),
+            ("short",       (1920, 1080), false, 4),
+            ("aspect",      (1170, 1080), true,  4),
+            ("too narrow",  (1169, 1080), true,  3),
+            ("too small",   (100, 100),   false, 1),
+        ];
+
+        for (name, (w, h), aspect, expected) in cases {
+            assert_eq!(
+                Scale::fit(256, 240, w, h, aspect).factor,
+                expected,
+                "{}",
+                name
+            );
+        }
+    }
+}
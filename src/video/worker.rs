@@ -0,0 +1,147 @@
+// Color conversion and the video sink on a thread of their own, overlapping with
+// the emulation of the next frame. Frames are double buffered: one with the
+// worker while the PPU draws the other, so a frame reaches the sink while the
+// one after it runs.
+
+use std::mem;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use super::*;
+
+type Sink = Box<dyn VideoSink + Send>;
+
+// A PPU frame and the pixels it converts into
+#[derive(Default)]
+struct Frame {
+    indexed: Vec<u16>,
+    pixels: Vec<u32>,
+}
+
+enum Job {
+    Convert(Frame, usize, usize),
+    SetSink(Option<Sink>),
+}
+
+pub(crate) struct VideoWorker {
+    jobs: Option<Sender<Job>>,
+    done: Receiver<Frame>,
+    thread: Option<JoinHandle<Option<Sink>>>,
+    // Whether a frame is with the worker, otherwise its buffers are spare
+    busy: bool,
+    spare: Frame,
+}
+
+impl VideoWorker {
+    pub(crate) fn new(sink: Option<Sink>) -> Self {
+        let (jobs, rx) = mpsc::channel();
+        let (tx, done) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut sink = sink;
+            for job in rx {
+                match job {
+                    Job::Convert(mut frame, width, height) => {
+                        convert(&frame.indexed, &mut frame.pixels);
+                        if let Some(sink) = &mut sink {
+                            sink.frame(&frame.pixels, width, height);
+                        }
+                        if tx.send(frame).is_err() {
+                            break;
+                        }
+                    }
+                    Job::SetSink(s) => sink = s,
+                }
+            }
+            sink
+        });
+        Self {
+            jobs: Some(jobs),
+            done,
+            thread: Some(thread),
+            busy: false,
+            spare: Frame::default(),
+        }
+    }
+
+    // Hand a finished PPU frame over, first taking the previous one's pixels
+    // back into `pixels`
+    pub(crate) fn submit(
+        &mut self,
+        indexed: &[u16],
+        width: usize,
+        height: usize,
+        pixels: &mut Vec<u32>,
+    ) {
+        self.wait(pixels);
+        let mut frame = mem::take(&mut self.spare);
+        frame.indexed.clear();
+        frame.indexed.extend_from_slice(indexed);
+        self.send(Job::Convert(frame, width, height));
+        self.busy = true;
+    }
+
+    // The sink for frames submitted from now on
+    pub(crate) fn set_sink(&mut self, sink: Option<Sink>) {
+        self.send(Job::SetSink(sink));
+    }
+
+    // Wait for the frame with the worker, if any, and swap its pixels into
+    // `pixels`
+    pub(crate) fn wait(&mut self, pixels: &mut Vec<u32>) {
+        if !self.busy {
+            return;
+        }
+        let mut frame = self.done.recv().expect("video worker panicked");
+        mem::swap(&mut frame.pixels, pixels);
+        self.spare = frame;
+        self.busy = false;
+    }
+
+    // Wait for the last frame and stop the thread, giving the sink back
+    pub(crate) fn finish(mut self, pixels: &mut Vec<u32>) -> Option<Sink> {
+        self.wait(pixels);
+        self.jobs = None;
+        let thread = self.thread.take()?;
+        thread.join().expect("video worker panicked")
+    }
+
+    fn send(&mut self, job: Job) {
+        if let Some(jobs) = &self.jobs {
+            // the worker only stops early by panicking, which `wait` reports
+            let _ = jobs.send(job);
+        }
+    }
+}
+
+impl Drop for VideoWorker {
+    fn drop(&mut self) {
+        self.jobs = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_worker() {
+        let mut worker = VideoWorker::new(Some(Box::new(FrameBuffer::new())));
+        let mut pixels = Vec::new();
+        worker.submit(&[0x30; 4], 2, 2, &mut pixels);
+        assert!(pixels.is_empty());
+        worker.submit(&[0x0F; 4], 2, 2, &mut pixels);
+        assert_eq!(pixels, [0xFFFF_FFFF; 4]);
+        worker.wait(&mut pixels);
+        assert_eq!(pixels, [0xFF00_0000; 4]);
+
+        let sink = worker.finish(&mut pixels).unwrap();
+        let mut worker = VideoWorker::new(Some(sink));
+        worker.set_sink(None);
+        worker.submit(&[0x30; 4], 2, 2, &mut pixels);
+        assert!(worker.finish(&mut pixels).is_none());
+        assert_eq!(pixels, [0xFFFF_FFFF; 4]);
+    }
+}
@@ -0,0 +1,119 @@
+// Frames as YUV4MPEG2, the uncompressed stream ffmpeg and most video tools read,
+// for diffing the rendering of two runs frame by frame. Pixels are stored 4:4:4
+// in BT.601 studio range, so equal frames make equal bytes.
+
+use std::io::{self, Write};
+
+use anyhow::Result;
+
+use super::convert;
+
+pub(crate) struct Y4mWriter<W: Write> {
+    out: W,
+    // Frames a second, in thousandths
+    rate: u64,
+    started: bool,
+    pixels: Vec<u32>,
+    planes: Vec<u8>,
+    // The first failed write, reported by `finish`; frames after it are dropped
+    error: Option<io::Error>,
+}
+
+impl<W: Write> Y4mWriter<W> {
+    pub(crate) fn new(out: W, frame_rate: f64) -> Self {
+        Self {
+            out,
+            rate: (frame_rate * 1000.0).round() as u64,
+            started: false,
+            pixels: Vec::new(),
+            planes: Vec::new(),
+            error: None,
+        }
+    }
+
+    // Append a frame of PPU pixels; the first one sets the stream's size
+    pub(crate) fn frame(&mut self, buffer: &[u16], width: usize, height: usize) {
+        if self.error.is_some() {
+            return;
+        }
+        convert(buffer, &mut self.pixels);
+        let n = width * height;
+        self.planes.resize(3 * n, 0);
+        for (i, &p) in self.pixels.iter().take(n).enumerate() {
+            let (y, u, v) = yuv(p);
+            self.planes[i] = y;
+            self.planes[n + i] = u;
+            self.planes[2 * n + i] = v;
+        }
+        if let Err(e) = self.write(width, height) {
+            self.error = Some(e);
+        }
+    }
+
+    fn write(&mut self, width: usize, height: usize) -> io::Result<()> {
+        if !self.started {
+            self.started = true;
+            writeln!(
+                self.out,
+                "YUV4MPEG2 W{} H{} F{}:1000 Ip A1:1 C444",
+                width, height, self.rate
+            )?;
+        }
+        self.out.write_all(b"FRAME\n")?;
+        self.out.write_all(&self.planes)
+    }
+
+    // Flush the stream, failing with the first error writing it
+    pub(crate) fn finish(mut self) -> Result<W> {
+        if let Some(e) = self.error {
+            return Err(e.into());
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+// 0xFFRRGGBB to BT.601 Y'CbCr
+fn yuv(pixel: u32) -> (u8, u8, u8) {
+    let [b, g, r, _] = pixel.to_le_bytes();
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    let y = ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16;
+    let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+    let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+    (y as u8, u as u8, v as u8)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_yuv() {
+        #[rustfmt::skip]
+        let cases = [
+            ("black",   0xFF00_0000, (16, 128, 128)),
+            ("white",   0xFFFF_FFFF, (235, 128, 128)),
+            ("red",     0xFFFF_0000, (82, 90, 240)),
+        ];
+
+        for (name, pixel, expected) in cases {
+            assert_eq!(yuv(pixel), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_y4m_writer() {
+        let mut writer = Y4mWriter::new(Vec::new(), 60.0988);
+        // white, then black
+        writer.frame(&[0x30; 2], 2, 1);
+        writer.frame(&[0x0F; 2], 2, 1);
+        let out = writer.finish().unwrap();
+
+        let mut expected = b"YUV4MPEG2 W2 H1 F60099:1000 Ip A1:1 C444\n".to_vec();
+        expected.extend_from_slice(b"FRAME\n");
+        expected.extend_from_slice(&[235, 235, 128, 128, 128, 128]);
+        expected.extend_from_slice(b"FRAME\n");
+        expected.extend_from_slice(&[16, 16, 128, 128, 128, 128]);
+        assert_eq!(out, expected);
+    }
+}